@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS geocoding_places;
+DROP TABLE IF EXISTS factory_locations;
@@ -0,0 +1,21 @@
+-- Coordinates of factories, maintained by the REST API rather than the chain
+CREATE TABLE IF NOT EXISTS factory_locations (
+  organization_id             VARCHAR           PRIMARY KEY,
+  latitude                    DOUBLE PRECISION  NOT NULL,
+  longitude                   DOUBLE PRECISION  NOT NULL,
+  source                      VARCHAR           NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS factory_locations_coordinates_index ON factory_locations (latitude, longitude);
+
+-- Local geocoding data used to locate factories by the city of their address
+CREATE TABLE IF NOT EXISTS geocoding_places (
+  id                          BIGSERIAL         PRIMARY KEY,
+  city                        VARCHAR           NOT NULL,
+  state_province              VARCHAR,
+  country                     VARCHAR           NOT NULL,
+  latitude                    DOUBLE PRECISION  NOT NULL,
+  longitude                   DOUBLE PRECISION  NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS geocoding_places_city_index ON geocoding_places (lower(city), lower(country));
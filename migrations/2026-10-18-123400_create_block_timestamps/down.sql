@@ -0,0 +1,3 @@
+DROP TRIGGER IF EXISTS record_block_timestamp ON blocks;
+DROP FUNCTION IF EXISTS record_block_timestamp();
+DROP TABLE IF EXISTS block_timestamps;
@@ -0,0 +1,21 @@
+-- Seconds since the epoch at which each block was written, recorded by a trigger because
+-- blocks themselves carry no timestamp
+CREATE TABLE IF NOT EXISTS block_timestamps (
+  block_num                   BIGINT            PRIMARY KEY,
+  committed_at                BIGINT            NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS block_timestamps_committed_at_index ON block_timestamps (committed_at);
+
+CREATE OR REPLACE FUNCTION record_block_timestamp() RETURNS TRIGGER AS $$
+BEGIN
+  INSERT INTO block_timestamps (block_num, committed_at)
+  VALUES (NEW.block_num, extract(epoch FROM now())::BIGINT)
+  ON CONFLICT (block_num) DO UPDATE SET committed_at = EXCLUDED.committed_at;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS record_block_timestamp ON blocks;
+CREATE TRIGGER record_block_timestamp AFTER INSERT ON blocks
+  FOR EACH ROW EXECUTE PROCEDURE record_block_timestamp();
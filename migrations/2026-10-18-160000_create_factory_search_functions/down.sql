@@ -0,0 +1,2 @@
+DROP FUNCTION IF EXISTS factory_search_score(VARCHAR, BIGINT, VARCHAR, VARCHAR, REAL, REAL, REAL, REAL);
+DROP FUNCTION IF EXISTS distance_km(DOUBLE PRECISION, DOUBLE PRECISION, DOUBLE PRECISION, DOUBLE PRECISION);
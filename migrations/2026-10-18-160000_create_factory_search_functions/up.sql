@@ -0,0 +1,60 @@
+-- Great-circle distance between two points, matching `Point::distance_km`
+CREATE OR REPLACE FUNCTION distance_km(
+  from_latitude DOUBLE PRECISION,
+  from_longitude DOUBLE PRECISION,
+  to_latitude DOUBLE PRECISION,
+  to_longitude DOUBLE PRECISION
+) RETURNS DOUBLE PRECISION AS $$
+  SELECT 2 * 6371.0 * asin(sqrt(
+    sin(radians(to_latitude - from_latitude) / 2) ^ 2
+    + cos(radians(from_latitude)) * cos(radians(to_latitude))
+      * sin(radians(to_longitude - from_longitude) / 2) ^ 2
+  ))
+$$ LANGUAGE SQL IMMUTABLE;
+
+-- Score of a factory matching a `/api/factories?search=`. Each kind of match scores its
+-- full-text rank plus its trigram similarity, multiplied by the kind's weight, and only the
+-- best matching standard the factory is certified for counts.
+CREATE OR REPLACE FUNCTION factory_search_score(
+  scored_factory_id VARCHAR,
+  head_block_num BIGINT,
+  search_text VARCHAR,
+  search_query VARCHAR,
+  min_similarity REAL,
+  name_weight REAL,
+  address_weight REAL,
+  standard_weight REAL
+) RETURNS REAL AS $$
+  SELECT (
+    name_weight * COALESCE((
+      SELECT SUM(ts_rank(to_tsvector(o.name), to_tsquery(search_query))
+                 + similarity(o.name, search_text))
+      FROM organizations o
+      WHERE o.organization_id = scored_factory_id
+        AND o.start_block_num <= head_block_num AND o.end_block_num > head_block_num
+        AND (to_tsvector(o.name) @@ to_tsquery(search_query)
+             OR similarity(o.name, search_text) > min_similarity)
+    ), 0)
+    + address_weight * COALESCE((
+      SELECT SUM(ts_rank(a.text_searchable_address_col, to_tsquery(search_query))
+                 + similarity(COALESCE(a.full_address, ''), search_text))
+      FROM addresses a
+      WHERE a.organization_id = scored_factory_id
+        AND a.start_block_num <= head_block_num AND a.end_block_num > head_block_num
+        AND (a.text_searchable_address_col @@ to_tsquery(search_query)
+             OR similarity(a.full_address, search_text) > min_similarity)
+    ), 0)
+    + standard_weight * COALESCE((
+      SELECT MAX(ts_rank(to_tsvector(s.name), to_tsquery(search_query))
+                 + similarity(s.name, search_text))
+      FROM certificates c
+      JOIN standards s
+        ON s.standard_id = c.standard_id
+       AND s.start_block_num <= head_block_num AND s.end_block_num > head_block_num
+      WHERE c.factory_id = scored_factory_id
+        AND c.start_block_num <= head_block_num AND c.end_block_num > head_block_num
+        AND (to_tsvector(s.name) @@ to_tsquery(search_query)
+             OR similarity(s.name, search_text) > min_similarity)
+    ), 0)
+  )::REAL
+$$ LANGUAGE SQL STABLE;
@@ -0,0 +1 @@
+DROP FUNCTION IF EXISTS certificate_search_score(VARCHAR, VARCHAR, VARCHAR, BIGINT, VARCHAR, VARCHAR, REAL);
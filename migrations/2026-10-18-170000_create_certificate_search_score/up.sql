@@ -0,0 +1,36 @@
+-- Score of a certificate matching an `/api/search`. Certificates have no name of their own, so
+-- a certificate whose id is the search scores 1, and the rest score the trigram similarity of
+-- the better of the factory holding them and the standard they certify, where those match.
+CREATE OR REPLACE FUNCTION certificate_search_score(
+  scored_certificate_id VARCHAR,
+  scored_factory_id VARCHAR,
+  scored_standard_id VARCHAR,
+  head_block_num BIGINT,
+  search_text VARCHAR,
+  search_query VARCHAR,
+  min_similarity REAL
+) RETURNS REAL AS $$
+  SELECT (
+    CASE WHEN scored_certificate_id = search_text THEN 1.0
+    ELSE COALESCE(GREATEST(
+      (
+        SELECT MAX(similarity(o.name, search_text))
+        FROM organizations o
+        WHERE o.organization_id = scored_factory_id
+          AND o.organization_type = 'FACTORY'
+          AND o.start_block_num <= head_block_num AND o.end_block_num > head_block_num
+          AND (to_tsvector(o.name) @@ to_tsquery(search_query)
+               OR similarity(o.name, search_text) > min_similarity)
+      ),
+      (
+        SELECT MAX(similarity(s.name, search_text))
+        FROM standards s
+        WHERE s.standard_id = scored_standard_id
+          AND s.start_block_num <= head_block_num AND s.end_block_num > head_block_num
+          AND (to_tsvector(s.name) @@ to_tsquery(search_query)
+               OR similarity(s.name, search_text) > min_similarity)
+      )
+    ), 0)
+    END
+  )::REAL
+$$ LANGUAGE SQL STABLE;
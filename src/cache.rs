@@ -0,0 +1,184 @@
+use errors::ApiError;
+use rocket_contrib::json::JsonValue;
+use route_handlers::prom::increment_cache_lookup;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::{Mutex, MutexGuard};
+
+/// Responses cached when `RESPONSE_CACHE_SIZE` is not set
+pub const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+lazy_static! {
+    /// Responses of hot list queries, configured through the environment and cleared by the
+    /// `BlockWatcher` whenever a block is committed, and whenever factory locations change
+    pub static ref RESPONSE_CACHE: ResponseCache = ResponseCache::from_env();
+}
+
+/// A route, its query parameters and the block it was answered at, which together fully
+/// determine a response
+type CacheKey = (&'static str, String, i64);
+
+/// An in-process cache of responses that holds at most `max_entries` of them, evicting the
+/// oldest entry first. A `max_entries` of 0 disables caching.
+pub struct ResponseCache {
+    max_entries: usize,
+    entries: Mutex<CacheEntries>,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    responses: HashMap<CacheKey, JsonValue>,
+    /// Keys in the order they were inserted
+    order: VecDeque<CacheKey>,
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: usize) -> Self {
+        ResponseCache {
+            max_entries,
+            entries: Mutex::new(CacheEntries::default()),
+        }
+    }
+
+    /// Reads `RESPONSE_CACHE_SIZE`, falling back to the default for unset or invalid values
+    fn from_env() -> Self {
+        let max_entries = match env::var("RESPONSE_CACHE_SIZE") {
+            Ok(value) => value.parse().unwrap_or_else(|_| {
+                warn!(
+                    "Invalid RESPONSE_CACHE_SIZE {}, using {}",
+                    value, DEFAULT_MAX_ENTRIES
+                );
+                DEFAULT_MAX_ENTRIES
+            }),
+            Err(_) => DEFAULT_MAX_ENTRIES,
+        };
+        ResponseCache::new(max_entries)
+    }
+
+    /// Returns the cached response of `route` for `params` at `head_block_num`, or loads and
+    /// caches it. Errors are not cached.
+    pub fn get_or_load<F>(
+        &self,
+        route: &'static str,
+        params: String,
+        head_block_num: i64,
+        load: F,
+    ) -> Result<JsonValue, ApiError>
+    where
+        F: FnOnce() -> Result<JsonValue, ApiError>,
+    {
+        if self.max_entries == 0 {
+            return load();
+        }
+
+        let key = (route, params, head_block_num);
+        if let Some(response) = self.lock().responses.get(&key) {
+            increment_cache_lookup(route, "hit");
+            return Ok(response.clone());
+        }
+        increment_cache_lookup(route, "miss");
+
+        // The lock is not held while loading, so slow queries do not block other requests
+        let response = load()?;
+        let mut entries = self.lock();
+        if !entries.responses.contains_key(&key) {
+            while entries.order.len() >= self.max_entries {
+                if let Some(oldest) = entries.order.pop_front() {
+                    entries.responses.remove(&oldest);
+                }
+            }
+            entries.order.push_back(key.clone());
+            entries.responses.insert(key, response.clone());
+        }
+        Ok(response)
+    }
+
+    /// Drops every cached response
+    pub fn invalidate(&self) {
+        let mut entries = self.lock();
+        entries.responses.clear();
+        entries.order.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<CacheEntries> {
+        // A panic while holding the lock cannot leave the entries inconsistent enough to
+        // matter, as they are only a cache
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    /// Test that a response is loaded once per route, parameters and head block
+    fn test_get_or_load() {
+        let cache = ResponseCache::new(10);
+        let loads = Cell::new(0);
+        let load = || {
+            loads.set(loads.get() + 1);
+            Ok(json!({ "data": [] }))
+        };
+
+        cache
+            .get_or_load("factories", "a".to_string(), 1, load)
+            .unwrap();
+        cache
+            .get_or_load("factories", "a".to_string(), 1, load)
+            .unwrap();
+        assert_eq!(loads.get(), 1);
+
+        cache
+            .get_or_load("factories", "a".to_string(), 2, load)
+            .unwrap();
+        cache
+            .get_or_load("factories", "b".to_string(), 1, load)
+            .unwrap();
+        cache
+            .get_or_load("organizations", "a".to_string(), 1, load)
+            .unwrap();
+        assert_eq!(loads.get(), 4);
+
+        cache.invalidate();
+        cache
+            .get_or_load("factories", "a".to_string(), 1, load)
+            .unwrap();
+        assert_eq!(loads.get(), 5);
+    }
+
+    #[test]
+    /// Test that the oldest response is evicted once the cache is full and that errors are
+    /// not cached
+    fn test_eviction() {
+        let cache = ResponseCache::new(2);
+        let loads = Cell::new(0);
+        let load = || {
+            loads.set(loads.get() + 1);
+            Ok(json!({ "data": [] }))
+        };
+
+        for head_block_num in 1..4 {
+            cache
+                .get_or_load("factories", String::new(), head_block_num, load)
+                .unwrap();
+        }
+        cache
+            .get_or_load("factories", String::new(), 3, load)
+            .unwrap();
+        assert_eq!(loads.get(), 3);
+        cache
+            .get_or_load("factories", String::new(), 1, load)
+            .unwrap();
+        assert_eq!(loads.get(), 4);
+
+        let failed = cache.get_or_load("factories", "error".to_string(), 1, || {
+            Err(ApiError::InternalError("failed".to_string()))
+        });
+        assert!(failed.is_err());
+        assert_eq!(cache.lock().responses.len(), 2);
+    }
+}
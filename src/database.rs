@@ -1,13 +1,106 @@
 use diesel::pg::PgConnection;
 use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
 use diesel::sql_function;
-use diesel::sql_types::{Nullable, Text};
+use diesel::sql_types::{BigInt, Float, Nullable, Text};
+use errors::ApiError;
 use rocket::http::Status;
 use rocket::request::{self, FromRequest};
 use rocket::{Outcome, Request, State};
+use std::env;
 use std::ops::Deref;
 
-pub const SIMILARITY_THRESHOLD: f32 = 0.2;
+pub const DEFAULT_SIMILARITY_THRESHOLD: f32 = 0.2;
+pub const DEFAULT_NAME_WEIGHT: f32 = 1.0;
+pub const DEFAULT_ADDRESS_WEIGHT: f32 = 0.5;
+pub const DEFAULT_STANDARD_WEIGHT: f32 = 1.0;
+
+lazy_static! {
+    /// Search settings configured through the environment, read on first use
+    pub static ref SEARCH_SETTINGS: SearchSettings = SearchSettings::from_env();
+}
+
+/// Controls which records fuzzy searches match and how matches are ranked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SearchSettings {
+    /// Minimum trigram similarity, from 0 to 1, for a fuzzy match
+    pub similarity_threshold: f32,
+    /// Weight of matches on a factory's name
+    pub name_weight: f32,
+    /// Weight of matches on a factory's address
+    pub address_weight: f32,
+    /// Weight of matches on the standards a factory is certified for
+    pub standard_weight: f32,
+}
+
+impl Default for SearchSettings {
+    fn default() -> Self {
+        SearchSettings {
+            similarity_threshold: DEFAULT_SIMILARITY_THRESHOLD,
+            name_weight: DEFAULT_NAME_WEIGHT,
+            address_weight: DEFAULT_ADDRESS_WEIGHT,
+            standard_weight: DEFAULT_STANDARD_WEIGHT,
+        }
+    }
+}
+
+impl SearchSettings {
+    /// Reads `SIMILARITY_THRESHOLD`, `SEARCH_NAME_WEIGHT`, `SEARCH_ADDRESS_WEIGHT` and
+    /// `SEARCH_STANDARD_WEIGHT`, falling back to the defaults for unset or invalid values.
+    fn from_env() -> Self {
+        let defaults = SearchSettings::default();
+        let settings = SearchSettings {
+            similarity_threshold: env_f32("SIMILARITY_THRESHOLD", defaults.similarity_threshold),
+            name_weight: env_f32("SEARCH_NAME_WEIGHT", defaults.name_weight),
+            address_weight: env_f32("SEARCH_ADDRESS_WEIGHT", defaults.address_weight),
+            standard_weight: env_f32("SEARCH_STANDARD_WEIGHT", defaults.standard_weight),
+        };
+        settings.validate().unwrap_or_else(|err| {
+            warn!("Invalid search settings, using defaults: {:?}", err);
+            defaults
+        })
+    }
+
+    /// Returns these settings with any values given in a request taking precedence
+    pub fn with_overrides(
+        self,
+        similarity_threshold: Option<f32>,
+        name_weight: Option<f32>,
+        address_weight: Option<f32>,
+        standard_weight: Option<f32>,
+    ) -> Result<Self, ApiError> {
+        SearchSettings {
+            similarity_threshold: similarity_threshold.unwrap_or(self.similarity_threshold),
+            name_weight: name_weight.unwrap_or(self.name_weight),
+            address_weight: address_weight.unwrap_or(self.address_weight),
+            standard_weight: standard_weight.unwrap_or(self.standard_weight),
+        }
+        .validate()
+    }
+
+    fn validate(self) -> Result<Self, ApiError> {
+        if !(self.similarity_threshold >= 0.0 && self.similarity_threshold <= 1.0) {
+            return Err(ApiError::BadRequest(
+                "The similarity threshold must be between 0 and 1".to_string(),
+            ));
+        }
+        if !(self.name_weight >= 0.0 && self.address_weight >= 0.0 && self.standard_weight >= 0.0) {
+            return Err(ApiError::BadRequest(
+                "Search weights must not be negative".to_string(),
+            ));
+        }
+        Ok(self)
+    }
+}
+
+fn env_f32(name: &str, default: f32) -> f32 {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            warn!("Invalid {} {}, using {}", name, value, default);
+            default
+        }),
+        Err(_) => default,
+    }
+}
 
 sql_function! {
   /// Returns a number that indicates how similar the two arguments are.
@@ -16,6 +109,35 @@ sql_function! {
   fn similarity(x: Nullable<Text>, y: Text) -> Float;
 }
 
+sql_function! {
+  /// Returns the score of a factory for a `/api/factories?search=`, given the search as text
+  /// and as a `tsquery`. Created by the factory search functions migration.
+  fn factory_search_score(
+      factory_id: Text,
+      head_block_num: BigInt,
+      search_text: Text,
+      search_query: Text,
+      min_similarity: Float,
+      name_weight: Float,
+      address_weight: Float,
+      standard_weight: Float
+  ) -> Float;
+}
+
+sql_function! {
+  /// Returns the score of a certificate for an `/api/search`, given the search as text and as
+  /// a `tsquery`. Created by the certificate search score migration.
+  fn certificate_search_score(
+      certificate_id: Text,
+      factory_id: Text,
+      standard_id: Text,
+      head_block_num: BigInt,
+      search_text: Text,
+      search_query: Text,
+      min_similarity: Float
+  ) -> Float;
+}
+
 pub type PgPool = Pool<ConnectionManager<PgConnection>>;
 
 pub fn init_pool(database_url: String) -> PgPool {
@@ -44,3 +166,37 @@ impl Deref for DbConn {
         &self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that request overrides replace only the given settings
+    fn test_search_settings_overrides() {
+        let settings = SearchSettings::default()
+            .with_overrides(Some(0.5), None, Some(2.0), None)
+            .unwrap();
+        assert_eq!(
+            settings,
+            SearchSettings {
+                similarity_threshold: 0.5,
+                name_weight: DEFAULT_NAME_WEIGHT,
+                address_weight: 2.0,
+                standard_weight: DEFAULT_STANDARD_WEIGHT,
+            }
+        );
+    }
+
+    #[test]
+    /// Test that out of range settings are rejected
+    fn test_search_settings_validation() {
+        let settings = SearchSettings::default();
+        assert!(settings
+            .with_overrides(Some(1.5), None, None, None)
+            .is_err());
+        assert!(settings
+            .with_overrides(None, Some(-1.0), None, None)
+            .is_err());
+    }
+}
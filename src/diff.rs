@@ -0,0 +1,183 @@
+use database::DbConn;
+use errors::ApiError;
+use paging::get_head_block_num;
+use rocket_contrib::json::JsonValue;
+use serde_json::Value;
+
+/// A field that differs between two versions of an entity, identified by its JSON Pointer
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum Change {
+    /// A value that was added, removed or replaced
+    Value {
+        path: String,
+        from: Value,
+        to: Value,
+    },
+    /// A list, like contacts or authorizations, that gained or lost entries. The order of
+    /// entries is not significant.
+    List {
+        path: String,
+        added: Vec<Value>,
+        removed: Vec<Value>,
+    },
+}
+
+/// Resolves the `from` and `to` blocks of a diff, where `to` defaults to the latest block.
+/// Both must be committed blocks, like any other requested head.
+pub fn diff_range(
+    from: Option<i64>,
+    to: Option<i64>,
+    conn: &DbConn,
+) -> Result<(i64, i64), ApiError> {
+    let from = from.ok_or_else(|| {
+        ApiError::BadRequest("The block to diff from must be provided with `from`".to_string())
+    })?;
+    let from = get_head_block_num(Some(from), conn)?;
+    let to = get_head_block_num(to, conn)?;
+    if from > to {
+        return Err(ApiError::BadRequest(format!(
+            "The block to diff from ({}) must not be after the block to diff to ({})",
+            from, to
+        )));
+    }
+    Ok((from, to))
+}
+
+/// Diffs two versions of an entity, where `None` means the entity did not exist
+pub fn diff(from: Option<JsonValue>, to: Option<JsonValue>) -> Vec<Change> {
+    let mut changes = vec![];
+    diff_values(
+        "",
+        &from.map_or(Value::Null, |value| value.0),
+        &to.map_or(Value::Null, |value| value.0),
+        &mut changes,
+    );
+    changes
+}
+
+fn diff_values(path: &str, from: &Value, to: &Value, changes: &mut Vec<Change>) {
+    match (from, to) {
+        (Value::Object(from_fields), Value::Object(to_fields)) => {
+            for (name, from_field) in from_fields {
+                let field_path = format!("{}/{}", path, escape_pointer(name));
+                diff_values(
+                    &field_path,
+                    from_field,
+                    to_fields.get(name).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+            for (name, to_field) in to_fields {
+                if !from_fields.contains_key(name) {
+                    let field_path = format!("{}/{}", path, escape_pointer(name));
+                    diff_values(&field_path, &Value::Null, to_field, changes);
+                }
+            }
+        }
+        (Value::Array(from_entries), Value::Array(to_entries)) => {
+            let added: Vec<Value> = to_entries
+                .iter()
+                .filter(|entry| !from_entries.contains(entry))
+                .cloned()
+                .collect();
+            let removed: Vec<Value> = from_entries
+                .iter()
+                .filter(|entry| !to_entries.contains(entry))
+                .cloned()
+                .collect();
+            if !added.is_empty() || !removed.is_empty() {
+                changes.push(Change::List {
+                    path: path.to_string(),
+                    added,
+                    removed,
+                });
+            }
+        }
+        _ => {
+            if from != to {
+                changes.push(Change::Value {
+                    path: path.to_string(),
+                    from: from.clone(),
+                    to: to.clone(),
+                });
+            }
+        }
+    }
+}
+
+/// Escapes a field name for use in a JSON Pointer (RFC 6901)
+fn escape_pointer(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that nested fields are diffed individually and lists are diffed by entry
+    fn test_diff() {
+        let from = json!({
+            "name": "factory",
+            "address": { "city": "Lima", "country": "Peru" },
+            "contacts": [{ "name": "Ana" }, { "name": "Luis" }],
+        });
+        let to = json!({
+            "name": "factory",
+            "address": { "city": "Cusco", "country": "Peru", "postal_code": "08000" },
+            "contacts": [{ "name": "Luis" }, { "name": "Rosa" }],
+        });
+
+        assert_eq!(
+            diff(Some(from), Some(to)),
+            vec![
+                Change::Value {
+                    path: "/address/city".to_string(),
+                    from: json!("Lima").0,
+                    to: json!("Cusco").0,
+                },
+                Change::Value {
+                    path: "/address/postal_code".to_string(),
+                    from: Value::Null,
+                    to: json!("08000").0,
+                },
+                Change::List {
+                    path: "/contacts".to_string(),
+                    added: vec![json!({ "name": "Rosa" }).0],
+                    removed: vec![json!({ "name": "Ana" }).0],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    /// Test that identical versions have no changes, even if lists are reordered
+    fn test_diff_unchanged() {
+        let from = json!({ "contacts": [{ "name": "Ana" }, { "name": "Luis" }] });
+        let to = json!({ "contacts": [{ "name": "Luis" }, { "name": "Ana" }] });
+
+        assert!(diff(Some(from), Some(to)).is_empty());
+    }
+
+    #[test]
+    /// Test that an entity that did not exist at the first block is diffed as a whole
+    fn test_diff_created() {
+        let to = json!({ "name": "factory" });
+
+        assert_eq!(
+            diff(None, Some(to.clone())),
+            vec![Change::Value {
+                path: "".to_string(),
+                from: Value::Null,
+                to: to.0,
+            }]
+        );
+    }
+
+    #[test]
+    /// Test that field names are escaped in paths
+    fn test_escape_pointer() {
+        assert_eq!(escape_pointer("a/b~c"), "a~1b~0c");
+    }
+}
@@ -35,10 +35,11 @@ pub fn service_unavailable() -> JsonValue {
     })
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum ApiError {
     /// Defines the HTTP Errors that the API can return.
     BadRequest(String),
+    Forbidden(String),
     InternalError(String),
     NotFound(String),
     TooManyRequests(String),
@@ -63,6 +64,19 @@ impl<'r> Responder<'r> for ApiError {
                     .to_string(),
                 ))
                 .ok(),
+            ApiError::Forbidden(ref msg) => Response::build()
+                .header(ContentType::JSON)
+                .status(Status::Forbidden)
+                .sized_body(Cursor::new(
+                    json!({
+                        "error": {
+                            "status": Status::Forbidden.code,
+                            "message": format!("Forbidden: {}", msg),
+                        }
+                    })
+                    .to_string(),
+                ))
+                .ok(),
             ApiError::InternalError(ref msg) => Response::build()
                 .header(ContentType::JSON)
                 .status(Status::InternalServerError)
@@ -0,0 +1,400 @@
+use database::DbConn;
+use errors::ApiError;
+use rocket_contrib::json::JsonValue;
+use route_handlers::assertions::load_assertions;
+use route_handlers::certificates::load_certificates;
+use route_handlers::organizations::load_organizations;
+use route_handlers::standards::{load_standards, load_versions};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// How many levels expansions may be nested, e.g. `certificate.standard.versions` is three
+/// levels deep
+pub const MAX_EXPAND_DEPTH: usize = 3;
+
+/// A kind of resource that related resources can be expanded into
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resource {
+    Agent,
+    Assertion,
+    Certificate,
+    Organization,
+    Request,
+    Standard,
+    /// The versions of a standard, which have no relations of their own
+    Versions,
+}
+
+/// A resource related to another, which is expanded under `name` by looking up the id found at
+/// `id_pointer` in the JSON of the other resource
+struct Relation {
+    name: &'static str,
+    resource: Resource,
+    id_pointer: &'static str,
+    /// The pointer and value that the JSON of the other resource must have for it to be related
+    only_if: Option<(&'static str, &'static str)>,
+}
+
+impl Relation {
+    /// The id of the resource related to `item`, if it has one
+    fn related_id<'a>(&self, item: &'a Value) -> Option<&'a str> {
+        if let Some((pointer, value)) = self.only_if {
+            if item.pointer(pointer).and_then(Value::as_str) != Some(value) {
+                return None;
+            }
+        }
+        item.pointer(self.id_pointer).and_then(Value::as_str)
+    }
+}
+
+const AGENT_RELATIONS: &[Relation] = &[Relation {
+    name: "organization",
+    resource: Resource::Organization,
+    id_pointer: "/organization/id",
+    only_if: None,
+}];
+
+/// An assertion is about a factory, a certificate or a standard, so only the expansion that
+/// matches the type of its object is looked up
+const ASSERTION_RELATIONS: &[Relation] = &[
+    Relation {
+        name: "certificate",
+        resource: Resource::Certificate,
+        id_pointer: "/object_id",
+        only_if: Some(("/assertion_type", "Certificate")),
+    },
+    Relation {
+        name: "factory",
+        resource: Resource::Organization,
+        id_pointer: "/object_id",
+        only_if: Some(("/assertion_type", "Factory")),
+    },
+    Relation {
+        name: "standard",
+        resource: Resource::Standard,
+        id_pointer: "/object_id",
+        only_if: Some(("/assertion_type", "Standard")),
+    },
+];
+
+const CERTIFICATE_RELATIONS: &[Relation] = &[
+    Relation {
+        name: "assertion",
+        resource: Resource::Assertion,
+        id_pointer: "/assertion_id",
+        only_if: None,
+    },
+    Relation {
+        name: "certifying_body",
+        resource: Resource::Organization,
+        id_pointer: "/certifying_body_id",
+        only_if: None,
+    },
+    Relation {
+        name: "factory",
+        resource: Resource::Organization,
+        id_pointer: "/factory_id",
+        only_if: None,
+    },
+    Relation {
+        name: "standard",
+        resource: Resource::Standard,
+        id_pointer: "/standard_id",
+        only_if: None,
+    },
+];
+
+const ORGANIZATION_RELATIONS: &[Relation] = &[Relation {
+    name: "assertion",
+    resource: Resource::Assertion,
+    id_pointer: "/assertion_id",
+    only_if: None,
+}];
+
+const REQUEST_RELATIONS: &[Relation] = &[
+    Relation {
+        name: "factory",
+        resource: Resource::Organization,
+        id_pointer: "/factory/id",
+        only_if: None,
+    },
+    Relation {
+        name: "standard",
+        resource: Resource::Standard,
+        id_pointer: "/standard/id",
+        only_if: None,
+    },
+];
+
+const STANDARD_RELATIONS: &[Relation] = &[
+    Relation {
+        name: "assertion",
+        resource: Resource::Assertion,
+        id_pointer: "/assertion_id",
+        only_if: None,
+    },
+    Relation {
+        name: "versions",
+        resource: Resource::Versions,
+        id_pointer: "/standard_id",
+        only_if: None,
+    },
+];
+
+impl Resource {
+    fn relations(self) -> &'static [Relation] {
+        match self {
+            Resource::Agent => AGENT_RELATIONS,
+            Resource::Assertion => ASSERTION_RELATIONS,
+            Resource::Certificate => CERTIFICATE_RELATIONS,
+            Resource::Organization => ORGANIZATION_RELATIONS,
+            Resource::Request => REQUEST_RELATIONS,
+            Resource::Standard => STANDARD_RELATIONS,
+            Resource::Versions => &[],
+        }
+    }
+
+    fn relation(self, name: &str) -> Option<&'static Relation> {
+        self.relations()
+            .iter()
+            .find(|relation| relation.name == name)
+    }
+
+    /// Loads the resources with the given ids as they were at `head_block_num`, keyed by id
+    fn load(
+        self,
+        conn: &DbConn,
+        ids: &[String],
+        head_block_num: i64,
+    ) -> Result<HashMap<String, JsonValue>, ApiError> {
+        match self {
+            Resource::Assertion => load_assertions(conn, ids, head_block_num),
+            Resource::Certificate => load_certificates(conn, ids, head_block_num),
+            Resource::Organization => load_organizations(conn, ids, head_block_num),
+            Resource::Standard => load_standards(conn, ids, head_block_num),
+            Resource::Versions => load_versions(conn, ids, head_block_num),
+            Resource::Agent | Resource::Request => {
+                unreachable!("No relation refers to {:?}", self)
+            }
+        }
+    }
+}
+
+/// The related resources to inline into a resource, parsed from the `expand` query parameter:
+/// a comma separated list of relations, each of which may be followed by the relations to
+/// expand within it, e.g. `factory.assertion,standard.versions`
+#[derive(Debug, PartialEq)]
+pub struct Expansions {
+    resource: Resource,
+    nested: BTreeMap<&'static str, Expansions>,
+}
+
+impl Expansions {
+    fn new(resource: Resource) -> Self {
+        Expansions {
+            resource,
+            nested: BTreeMap::new(),
+        }
+    }
+
+    /// Parses `expand` for `resource`, rejecting relations the resource does not have and
+    /// paths nested deeper than `MAX_EXPAND_DEPTH`
+    pub fn parse(resource: Resource, expand: &Option<String>) -> Result<Self, ApiError> {
+        let mut expansions = Expansions::new(resource);
+        let paths = expand
+            .as_ref()
+            .map_or("", String::as_str)
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty());
+        for path in paths {
+            let names: Vec<&str> = path.split('.').collect();
+            if names.len() > MAX_EXPAND_DEPTH {
+                return Err(ApiError::BadRequest(format!(
+                    "Cannot expand {}, expansions can be nested at most {} levels deep",
+                    path, MAX_EXPAND_DEPTH
+                )));
+            }
+            expansions.insert(path, &names)?;
+        }
+        Ok(expansions)
+    }
+
+    fn insert(&mut self, path: &str, names: &[&str]) -> Result<(), ApiError> {
+        let (name, rest) = match names.split_first() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        let relation = self.resource.relation(name).ok_or_else(|| {
+            let valid: Vec<&str> = self
+                .resource
+                .relations()
+                .iter()
+                .map(|relation| relation.name)
+                .collect();
+            if valid.is_empty() {
+                // Only nested resources lack relations, so the path has a parent
+                let parent = path.rsplitn(names.len() + 1, '.').last().unwrap_or(path);
+                ApiError::BadRequest(format!(
+                    "Cannot expand {}, {} has nothing to expand",
+                    path, parent
+                ))
+            } else {
+                ApiError::BadRequest(format!(
+                    "Cannot expand {}, {} is not one of: {}",
+                    path,
+                    name,
+                    valid.join(", ")
+                ))
+            }
+        })?;
+        self.nested
+            .entry(relation.name)
+            .or_insert_with(|| Expansions::new(relation.resource))
+            .insert(path, rest)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nested.is_empty()
+    }
+
+    /// Inlines the related resources of every resource in `data` as they were at
+    /// `head_block_num`. Each relation is loaded for all of `data` at once, so the number of
+    /// queries does not grow with the number of resources.
+    pub fn apply(
+        &self,
+        conn: &DbConn,
+        data: &mut [Value],
+        head_block_num: i64,
+    ) -> Result<(), ApiError> {
+        for (name, nested) in &self.nested {
+            let relation = self
+                .resource
+                .relation(name)
+                .expect("Expansions only contain relations of their resource");
+
+            let mut ids: Vec<String> = data
+                .iter()
+                .filter_map(|item| relation.related_id(item))
+                .map(String::from)
+                .collect();
+            ids.sort();
+            ids.dedup();
+            if ids.is_empty() {
+                continue;
+            }
+
+            let (ids, mut related): (Vec<String>, Vec<Value>) = relation
+                .resource
+                .load(conn, &ids, head_block_num)?
+                .into_iter()
+                .map(|(id, value)| (id, value.0))
+                .unzip();
+            nested.apply(conn, &mut related, head_block_num)?;
+            let related: HashMap<String, Value> = ids.into_iter().zip(related).collect();
+
+            for item in data.iter_mut() {
+                let value = relation
+                    .related_id(item)
+                    .and_then(|id| related.get(id))
+                    .cloned();
+                if let (Some(value), Some(object)) = (value, item.as_object_mut()) {
+                    object.insert(name.to_string(), value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The expansions as they are written in the `expand` query parameter
+    pub fn to_param(&self) -> String {
+        let mut paths = Vec::new();
+        for (name, nested) in &self.nested {
+            if nested.is_empty() {
+                paths.push(name.to_string());
+            } else {
+                for path in nested.to_param().split(',') {
+                    paths.push(format!("{}.{}", name, path));
+                }
+            }
+        }
+        paths.join(",")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that nested relations are parsed into a tree and written back as paths
+    fn test_parse_expansions() {
+        let expansions = Expansions::parse(
+            Resource::Certificate,
+            &Some("standard.versions, factory.assertion,factory,standard".to_string()),
+        )
+        .unwrap();
+
+        assert_eq!(expansions.nested.len(), 2);
+        assert_eq!(
+            expansions.nested["factory"].resource,
+            Resource::Organization
+        );
+        assert_eq!(expansions.nested["standard"].resource, Resource::Standard);
+        assert_eq!(expansions.to_param(), "factory.assertion,standard.versions");
+
+        assert!(Expansions::parse(Resource::Agent, &None)
+            .unwrap()
+            .is_empty());
+        assert!(Expansions::parse(Resource::Agent, &Some(" , ".to_string()))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    /// Test that an assertion is only related to the resource of its type
+    fn test_assertion_related_id() {
+        let assertion = json!({ "assertion_type": "Standard", "object_id": "standard_1" }).0;
+        let relation = |name| Resource::Assertion.relation(name).unwrap();
+
+        assert_eq!(
+            relation("standard").related_id(&assertion),
+            Some("standard_1")
+        );
+        assert_eq!(relation("factory").related_id(&assertion), None);
+        assert_eq!(relation("certificate").related_id(&assertion), None);
+    }
+
+    #[test]
+    /// Test that unknown relations and paths nested too deeply are rejected
+    fn test_parse_invalid_expansions() {
+        match Expansions::parse(Resource::Agent, &Some("standard".to_string())) {
+            Err(ApiError::BadRequest(message)) => assert_eq!(
+                message,
+                "Cannot expand standard, standard is not one of: organization"
+            ),
+            _ => panic!("Expected a bad request"),
+        }
+        match Expansions::parse(
+            Resource::Certificate,
+            &Some("standard.versions.version".to_string()),
+        ) {
+            Err(ApiError::BadRequest(message)) => assert_eq!(
+                message,
+                "Cannot expand standard.versions.version, standard.versions has nothing to expand"
+            ),
+            _ => panic!("Expected a bad request"),
+        }
+        match Expansions::parse(
+            Resource::Assertion,
+            &Some("certificate.factory.assertion.factory".to_string()),
+        ) {
+            Err(ApiError::BadRequest(message)) => assert_eq!(
+                message,
+                "Cannot expand certificate.factory.assertion.factory, expansions can be nested \
+                 at most 3 levels deep"
+            ),
+            _ => panic!("Expected a bad request"),
+        }
+    }
+}
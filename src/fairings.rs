@@ -1,8 +1,54 @@
+use chrono::{TimeZone, Utc};
+use database::DbConn;
+use errors::ApiError;
+use negotiation::Representation;
+use paging::{get_block_committed_at, get_block_num_as_of, get_head_block_num};
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::http::{ContentType, Header, Method};
-use rocket::{Request, Response};
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Header, Method, RawStr, Status};
+use rocket::response::Responder;
+use rocket::{Data, Outcome, Request, Response};
 use std::io::Cursor;
 
+/// The response header carrying the block a response was answered at
+pub const HEAD_BLOCK_HEADER: &str = "X-Head-Block";
+
+/// Responses at an explicit `head` never change
+const SNAPSHOT_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Responses at the latest block change with every block, so caches revalidate them
+const LATEST_CACHE_CONTROL: &str = "public, no-cache";
+
+/// Responses to authenticated requests are only meant for the client that made them
+const PRIVATE_CACHE_CONTROL: &str = "private, no-store";
+
+/// The endpoints whose responses are fully determined by the block they were answered at, and
+/// so are given an `ETag` and cached as snapshots. Factories are left out because their
+/// locations are kept off chain and change without a new block.
+const SNAPSHOT_PATHS: &[&str] = &[
+    "/api/agents",
+    "/api/assertions",
+    "/api/blocks",
+    "/api/certificates",
+    "/api/organizations",
+    "/api/requests",
+    "/api/standards",
+];
+
+/// The API, whose responses are answered at a block
+const API_PATH: &str = "/api";
+
+/// Administrative endpoints report on the service itself rather than on a block, so their
+/// responses are never decorated
+const ADMIN_PATH: &str = "/api/admin";
+
+/// Where requests whose `as_of` cannot be resolved are routed to, so that no handler runs
+const UNRESOLVED_AS_OF_PATH: &str = "/as_of/unresolved";
+
+/// Where requests that are answered with `304 Not Modified` are routed to, so that no handler
+/// runs
+const NOT_MODIFIED_PATH: &str = "/head_block/not_modified";
+
 pub struct CORS();
 
 impl Fairing for CORS {
@@ -35,11 +81,307 @@ impl Fairing for CORS {
     }
 }
 
+/// Lets every endpoint that accepts a `head` block number be queried by time instead, by
+/// rewriting an `as_of=<RFC 3339 timestamp>` query parameter to the `head` of the last block
+/// committed at or before that time.
+///
+/// When `as_of` cannot be resolved the request is not handled, and the error is sent instead.
+pub struct AsOf();
+
+/// The error of a request whose `as_of` could not be resolved
+struct UnresolvedAsOf(Option<ApiError>);
+
+/// Whether the `head` of a request was resolved from `as_of`. A time after the latest block
+/// resolves to a later block once one is committed, so these are not snapshots.
+struct ResolvedAsOf(bool);
+
+impl Fairing for AsOf {
+    fn info(&self) -> Info {
+        Info {
+            name: "Resolve as_of timestamps to head block numbers",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let query = match request.uri().query() {
+            Some(query) => query.to_string(),
+            None => return,
+        };
+
+        let mut as_of = None;
+        let mut has_head = false;
+        let mut items = vec![];
+        for item in query.split('&') {
+            let mut parts = item.splitn(2, '=');
+            match parts.next() {
+                Some("as_of") => as_of = Some(parts.next().unwrap_or("").to_string()),
+                Some("head") => {
+                    has_head = true;
+                    items.push(item.to_string());
+                }
+                _ => items.push(item.to_string()),
+            }
+        }
+        let as_of = match as_of {
+            Some(as_of) => as_of,
+            None => return,
+        };
+
+        let path = request.uri().path().to_string();
+        match resolve_as_of(request, &as_of, has_head) {
+            Ok(block_num) => {
+                request.local_cache(|| ResolvedAsOf(true));
+                items.push(format!("head={}", block_num));
+                request.set_uri(Origin::new(path, Some(items.join("&"))));
+            }
+            Err(err) => {
+                request.local_cache(|| UnresolvedAsOf(Some(err)));
+                request.set_uri(Origin::new::<_, String>(UNRESOLVED_AS_OF_PATH, None));
+            }
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let Some(ref err) = request.local_cache(|| UnresolvedAsOf(None)).0 {
+            match err.clone().respond_to(request) {
+                Ok(error_response) => *response = error_response,
+                Err(status) => response.set_status(status),
+            }
+        }
+    }
+}
+
+fn resolve_as_of(request: &Request, as_of: &str, has_head: bool) -> Result<i64, ApiError> {
+    if has_head {
+        return Err(ApiError::BadRequest(
+            "Only one of head and as_of can be given".to_string(),
+        ));
+    }
+    let as_of = RawStr::from_str(as_of)
+        .url_decode()
+        .map_err(|err| ApiError::BadRequest(format!("Invalid as_of: {}", err)))?;
+    match request.guard::<DbConn>() {
+        Outcome::Success(conn) => get_block_num_as_of(&as_of, &conn),
+        _ => Err(ApiError::ServiceUnavailable),
+    }
+}
+
+/// Adds the block a successful GET response was answered at as an `X-Head-Block` header,
+/// along with caching headers derived from it.
+///
+/// The block is taken from an `X-Head-Block` header set by the responder, or else, for JSON
+/// responses, resolved from the `head` of the request, or the latest block, before the request
+/// is handled. The representation depends on the `Accept` header, so every decorated response
+/// varies by it. The contents of a response at one of the `SNAPSHOT_PATHS` are fully determined
+/// by its block, so:
+///
+/// - the `ETag` is the block and the representation asked for by the request, and requests with a
+///   matching `If-None-Match` are answered with `304 Not Modified` and no body, without being
+///   handled
+/// - `Last-Modified` is the time the block was committed, when it was recorded, which is only
+///   looked up for these responses
+/// - responses at an explicit `head` can be cached forever, and all others are revalidated,
+///   unless the request was authenticated, in which case the response is not stored at all
+pub struct HeadBlock();
+
+/// The block a request is answered at, as resolved before it is handled
+#[derive(Clone, Copy)]
+struct RequestHead {
+    block_num: i64,
+    /// Whether the block was requested with `head`, rather than being the latest block or
+    /// resolved from `as_of`
+    explicit: bool,
+}
+
+/// The block resolved for a request, if it is one the `HeadBlock` fairing decorates
+struct ResolvedHead(Option<RequestHead>);
+
+/// Whether a request was answered with `304 Not Modified` instead of being handled
+struct NotModified(bool);
+
+impl Fairing for HeadBlock {
+    fn info(&self) -> Info {
+        Info {
+            name: "Add the head block to responses",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let path = request.uri().path().to_string();
+        if request.method() != Method::Get
+            || !is_under(&path, API_PATH)
+            || is_under(&path, ADMIN_PATH)
+        {
+            return;
+        }
+
+        let head = match resolve_head(request) {
+            Some(head) => head,
+            None => return,
+        };
+        request.local_cache(|| ResolvedHead(Some(head)));
+
+        // A block committed while the request is handled only makes the response one block
+        // older than it could have been, as if the request had arrived a moment earlier, so the
+        // latest block resolved here is good enough to revalidate against
+        if is_snapshot(&path) && matches_etag(request, &etag(request, head.block_num)) {
+            request.local_cache(|| NotModified(true));
+            request.set_uri(Origin::new::<_, String>(NOT_MODIFIED_PATH, None));
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let resolved = request.local_cache(|| ResolvedHead(None)).0;
+        if request.local_cache(|| NotModified(false)).0 {
+            if let Some(head) = resolved {
+                *response = Response::build().status(Status::NotModified).finalize();
+                decorate(request, response, head, true);
+            }
+            return;
+        }
+
+        let path = request.uri().path();
+        if request.method() != Method::Get
+            || response.status() != Status::Ok
+            || is_under(path, ADMIN_PATH)
+        {
+            return;
+        }
+
+        // Responders that know the block they answered at report it, which takes precedence
+        // over the block resolved before the request was handled. JSON responses that do not
+        // may be labelled with a block older than their contents, if one was committed in
+        // between, which costs the client a full response on its next revalidation. Other
+        // representations, like file downloads and metrics, are not answered at a block.
+        let head = match response
+            .headers()
+            .get_one(HEAD_BLOCK_HEADER)
+            .and_then(|head| head.parse::<i64>().ok())
+        {
+            Some(block_num) => RequestHead {
+                block_num,
+                explicit: resolved.map_or(false, |head| head.explicit),
+            },
+            None => match resolved {
+                Some(head) if response.content_type() == Some(ContentType::JSON) => head,
+                _ => return,
+            },
+        };
+        decorate(request, response, head, is_snapshot(path));
+    }
+}
+
+/// Resolves the block a request will be answered at: its `head`, or else the latest block.
+/// Requests with an invalid `head` are rejected by their handler, so they are not resolved.
+fn resolve_head(request: &Request) -> Option<RequestHead> {
+    match request.get_query_value::<i64>("head") {
+        Some(Ok(block_num)) if block_num >= 0 => Some(RequestHead {
+            block_num,
+            explicit: !request.local_cache(|| ResolvedAsOf(false)).0,
+        }),
+        Some(_) => None,
+        None => match request.guard::<DbConn>() {
+            Outcome::Success(conn) => {
+                get_head_block_num(None, &conn)
+                    .ok()
+                    .map(|block_num| RequestHead {
+                        block_num,
+                        explicit: false,
+                    })
+            }
+            _ => None,
+        },
+    }
+}
+
+/// Adds the `X-Head-Block` header to a response, and the caching headers of a snapshot
+fn decorate(request: &Request, response: &mut Response, head: RequestHead, snapshot: bool) {
+    response.set_raw_header(HEAD_BLOCK_HEADER, head.block_num.to_string());
+    response.set_raw_header("Vary", "Accept");
+    if !snapshot {
+        return;
+    }
+
+    response.set_raw_header("ETag", etag(request, head.block_num));
+    if let Some(committed_at) = committed_at(request, head.block_num) {
+        response.set_raw_header(
+            "Last-Modified",
+            Utc.timestamp(committed_at, 0)
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        );
+    }
+    if request.headers().contains("Authorization") {
+        response.set_raw_header("Cache-Control", PRIVATE_CACHE_CONTROL);
+    } else if head.explicit {
+        response.set_raw_header("Cache-Control", SNAPSHOT_CACHE_CONTROL);
+    } else {
+        response.set_raw_header("Cache-Control", LATEST_CACHE_CONTROL);
+    }
+}
+
+/// The entity tag of a response at `head`, in the representation asked for by the request
+fn etag(request: &Request, head: i64) -> String {
+    format!(
+        "\"{}-{}\"",
+        head,
+        Representation::from_request(request).subtype()
+    )
+}
+
+/// Whether `path` is one of the `SNAPSHOT_PATHS` or below one
+fn is_snapshot(path: &str) -> bool {
+    SNAPSHOT_PATHS
+        .iter()
+        .any(|snapshot_path| is_under(path, snapshot_path))
+}
+
+/// The time `head` was committed, if the database can be reached and it was recorded
+fn committed_at(request: &Request, head: i64) -> Option<i64> {
+    let conn = match request.guard::<DbConn>() {
+        Outcome::Success(conn) => conn,
+        _ => return None,
+    };
+    match get_block_committed_at(head, &conn) {
+        Ok(committed_at) => committed_at,
+        Err(err) => {
+            error!(
+                "Unable to read the time block {} was committed: {:?}",
+                head, err
+            );
+            None
+        }
+    }
+}
+
+/// Whether `path` is `base` or one of the paths below it
+fn is_under(path: &str, base: &str) -> bool {
+    path.starts_with(base) && (path.len() == base.len() || path[base.len()..].starts_with('/'))
+}
+
+/// Whether any of the entity tags in the `If-None-Match` header of the request matches `etag`,
+/// using the weak comparison that `If-None-Match` calls for
+fn matches_etag(request: &Request, etag: &str) -> bool {
+    request
+        .headers()
+        .get("If-None-Match")
+        .flat_map(|header| header.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use negotiation::Negotiated;
     use rocket::local::Client;
     use rocket_contrib::json::JsonValue;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// How many requests `get_counted_route` has handled
+    static COUNTED_REQUESTS: AtomicUsize = AtomicUsize::new(0);
 
     #[options("/")]
     fn options_route() -> &'static str {
@@ -51,6 +393,22 @@ mod tests {
         json!("Hello, world!")
     }
 
+    #[get("/block?<head>")]
+    fn get_block_route(head: Option<i64>) -> Negotiated {
+        Negotiated(json!({ "data": [], "head": head.unwrap_or(3) }))
+    }
+
+    #[get("/counted?<head>")]
+    fn get_counted_route(head: Option<i64>) -> Negotiated {
+        COUNTED_REQUESTS.fetch_add(1, Ordering::SeqCst);
+        Negotiated(json!({ "data": [], "head": head.unwrap_or(3) }))
+    }
+
+    #[get("/head?<head>&<limit>")]
+    fn get_head_route(head: Option<i64>, limit: Option<i64>) -> String {
+        format!("{:?} {:?}", head, limit)
+    }
+
     fn get_rocket_client() -> Client {
         let rocket = rocket::ignite()
             .mount("/", routes![options_route, get_json_route])
@@ -99,4 +457,154 @@ mod tests {
             Some("true")
         );
     }
+
+    #[test]
+    /// Test that requests without `as_of` are handled unchanged, and that requests with both
+    /// `head` and `as_of` are rejected without being handled
+    fn test_as_of() {
+        let rocket = rocket::ignite()
+            .mount("/", routes![get_head_route])
+            .attach(AsOf());
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let mut response = client.get("/head?head=2&limit=5").dispatch();
+        assert_eq!(response.body_string(), Some("Some(2) Some(5)".to_string()));
+
+        let response = client
+            .get("/head?head=2&as_of=2020-03-01T00:00:00Z")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    fn get_head_block_client() -> Client {
+        let rocket = rocket::ignite()
+            .mount("/", routes![get_json_route])
+            .mount("/api/blocks", routes![get_block_route, get_counted_route])
+            .mount("/api/factories", routes![get_block_route])
+            .mount("/api/admin", routes![get_block_route])
+            .attach(HeadBlock());
+        Client::new(rocket).expect("valid rocket instance")
+    }
+
+    #[test]
+    /// Test that the head of a JSON response is added as the `X-Head-Block` and `ETag` headers,
+    /// without changing the body
+    fn test_head_block() {
+        let client = get_head_block_client();
+
+        let mut response = client.get("/api/blocks/block").dispatch();
+        assert_eq!(response.headers().get_one(HEAD_BLOCK_HEADER), Some("3"));
+        assert_eq!(response.headers().get_one("ETag"), Some("\"3-json\""));
+        assert_eq!(response.headers().get_one("Vary"), Some("Accept"));
+        assert_eq!(
+            response.body_string(),
+            Some(json!({ "data": [], "head": 3 }).to_string())
+        );
+
+        let response = client.get("/").dispatch();
+        assert_eq!(response.headers().get_one(HEAD_BLOCK_HEADER), None);
+        assert_eq!(response.headers().get_one("ETag"), None);
+    }
+
+    #[test]
+    /// Test that requests with a matching `If-None-Match` are answered with `304 Not Modified`
+    /// and that only responses at an explicit `head` are cached forever
+    fn test_head_block_caching() {
+        let client = get_head_block_client();
+
+        let response = client.get("/api/blocks/block").dispatch();
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some(LATEST_CACHE_CONTROL)
+        );
+
+        let response = client.get("/api/blocks/block?head=2").dispatch();
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some(SNAPSHOT_CACHE_CONTROL)
+        );
+
+        let mut response = client
+            .get("/api/blocks/block?head=2")
+            .header(Header::new("If-None-Match", "\"1-json\", W/\"2-json\""))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+        assert_eq!(response.body_string(), None);
+
+        let response = client
+            .get("/api/blocks/block")
+            .header(Header::new("If-None-Match", "\"2-json\""))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    /// Test that requests with a matching `If-None-Match` are answered without being handled,
+    /// and that the `304 Not Modified` carries the headers of the response it stands for
+    fn test_head_block_not_modified_unhandled() {
+        let client = get_head_block_client();
+
+        let response = client.get("/api/blocks/counted?head=2").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(COUNTED_REQUESTS.load(Ordering::SeqCst), 1);
+
+        let mut response = client
+            .get("/api/blocks/counted?head=2")
+            .header(Header::new("If-None-Match", "\"2-json\""))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+        assert_eq!(response.body_string(), None);
+        assert_eq!(response.headers().get_one(HEAD_BLOCK_HEADER), Some("2"));
+        assert_eq!(response.headers().get_one("ETag"), Some("\"2-json\""));
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some(SNAPSHOT_CACHE_CONTROL)
+        );
+        assert_eq!(COUNTED_REQUESTS.load(Ordering::SeqCst), 1);
+
+        let response = client
+            .get("/api/blocks/counted?head=2")
+            .header(Header::new("Accept", "text/csv"))
+            .header(Header::new("If-None-Match", "\"2-json\""))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("ETag"), Some("\"2-csv\""));
+        assert_eq!(COUNTED_REQUESTS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    /// Test that responses to authenticated requests are not stored by shared caches
+    fn test_head_block_authenticated() {
+        let client = get_head_block_client();
+
+        let response = client
+            .get("/api/blocks/block?head=2")
+            .header(Header::new("Authorization", "Bearer token"))
+            .dispatch();
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some(PRIVATE_CACHE_CONTROL)
+        );
+    }
+
+    #[test]
+    /// Test that responses outside the snapshot endpoints are not cached by their block, and
+    /// that administrative responses are left alone
+    fn test_head_block_not_snapshot() {
+        let client = get_head_block_client();
+
+        let response = client
+            .get("/api/factories/block?head=2")
+            .header(Header::new("If-None-Match", "\"2-json\""))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one(HEAD_BLOCK_HEADER), Some("2"));
+        assert_eq!(response.headers().get_one("Vary"), Some("Accept"));
+        assert_eq!(response.headers().get_one("ETag"), None);
+        assert_eq!(response.headers().get_one("Cache-Control"), None);
+
+        let response = client.get("/api/admin/block?head=2").dispatch();
+        assert_eq!(response.headers().get_one(HEAD_BLOCK_HEADER), None);
+        assert_eq!(response.headers().get_one("Cache-Control"), None);
+    }
 }
@@ -0,0 +1,153 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// The fields to respond with, parsed from the `fields` query parameter: a comma separated list
+/// of fields, where the fields of a nested object follow it after a dot, e.g.
+/// `id,name,address.country`. Every field is included when no fields are given.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Fields {
+    /// `None` when every field is included
+    selected: Option<BTreeMap<String, Fields>>,
+}
+
+impl Fields {
+    pub fn parse(fields: &Option<String>) -> Self {
+        let paths: Vec<&str> = fields
+            .as_ref()
+            .map_or("", String::as_str)
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .collect();
+        if paths.is_empty() {
+            return Fields::default();
+        }
+
+        let mut parsed = Fields::none();
+        for path in paths {
+            let names: Vec<&str> = path.split('.').filter(|name| !name.is_empty()).collect();
+            parsed.insert(&names);
+        }
+        parsed
+    }
+
+    fn none() -> Self {
+        Fields {
+            selected: Some(BTreeMap::new()),
+        }
+    }
+
+    fn insert(&mut self, names: &[&str]) {
+        match names.split_first() {
+            // The whole field is included, whatever was selected within it before
+            None => self.selected = None,
+            Some((name, rest)) => {
+                if let Some(ref mut selected) = self.selected {
+                    selected
+                        .entry(name.to_string())
+                        .or_insert_with(Fields::none)
+                        .insert(rest);
+                }
+            }
+        }
+    }
+
+    /// Whether `name`, or any field within it, is included, i.e. whether it needs to be loaded
+    pub fn includes(&self, name: &str) -> bool {
+        self.selected
+            .as_ref()
+            .map_or(true, |selected| selected.contains_key(name))
+    }
+
+    /// Removes the fields that are not included from every resource in `data`
+    pub fn select(&self, data: &mut [Value]) {
+        for value in data {
+            self.select_value(value);
+        }
+    }
+
+    fn select_value(&self, value: &mut Value) {
+        let selected = match self.selected {
+            Some(ref selected) => selected,
+            None => return,
+        };
+        match *value {
+            Value::Object(ref mut object) => {
+                let excluded: Vec<String> = object
+                    .keys()
+                    .filter(|name| !selected.contains_key(*name))
+                    .cloned()
+                    .collect();
+                for name in excluded {
+                    object.remove(&name);
+                }
+                for (name, fields) in selected {
+                    if let Some(value) = object.get_mut(name) {
+                        fields.select_value(value);
+                    }
+                }
+            }
+            // The fields of a list, such as a factory's contacts, apply to each of its entries
+            Value::Array(ref mut values) => self.select(values),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that only the given fields, nested ones included, are kept
+    fn test_select_fields() {
+        let fields = Fields::parse(&Some("id, name,address.country,contacts.name".to_string()));
+        let mut data = vec![
+            json!({
+                "id": "factory_1",
+                "name": "Factory 1",
+                "address": { "city": "Lisbon", "country": "Portugal" },
+                "contacts": [
+                    { "name": "Ana", "phone_number": "1" },
+                    { "name": "Rui", "phone_number": "2" },
+                ],
+                "authorizations": [],
+            })
+            .0,
+        ];
+
+        fields.select(&mut data);
+        assert_eq!(
+            data[0],
+            json!({
+                "id": "factory_1",
+                "name": "Factory 1",
+                "address": { "country": "Portugal" },
+                "contacts": [{ "name": "Ana" }, { "name": "Rui" }],
+            })
+            .0
+        );
+        assert!(fields.includes("address"));
+        assert!(!fields.includes("authorizations"));
+    }
+
+    #[test]
+    /// Test that every field is kept without fields, and that a field given whole keeps all
+    /// of the fields within it
+    fn test_select_all_fields() {
+        let factory = json!({ "id": "factory_1", "address": { "city": "Lisbon" } }).0;
+
+        for fields in &[None, Some(String::new()), Some(" , ".to_string())] {
+            let fields = Fields::parse(fields);
+            assert_eq!(fields, Fields::default());
+            assert!(fields.includes("address"));
+            let mut data = vec![factory.clone()];
+            fields.select(&mut data);
+            assert_eq!(data[0], factory);
+        }
+
+        let mut data = vec![factory.clone()];
+        Fields::parse(&Some("address.country,address,id".to_string())).select(&mut data);
+        assert_eq!(data[0], factory);
+    }
+}
@@ -0,0 +1,482 @@
+use database::DbConn;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_function;
+use diesel::sql_types::{BigInt, Double, Text};
+use errors::ApiError;
+use serde_json;
+use std::collections::HashMap;
+
+/// Mean radius of the Earth
+const EARTH_RADIUS_KM: f64 = 6371.0;
+/// Length of a degree of latitude
+const KM_PER_DEGREE: f64 = 111.32;
+
+pub const SUPPLIED_SOURCE: &str = "supplied";
+pub const GEOCODED_SOURCE: &str = "geocoded";
+
+table! {
+    /// Coordinates of factories, either supplied to the API or geocoded from their address.
+    /// Unlike the other tables this is not derived from chain state.
+    factory_locations (organization_id) {
+        organization_id -> Varchar,
+        latitude -> Double,
+        longitude -> Double,
+        source -> Varchar,
+    }
+}
+
+sql_function! {
+  /// Returns the great-circle distance in kilometres between two points, like
+  /// `Point::distance_km`. Created by the factory search functions migration.
+  fn distance_km(
+      from_latitude: Double,
+      from_longitude: Double,
+      to_latitude: Double,
+      to_longitude: Double
+  ) -> Double;
+}
+
+#[derive(Clone, Debug, PartialEq, Queryable, Insertable)]
+#[table_name = "factory_locations"]
+pub struct FactoryLocation {
+    pub organization_id: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub source: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ApiLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub source: String,
+}
+
+impl From<FactoryLocation> for ApiLocation {
+    fn from(location: FactoryLocation) -> Self {
+        ApiLocation {
+            latitude: location.latitude,
+            longitude: location.longitude,
+            source: location.source,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Point {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Point {
+    pub fn new(latitude: f64, longitude: f64) -> Result<Self, ApiError> {
+        if !(-90.0..=90.0).contains(&latitude) {
+            return Err(ApiError::BadRequest(format!(
+                "Latitude {} must be between -90 and 90",
+                latitude
+            )));
+        }
+        if !(-180.0..=180.0).contains(&longitude) {
+            return Err(ApiError::BadRequest(format!(
+                "Longitude {} must be between -180 and 180",
+                longitude
+            )));
+        }
+        Ok(Point {
+            latitude,
+            longitude,
+        })
+    }
+
+    /// Parses a `lat,lon` pair, as given to the `near` param
+    pub fn parse(value: &str) -> Result<Self, ApiError> {
+        match parse_coordinates(value)?.as_slice() {
+            [latitude, longitude] => Point::new(*latitude, *longitude),
+            _ => Err(ApiError::BadRequest(format!(
+                "Invalid point {}, expected lat,lon",
+                value
+            ))),
+        }
+    }
+
+    /// Great-circle distance between two points, using the haversine formula
+    pub fn distance_km(&self, other: &Point) -> f64 {
+        let d_lat = (other.latitude - self.latitude).to_radians();
+        let d_lon = (other.longitude - self.longitude).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2)
+            + self.latitude.to_radians().cos()
+                * other.latitude.to_radians().cos()
+                * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+/// A latitude/longitude rectangle. When `min_longitude` is greater than `max_longitude`
+/// the box crosses the antimeridian.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub min_longitude: f64,
+    pub min_latitude: f64,
+    pub max_longitude: f64,
+    pub max_latitude: f64,
+}
+
+impl BoundingBox {
+    /// Parses a `min_lon,min_lat,max_lon,max_lat` box, the order used by GeoJSON
+    pub fn parse(value: &str) -> Result<Self, ApiError> {
+        match parse_coordinates(value)?.as_slice() {
+            [min_longitude, min_latitude, max_longitude, max_latitude] => {
+                let min = Point::new(*min_latitude, *min_longitude)?;
+                let max = Point::new(*max_latitude, *max_longitude)?;
+                if min.latitude > max.latitude {
+                    return Err(ApiError::BadRequest(
+                        "The minimum latitude of a bbox must not exceed its maximum".to_string(),
+                    ));
+                }
+                Ok(BoundingBox {
+                    min_longitude: min.longitude,
+                    min_latitude: min.latitude,
+                    max_longitude: max.longitude,
+                    max_latitude: max.latitude,
+                })
+            }
+            _ => Err(ApiError::BadRequest(format!(
+                "Invalid bbox {}, expected min_lon,min_lat,max_lon,max_lat",
+                value
+            ))),
+        }
+    }
+
+    /// The smallest box containing every point within `radius_km` of `center`
+    pub fn around(center: &Point, radius_km: f64) -> Self {
+        let d_lat = radius_km / KM_PER_DEGREE;
+        let min_latitude = (center.latitude - d_lat).max(-90.0);
+        let max_latitude = (center.latitude + d_lat).min(90.0);
+
+        // Near the poles a circle spans every longitude
+        let cos_latitude = center.latitude.to_radians().cos();
+        let d_lon = if cos_latitude > 0.0 {
+            radius_km / (KM_PER_DEGREE * cos_latitude)
+        } else {
+            180.0
+        };
+        if min_latitude <= -90.0 || max_latitude >= 90.0 || d_lon >= 180.0 {
+            return BoundingBox {
+                min_longitude: -180.0,
+                min_latitude,
+                max_longitude: 180.0,
+                max_latitude,
+            };
+        }
+
+        BoundingBox {
+            min_longitude: wrap_longitude(center.longitude - d_lon),
+            min_latitude,
+            max_longitude: wrap_longitude(center.longitude + d_lon),
+            max_latitude,
+        }
+    }
+
+    fn crosses_antimeridian(&self) -> bool {
+        self.min_longitude > self.max_longitude
+    }
+
+    pub fn contains(&self, point: &Point) -> bool {
+        let within_longitude = if self.crosses_antimeridian() {
+            point.longitude >= self.min_longitude || point.longitude <= self.max_longitude
+        } else {
+            point.longitude >= self.min_longitude && point.longitude <= self.max_longitude
+        };
+        within_longitude
+            && point.latitude >= self.min_latitude
+            && point.latitude <= self.max_latitude
+    }
+}
+
+fn wrap_longitude(longitude: f64) -> f64 {
+    if longitude < -180.0 {
+        longitude + 360.0
+    } else if longitude > 180.0 {
+        longitude - 360.0
+    } else {
+        longitude
+    }
+}
+
+fn parse_coordinates(value: &str) -> Result<Vec<f64>, ApiError> {
+    value
+        .split(',')
+        .map(|coordinate| {
+            coordinate.trim().parse::<f64>().map_err(|_| {
+                ApiError::BadRequest(format!("Invalid coordinate {}", coordinate.trim()))
+            })
+        })
+        .collect()
+}
+
+/// Returns a query of the ids of the located factories within `bbox` and within `radius_km`
+/// of `near`, to be used as a subquery of the factories query.
+///
+/// Candidates are first selected by bounding box, which can use the coordinates index, then by
+/// exact distance.
+pub fn located_factories(
+    bbox: Option<BoundingBox>,
+    near: Option<(Point, f64)>,
+) -> factory_locations::BoxedQuery<'static, Pg, Text> {
+    let mut locations_query = factory_locations::table
+        .select(factory_locations::organization_id)
+        .into_boxed();
+
+    let boxes = bbox
+        .into_iter()
+        .chain(near.map(|(center, radius_km)| BoundingBox::around(&center, radius_km)));
+    for bbox in boxes {
+        locations_query = locations_query
+            .filter(factory_locations::latitude.ge(bbox.min_latitude))
+            .filter(factory_locations::latitude.le(bbox.max_latitude));
+        locations_query = if bbox.crosses_antimeridian() {
+            locations_query.filter(
+                factory_locations::longitude
+                    .ge(bbox.min_longitude)
+                    .or(factory_locations::longitude.le(bbox.max_longitude)),
+            )
+        } else {
+            locations_query
+                .filter(factory_locations::longitude.ge(bbox.min_longitude))
+                .filter(factory_locations::longitude.le(bbox.max_longitude))
+        };
+    }
+
+    if let Some((center, radius_km)) = near {
+        locations_query = locations_query.filter(
+            distance_km(
+                center.latitude,
+                center.longitude,
+                factory_locations::latitude,
+                factory_locations::longitude,
+            )
+            .le(radius_km),
+        );
+    }
+    locations_query
+}
+
+/// Loads the stored locations of the given factories, keyed by organization id
+pub fn load_locations(
+    conn: &DbConn,
+    organization_ids: &[String],
+) -> Result<HashMap<String, FactoryLocation>, ApiError> {
+    Ok(factory_locations::table
+        .filter(factory_locations::organization_id.eq_any(organization_ids))
+        .load::<FactoryLocation>(&**conn)?
+        .into_iter()
+        .map(|location| (location.organization_id.clone(), location))
+        .collect())
+}
+
+/// Stores the location of a factory, replacing any location it already has
+pub fn store_location(conn: &DbConn, location: &FactoryLocation) -> Result<(), ApiError> {
+    diesel::insert_into(factory_locations::table)
+        .values(location)
+        .on_conflict(factory_locations::organization_id)
+        .do_update()
+        .set((
+            factory_locations::latitude.eq(location.latitude),
+            factory_locations::longitude.eq(location.longitude),
+            factory_locations::source.eq(&location.source),
+        ))
+        .execute(&**conn)?;
+    Ok(())
+}
+
+/// Locates every factory address live at `head_block_num` using the `geocoding_places` table,
+/// matching on city and country and preferring places in the same state or province.
+///
+/// Supplied locations are never replaced. Returns the number of locations stored.
+pub fn geocode_addresses(conn: &DbConn, head_block_num: i64) -> Result<usize, ApiError> {
+    Ok(diesel::sql_query(
+        "INSERT INTO factory_locations (organization_id, latitude, longitude, source)
+         SELECT DISTINCT ON (a.organization_id) a.organization_id, p.latitude, p.longitude, $2
+         FROM addresses a
+         JOIN geocoding_places p
+           ON lower(p.city) = lower(a.city)
+          AND lower(p.country) = lower(a.country)
+          AND (p.state_province IS NULL OR lower(p.state_province) = lower(a.state_province))
+         WHERE a.start_block_num <= $1 AND a.end_block_num > $1
+         ORDER BY a.organization_id, p.state_province IS NULL
+         ON CONFLICT (organization_id) DO UPDATE
+           SET latitude = EXCLUDED.latitude, longitude = EXCLUDED.longitude
+           WHERE factory_locations.source = $2",
+    )
+    .bind::<BigInt, _>(head_block_num)
+    .bind::<Text, _>(GEOCODED_SOURCE)
+    .execute(&**conn)?)
+}
+
+/// Builds a GeoJSON FeatureCollection from API factories. Each factory's `location` becomes
+/// the feature's point geometry and the rest of the factory its properties; factories without
+/// a location have a null geometry.
+pub fn feature_collection(factories: Vec<serde_json::Value>) -> serde_json::Value {
+    let features: Vec<serde_json::Value> = factories
+        .into_iter()
+        .map(|mut factory| {
+            let location = factory
+                .as_object_mut()
+                .and_then(|properties| properties.remove("location"));
+            let geometry = match location {
+                Some(ref location) if location.is_object() => {
+                    json!({
+                        "type": "Point",
+                        "coordinates": [location["longitude"], location["latitude"]],
+                    })
+                    .0
+                }
+                _ => serde_json::Value::Null,
+            };
+            json!({
+                "type": "Feature",
+                "id": factory["id"],
+                "geometry": geometry,
+                "properties": factory,
+            })
+            .0
+        })
+        .collect();
+
+    json!({ "type": "FeatureCollection", "features": features }).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use route_handlers::factories::tests::setup_factory_db;
+    use route_handlers::tests::run_test;
+
+    #[test]
+    /// Test that points are parsed as lat,lon and validated
+    fn test_parse_point() {
+        assert_eq!(
+            Point::parse("44.97, -93.26").unwrap(),
+            Point {
+                latitude: 44.97,
+                longitude: -93.26
+            }
+        );
+        assert!(Point::parse("91,0").is_err());
+        assert!(Point::parse("44.97").is_err());
+        assert!(Point::parse("north,west").is_err());
+    }
+
+    #[test]
+    /// Test that bounding boxes are parsed in GeoJSON order and validated
+    fn test_parse_bbox() {
+        let bbox = BoundingBox::parse("-94,44,-93,45").unwrap();
+        assert!(bbox.contains(&Point {
+            latitude: 44.97,
+            longitude: -93.26
+        }));
+        assert!(!bbox.contains(&Point {
+            latitude: 44.97,
+            longitude: -92.0
+        }));
+        assert!(BoundingBox::parse("-94,45,-93,44").is_err());
+        assert!(BoundingBox::parse("-94,44,-93").is_err());
+    }
+
+    #[test]
+    /// Test the distance between Minneapolis and Chicago
+    fn test_distance_km() {
+        let minneapolis = Point::new(44.9778, -93.265).unwrap();
+        let chicago = Point::new(41.8781, -87.6298).unwrap();
+        let distance = minneapolis.distance_km(&chicago);
+        assert!(distance > 570.0 && distance < 580.0);
+    }
+
+    #[test]
+    /// Test that the box around a point contains the points within the radius, including
+    /// across the antimeridian
+    fn test_bbox_around() {
+        let center = Point::new(0.0, 179.9).unwrap();
+        let bbox = BoundingBox::around(&center, 50.0);
+        assert!(bbox.crosses_antimeridian());
+        assert!(bbox.contains(&Point::new(0.1, -179.9).unwrap()));
+        assert!(!bbox.contains(&Point::new(0.0, 0.0).unwrap()));
+
+        let polar = BoundingBox::around(&Point::new(89.9, 0.0).unwrap(), 50.0);
+        assert!(polar.contains(&Point::new(89.9, 180.0).unwrap()));
+    }
+
+    #[test]
+    /// Test that factory addresses are geocoded from matching places, without replacing
+    /// supplied locations
+    fn test_geocode_addresses() {
+        run_test(|| {
+            let conn = DbConn(setup_factory_db(true));
+            diesel::sql_query(
+                "INSERT INTO geocoding_places (city, state_province, country, latitude, longitude)
+                 VALUES ('TEST_FACTORY_CITY', NULL, 'test_factory_country', 10.0, 20.0),
+                        ('test_factory_city', 'test_factory_province', 'test_factory_country',
+                         11.0, 21.0),
+                        ('test_factory_assertion_city', NULL, 'test_factory_assertion_country',
+                         5.0, 6.0)",
+            )
+            .execute(&*conn)
+            .unwrap();
+            store_location(
+                &conn,
+                &FactoryLocation {
+                    organization_id: "test_factory_assertion_id".to_string(),
+                    latitude: 1.0,
+                    longitude: 2.0,
+                    source: SUPPLIED_SOURCE.to_string(),
+                },
+            )
+            .unwrap();
+
+            assert_eq!(geocode_addresses(&conn, 1).unwrap(), 1);
+            let ids = [
+                "test_factory_id".to_string(),
+                "test_factory_assertion_id".to_string(),
+            ];
+            let locations = load_locations(&conn, &ids).unwrap();
+            assert_eq!(
+                locations["test_factory_id"],
+                FactoryLocation {
+                    organization_id: "test_factory_id".to_string(),
+                    latitude: 11.0,
+                    longitude: 21.0,
+                    source: GEOCODED_SOURCE.to_string(),
+                }
+            );
+            assert_eq!(
+                locations["test_factory_assertion_id"].source,
+                SUPPLIED_SOURCE
+            );
+        })
+    }
+
+    #[test]
+    /// Test that factories become GeoJSON features with their location as the geometry
+    fn test_feature_collection() {
+        let collection = feature_collection(vec![
+            json!({
+                "id": "located",
+                "name": "Located Factory",
+                "location": { "latitude": 44.97, "longitude": -93.26, "source": "supplied" },
+            })
+            .0,
+            json!({ "id": "unlocated", "name": "Unlocated Factory" }).0,
+        ]);
+
+        assert_eq!(collection["type"], "FeatureCollection");
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(
+            features[0]["geometry"]["coordinates"],
+            json!([-93.26, 44.97]).0
+        );
+        assert_eq!(features[0]["properties"]["name"], "Located Factory");
+        assert!(features[0]["properties"].get("location").is_none());
+        assert!(features[1]["geometry"].is_null());
+    }
+}
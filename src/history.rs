@@ -0,0 +1,112 @@
+use errors::ApiError;
+use rocket_contrib::json::JsonValue;
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+/// The `end_block_num` of rows that are still live
+const LIVE_END_BLOCK_NUM: i64 = std::i64::MAX;
+
+/// A version of an entity and the range of blocks it was live for
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Version {
+    start_block_num: i64,
+    /// `None` while the version is still live
+    end_block_num: Option<i64>,
+    data: Value,
+}
+
+/// Builds every version of an entity up to `head_block_num` from the `(start_block_num,
+/// end_block_num)` ranges of the rows it is made of, loading each version with `load` at the
+/// block it started at.
+///
+/// An entity changes whenever one of its rows starts or ends, so a version is loaded at every
+/// such block. Consecutive versions that load identically, e.g. because a row that is not part
+/// of the representation changed, are merged into one.
+pub fn versions<F>(
+    ranges: &[(i64, i64)],
+    head_block_num: i64,
+    mut load: F,
+) -> Result<Vec<Version>, ApiError>
+where
+    F: FnMut(i64) -> Result<Option<JsonValue>, ApiError>,
+{
+    let boundaries: Vec<i64> = ranges
+        .iter()
+        .flat_map(|&(start, end)| vec![start, end])
+        .filter(|&block_num| block_num != LIVE_END_BLOCK_NUM && block_num <= head_block_num)
+        .collect::<BTreeSet<i64>>()
+        .into_iter()
+        .collect();
+
+    let mut versions: Vec<Version> = vec![];
+    for (index, &start_block_num) in boundaries.iter().enumerate() {
+        let end_block_num = boundaries.get(index + 1).cloned();
+        let data = match load(start_block_num)? {
+            Some(data) => data.0,
+            None => continue,
+        };
+
+        if let Some(last) = versions.last_mut() {
+            if last.end_block_num == Some(start_block_num) && last.data == data {
+                last.end_block_num = end_block_num;
+                continue;
+            }
+        }
+        versions.push(Version {
+            start_block_num,
+            end_block_num,
+            data,
+        });
+    }
+
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that a version is loaded for every block a row starts or ends at and that
+    /// identical consecutive versions are merged
+    fn test_versions() {
+        // The entity was created at block 1, changed at block 3, had an unrelated row change
+        // at block 5 and was deleted at block 7
+        let ranges = [(1, 3), (3, 7), (5, LIVE_END_BLOCK_NUM)];
+        let versions = versions(&ranges, 10, |block_num| {
+            Ok(match block_num {
+                1 => Some(json!({ "name": "first" })),
+                3 | 5 => Some(json!({ "name": "second" })),
+                _ => None,
+            })
+        })
+        .unwrap();
+
+        assert_eq!(
+            versions,
+            vec![
+                Version {
+                    start_block_num: 1,
+                    end_block_num: Some(3),
+                    data: json!({ "name": "first" }).0,
+                },
+                Version {
+                    start_block_num: 3,
+                    end_block_num: Some(7),
+                    data: json!({ "name": "second" }).0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    /// Test that versions starting after the head are left out and the last version before
+    /// the head is still live
+    fn test_versions_at_head() {
+        let ranges = [(1, 4), (4, LIVE_END_BLOCK_NUM)];
+        let versions = versions(&ranges, 2, |_| Ok(Some(json!({})))).unwrap();
+
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].end_block_num, None);
+    }
+}
@@ -0,0 +1,346 @@
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use base64;
+use chrono::{TimeZone, Utc};
+use errors::ApiError;
+use key_store::{KeyStore, KeyVersion};
+use rand::{thread_rng, Rng};
+use serde_json;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const SECRET_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// Escrows keys in a local directory, one file per user, encrypted at rest with AES-256-GCM.
+///
+/// Intended for development and tests where no Vault server is available.
+pub struct LocalKeyStore {
+    directory: PathBuf,
+    cipher: Aes256Gcm,
+    /// Serializes read-modify-write cycles on the key files
+    lock: Mutex<()>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct KeyFile {
+    versions: Vec<EncryptedKey>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct EncryptedKey {
+    version: u64,
+    created_at: i64,
+    nonce: String,
+    ciphertext: String,
+    /// Deleted versions keep their number, so versions are never reused
+    #[serde(default)]
+    deleted: bool,
+}
+
+impl LocalKeyStore {
+    /// Create a key store in `directory` that encrypts keys with the 32 byte `secret`.
+    pub fn new(directory: PathBuf, secret: &[u8]) -> Result<Self, String> {
+        if secret.len() != SECRET_LEN {
+            return Err(format!(
+                "Local key store secret must be {} bytes, found {}",
+                SECRET_LEN,
+                secret.len()
+            ));
+        }
+        fs::create_dir_all(&directory).map_err(|err| {
+            format!(
+                "Unable to create key store directory {:?}: {}",
+                directory, err
+            )
+        })?;
+
+        Ok(LocalKeyStore {
+            directory,
+            cipher: Aes256Gcm::new(GenericArray::clone_from_slice(secret)),
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Create a key store in `LOCAL_KEY_STORE_PATH` encrypted with the base64-encoded
+    /// `LOCAL_KEY_STORE_SECRET`.
+    pub fn from_env() -> Result<Self, String> {
+        let directory = env::var("LOCAL_KEY_STORE_PATH")
+            .map_err(|_| "No LOCAL_KEY_STORE_PATH provided".to_string())?;
+        let secret = env::var("LOCAL_KEY_STORE_SECRET")
+            .map_err(|_| "No LOCAL_KEY_STORE_SECRET provided".to_string())?;
+        let secret = base64::decode(&secret)
+            .map_err(|err| format!("LOCAL_KEY_STORE_SECRET is not valid base64: {}", err))?;
+
+        LocalKeyStore::new(PathBuf::from(directory), &secret)
+    }
+
+    fn key_file_path(&self, user_id: &str) -> PathBuf {
+        self.directory.join(format!(
+            "{}.json",
+            base64::encode_config(user_id, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    fn load(&self, user_id: &str) -> Result<KeyFile, ApiError> {
+        match fs::read(self.key_file_path(user_id)) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|err| ApiError::InternalError(err.to_string())),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(KeyFile::default()),
+            Err(err) => Err(ApiError::InternalError(err.to_string())),
+        }
+    }
+
+    /// Write the key file to a temporary file first, so a crash never leaves a partial file
+    fn save(&self, user_id: &str, key_file: &KeyFile) -> Result<(), ApiError> {
+        let path = self.key_file_path(user_id);
+        let tmp_path = path.with_extension("json.tmp");
+        let contents =
+            serde_json::to_vec(key_file).map_err(|err| ApiError::InternalError(err.to_string()))?;
+        fs::write(&tmp_path, contents)
+            .and_then(|_| fs::rename(&tmp_path, &path))
+            .map_err(|err| ApiError::InternalError(err.to_string()))
+    }
+
+    /// The user id is bound to the ciphertext as associated data, so a key file
+    /// copied to another user's name will fail to decrypt.
+    fn encrypt(
+        &self,
+        user_id: &str,
+        version: u64,
+        private_key: &str,
+    ) -> Result<EncryptedKey, ApiError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        thread_rng().fill(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: private_key.as_bytes(),
+                    aad: user_id.as_bytes(),
+                },
+            )
+            .map_err(|_| ApiError::InternalError("Unable to encrypt private key".to_string()))?;
+
+        Ok(EncryptedKey {
+            version,
+            created_at: Utc::now().timestamp(),
+            nonce: base64::encode(&nonce),
+            ciphertext: base64::encode(&ciphertext),
+            deleted: false,
+        })
+    }
+
+    fn decrypt(&self, user_id: &str, key: &EncryptedKey) -> Result<String, ApiError> {
+        let decrypt_error = || ApiError::InternalError("Unable to decrypt private key".to_string());
+        let nonce = base64::decode(&key.nonce).map_err(|_| decrypt_error())?;
+        let ciphertext = base64::decode(&key.ciphertext).map_err(|_| decrypt_error())?;
+        if nonce.len() != NONCE_LEN {
+            return Err(decrypt_error());
+        }
+        let plaintext = self
+            .cipher
+            .decrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: user_id.as_bytes(),
+                },
+            )
+            .map_err(|_| decrypt_error())?;
+
+        String::from_utf8(plaintext).map_err(|_| decrypt_error())
+    }
+}
+
+impl KeyStore for LocalKeyStore {
+    fn store_key(&self, user_id: &str, private_key: &str) -> Result<Option<u64>, ApiError> {
+        let _guard = self.lock.lock().expect("Could not acquire key store lock");
+        let mut key_file = self.load(user_id)?;
+        let version = key_file.versions.last().map_or(1, |key| key.version + 1);
+        let encrypted_key = self.encrypt(user_id, version, private_key)?;
+        key_file.versions.push(encrypted_key);
+        self.save(user_id, &key_file)?;
+
+        Ok(Some(version))
+    }
+
+    fn get_key(&self, user_id: &str, version: Option<u64>) -> Result<String, ApiError> {
+        let key_file = {
+            let _guard = self.lock.lock().expect("Could not acquire key store lock");
+            self.load(user_id)?
+        };
+        let encrypted_key = match version {
+            Some(version) => key_file.versions.iter().find(|key| key.version == version),
+            None => key_file.versions.last(),
+        }
+        .ok_or_else(no_key_error)?;
+        if encrypted_key.deleted {
+            return Err(ApiError::NotFound(
+                "This version of the private key has been deleted".to_string(),
+            ));
+        }
+
+        self.decrypt(user_id, encrypted_key)
+    }
+
+    /// Deleting the whole key marks every version deleted rather than removing the file, so the
+    /// next key stored for the user does not reuse an old version number
+    fn delete_key(&self, user_id: &str, version: Option<u64>) -> Result<(), ApiError> {
+        let _guard = self.lock.lock().expect("Could not acquire key store lock");
+        let mut key_file = self.load(user_id)?;
+        {
+            let deleted_keys: Vec<&mut EncryptedKey> = match version {
+                Some(version) => key_file
+                    .versions
+                    .iter_mut()
+                    .filter(|key| key.version == version)
+                    .collect(),
+                None => key_file
+                    .versions
+                    .iter_mut()
+                    .filter(|key| !key.deleted)
+                    .collect(),
+            };
+            if deleted_keys.is_empty() {
+                return Err(no_key_error());
+            }
+            for encrypted_key in deleted_keys {
+                encrypted_key.nonce.clear();
+                encrypted_key.ciphertext.clear();
+                encrypted_key.deleted = true;
+            }
+        }
+        self.save(user_id, &key_file)
+    }
+
+    fn list_versions(&self, user_id: &str) -> Result<Vec<KeyVersion>, ApiError> {
+        let key_file = {
+            let _guard = self.lock.lock().expect("Could not acquire key store lock");
+            self.load(user_id)?
+        };
+        if key_file.versions.is_empty() {
+            return Err(no_key_error());
+        }
+
+        Ok(key_file
+            .versions
+            .iter()
+            .map(|key| KeyVersion {
+                version: key.version,
+                created_at: Utc.timestamp(key.created_at, 0).to_rfc3339(),
+                deleted: key.deleted,
+            })
+            .collect())
+    }
+}
+
+fn no_key_error() -> ApiError {
+    ApiError::NotFound("No private key stored for this user".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid;
+
+    fn get_key_store() -> LocalKeyStore {
+        let directory = env::temp_dir().join(format!(
+            "consensource-keys-{}",
+            uuid::Uuid::new_v4().to_simple()
+        ));
+        LocalKeyStore::new(directory, &[7u8; SECRET_LEN]).unwrap()
+    }
+
+    #[test]
+    /// Test that a stored key can be read back and is not stored in plain text
+    fn test_store_and_get_key() {
+        let key_store = get_key_store();
+        let version = key_store.store_key("user", "test_private_key").unwrap();
+        assert_eq!(version, Some(1));
+        assert_eq!(
+            key_store.get_key("user", None).unwrap(),
+            "test_private_key".to_string()
+        );
+
+        let contents = fs::read_to_string(key_store.key_file_path("user")).unwrap();
+        assert!(!contents.contains("test_private_key"));
+    }
+
+    #[test]
+    /// Test that storing a key again creates a new version and keeps the old one
+    fn test_key_versions() {
+        let key_store = get_key_store();
+        key_store.store_key("user", "first_key").unwrap();
+        let version = key_store.store_key("user", "second_key").unwrap();
+        assert_eq!(version, Some(2));
+        assert_eq!(key_store.get_key("user", None).unwrap(), "second_key");
+        assert_eq!(key_store.get_key("user", Some(1)).unwrap(), "first_key");
+        assert!(key_store.get_key("user", Some(3)).is_err());
+    }
+
+    #[test]
+    /// Test that deleting a version keeps its number and hides its key
+    fn test_delete_key_version() {
+        let key_store = get_key_store();
+        key_store.store_key("user", "first_key").unwrap();
+        key_store.store_key("user", "second_key").unwrap();
+        key_store.delete_key("user", Some(2)).unwrap();
+        assert!(key_store.get_key("user", None).is_err());
+        assert_eq!(key_store.get_key("user", Some(1)).unwrap(), "first_key");
+
+        let versions = key_store.list_versions("user").unwrap();
+        assert_eq!(versions.len(), 2);
+        assert!(!versions[0].deleted);
+        assert!(versions[1].deleted);
+
+        assert_eq!(key_store.rollback_key("user", 1).unwrap(), Some(3));
+        assert_eq!(key_store.get_key("user", None).unwrap(), "first_key");
+    }
+
+    #[test]
+    /// Test that deleting a key deletes every version without reusing their numbers
+    fn test_delete_key() {
+        let key_store = get_key_store();
+        key_store.store_key("user", "first_key").unwrap();
+        key_store.store_key("user", "second_key").unwrap();
+        key_store.delete_key("user", None).unwrap();
+        assert!(key_store.get_key("user", None).is_err());
+        assert!(key_store.get_key("user", Some(1)).is_err());
+        assert!(key_store.rollback_key("user", 1).is_err());
+        assert!(key_store
+            .list_versions("user")
+            .unwrap()
+            .iter()
+            .all(|version| version.deleted));
+        assert!(key_store.delete_key("user", None).is_err());
+
+        assert_eq!(key_store.store_key("user", "third_key").unwrap(), Some(3));
+        assert_eq!(key_store.get_key("user", None).unwrap(), "third_key");
+    }
+
+    #[test]
+    /// Test that a key file cannot be decrypted as another user's key
+    fn test_key_bound_to_user() {
+        let key_store = get_key_store();
+        key_store.store_key("user", "test_private_key").unwrap();
+        fs::copy(
+            key_store.key_file_path("user"),
+            key_store.key_file_path("other_user"),
+        )
+        .unwrap();
+        assert!(key_store.get_key("other_user", None).is_err());
+        assert!(key_store.get_key("missing_user", None).is_err());
+    }
+
+    #[test]
+    /// Test that a secret of the wrong length is rejected
+    fn test_invalid_secret() {
+        assert!(LocalKeyStore::new(env::temp_dir(), &[7u8; 16]).is_err());
+    }
+}
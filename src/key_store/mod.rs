@@ -0,0 +1,55 @@
+pub mod local;
+pub mod vault;
+
+use errors::ApiError;
+use std::env;
+
+/// A backend that user private keys are escrowed in, served at `/api/key`.
+pub trait KeyStore: Send + Sync {
+    /// Store `private_key` for `user_id`, returning the version that was created
+    /// if the backend keeps versions.
+    fn store_key(&self, user_id: &str, private_key: &str) -> Result<Option<u64>, ApiError>;
+
+    /// Read the latest private key for `user_id`, or a specific `version` of it.
+    fn get_key(&self, user_id: &str, version: Option<u64>) -> Result<String, ApiError>;
+
+    /// Delete every version of the key for `user_id`, or only the given `version`.
+    fn delete_key(&self, user_id: &str, version: Option<u64>) -> Result<(), ApiError>;
+
+    /// List the versions of the key stored for `user_id`, oldest first.
+    fn list_versions(&self, user_id: &str) -> Result<Vec<KeyVersion>, ApiError>;
+
+    /// Make `version` the latest key for `user_id` by storing it again as a new version,
+    /// returning the version that was created.
+    fn rollback_key(&self, user_id: &str, version: u64) -> Result<Option<u64>, ApiError> {
+        let private_key = self.get_key(user_id, Some(version))?;
+        self.store_key(user_id, &private_key)
+    }
+}
+
+/// A version of a user's escrowed key, without the key itself
+#[derive(Debug, PartialEq, Serialize)]
+pub struct KeyVersion {
+    pub version: u64,
+    /// RFC 3339 timestamp of when the version was stored
+    pub created_at: String,
+    /// Whether the version has been deleted and can no longer be read
+    pub deleted: bool,
+}
+
+/// Build the key store selected by the `KEY_STORE` env var.
+///
+/// `vault`, the default, escrows keys in the HashiCorp Vault at `VAULT_URL`.
+/// `local` escrows keys in encrypted files, for development and tests.
+pub fn from_env() -> Result<Box<dyn KeyStore>, String> {
+    match env::var("KEY_STORE")
+        .unwrap_or_else(|_| "vault".to_string())
+        .as_str()
+    {
+        "vault" => Ok(Box::new(vault::VaultKeyStore::new(
+            env::var("VAULT_URL").unwrap_or_else(|_| "".into()),
+        ))),
+        "local" => Ok(Box::new(local::LocalKeyStore::from_env()?)),
+        other => Err(format!("Unsupported KEY_STORE {}", other)),
+    }
+}
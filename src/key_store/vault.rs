@@ -0,0 +1,808 @@
+extern crate reqwest;
+use self::reqwest::StatusCode;
+use errors::ApiError;
+use key_store::{KeyStore, KeyVersion};
+use serde_json;
+use std::env;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Percentage of a token's lease after which it is renewed
+const RENEWAL_POINT: u64 = 75;
+const DEFAULT_K8S_TOKEN_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+/// Escrows keys in a HashiCorp Vault key-value secrets engine.
+pub struct VaultKeyStore {
+    url: String,
+    token: Mutex<Option<VaultToken>>,
+}
+
+impl VaultKeyStore {
+    pub fn new(url: String) -> Self {
+        VaultKeyStore {
+            url,
+            token: Mutex::new(None),
+        }
+    }
+}
+
+impl KeyStore for VaultKeyStore {
+    fn store_key(&self, user_id: &str, private_key: &str) -> Result<Option<u64>, ApiError> {
+        with_client_token(self, |client_token| {
+            vault_write(&self.url, client_token, user_id, private_key)
+        })
+    }
+
+    fn get_key(&self, user_id: &str, version: Option<u64>) -> Result<String, ApiError> {
+        with_client_token(self, |client_token| {
+            vault_read(&self.url, client_token, user_id, version)
+        })
+    }
+
+    fn delete_key(&self, user_id: &str, version: Option<u64>) -> Result<(), ApiError> {
+        with_client_token(self, |client_token| {
+            vault_delete(&self.url, client_token, user_id, version)
+        })
+    }
+
+    fn list_versions(&self, user_id: &str) -> Result<Vec<KeyVersion>, ApiError> {
+        with_client_token(self, |client_token| {
+            vault_list_versions(&self.url, client_token, user_id)
+        })
+    }
+}
+
+/// A Vault client token along with the lease information needed to reuse it.
+#[derive(Clone, Debug)]
+struct VaultToken {
+    client_token: String,
+    renewable: bool,
+    /// `None` for tokens without a lease, such as root tokens
+    renew_at: Option<Instant>,
+    expires_at: Option<Instant>,
+}
+
+impl VaultToken {
+    /// Builds a token from the `auth` block of a Vault login or renewal response
+    fn from_auth(auth: &serde_json::Value, issued_at: Instant) -> Result<Self, ApiError> {
+        let client_token = match &auth["client_token"] {
+            serde_json::Value::String(token) => token.to_string(),
+            _ => {
+                return Err(ApiError::InternalError(
+                    "Vault did not return client token".to_string(),
+                ))
+            }
+        };
+        let lease_duration = auth["lease_duration"].as_u64().unwrap_or(0);
+        let (renew_at, expires_at) = if lease_duration == 0 {
+            (None, None)
+        } else {
+            (
+                Some(issued_at + Duration::from_secs(lease_duration * RENEWAL_POINT / 100)),
+                Some(issued_at + Duration::from_secs(lease_duration)),
+            )
+        };
+
+        Ok(VaultToken {
+            client_token,
+            renewable: auth["renewable"].as_bool().unwrap_or(false),
+            renew_at,
+            expires_at,
+        })
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| now >= expires_at)
+    }
+
+    /// Whether the lease of a renewable token is close enough to expiring to be extended.
+    /// Tokens that cannot be renewed are used until they expire.
+    fn needs_renewal(&self, now: Instant) -> bool {
+        self.renewable && self.renew_at.map_or(false, |renew_at| now >= renew_at)
+    }
+}
+
+/// Errors returned by a single request to Vault
+enum VaultRequestError {
+    /// Vault rejected the client token, so logging in again may succeed
+    Forbidden,
+    Api(ApiError),
+}
+
+impl From<VaultRequestError> for ApiError {
+    fn from(err: VaultRequestError) -> Self {
+        match err {
+            VaultRequestError::Forbidden => {
+                ApiError::InternalError("Vault rejected the client token".to_string())
+            }
+            VaultRequestError::Api(err) => err,
+        }
+    }
+}
+
+/// Passes through successful Vault responses and converts the rest into the matching
+/// error, including the `errors` Vault lists in the response body.
+fn check_response(mut res: reqwest::Response) -> Result<reqwest::Response, VaultRequestError> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    if status == StatusCode::FORBIDDEN {
+        return Err(VaultRequestError::Forbidden);
+    }
+    let body: serde_json::Value = res.json().unwrap_or(serde_json::Value::Null);
+    Err(VaultRequestError::Api(vault_error(status, &body)))
+}
+
+fn vault_error(status: StatusCode, body: &serde_json::Value) -> ApiError {
+    let errors = body["errors"]
+        .as_array()
+        .map(|errors| {
+            errors
+                .iter()
+                .filter_map(|err| err.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default();
+    match status {
+        StatusCode::NOT_FOUND => {
+            ApiError::NotFound("No private key stored for this user".to_string())
+        }
+        StatusCode::BAD_REQUEST => {
+            ApiError::BadRequest(format!("Vault rejected the request: {}", errors))
+        }
+        StatusCode::TOO_MANY_REQUESTS => {
+            ApiError::TooManyRequests("Vault rate limit exceeded".to_string())
+        }
+        // Vault responds with a 503 while it is sealed
+        StatusCode::SERVICE_UNAVAILABLE => ApiError::ServiceUnavailable,
+        _ => ApiError::InternalError(format!("Vault responded with {}: {}", status, errors)),
+    }
+}
+
+impl From<reqwest::Error> for VaultRequestError {
+    fn from(err: reqwest::Error) -> Self {
+        VaultRequestError::Api(ApiError::InternalError(err.to_string()))
+    }
+}
+
+/// Run `request` with a valid client token, logging in again and retrying once
+/// if Vault responds with a 403.
+fn with_client_token<T, F>(store: &VaultKeyStore, request: F) -> Result<T, ApiError>
+where
+    F: Fn(&str) -> Result<T, VaultRequestError>,
+{
+    let client_token = client_token(store)?;
+    match request(&client_token) {
+        Err(VaultRequestError::Forbidden) => {
+            debug!("Vault rejected the cached client token, logging in again");
+            let client_token = login(store)?;
+            request(&client_token).map_err(ApiError::from)
+        }
+        result => result.map_err(ApiError::from),
+    }
+}
+
+/// Returns the cached client token, renewing it if its lease is close to expiring
+/// and logging in if there is no token or it has expired.
+///
+/// The lock on the cached token is only held while reading or replacing it, never
+/// while waiting on Vault.
+fn client_token(store: &VaultKeyStore) -> Result<String, ApiError> {
+    let cached = store
+        .token
+        .lock()
+        .expect("Could not acquire Vault token lock")
+        .clone();
+    let now = Instant::now();
+
+    match cached {
+        Some(ref token) if token.is_expired(now) => login(store),
+        Some(ref token) if token.needs_renewal(now) => {
+            match vault_renew(&store.url, &token.client_token) {
+                Ok(token) => Ok(cache_token(store, token)),
+                Err(err) => {
+                    warn!("Unable to renew Vault token, logging in again: {:?}", err);
+                    login(store)
+                }
+            }
+        }
+        Some(token) => Ok(token.client_token),
+        None => login(store),
+    }
+}
+
+fn login(store: &VaultKeyStore) -> Result<String, ApiError> {
+    let token = vault_login(&store.url)?;
+    Ok(cache_token(store, token))
+}
+
+fn cache_token(store: &VaultKeyStore, token: VaultToken) -> String {
+    let client_token = token.client_token.clone();
+    *store
+        .token
+        .lock()
+        .expect("Could not acquire Vault token lock") = Some(token);
+    client_token
+}
+
+/// The ways the API can authenticate with Vault, selected with `VAULT_AUTH_METHOD`.
+#[derive(Debug, PartialEq)]
+enum AuthMethod {
+    /// `VAULT_USERNAME` and `VAULT_PASSWORD`, the default
+    Ldap { username: String, password: String },
+    /// `VAULT_ROLE_ID` and `VAULT_SECRET_ID`
+    AppRole { role_id: String, secret_id: String },
+    /// `VAULT_K8S_ROLE` and the service account token at `VAULT_K8S_TOKEN_PATH`
+    Kubernetes { role: String, jwt: String },
+    /// A pre-issued `VAULT_TOKEN`
+    Token(String),
+}
+
+impl AuthMethod {
+    fn from_env() -> Result<Self, ApiError> {
+        let method = env::var("VAULT_AUTH_METHOD").unwrap_or_else(|_| "ldap".to_string());
+        match method.as_str() {
+            "ldap" => Ok(AuthMethod::Ldap {
+                username: required_env("VAULT_USERNAME")?,
+                password: required_env("VAULT_PASSWORD")?,
+            }),
+            "approle" => Ok(AuthMethod::AppRole {
+                role_id: required_env("VAULT_ROLE_ID")?,
+                secret_id: required_env("VAULT_SECRET_ID")?,
+            }),
+            "kubernetes" => {
+                let token_path = env::var("VAULT_K8S_TOKEN_PATH")
+                    .unwrap_or_else(|_| DEFAULT_K8S_TOKEN_PATH.to_string());
+                let jwt = fs::read_to_string(&token_path).map_err(|err| {
+                    ApiError::InternalError(format!(
+                        "Unable to read service account token {}: {}",
+                        token_path, err
+                    ))
+                })?;
+                Ok(AuthMethod::Kubernetes {
+                    role: required_env("VAULT_K8S_ROLE")?,
+                    jwt: jwt.trim().to_string(),
+                })
+            }
+            "token" => Ok(AuthMethod::Token(required_env("VAULT_TOKEN")?)),
+            other => Err(ApiError::InternalError(format!(
+                "Unsupported VAULT_AUTH_METHOD {}",
+                other
+            ))),
+        }
+    }
+
+    /// Returns the login path, relative to the Vault URL, and the request body.
+    ///
+    /// The auth mount defaults to the method name and may be overridden with `VAULT_AUTH_MOUNT`.
+    fn login_request(&self, mount: Option<String>) -> Option<(String, serde_json::Value)> {
+        let (default_mount, path, body) = match self {
+            AuthMethod::Ldap { username, password } => (
+                "ldap",
+                format!("login/{}", username),
+                json!({ "password": password }),
+            ),
+            AuthMethod::AppRole { role_id, secret_id } => (
+                "approle",
+                "login".to_string(),
+                json!({ "role_id": role_id, "secret_id": secret_id }),
+            ),
+            AuthMethod::Kubernetes { role, jwt } => (
+                "kubernetes",
+                "login".to_string(),
+                json!({ "role": role, "jwt": jwt }),
+            ),
+            AuthMethod::Token(_) => return None,
+        };
+        let mount = mount.unwrap_or_else(|| default_mount.to_string());
+        Some((format!("v1/auth/{}/{}", mount, path), body.into()))
+    }
+}
+
+/// Attempt to log in to an instance of HashiCorp Vault with the configured auth method.
+///
+/// If the env vars required by the auth method are not provided, or Vault rejects the
+/// credentials, this will fail with an `InternalError`
+fn vault_login(url: &str) -> Result<VaultToken, ApiError> {
+    let auth_method = AuthMethod::from_env()?;
+    match auth_method.login_request(env::var("VAULT_AUTH_MOUNT").ok()) {
+        Some((login_path, body)) => {
+            let login_url = url.to_string() + &login_path;
+            let client = reqwest::Client::new();
+            let issued_at = Instant::now();
+            let res: serde_json::Value = client
+                .post(&login_url)
+                .json(&body)
+                .send()
+                .map_err(VaultRequestError::from)
+                .and_then(check_response)
+                .map_err(|err| login_error(err, &login_path))?
+                .json()
+                .map_err(|e| ApiError::InternalError(e.to_string()))?;
+
+            VaultToken::from_auth(&res["auth"], issued_at)
+        }
+        None => match auth_method {
+            AuthMethod::Token(client_token) => Ok(VaultToken {
+                client_token,
+                renewable: false,
+                renew_at: None,
+                expires_at: None,
+            }),
+            _ => unreachable!("Only token auth skips login"),
+        },
+    }
+}
+
+/// Failing to log in is down to the configuration of the API rather than the request being
+/// handled, so rejected credentials and a missing auth method are internal errors
+fn login_error(err: VaultRequestError, login_path: &str) -> ApiError {
+    match err {
+        VaultRequestError::Forbidden => {
+            ApiError::InternalError("Vault rejected the login credentials".to_string())
+        }
+        VaultRequestError::Api(ApiError::BadRequest(errors)) => {
+            ApiError::InternalError(format!("Unable to log in to Vault: {}", errors))
+        }
+        VaultRequestError::Api(ApiError::NotFound(_)) => {
+            ApiError::InternalError(format!("Vault has no auth method at {}", login_path))
+        }
+        VaultRequestError::Api(err) => err,
+    }
+}
+
+/// Attempt to extend the lease of a client token before it expires.
+fn vault_renew(url: &str, token: &str) -> Result<VaultToken, ApiError> {
+    let renew_url = url.to_string() + "v1/auth/token/renew-self";
+    let client = reqwest::Client::new();
+    let issued_at = Instant::now();
+    let res: serde_json::Value = client
+        .post(&renew_url)
+        .header("X-Vault-Token", token)
+        .send()
+        .map_err(VaultRequestError::from)
+        .and_then(check_response)?
+        .json()
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+
+    VaultToken::from_auth(&res["auth"], issued_at)
+}
+
+/// The version of the key-value secrets engine keys are escrowed in, selected with
+/// `VAULT_KV_VERSION`.
+#[derive(Debug, PartialEq)]
+enum KvVersion {
+    V1,
+    V2,
+}
+
+/// Location of escrowed keys: `VAULT_MOUNT` (default `secret`) and the `VAULT_PATH` prefix.
+#[derive(Debug)]
+struct KvEngine {
+    mount: String,
+    path: String,
+    version: KvVersion,
+}
+
+impl KvEngine {
+    fn from_env() -> Result<Self, ApiError> {
+        let path = env::var("VAULT_PATH")
+            .map_err(|_| ApiError::InternalError("No VAULT_PATH provided".to_string()))?;
+        let version = match env::var("VAULT_KV_VERSION") {
+            Ok(ref version) if version == "2" => KvVersion::V2,
+            Ok(ref version) if version != "1" => {
+                return Err(ApiError::InternalError(format!(
+                    "Unsupported VAULT_KV_VERSION {}",
+                    version
+                )))
+            }
+            _ => KvVersion::V1,
+        };
+
+        Ok(KvEngine {
+            mount: env::var("VAULT_MOUNT").unwrap_or_else(|_| "secret".to_string()),
+            path,
+            version,
+        })
+    }
+
+    /// URL of a user's secret, optionally pinned to a KV v2 version
+    fn data_url(&self, url: &str, user_id: &str, version: Option<u64>) -> String {
+        let data_url = match self.version {
+            KvVersion::V1 => format!("{}v1/{}/{}{}", url, self.mount, self.path, user_id),
+            KvVersion::V2 => self.v2_url(url, "data", user_id),
+        };
+        match version {
+            Some(version) => format!("{}?version={}", data_url, version),
+            None => data_url,
+        }
+    }
+
+    /// URL of one of the KV v2 endpoints, such as `metadata` or `destroy`, for a user's secret
+    fn v2_url(&self, url: &str, endpoint: &str, user_id: &str) -> String {
+        format!(
+            "{}v1/{}/{}/{}{}",
+            url, self.mount, endpoint, self.path, user_id
+        )
+    }
+
+    fn require_v2(&self) -> Result<(), VaultRequestError> {
+        match self.version {
+            KvVersion::V2 => Ok(()),
+            KvVersion::V1 => Err(VaultRequestError::Api(ApiError::BadRequest(
+                "Key versions require a KV v2 secrets engine".to_string(),
+            ))),
+        }
+    }
+
+    fn write_body(&self, private_key: &str) -> serde_json::Value {
+        let data = json!({ "private_key": private_key });
+        match self.version {
+            KvVersion::V1 => data.into(),
+            KvVersion::V2 => json!({ "data": data }).into(),
+        }
+    }
+
+    /// Returns the secret's data from a read response
+    fn read_data<'a>(&self, res: &'a serde_json::Value) -> &'a serde_json::Value {
+        match self.version {
+            KvVersion::V1 => &res["data"],
+            KvVersion::V2 => &res["data"]["data"],
+        }
+    }
+}
+
+/// Attempt to write to an instance of HashiCorp Vault given the Vault URL and a valid client token.
+/// This stores `private_key` at Vault location `VAULT_MOUNT/VAULT_PATH/user_id`, returning
+/// the version that was created when using KV v2.
+///
+/// If the env var `VAULT_PATH` is not provided
+/// this will fail with an `InternalError`
+fn vault_write(
+    url: &str,
+    token: &str,
+    user_id: &str,
+    private_key: &str,
+) -> Result<Option<u64>, VaultRequestError> {
+    let kv = KvEngine::from_env().map_err(VaultRequestError::Api)?;
+    let client = reqwest::Client::new();
+    let mut res = check_response(
+        client
+            .post(&kv.data_url(url, user_id, None))
+            .json(&kv.write_body(private_key))
+            .header("X-Vault-Token", token)
+            .send()?,
+    )?;
+    match kv.version {
+        KvVersion::V1 => Ok(None),
+        KvVersion::V2 => {
+            let res: serde_json::Value = res.json()?;
+            Ok(res["data"]["version"].as_u64())
+        }
+    }
+}
+
+/// Attempt to read from an instance of HashiCorp Vault given the Vault URL and a valid client token.
+/// This reads from the Vault location `VAULT_MOUNT/VAULT_PATH/user_id` and assumes a JSON response.
+/// A specific `version` may be requested when using KV v2.
+///
+/// If the env var `VAULT_PATH` is not provided
+/// this will fail with an `InternalError`
+fn vault_read(
+    url: &str,
+    token: &str,
+    user_id: &str,
+    version: Option<u64>,
+) -> Result<String, VaultRequestError> {
+    let kv = KvEngine::from_env().map_err(VaultRequestError::Api)?;
+    if version.is_some() {
+        kv.require_v2()?;
+    }
+    let client = reqwest::Client::new();
+    let mut res = check_response(
+        client
+            .get(&kv.data_url(url, user_id, version))
+            .header("X-Vault-Token", token)
+            .send()?,
+    )?;
+    let res: serde_json::Value = res.json()?;
+    if let serde_json::Value::String(res_private_key) = &kv.read_data(&res)["private_key"] {
+        Ok(res_private_key.to_string())
+    } else {
+        Err(VaultRequestError::Api(ApiError::InternalError(
+            "Vault did not return the private key".to_string(),
+        )))
+    }
+}
+
+/// Attempt to delete a user's key from an instance of HashiCorp Vault given the Vault URL and a
+/// valid client token.
+///
+/// Without a `version` the secret and, when using KV v2, its whole history is removed. A single
+/// KV v2 `version` is permanently destroyed.
+fn vault_delete(
+    url: &str,
+    token: &str,
+    user_id: &str,
+    version: Option<u64>,
+) -> Result<(), VaultRequestError> {
+    let kv = KvEngine::from_env().map_err(VaultRequestError::Api)?;
+    let client = reqwest::Client::new();
+    let request = match (version, &kv.version) {
+        (None, KvVersion::V1) => client.delete(&kv.data_url(url, user_id, None)),
+        (None, KvVersion::V2) => client.delete(&kv.v2_url(url, "metadata", user_id)),
+        (Some(version), _) => {
+            kv.require_v2()?;
+            let body: serde_json::Value = json!({ "versions": [version] }).into();
+            client.post(&kv.v2_url(url, "destroy", user_id)).json(&body)
+        }
+    };
+    check_response(request.header("X-Vault-Token", token).send()?)?;
+    Ok(())
+}
+
+/// Attempt to list the versions of a user's key from the KV v2 metadata in an instance of
+/// HashiCorp Vault given the Vault URL and a valid client token.
+fn vault_list_versions(
+    url: &str,
+    token: &str,
+    user_id: &str,
+) -> Result<Vec<KeyVersion>, VaultRequestError> {
+    let kv = KvEngine::from_env().map_err(VaultRequestError::Api)?;
+    kv.require_v2()?;
+    let client = reqwest::Client::new();
+    let mut res = check_response(
+        client
+            .get(&kv.v2_url(url, "metadata", user_id))
+            .header("X-Vault-Token", token)
+            .send()?,
+    )?;
+    let res: serde_json::Value = res.json()?;
+    Ok(parse_versions(&res["data"]))
+}
+
+/// Reads the versions out of a KV v2 metadata response. Versions that were soft deleted
+/// have a `deletion_time`, those that were destroyed are flagged `destroyed`.
+fn parse_versions(metadata: &serde_json::Value) -> Vec<KeyVersion> {
+    let mut versions: Vec<KeyVersion> = metadata["versions"]
+        .as_object()
+        .map(|versions| {
+            versions
+                .iter()
+                .filter_map(|(version, info)| {
+                    Some(KeyVersion {
+                        version: version.parse().ok()?,
+                        created_at: info["created_time"].as_str().unwrap_or("").to_string(),
+                        deleted: info["destroyed"].as_bool().unwrap_or(false)
+                            || !info["deletion_time"].as_str().unwrap_or("").is_empty(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    versions.sort_by_key(|version| version.version);
+    versions
+}
+
+fn required_env(name: &str) -> Result<String, ApiError> {
+    env::var(name).map_err(|_| ApiError::InternalError(format!("No {} provided", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that a token with a lease is renewed before it expires
+    fn test_token_with_lease() {
+        let issued_at = Instant::now();
+        let token = VaultToken::from_auth(
+            &json!({
+                "client_token": "test_token",
+                "lease_duration": 300,
+                "renewable": true,
+            }),
+            issued_at,
+        )
+        .unwrap();
+
+        assert_eq!(token.client_token, "test_token");
+        assert!(token.renewable);
+        assert!(!token.needs_renewal(issued_at));
+        assert!(token.needs_renewal(issued_at + Duration::from_secs(250)));
+        assert!(!token.is_expired(issued_at + Duration::from_secs(250)));
+        assert!(token.is_expired(issued_at + Duration::from_secs(300)));
+    }
+
+    #[test]
+    /// Test that a token without a lease is never renewed or expired
+    fn test_token_without_lease() {
+        let issued_at = Instant::now();
+        let token = VaultToken::from_auth(
+            &json!({ "client_token": "root", "lease_duration": 0 }),
+            issued_at,
+        )
+        .unwrap();
+
+        assert!(!token.renewable);
+        assert!(!token.needs_renewal(issued_at + Duration::from_secs(86400)));
+        assert!(!token.is_expired(issued_at + Duration::from_secs(86400)));
+    }
+
+    #[test]
+    /// Test that a token that cannot be renewed is used until it expires
+    fn test_token_not_renewable() {
+        let issued_at = Instant::now();
+        let token = VaultToken::from_auth(
+            &json!({
+                "client_token": "test_token",
+                "lease_duration": 300,
+                "renewable": false,
+            }),
+            issued_at,
+        )
+        .unwrap();
+
+        assert!(!token.needs_renewal(issued_at + Duration::from_secs(250)));
+        assert!(!token.is_expired(issued_at + Duration::from_secs(250)));
+        assert!(token.is_expired(issued_at + Duration::from_secs(300)));
+    }
+
+    #[test]
+    /// Test that a response without a client token is rejected
+    fn test_token_missing_client_token() {
+        let token = VaultToken::from_auth(&serde_json::Value::Null, Instant::now());
+        assert!(token.is_err());
+    }
+
+    #[test]
+    /// Test that each auth method logs in against its own mount unless one is configured
+    fn test_login_request_mounts() {
+        let ldap = AuthMethod::Ldap {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+        };
+        let (path, body) = ldap.login_request(None).unwrap();
+        assert_eq!(path, "v1/auth/ldap/login/user");
+        assert_eq!(body["password"], "pass");
+
+        let approle = AuthMethod::AppRole {
+            role_id: "role".to_string(),
+            secret_id: "secret".to_string(),
+        };
+        let (path, body) = approle.login_request(Some("ci".to_string())).unwrap();
+        assert_eq!(path, "v1/auth/ci/login");
+        assert_eq!(body["role_id"], "role");
+        assert_eq!(body["secret_id"], "secret");
+
+        let kubernetes = AuthMethod::Kubernetes {
+            role: "api".to_string(),
+            jwt: "jwt".to_string(),
+        };
+        let (path, body) = kubernetes.login_request(None).unwrap();
+        assert_eq!(path, "v1/auth/kubernetes/login");
+        assert_eq!(body["jwt"], "jwt");
+
+        assert!(AuthMethod::Token("token".to_string())
+            .login_request(None)
+            .is_none());
+    }
+
+    #[test]
+    /// Test that KV v1 and v2 secrets are addressed and shaped correctly
+    fn test_kv_engine_paths() {
+        let v1 = KvEngine {
+            mount: "secret".to_string(),
+            path: "consensource/".to_string(),
+            version: KvVersion::V1,
+        };
+        assert_eq!(
+            v1.data_url("http://vault/", "user", None),
+            "http://vault/v1/secret/consensource/user"
+        );
+        assert_eq!(
+            v1.write_body("test_private_key")["private_key"],
+            "test_private_key"
+        );
+
+        let v2 = KvEngine {
+            mount: "kv".to_string(),
+            path: "consensource/".to_string(),
+            version: KvVersion::V2,
+        };
+        assert_eq!(
+            v2.data_url("http://vault/", "user", Some(3)),
+            "http://vault/v1/kv/data/consensource/user?version=3"
+        );
+        assert_eq!(
+            v2.write_body("test_private_key")["data"]["private_key"],
+            "test_private_key"
+        );
+        let res = json!({ "data": { "data": { "private_key": "test_private_key" } } });
+        assert_eq!(v2.read_data(&res)["private_key"], "test_private_key");
+        assert_eq!(
+            v2.v2_url("http://vault/", "metadata", "user"),
+            "http://vault/v1/kv/metadata/consensource/user"
+        );
+        assert!(v1.require_v2().is_err());
+    }
+
+    #[test]
+    /// Test that the versions in a KV v2 metadata response are listed in order
+    fn test_parse_versions() {
+        let metadata = json!({
+            "current_version": 3,
+            "versions": {
+                "3": { "created_time": "2019-06-03T00:00:00Z", "deletion_time": "", "destroyed": false },
+                "1": { "created_time": "2019-06-01T00:00:00Z", "deletion_time": "", "destroyed": true },
+                "2": {
+                    "created_time": "2019-06-02T00:00:00Z",
+                    "deletion_time": "2019-06-04T00:00:00Z",
+                    "destroyed": false
+                },
+            }
+        });
+        let versions = parse_versions(&metadata);
+        assert_eq!(
+            versions.iter().map(|v| v.version).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert!(versions[0].deleted);
+        assert!(versions[1].deleted);
+        assert!(!versions[2].deleted);
+        assert_eq!(versions[2].created_at, "2019-06-03T00:00:00Z");
+    }
+
+    #[test]
+    /// Test that Vault error responses are surfaced as the matching API errors
+    fn test_vault_error() {
+        let body = json!({ "errors": ["invalid version", "missing data"] });
+        match vault_error(StatusCode::BAD_REQUEST, &body) {
+            ApiError::BadRequest(msg) => assert!(msg.contains("invalid version, missing data")),
+            _ => panic!("Expected a BadRequest error"),
+        }
+        match vault_error(StatusCode::NOT_FOUND, &serde_json::Value::Null) {
+            ApiError::NotFound(_) => (),
+            _ => panic!("Expected a NotFound error"),
+        }
+        match vault_error(StatusCode::SERVICE_UNAVAILABLE, &serde_json::Value::Null) {
+            ApiError::ServiceUnavailable => (),
+            _ => panic!("Expected a ServiceUnavailable error"),
+        }
+        match vault_error(StatusCode::INTERNAL_SERVER_ERROR, &body) {
+            ApiError::InternalError(msg) => assert!(msg.contains("500")),
+            _ => panic!("Expected an InternalError"),
+        }
+    }
+
+    #[test]
+    /// Test that failed logins surface as rejected credentials rather than as errors of the
+    /// request being handled
+    fn test_login_error() {
+        match login_error(VaultRequestError::Forbidden, "v1/auth/ldap/login/api") {
+            ApiError::InternalError(msg) => assert!(msg.contains("login credentials")),
+            _ => panic!("Expected an InternalError"),
+        }
+        let err = VaultRequestError::Api(ApiError::BadRequest("invalid role".to_string()));
+        match login_error(err, "v1/auth/approle/login") {
+            ApiError::InternalError(msg) => assert!(msg.contains("invalid role")),
+            _ => panic!("Expected an InternalError"),
+        }
+        let err = VaultRequestError::Api(ApiError::NotFound(String::new()));
+        match login_error(err, "v1/auth/approle/login") {
+            ApiError::InternalError(msg) => assert!(msg.contains("v1/auth/approle/login")),
+            _ => panic!("Expected an InternalError"),
+        }
+        match login_error(
+            VaultRequestError::Api(ApiError::ServiceUnavailable),
+            "v1/auth/approle/login",
+        ) {
+            ApiError::ServiceUnavailable => (),
+            _ => panic!("Expected a ServiceUnavailable error"),
+        }
+    }
+}
@@ -3,7 +3,7 @@ use common::proto::payload;
 use database::DbConn;
 use protobuf::{Message, ProtobufError};
 use route_handlers::authorization::find_user_by_pub_key;
-use route_handlers::prom::increment_action;
+use route_handlers::prom::{increment_action, increment_key_access};
 use sawtooth_sdk::messages::batch::{Batch, BatchHeader};
 use sawtooth_sdk::messages::transaction::Transaction;
 
@@ -86,6 +86,22 @@ pub fn log_batch(conn: &DbConn, batch: &Batch) {
     info!("{} | User: {} | Actions: {:?}", now, &username, &actions)
 }
 
+/// Log an audit event for an access to a user's escrowed private key.
+///
+/// Events are logged with the `audit` target so they can be routed separately.
+pub fn log_key_access(username: &str, action: &str, version: Option<u64>) {
+    let now: DateTime<Utc> = Utc::now();
+    increment_key_access(action, username);
+    info!(
+        target: "audit",
+        "{} | User: {} | Key action: {} | Version: {}",
+        now,
+        username,
+        action,
+        version.map_or_else(|| "latest".to_string(), |version| version.to_string())
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,10 +1,12 @@
 #![allow(clippy::type_complexity)]
 #![feature(plugin)]
 #![feature(proc_macro_hygiene, decl_macro)]
+#![cfg_attr(test, feature(test))]
 // 'needless_pass_by_value' lint disabled due to an issue in Rocket
 // https://github.com/SergioBenitez/Rocket/issues/294
 #![allow(clippy::needless_pass_by_value)]
 
+extern crate aes_gcm;
 #[macro_use]
 extern crate clap;
 extern crate chrono;
@@ -15,10 +17,13 @@ extern crate database as database_manager;
 extern crate diesel;
 extern crate diesel_full_text_search;
 #[macro_use]
+extern crate diesel_migrations;
+#[macro_use]
 extern crate rocket;
 #[macro_use]
 extern crate rocket_contrib;
 extern crate protobuf;
+extern crate rand;
 extern crate sawtooth_sdk;
 extern crate serde;
 #[macro_use]
@@ -30,8 +35,12 @@ extern crate http;
 extern crate hyper;
 extern crate hyper_tls;
 extern crate serde_json;
+extern crate sha2;
+#[cfg(test)]
+extern crate test;
 extern crate tokio_core;
 extern crate uuid;
+extern crate zip;
 #[macro_use]
 extern crate log;
 extern crate log4rs;
@@ -41,33 +50,42 @@ extern crate hyper_sse;
 #[macro_use]
 extern crate prometheus;
 
+mod cache;
 mod database;
+mod diff;
 mod errors;
+mod expand;
 mod fairings;
+mod fields;
+mod geo;
+mod history;
 mod jwt;
+mod key_store;
 mod logging;
+mod negotiation;
 mod paging;
 mod route_handlers;
+mod xlsx;
+mod xml;
 
-use database::init_pool;
-use fairings::CORS;
+use database::{init_pool, DbConn};
+use fairings::{AsOf, HeadBlock, CORS};
 use log::LevelFilter;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::config::{Appender, Config, Root};
 use log4rs::encode::pattern::PatternEncoder;
 use rocket::response::NamedFile;
 use route_handlers::{
-    agents, assertions, authorization, blockchain, blocks, certificates, cors, factories, file,
-    health, organizations, prom, requests, standards, standards_body, vault,
+    agents, assertions, authorization, autocomplete, blockchain, blocks, certificates, consistency,
+    cors, factories, file, health, imports, locations, organizations, prom, requests, search,
+    standards, standards_body, transactions, vault,
 };
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
 use std::{env, io, process};
 
-pub struct VaultConfig {
-    url: String,
-    token: Mutex<String>,
-}
+// The API's own tables and functions, applied at startup. Every migration is idempotent, so
+// databases they were already applied to by hand are left as they are.
+embed_migrations!("migrations");
 
 #[get("/")]
 fn index() -> io::Result<NamedFile> {
@@ -99,6 +117,10 @@ fn main() {
         "the authorized user of the database")
     (@arg dbpass: default_value("consensourcedb") --dbpass +takes_value
         "the authorized user's password for database access")
+    (@subcommand check =>
+        (about: "compare the database against on-chain state and exit")
+        (@arg head: --head +takes_value
+         "the block number to check, defaults to the current head"))
     )
     .get_matches();
 
@@ -150,6 +172,47 @@ fn main() {
 
     let connection_pool = init_pool(database_url);
 
+    if let Some(check_matches) = matches.subcommand_matches("check") {
+        let head = if check_matches.is_present("head") {
+            Some(value_t_or_exit!(check_matches, "head", i64))
+        } else {
+            None
+        };
+        let conn = match connection_pool.get() {
+            Ok(conn) => DbConn(conn),
+            Err(err) => {
+                error!("Unable to connect to database: {}", err);
+                process::exit(1);
+            }
+        };
+        match consistency::run_consistency_check(&conn, &validator_url, head) {
+            Ok(report) => {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&report).expect("Report is serializable")
+                );
+                process::exit(if report.is_consistent() { 0 } else { 2 });
+            }
+            Err(err) => {
+                error!("Consistency check failed: {:?}", err);
+                process::exit(1);
+            }
+        }
+    }
+
+    match connection_pool.get() {
+        Ok(conn) => {
+            if let Err(err) = embedded_migrations::run(&*conn) {
+                error!("Unable to run database migrations: {}", err);
+                process::exit(1);
+            }
+        }
+        Err(err) => {
+            error!("Unable to connect to database: {}", err);
+            process::exit(1);
+        }
+    }
+
     let host = env::var("ROCKET_ADDRESS").unwrap_or_else(|_| "127.0.0.1".into());
 
     let port: u16 = match env::var("ROCKET_PORT")
@@ -164,7 +227,13 @@ fn main() {
         }
     };
 
-    let vault_url = env::var("VAULT_URL").unwrap_or_else(|_| "".into());
+    let key_store = match key_store::from_env() {
+        Ok(key_store) => key_store,
+        Err(err) => {
+            error!("Unable to configure key store: {}", err);
+            process::exit(1);
+        }
+    };
 
     let block_watcher = blocks::BlockWatcher::new(connection_pool.clone());
     let watcher_thread = blocks::WatcherThread::run(block_watcher, 250, &host, port + 1);
@@ -177,10 +246,7 @@ fn main() {
         ])
         .manage(connection_pool)
         .manage(validator_url)
-        .manage(VaultConfig {
-            url: vault_url,
-            token: Mutex::new("".to_string()),
-        })
+        .manage(key_store)
         .mount(
             "/api",
             routes![
@@ -189,6 +255,7 @@ fn main() {
                 cors::cors_batches_route,
                 agents::fetch_agent,
                 agents::fetch_agent_with_head_param,
+                agents::fetch_agent_history,
                 agents::list_agents,
                 agents::list_agents_with_params,
                 assertions::fetch_assertions,
@@ -209,32 +276,71 @@ fn main() {
                 factories::fetch_factory_with_head_param,
                 factories::list_factories,
                 factories::list_factories_params,
+                imports::preview_factory_import,
+                locations::put_factory_location,
+                locations::put_factory_location_jwt_failure,
+                locations::geocode_factories,
+                locations::geocode_factories_jwt_failure,
                 health::check,
+                transactions::build_transaction,
                 requests::fetch_request,
                 requests::fetch_request_with_head_param,
+                requests::fetch_request_history,
                 requests::list_requests,
                 requests::list_request_with_params,
                 organizations::fetch_organization,
                 organizations::fetch_organization_with_params,
+                organizations::fetch_organization_history,
+                organizations::fetch_organization_diff,
                 organizations::list_organizations,
                 organizations::list_organizations_with_params,
                 certificates::fetch_certificate,
                 certificates::fetch_certificate_with_head_param,
+                certificates::fetch_certificate_history,
+                certificates::fetch_certificate_diff,
                 certificates::list_certificates,
                 certificates::list_certificates_with_params,
+                consistency::check_consistency,
+                consistency::check_consistency_with_params,
+                consistency::check_consistency_jwt_failure,
                 standards::list_standards,
                 standards::list_standards_with_params,
+                standards::fetch_standard_history,
+                standards::fetch_standard_diff,
                 standards_body::list_standards_belonging_to_org,
                 prom::get_metrics,
+                search::search,
+                search::search_with_params,
+                autocomplete::autocomplete,
+                autocomplete::autocomplete_with_params,
                 vault::get_key,
                 vault::store_key,
                 vault::store_key_jwt_failure,
                 vault::get_key_jwt_failure,
+                vault::delete_key,
+                vault::delete_key_jwt_failure,
+                vault::list_key_versions,
+                vault::list_key_versions_jwt_failure,
+                vault::rollback_key,
+                vault::rollback_key_jwt_failure,
                 file::get_factories,
+                file::get_factories_with_params,
+                file::get_factories_geojson,
+                file::get_factories_geojson_with_params,
+                file::get_factories_kml,
+                file::get_factories_kml_with_params,
+                file::get_certificates_csv,
+                file::get_certificates_xlsx,
+                file::get_requests_csv,
+                file::get_requests_xlsx,
+                file::get_standards_csv,
+                file::get_standards_xlsx,
             ],
         )
         .mount("/", routes![index, files])
         .attach(CORS())
+        .attach(AsOf())
+        .attach(HeadBlock())
         .launch();
 
     watcher_thread.join().unwrap();
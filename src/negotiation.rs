@@ -0,0 +1,212 @@
+use csv;
+use fairings::HEAD_BLOCK_HEADER;
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket_contrib::json::JsonValue;
+use serde_json::{Map, Value};
+use std::io::Cursor;
+use std::ops::Deref;
+
+/// The representations a list response can be sent as
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Representation {
+    Json,
+    Csv,
+    NdJson,
+}
+
+impl Representation {
+    /// Picks the representation preferred by the `Accept` header, defaulting to JSON
+    pub fn from_request(request: &Request) -> Self {
+        let media_type = match request.accept() {
+            Some(accept) => accept.preferred().media_type().clone(),
+            None => return Representation::Json,
+        };
+        if media_type.top() == "text" && media_type.sub() == "csv" {
+            Representation::Csv
+        } else if media_type.top() == "application" && media_type.sub() == "x-ndjson" {
+            Representation::NdJson
+        } else {
+            Representation::Json
+        }
+    }
+
+    /// The subtype of the media type of the representation
+    pub fn subtype(self) -> &'static str {
+        match self {
+            Representation::Json => "json",
+            Representation::Csv => "csv",
+            Representation::NdJson => "x-ndjson",
+        }
+    }
+}
+
+/// Responds with the `data` of a list response as JSON, CSV or newline delimited JSON,
+/// depending on the `Accept` header of the request.
+///
+/// CSV and NDJSON only contain the records in `data`, so the total number of records that
+/// `paging` would report is sent in an `X-Total-Count` header instead. The `head` is sent in an
+/// `X-Head-Block` header in every representation. Responses without a `data` array, like
+/// GeoJSON, are always sent as JSON.
+#[derive(Debug)]
+pub struct Negotiated(pub JsonValue);
+
+impl Deref for Negotiated {
+    type Target = JsonValue;
+
+    fn deref(&self) -> &JsonValue {
+        &self.0
+    }
+}
+
+impl PartialEq<JsonValue> for Negotiated {
+    fn eq(&self, other: &JsonValue) -> bool {
+        self.0 == *other
+    }
+}
+
+impl<'r> Responder<'r> for Negotiated {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let records = match self.0.get("data").and_then(Value::as_array) {
+            Some(records) => records.clone(),
+            None => return self.0.respond_to(request),
+        };
+        let head = self.0.get("head").map(Value::to_string);
+        let (content_type, body) = match Representation::from_request(request) {
+            Representation::Json => {
+                let mut response = self.0.respond_to(request)?;
+                if let Some(head) = head {
+                    response.set_raw_header(HEAD_BLOCK_HEADER, head);
+                }
+                return Ok(response);
+            }
+            Representation::Csv => (
+                ContentType::CSV,
+                write_csv(&records).map_err(|err| {
+                    error!("Unable to write records as CSV: {}", err);
+                    Status::InternalServerError
+                })?,
+            ),
+            Representation::NdJson => (
+                ContentType::new("application", "x-ndjson"),
+                write_ndjson(&records),
+            ),
+        };
+
+        let mut response = Response::build();
+        response.header(content_type).sized_body(Cursor::new(body));
+        if let Some(total) = self.0.pointer("/paging/total") {
+            response.raw_header("X-Total-Count", total.to_string());
+        }
+        if let Some(head) = head {
+            response.raw_header(HEAD_BLOCK_HEADER, head);
+        }
+        response.ok()
+    }
+}
+
+fn write_ndjson(records: &[Value]) -> String {
+    records
+        .iter()
+        .map(|record| format!("{}\n", record))
+        .collect()
+}
+
+/// Writes records as CSV with one column per field. Nested objects are flattened into
+/// `parent.child` columns and arrays are written as JSON.
+fn write_csv(records: &[Value]) -> Result<String, csv::Error> {
+    let rows: Vec<Map<String, Value>> = records
+        .iter()
+        .map(|record| {
+            let mut row = Map::new();
+            flatten("", record, &mut row);
+            row
+        })
+        .collect();
+
+    // Records may be missing optional fields, so the header has every field of every record
+    let mut columns: Vec<&String> = vec![];
+    for row in &rows {
+        for column in row.keys() {
+            if !columns.contains(&column) {
+                columns.push(column);
+            }
+        }
+    }
+
+    if columns.is_empty() {
+        return Ok(String::new());
+    }
+
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    wtr.write_record(&columns)?;
+    for row in &rows {
+        wtr.write_record(columns.iter().map(|column| match row.get(*column) {
+            Some(Value::String(value)) => value.clone(),
+            Some(Value::Null) | None => String::new(),
+            Some(value) => value.to_string(),
+        }))?;
+    }
+    let csv = wtr
+        .into_inner()
+        .map_err(|err| csv::Error::from(err.into_error()))?;
+    Ok(String::from_utf8_lossy(&csv).into_owned())
+}
+
+fn flatten(prefix: &str, value: &Value, row: &mut Map<String, Value>) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields {
+                let column = if prefix.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", prefix, name)
+                };
+                flatten(&column, field, row);
+            }
+        }
+        _ => {
+            let column = if prefix.is_empty() { "value" } else { prefix };
+            row.insert(column.to_string(), value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that records are written as CSV with flattened nested fields
+    fn test_write_csv() {
+        let records = vec![
+            json!({
+                "id": "factory_1",
+                "address": { "city": "Lima", "country": "Peru" },
+                "contacts": [{ "name": "Ana" }],
+            })
+            .0,
+            json!({ "id": "factory_2", "assertion_id": "assertion_1" }).0,
+        ];
+        assert_eq!(
+            write_csv(&records).unwrap(),
+            "address.city,address.country,contacts,id,assertion_id\n\
+             Lima,Peru,\"[{\"\"name\"\":\"\"Ana\"\"}]\",factory_1,\n\
+             ,,,factory_2,assertion_1\n"
+        );
+    }
+
+    #[test]
+    /// Test that an empty list is written as an empty CSV without a header
+    fn test_write_csv_empty() {
+        assert_eq!(write_csv(&[]).unwrap(), "");
+    }
+
+    #[test]
+    /// Test that records are written as one JSON document per line
+    fn test_write_ndjson() {
+        let records = vec![json!({ "id": 1 }).0, json!({ "id": 2 }).0];
+        assert_eq!(write_ndjson(&records), "{\"id\":1}\n{\"id\":2}\n");
+    }
+}
@@ -1,10 +1,21 @@
+use chrono::DateTime;
 use database::DbConn;
 use database_manager::tables_schema::blocks;
-use diesel::dsl::max;
+use diesel::dsl::{exists, max};
 use diesel::prelude::*;
+use diesel::select;
 use errors::ApiError;
 use rocket_contrib::json::JsonValue;
 
+table! {
+    /// Seconds since the epoch at which each block was written to the database. Blocks carry
+    /// no timestamp on chain, so these are recorded by a trigger on `blocks`.
+    block_timestamps (block_num) {
+        block_num -> BigInt,
+        committed_at -> BigInt,
+    }
+}
+
 pub const DEFAULT_LIMIT: i64 = 100;
 pub const DEFAULT_OFFSET: i64 = 0;
 
@@ -53,18 +64,62 @@ pub fn get_response_paging_info(
     }}))
 }
 
+/// Resolves the block a request is answered at: the requested `head`, which must be a block
+/// that has been committed, or else the latest block
 pub fn get_head_block_num(head: Option<i64>, conn: &DbConn) -> Result<i64, ApiError> {
-    if let Some(head) = head {
-        Ok(head)
-    } else {
-        blocks::table
+    match head {
+        Some(head) if head < 0 => Err(ApiError::BadRequest(format!(
+            "Invalid head {}: block numbers are not negative",
+            head
+        ))),
+        Some(head) => {
+            let committed = select(exists(blocks::table.filter(blocks::block_num.eq(head))))
+                .get_result::<bool>(&**conn)?;
+            if committed {
+                Ok(head)
+            } else {
+                Err(ApiError::NotFound(format!(
+                    "Block {} has not been committed",
+                    head
+                )))
+            }
+        }
+        None => blocks::table
             .select(max(blocks::block_num))
             .first::<Option<i64>>(&**conn)
             .map_err(|err| ApiError::InternalError(err.to_string()))
-            .and_then(|block_num| block_num.ok_or(ApiError::ServiceUnavailable))
+            .and_then(|block_num| block_num.ok_or(ApiError::ServiceUnavailable)),
     }
 }
 
+/// The time a block was committed, in seconds since the epoch, if it was recorded
+pub fn get_block_committed_at(block_num: i64, conn: &DbConn) -> Result<Option<i64>, ApiError> {
+    Ok(block_timestamps::table
+        .select(block_timestamps::committed_at)
+        .filter(block_timestamps::block_num.eq(block_num))
+        .first::<i64>(&**conn)
+        .optional()?)
+}
+
+/// Resolves an RFC 3339 timestamp to the last block committed at or before it
+pub fn get_block_num_as_of(as_of: &str, conn: &DbConn) -> Result<i64, ApiError> {
+    let timestamp = DateTime::parse_from_rfc3339(as_of)
+        .map_err(|err| {
+            ApiError::BadRequest(format!(
+                "Invalid as_of {}, expected RFC 3339: {}",
+                as_of, err
+            ))
+        })?
+        .timestamp();
+
+    block_timestamps::table
+        .select(max(block_timestamps::block_num))
+        .filter(block_timestamps::committed_at.le(timestamp))
+        .filter(block_timestamps::block_num.eq_any(blocks::table.select(blocks::block_num)))
+        .first::<Option<i64>>(&**conn)?
+        .ok_or_else(|| ApiError::NotFound(format!("No block was committed at or before {}", as_of)))
+}
+
 #[cfg(test)]
 mod tests {
     use paging::*;
@@ -4,11 +4,17 @@ use database_manager::models::{Agent, Organization};
 use database_manager::tables_schema::{agents, organizations};
 use diesel::prelude::*;
 use errors::ApiError;
+use expand::{Expansions, Resource};
+use fields::Fields;
+use history::versions;
+use negotiation::Negotiated;
 use paging::*;
+use rocket::http::uri::Uri;
 use rocket::request::Form;
 use rocket_contrib::json::JsonValue;
 use route_handlers::prom::increment_http_req;
 use std::collections::HashMap;
+use std::slice;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ApiOrganization {
@@ -65,13 +71,46 @@ pub fn fetch_agent_with_head_param(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
+    let expansions = Expansions::parse(Resource::Agent, &head_param.expand)?;
+    let fields = Fields::parse(&head_param.fields);
     let head_block_num: i64 = get_head_block_num(head_param.head, &conn)?;
 
+    let mut link = format!("/api/agents/{}?head={}", public_key, head_block_num);
+    if !expansions.is_empty() {
+        link = format!("{}&expand={}", link, expansions.to_param());
+    }
+    if let Some(ref fields) = head_param.fields {
+        link = format!("{}&fields={}", link, Uri::percent_encode(fields));
+    }
+
+    match load_agent(&conn, &public_key, head_block_num)? {
+        Some(agent) => {
+            let mut data = agent.0;
+            expansions.apply(&conn, slice::from_mut(&mut data), head_block_num)?;
+            fields.select(slice::from_mut(&mut data));
+            Ok(json!({
+                "data": data,
+                "link": link,
+                "head": head_block_num, }))
+        }
+        None => Err(ApiError::NotFound(format!(
+            "No agent with the public key {} exists",
+            public_key
+        ))),
+    }
+}
+
+/// Loads the agent with its organization as they were at `head_block_num`
+pub fn load_agent(
+    conn: &DbConn,
+    public_key: &str,
+    head_block_num: i64,
+) -> Result<Option<JsonValue>, ApiError> {
     let agent = agents::table
-        .filter(agents::public_key.eq(public_key.to_string()))
+        .filter(agents::public_key.eq(public_key))
         .filter(agents::start_block_num.le(head_block_num))
         .filter(agents::end_block_num.gt(head_block_num))
-        .first::<Agent>(&*conn)
+        .first::<Agent>(&**conn)
         .optional()
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
 
@@ -83,35 +122,64 @@ pub fn fetch_agent_with_head_param(
                         .filter(organizations::organization_id.eq(organization_id))
                         .filter(organizations::start_block_num.le(head_block_num))
                         .filter(organizations::end_block_num.gt(head_block_num))
-                        .first::<Organization>(&*conn)
+                        .first::<Organization>(&**conn)
                         .optional()
                         .map_err(|err| ApiError::InternalError(err.to_string()))?
                 } else {
                     None
                 };
 
-            let link = format!("/api/agents/{}?head={}", public_key, head_block_num);
-            Ok(json!({
-                "data": ApiAgent::with_org(&agent, &org),
-                "link": link,
-                "head": head_block_num, }))
+            Ok(Some(json!(ApiAgent::with_org(&agent, &org))))
         }
-        None => Err(ApiError::NotFound(format!(
+        None => Ok(None),
+    }
+}
+
+/// Returns every version of an agent up to `head` with the range of blocks it was live for,
+/// e.g. to see when it joined an organization
+#[get("/agents/<public_key>/history?<head>")]
+pub fn fetch_agent_history(
+    public_key: String,
+    head: Option<i64>,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let head_block_num: i64 = get_head_block_num(head, &conn)?;
+    let ranges = agents::table
+        .select((agents::start_block_num, agents::end_block_num))
+        .filter(agents::public_key.eq(&public_key))
+        .load::<(i64, i64)>(&*conn)?;
+
+    let versions = versions(&ranges, head_block_num, |block_num| {
+        load_agent(&conn, &public_key, block_num)
+    })?;
+    if versions.is_empty() {
+        return Err(ApiError::NotFound(format!(
             "No agent with the public key {} exists",
             public_key
-        ))),
+        )));
     }
+
+    Ok(json!({
+        "data": versions,
+        "link": format!("/api/agents/{}/history?head={}", public_key, head_block_num),
+        "head": head_block_num,
+    }))
 }
 
 #[derive(Default, FromForm, Clone)]
 pub struct AgentParams {
+    expand: Option<String>,
+    fields: Option<String>,
     limit: Option<i64>,
     offset: Option<i64>,
     head: Option<i64>,
 }
 
 #[get("/agents")]
-pub fn list_agents(conn: DbConn) -> Result<JsonValue, ApiError> {
+pub fn list_agents(conn: DbConn) -> Result<Negotiated, ApiError> {
     list_agents_with_params(None, conn)
 }
 
@@ -119,7 +187,7 @@ pub fn list_agents(conn: DbConn) -> Result<JsonValue, ApiError> {
 pub fn list_agents_with_params(
     params: Option<Form<AgentParams>>,
     conn: DbConn,
-) -> Result<JsonValue, ApiError> {
+) -> Result<Negotiated, ApiError> {
     // Increment HTTP request count for Prometheus metrics
     increment_http_req();
 
@@ -127,6 +195,8 @@ pub fn list_agents_with_params(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
+    let expansions = Expansions::parse(Resource::Agent, &params.expand)?;
+    let fields = Fields::parse(&params.fields);
     let head_block_num: i64 = get_head_block_num(params.head, &conn)?;
 
     let mut agents_query = agents::table
@@ -142,7 +212,7 @@ pub fn list_agents_with_params(
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
 
     let link_params = params.clone();
-    let paging_info = apply_paging(link_params, head_block_num, total_count)?;
+    let paging_info = apply_paging(&expansions, link_params, head_block_num, total_count)?;
 
     agents_query = agents_query.limit(params.limit.unwrap_or(DEFAULT_LIMIT));
     agents_query = agents_query.offset(params.offset.unwrap_or(DEFAULT_OFFSET));
@@ -157,30 +227,55 @@ pub fn list_agents_with_params(
         .filter_map(|x| x)
         .collect();
 
-    let mut organization_results: HashMap<String, Organization> = organizations::table
-        .filter(organizations::start_block_num.le(head_block_num))
-        .filter(organizations::end_block_num.gt(head_block_num))
-        .filter(organizations::organization_id.eq_any(org_ids))
-        .load::<Organization>(&*conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, org| {
-            acc.insert(org.organization_id.clone(), org);
-            acc
-        });
-
-    Ok(json!({ "data": agent_results.iter().map(|agent| {
-        let org: Option<Organization> = agent.organization_id.as_ref()
-            .and_then(|id| organization_results.remove(id));
-        ApiAgent::with_org(agent, &org)
-    }).collect::<Vec<_>>(),
+    let mut organization_results = if fields.includes("organization") {
+        organizations::table
+            .filter(organizations::start_block_num.le(head_block_num))
+            .filter(organizations::end_block_num.gt(head_block_num))
+            .filter(organizations::organization_id.eq_any(org_ids))
+            .load::<Organization>(&*conn)
+            .map_err(|err| ApiError::InternalError(err.to_string()))?
+            .into_iter()
+            .fold(HashMap::new(), |mut acc, org| {
+                acc.insert(org.organization_id.clone(), org);
+                acc
+            })
+    } else {
+        HashMap::new()
+    };
+
+    let mut data = agent_results
+        .iter()
+        .map(|agent| {
+            let org: Option<Organization> = agent
+                .organization_id
+                .as_ref()
+                .and_then(|id| organization_results.remove(id));
+            json!(ApiAgent::with_org(agent, &org)).0
+        })
+        .collect::<Vec<_>>();
+    expansions.apply(&conn, &mut data, head_block_num)?;
+    fields.select(&mut data);
+
+    Ok(Negotiated(json!({ "data": data,
                     "link": paging_info.get("link"),
                     "head": head_block_num,
-                    "paging": paging_info.get("paging") }))
+                    "paging": paging_info.get("paging") })))
 }
 
-fn apply_paging(params: AgentParams, head: i64, total_count: i64) -> Result<JsonValue, ApiError> {
-    let link = format!("/api/agents?head={}&", head);
+fn apply_paging(
+    expansions: &Expansions,
+    params: AgentParams,
+    head: i64,
+    total_count: i64,
+) -> Result<JsonValue, ApiError> {
+    let mut link = String::from("/api/agents?");
+    if !expansions.is_empty() {
+        link = format!("{}expand={}&", link, expansions.to_param());
+    }
+    if let Some(fields) = params.fields {
+        link = format!("{}fields={}&", link, Uri::percent_encode(&fields));
+    }
+    link = format!("{}head={}&", link, head);
 
     get_response_paging_info(params.limit, params.offset, link, total_count)
 }
@@ -4,14 +4,22 @@ use database_manager::models::Assertion;
 use database_manager::tables_schema::assertions;
 use diesel::prelude::*;
 use errors::ApiError;
+use expand::{Expansions, Resource};
+use fields::Fields;
+use negotiation::Negotiated;
 use paging::*;
+use rocket::http::uri::Uri;
 use rocket::request::Form;
 use rocket_contrib::json::JsonValue;
 use route_handlers::prom::increment_http_req;
+use std::collections::HashMap;
+use std::slice;
 
 #[derive(Default, FromForm, Clone)]
 pub struct AssertionParams {
     organization_id: Option<String>,
+    expand: Option<String>,
+    fields: Option<String>,
     limit: Option<i64>,
     offset: Option<i64>,
     head: Option<i64>,
@@ -72,6 +80,8 @@ pub fn fetch_assertions_with_params(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
+    let expansions = Expansions::parse(Resource::Assertion, &params.expand)?;
+    let fields = Fields::parse(&params.fields);
     let head_block_num: i64 = get_head_block_num(params.head, &conn)?;
     let assertion = assertions::table
         .filter(assertions::assertion_id.eq(assertion_id.clone()))
@@ -80,11 +90,20 @@ pub fn fetch_assertions_with_params(
         .first::<Assertion>(&*conn)
         .optional()
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
-    let link = format!("/api/assertions/{}?head={}", assertion_id, head_block_num);
+    let mut link = format!("/api/assertions/{}?head={}", assertion_id, head_block_num);
+    if !expansions.is_empty() {
+        link = format!("{}&expand={}", link, expansions.to_param());
+    }
+    if let Some(ref fields) = params.fields {
+        link = format!("{}&fields={}", link, Uri::percent_encode(fields));
+    }
     match assertion {
-        Some(assertion) => Ok(
-            json!({ "data": ApiAssertion::from(assertion), "link": link, "head": head_block_num }),
-        ),
+        Some(assertion) => {
+            let mut data = json!(ApiAssertion::from(assertion)).0;
+            expansions.apply(&conn, slice::from_mut(&mut data), head_block_num)?;
+            fields.select(slice::from_mut(&mut data));
+            Ok(json!({ "data": data, "link": link, "head": head_block_num }))
+        }
         None => Err(ApiError::NotFound(format!(
             "No assertion with the id {} exists",
             assertion_id
@@ -93,7 +112,7 @@ pub fn fetch_assertions_with_params(
 }
 
 #[get("/assertions")]
-pub fn list_assertions(conn: DbConn) -> Result<JsonValue, ApiError> {
+pub fn list_assertions(conn: DbConn) -> Result<Negotiated, ApiError> {
     list_assertions_with_params(None, conn)
 }
 
@@ -101,7 +120,7 @@ pub fn list_assertions(conn: DbConn) -> Result<JsonValue, ApiError> {
 pub fn list_assertions_with_params(
     params: Option<Form<AssertionParams>>,
     conn: DbConn,
-) -> Result<JsonValue, ApiError> {
+) -> Result<Negotiated, ApiError> {
     // Increment HTTP request count for Prometheus metrics
     increment_http_req();
 
@@ -109,6 +128,8 @@ pub fn list_assertions_with_params(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
+    let expansions = Expansions::parse(Resource::Assertion, &params.expand)?;
+    let fields = Fields::parse(&params.fields);
     let head_block_num: i64 = get_head_block_num(params.head, &conn)?;
     let mut assertions_query = assertions::table
         .filter(assertions::start_block_num.le(head_block_num))
@@ -122,7 +143,7 @@ pub fn list_assertions_with_params(
         .get_result(&*conn)
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
 
-    let paging_info = apply_paging(params.clone(), head_block_num, total_count)?;
+    let paging_info = apply_paging(&expansions, params.clone(), head_block_num, total_count)?;
 
     assertions_query = assertions_query.limit(params.limit.unwrap_or(DEFAULT_LIMIT));
     assertions_query = assertions_query.offset(params.offset.unwrap_or(DEFAULT_OFFSET));
@@ -130,22 +151,55 @@ pub fn list_assertions_with_params(
     let assertions = assertions_query
         .load::<Assertion>(&*conn)
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
+    let mut data = assertions
+        .iter()
+        .map(|assertion| json!(ApiAssertion::from(assertion)).0)
+        .collect::<Vec<_>>();
+    expansions.apply(&conn, &mut data, head_block_num)?;
+    fields.select(&mut data);
 
-    Ok(
-        json!({ "data": assertions.iter().map(ApiAssertion::from).collect::<Vec<_>>(),
-           "link": paging_info.get("link"),
-           "head": head_block_num,
-           "paging": paging_info.get("paging")
-        }),
-    )
+    Ok(Negotiated(json!({ "data": data,
+       "link": paging_info.get("link"),
+       "head": head_block_num,
+       "paging": paging_info.get("paging")
+    })))
+}
+
+/// Loads the assertions with the given ids as they were at `head_block_num`, keyed by id
+pub fn load_assertions(
+    conn: &DbConn,
+    assertion_ids: &[String],
+    head_block_num: i64,
+) -> Result<HashMap<String, JsonValue>, ApiError> {
+    Ok(assertions::table
+        .filter(assertions::assertion_id.eq_any(assertion_ids))
+        .filter(assertions::start_block_num.le(head_block_num))
+        .filter(assertions::end_block_num.gt(head_block_num))
+        .load::<Assertion>(&**conn)?
+        .into_iter()
+        .map(|assertion| {
+            (
+                assertion.assertion_id.clone(),
+                json!(ApiAssertion::from(assertion)),
+            )
+        })
+        .collect())
 }
 
 fn apply_paging(
+    expansions: &Expansions,
     params: AssertionParams,
     head: i64,
     total_count: i64,
 ) -> Result<JsonValue, ApiError> {
-    let link = format!("/api/assertions?head={}&", head);
+    let mut link = String::from("/api/assertions?");
+    if !expansions.is_empty() {
+        link = format!("{}expand={}&", link, expansions.to_param());
+    }
+    if let Some(fields) = params.fields {
+        link = format!("{}fields={}&", link, Uri::percent_encode(&fields));
+    }
+    link = format!("{}head={}&", link, head);
 
     get_response_paging_info(params.limit, params.offset, link, total_count)
 }
@@ -4,11 +4,13 @@ use route_handlers::prom::{increment_http_req, increment_signin};
 
 use database::DbConn;
 use database_manager::models::User;
-use database_manager::tables_schema::users;
+use database_manager::tables_schema::{agents, users};
 use diesel;
 use diesel::prelude::*;
 use errors::ApiError;
 use jwt;
+use serde_json;
+use std::env;
 
 #[derive(Serialize, Deserialize)]
 pub struct UserCreate {
@@ -148,6 +150,66 @@ pub fn find_user_by_pub_key(conn: &DbConn, public_key: &str) -> Result<Option<Us
         .optional()
         .map_err(|e| ApiError::InternalError(format!("Unable to access database: {}", e)))
 }
+/// Returns the public key of the user a JWT was issued to, from its `username` claim
+fn jwt_public_key(conn: &DbConn, claims: &jwt::JWT) -> Result<String, ApiError> {
+    let username = match claims.0["username"] {
+        serde_json::Value::String(ref username) => username,
+        _ => {
+            return Err(ApiError::Forbidden(
+                "The token does not identify a user".to_string(),
+            ))
+        }
+    };
+    match find_user_by_username(conn, username)? {
+        Some(user) => Ok(user.public_key),
+        None => Err(ApiError::Forbidden(format!("No user {} exists", username))),
+    }
+}
+
+/// Whether `public_key` is one of the comma separated `API_ADMIN_PUBLIC_KEYS`
+fn is_admin(public_key: &str) -> bool {
+    env::var("API_ADMIN_PUBLIC_KEYS")
+        .map(|admins| admins.split(',').any(|admin| admin.trim() == public_key))
+        .unwrap_or(false)
+}
+
+/// Checks that the user a JWT was issued to is an admin of the API
+pub fn require_admin(conn: &DbConn, claims: &jwt::JWT) -> Result<(), ApiError> {
+    if is_admin(&jwt_public_key(conn, claims)?) {
+        Ok(())
+    } else {
+        Err(ApiError::Forbidden("Only admins can do this".to_string()))
+    }
+}
+
+/// Checks that the user a JWT was issued to is an agent of `organization_id` at
+/// `head_block_num`, or an admin of the API
+pub fn require_agent_of(
+    conn: &DbConn,
+    claims: &jwt::JWT,
+    organization_id: &str,
+    head_block_num: i64,
+) -> Result<(), ApiError> {
+    let public_key = jwt_public_key(conn, claims)?;
+    if is_admin(&public_key) {
+        return Ok(());
+    }
+    let agent_count: i64 = agents::table
+        .filter(agents::public_key.eq(&public_key))
+        .filter(agents::organization_id.eq(organization_id))
+        .filter(agents::start_block_num.le(head_block_num))
+        .filter(agents::end_block_num.gt(head_block_num))
+        .count()
+        .get_result(&**conn)?;
+    if agent_count == 0 {
+        Err(ApiError::Forbidden(format!(
+            "Only agents of {} can do this",
+            organization_id
+        )))
+    } else {
+        Ok(())
+    }
+}
 /// Saves a User to the database
 fn save_user(conn: &DbConn, user: User) -> Result<(), ApiError> {
     diesel::insert_into(users::table)
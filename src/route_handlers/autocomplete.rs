@@ -0,0 +1,222 @@
+use database::DbConn;
+use database_manager::tables_schema::{addresses, organizations, standards};
+use diesel::prelude::*;
+use errors::ApiError;
+use paging::*;
+use rocket::http::uri::Uri;
+use rocket::request::Form;
+use rocket_contrib::json::JsonValue;
+use route_handlers::prom::increment_http_req;
+
+const DEFAULT_SUGGESTION_LIMIT: i64 = 10;
+const MAX_SUGGESTION_LIMIT: i64 = 50;
+
+#[derive(Default, FromForm, Clone)]
+pub struct AutocompleteParams {
+    field: Option<String>,
+    prefix: Option<String>,
+    limit: Option<i64>,
+    head: Option<i64>,
+}
+
+/// The values that can be suggested
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    City,
+    Country,
+    Standard,
+    Organization,
+}
+
+impl Field {
+    fn parse(name: &str) -> Result<Self, ApiError> {
+        match name {
+            "city" => Ok(Field::City),
+            "country" => Ok(Field::Country),
+            "standard" => Ok(Field::Standard),
+            "organization" => Ok(Field::Organization),
+            _ => Err(ApiError::BadRequest(format!(
+                "Invalid field {}, expected one of city, country, standard or organization",
+                name
+            ))),
+        }
+    }
+}
+
+#[get("/autocomplete")]
+pub fn autocomplete(conn: DbConn) -> Result<JsonValue, ApiError> {
+    autocomplete_with_params(None, conn)
+}
+
+/// Suggests distinct values of `field` that start with `prefix`, in alphabetical order, so a
+/// completion comes before the longer completions that start with it.
+///
+/// Prefix matches are case-insensitive `ILIKE` queries, so address fields are served by
+/// the trigram indexes on `addresses`.
+#[get("/autocomplete?<params..>")]
+pub fn autocomplete_with_params(
+    params: Option<Form<AutocompleteParams>>,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let params = match params {
+        Some(param) => param.into_inner(),
+        None => Default::default(),
+    };
+    let field = match params.field {
+        Some(ref field) => Field::parse(field)?,
+        None => {
+            return Err(ApiError::BadRequest(
+                "A field to autocomplete must be provided with the `field` param".to_string(),
+            ))
+        }
+    };
+    let prefix = match params.prefix {
+        Some(ref prefix) if !prefix.trim().is_empty() => prefix.trim().to_string(),
+        _ => {
+            return Err(ApiError::BadRequest(
+                "A prefix must be provided with the `prefix` param".to_string(),
+            ))
+        }
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_SUGGESTION_LIMIT);
+    if limit < 1 || limit > MAX_SUGGESTION_LIMIT {
+        return Err(ApiError::BadRequest(format!(
+            "The limit must be between 1 and {}",
+            MAX_SUGGESTION_LIMIT
+        )));
+    }
+    let head_block_num: i64 = get_head_block_num(params.head, &conn)?;
+
+    let pattern = format!("{}%", escape_like(&prefix));
+    let suggestions = match field {
+        Field::City => addresses::table
+            .select(addresses::city)
+            .distinct()
+            .filter(addresses::start_block_num.le(head_block_num))
+            .filter(addresses::end_block_num.gt(head_block_num))
+            .filter(addresses::city.ilike(&pattern))
+            .order_by(addresses::city.asc())
+            .limit(limit)
+            .load::<String>(&*conn)?,
+        Field::Country => addresses::table
+            .select(addresses::country)
+            .distinct()
+            .filter(addresses::start_block_num.le(head_block_num))
+            .filter(addresses::end_block_num.gt(head_block_num))
+            .filter(addresses::country.ilike(&pattern))
+            .order_by(addresses::country.asc())
+            .limit(limit)
+            .load::<String>(&*conn)?,
+        Field::Standard => standards::table
+            .select(standards::name)
+            .distinct()
+            .filter(standards::start_block_num.le(head_block_num))
+            .filter(standards::end_block_num.gt(head_block_num))
+            .filter(standards::name.ilike(&pattern))
+            .order_by(standards::name.asc())
+            .limit(limit)
+            .load::<String>(&*conn)?,
+        Field::Organization => organizations::table
+            .select(organizations::name)
+            .distinct()
+            .filter(organizations::start_block_num.le(head_block_num))
+            .filter(organizations::end_block_num.gt(head_block_num))
+            .filter(organizations::name.ilike(&pattern))
+            .order_by(organizations::name.asc())
+            .limit(limit)
+            .load::<String>(&*conn)?,
+    };
+
+    let link = format!(
+        "/api/autocomplete?field={}&prefix={}&limit={}&head={}",
+        params.field.unwrap_or_default(),
+        Uri::percent_encode(&prefix),
+        limit,
+        head_block_num
+    );
+
+    Ok(json!({
+        "data": suggestions,
+        "link": link,
+        "head": head_block_num,
+    }))
+}
+
+/// Escapes the `LIKE` wildcards in `prefix` so it only matches literally
+fn escape_like(prefix: &str) -> String {
+    prefix
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use route_handlers::factories::tests::setup_factory_db;
+    use route_handlers::tests::run_test;
+
+    #[test]
+    /// Test that `LIKE` wildcards in a prefix are escaped
+    fn test_escape_like() {
+        assert_eq!(escape_like("cotton"), "cotton");
+        assert_eq!(escape_like("100%_co\\"), "100\\%\\_co\\\\");
+    }
+
+    #[test]
+    /// Test that only the supported fields can be autocompleted
+    fn test_parse_field() {
+        assert_eq!(Field::parse("city").unwrap(), Field::City);
+        assert_eq!(Field::parse("organization").unwrap(), Field::Organization);
+        assert!(Field::parse("street").is_err());
+    }
+
+    #[test]
+    /// Test that a GET to `/api/autocomplete?field=city&prefix=TEST_FACTORY_C` suggests the
+    /// factory's city regardless of case
+    fn test_autocomplete_city() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            let params = AutocompleteParams {
+                field: Some("city".to_string()),
+                prefix: Some("TEST_FACTORY_C".to_string()),
+                ..Default::default()
+            };
+            let res = autocomplete_with_params(Some(Form(params)), DbConn(conn)).unwrap();
+
+            assert_eq!(res.get("data").unwrap(), &json!(["test_factory_city"]).0);
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/autocomplete?field=standard&prefix=nomatch` returns no
+    /// suggestions
+    fn test_autocomplete_no_match() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            let params = AutocompleteParams {
+                field: Some("standard".to_string()),
+                prefix: Some("nomatch".to_string()),
+                ..Default::default()
+            };
+            let res = autocomplete_with_params(Some(Form(params)), DbConn(conn)).unwrap();
+
+            assert!(res.get("data").unwrap().as_array().unwrap().is_empty());
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/autocomplete` without a field returns a `BadRequest`
+    fn test_autocomplete_without_field() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            match autocomplete(DbConn(conn)) {
+                Err(ApiError::BadRequest(_)) => (),
+                _ => panic!("Expected a BadRequest error"),
+            }
+        })
+    }
+}
@@ -83,7 +83,7 @@ pub fn submit_batches(
     let mut batch_submit_request = ClientBatchSubmitRequest::new();
     batch_submit_request.set_batches(batch_list.batches);
     let response: ClientBatchSubmitResponse = send_request(
-        validator_url,
+        &validator_url,
         Message_MessageType::CLIENT_BATCH_SUBMIT_REQUEST,
         &batch_submit_request,
     )
@@ -132,7 +132,7 @@ pub fn list_statuses(
     }
 
     let response: ClientBatchStatusResponse = send_request(
-        validator_url,
+        &validator_url,
         Message_MessageType::CLIENT_BATCH_STATUS_REQUEST,
         &batch_status_request,
     )
@@ -167,8 +167,9 @@ pub fn list_statuses(
     }
 }
 
-fn send_request<T, U>(
-    validator_url: State<String>,
+/// Sends a message of the given type to the validator and waits for its response
+pub fn send_request<T, U>(
+    validator_url: &str,
     msg_type: Message_MessageType,
     msg: &T,
 ) -> Result<U, String>
@@ -176,7 +177,7 @@ where
     T: protobuf::Message,
     U: protobuf::Message,
 {
-    let connection = ZmqMessageConnection::new(&validator_url);
+    let connection = ZmqMessageConnection::new(validator_url);
     let (sender, _) = connection.create();
     let correlation_id = uuid::Uuid::new_v4().to_simple().to_string();
     let msg_bytes = T::write_to_bytes(&msg).unwrap();
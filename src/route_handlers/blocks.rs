@@ -1,14 +1,19 @@
+use cache::RESPONSE_CACHE;
 use database::{DbConn, PgPool};
 use database_manager::models::Block;
 use database_manager::tables_schema::blocks;
 use diesel::prelude::*;
 use errors::ApiError;
+use fields::Fields;
 use hyper_sse::Server;
+use negotiation::Negotiated;
 use paging::*;
+use rocket::http::uri::Uri;
 use rocket::request::Form;
 use rocket_contrib::json::JsonValue;
 use route_handlers::prom::increment_http_req;
-use std::{thread, time};
+use serde_json::Value;
+use std::{slice, thread, time};
 
 const DEFAULT_CHANNEL: u8 = 0;
 
@@ -73,6 +78,9 @@ impl BlockWatcher {
                 .load(&*db_conn)?;
 
             if !blocks.is_empty() {
+                // Cached responses at the latest block are superseded, and those at blocks
+                // that were replaced by a fork are wrong
+                RESPONSE_CACHE.invalidate();
                 self.block_queue.append(&mut blocks);
             }
         }
@@ -152,6 +160,7 @@ pub fn fetch_block_with_head_param(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
+    let fields = Fields::parse(&head_param.fields);
     let head_block_num: i64 = get_head_block_num(head_param.head, &conn)?;
 
     let block = blocks::table
@@ -161,13 +170,20 @@ pub fn fetch_block_with_head_param(
         .optional()
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
 
-    let link = format!("/api/blocks/{}", block_id);
+    let mut link = format!("/api/blocks/{}", block_id);
+    if let Some(ref fields) = head_param.fields {
+        link = format!("{}?fields={}", link, Uri::percent_encode(fields));
+    }
 
     match block {
-        Some(block) => Ok(json!({
-                "data": block,
+        Some(block) => {
+            let mut data = json!(block).0;
+            fields.select(slice::from_mut(&mut data));
+            Ok(json!({
+                "data": data,
                 "link": link,
-                "head": head_block_num, })),
+                "head": head_block_num, }))
+        }
         None => Err(ApiError::NotFound(format!(
             "No block with the ID {} exists",
             block_id
@@ -180,10 +196,11 @@ pub struct BlockParams {
     limit: Option<i64>,
     offset: Option<i64>,
     head: Option<i64>,
+    fields: Option<String>,
 }
 
 #[get("/blocks")]
-pub fn list_blocks(conn: DbConn) -> Result<JsonValue, ApiError> {
+pub fn list_blocks(conn: DbConn) -> Result<Negotiated, ApiError> {
     list_blocks_with_params(None, conn)
 }
 
@@ -191,7 +208,7 @@ pub fn list_blocks(conn: DbConn) -> Result<JsonValue, ApiError> {
 pub fn list_blocks_with_params(
     params: Option<Form<BlockParams>>,
     conn: DbConn,
-) -> Result<JsonValue, ApiError> {
+) -> Result<Negotiated, ApiError> {
     // Increment HTTP request count for Prometheus metrics
     increment_http_req();
 
@@ -199,6 +216,7 @@ pub fn list_blocks_with_params(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
+    let fields = Fields::parse(&params.fields);
     let head_block_num: i64 = get_head_block_num(params.head, &conn)?;
 
     let mut blocks_query = blocks::table
@@ -217,18 +235,25 @@ pub fn list_blocks_with_params(
     blocks_query = blocks_query.limit(params.limit.unwrap_or(DEFAULT_LIMIT));
     blocks_query = blocks_query.offset(params.offset.unwrap_or(DEFAULT_OFFSET));
 
-    let blocks = blocks_query
+    let mut blocks: Vec<Value> = blocks_query
         .load::<Block>(&*conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?;
+        .map_err(|err| ApiError::InternalError(err.to_string()))?
+        .iter()
+        .map(|block| json!(block).0)
+        .collect();
+    fields.select(&mut blocks);
 
-    Ok(json!({ "data": blocks,
+    Ok(Negotiated(json!({ "data": blocks,
                     "link": paging_info.get("link"),
                     "head": head_block_num,
-                    "paging": paging_info.get("paging") }))
+                    "paging": paging_info.get("paging") })))
 }
 
 fn apply_paging(params: BlockParams, head: i64, total_count: i64) -> Result<JsonValue, ApiError> {
-    let link = format!("/api/blocks?head={}&", head);
+    let mut link = format!("/api/blocks?head={}&", head);
+    if let Some(fields) = params.fields {
+        link = format!("{}fields={}&", link, Uri::percent_encode(&fields));
+    }
 
     get_response_paging_info(params.limit, params.offset, link, total_count)
 }
@@ -2,11 +2,19 @@ use database::DbConn;
 use database_manager::models::{Certificate, Organization, Standard};
 use database_manager::tables_schema::{assertions, certificates, organizations, standards};
 use diesel::prelude::*;
+use diff::{diff, diff_range};
 use errors::ApiError;
+use expand::{Expansions, Resource};
+use fields::Fields;
+use history::versions;
+use negotiation::Negotiated;
 use paging::*;
+use rocket::http::uri::Uri;
 use rocket::request::Form;
 use rocket_contrib::json::JsonValue;
 use route_handlers::prom::increment_http_req;
+use std::collections::HashMap;
+use std::slice;
 
 #[derive(Serialize)]
 pub struct ApiCertificate {
@@ -101,20 +109,58 @@ pub fn fetch_certificate_with_head_param(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
+    let expansions = Expansions::parse(Resource::Certificate, &head_param.expand)?;
+    let fields = Fields::parse(&head_param.fields);
     let head_block_num: i64 = get_head_block_num(head_param.head, &conn)?;
-    let result: Option<
-        Result<
-            (
-                Certificate,
-                Organization,
-                Standard,
-                Organization,
-                Option<String>,
-            ),
-            ApiError,
-        >,
-    > = certificates::table
-        .filter(certificates::certificate_id.eq(certificate_id.clone()))
+    let mut link = format!(
+        "/api/certificates/{}?head={}",
+        certificate_id, head_block_num
+    );
+    if !expansions.is_empty() {
+        link = format!("{}&expand={}", link, expansions.to_param());
+    }
+    if let Some(ref fields) = head_param.fields {
+        link = format!("{}&fields={}", link, Uri::percent_encode(fields));
+    }
+
+    match load_certificate(&conn, &certificate_id, head_block_num)? {
+        Some(certificate) => {
+            let mut data = certificate.0;
+            expansions.apply(&conn, slice::from_mut(&mut data), head_block_num)?;
+            fields.select(slice::from_mut(&mut data));
+            Ok(json!({
+                "data": data,
+                "link": link,
+                "head": head_block_num, }))
+        }
+        None => Err(ApiError::NotFound(format!(
+            "No certificate with the ID {} exists",
+            certificate_id
+        ))),
+    }
+}
+
+/// Loads the certificate with the names of its factory, standard and certifying body as they
+/// were at `head_block_num`
+pub fn load_certificate(
+    conn: &DbConn,
+    certificate_id: &str,
+    head_block_num: i64,
+) -> Result<Option<JsonValue>, ApiError> {
+    Ok(
+        load_certificates(conn, &[certificate_id.to_string()], head_block_num)?
+            .remove(certificate_id),
+    )
+}
+
+/// Loads the certificates with the given ids as they were at `head_block_num`, keyed by id
+pub fn load_certificates(
+    conn: &DbConn,
+    certificate_ids: &[String],
+    head_block_num: i64,
+) -> Result<HashMap<String, JsonValue>, ApiError> {
+    let rows = certificates::table
+        .filter(certificates::certificate_id.eq_any(certificate_ids))
         .filter(certificates::start_block_num.le(head_block_num))
         .filter(certificates::end_block_num.gt(head_block_num))
         .left_join(
@@ -131,7 +177,7 @@ pub fn fetch_certificate_with_head_param(
         )
         .left_join(
             assertions::table.on(assertions::object_id
-                .eq(certificate_id.clone())
+                .eq(certificates::certificate_id)
                 .and(assertions::start_block_num.le(head_block_num))
                 .and(assertions::end_block_num.gt(head_block_num))),
         )
@@ -141,61 +187,134 @@ pub fn fetch_certificate_with_head_param(
             organizations::table::all_columns().nullable(),
             assertions::assertion_id.nullable(),
         ))
-        .first::<(
-            Certificate,
-            Option<Standard>,
-            Option<Organization>,
-            Option<String>,
-        )>(&*conn)
-        .optional()
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .map(|(cert, std_opt, org_opt, assertion_id)| {
-            let factory = require_org(&conn, &cert.factory_id, head_block_num)?;
-            Ok((
-                cert,
-                factory,
-                std_opt.ok_or_else(|| {
-                    ApiError::InternalError(
-                        "No Standard was provided, but one must exist".to_string(),
-                    )
-                })?,
-                org_opt.ok_or_else(|| {
-                    ApiError::InternalError(
-                        "No Certifying Body was provided, but one must exist".to_string(),
-                    )
-                })?,
-                assertion_id,
-            ))
-        });
+        .load::<CertificateRow>(&**conn)
+        .map_err(|err| ApiError::InternalError(err.to_string()))?;
 
-    let link = format!(
-        "/api/certificates/{}?head={}",
-        certificate_id, head_block_num
+    Ok(to_api_certificates(conn, rows, head_block_num)?
+        .into_iter()
+        .map(|certificate| (certificate.id.clone(), json!(certificate)))
+        .collect())
+}
+
+/// Returns every version of a certificate up to `head` with the range of blocks it was live
+/// for, e.g. to see when its validity changed, including changes to its factory, certifying
+/// body and standard
+#[get("/certificates/<certificate_id>/history?<head>")]
+pub fn fetch_certificate_history(
+    certificate_id: String,
+    head: Option<i64>,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let head_block_num: i64 = get_head_block_num(head, &conn)?;
+    let rows = certificates::table
+        .select((
+            certificates::start_block_num,
+            certificates::end_block_num,
+            certificates::factory_id,
+            certificates::certifying_body_id,
+            certificates::standard_id,
+        ))
+        .filter(certificates::certificate_id.eq(&certificate_id))
+        .load::<(i64, i64, String, String, String)>(&*conn)?;
+    // A certificate includes the factory that holds it, the body that certified it and the
+    // standard it certifies, so it also changes whenever one of those does
+    let organization_ids: Vec<&str> = rows
+        .iter()
+        .flat_map(|(_, _, factory_id, certifying_body_id, _)| {
+            vec![factory_id.as_str(), certifying_body_id.as_str()]
+        })
+        .collect();
+    let standard_ids: Vec<&str> = rows
+        .iter()
+        .map(|(_, _, _, _, standard_id)| standard_id.as_str())
+        .collect();
+
+    let mut ranges: Vec<(i64, i64)> = rows.iter().map(|row| (row.0, row.1)).collect();
+    ranges.extend(
+        organizations::table
+            .select((organizations::start_block_num, organizations::end_block_num))
+            .filter(organizations::organization_id.eq_any(organization_ids))
+            .load::<(i64, i64)>(&*conn)?,
+    );
+    ranges.extend(
+        standards::table
+            .select((standards::start_block_num, standards::end_block_num))
+            .filter(standards::standard_id.eq_any(standard_ids))
+            .load::<(i64, i64)>(&*conn)?,
+    );
+    ranges.extend(
+        assertions::table
+            .select((assertions::start_block_num, assertions::end_block_num))
+            .filter(assertions::object_id.eq(&certificate_id))
+            .load::<(i64, i64)>(&*conn)?,
     );
 
-    match result {
-        Some(cert_std_tuple) => Ok(json!({
-                "data": ApiCertificate::from(cert_std_tuple?),
-                "link": link,
-                "head": head_block_num, })),
-        None => Err(ApiError::NotFound(format!(
+    let versions = versions(&ranges, head_block_num, |block_num| {
+        load_certificate(&conn, &certificate_id, block_num)
+    })?;
+    if versions.is_empty() {
+        return Err(ApiError::NotFound(format!(
             "No certificate with the ID {} exists",
             certificate_id
-        ))),
+        )));
     }
+
+    Ok(json!({
+        "data": versions,
+        "link": format!("/api/certificates/{}/history?head={}", certificate_id, head_block_num),
+        "head": head_block_num,
+    }))
+}
+
+/// Returns the changes to a certificate between the `from` block and the `to` block, which
+/// defaults to the latest block
+#[get("/certificates/<certificate_id>/diff?<from>&<to>")]
+pub fn fetch_certificate_diff(
+    certificate_id: String,
+    from: Option<i64>,
+    to: Option<i64>,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let (from, to) = diff_range(from, to, &conn)?;
+    let before = load_certificate(&conn, &certificate_id, from)?;
+    let after = load_certificate(&conn, &certificate_id, to)?;
+    if before.is_none() && after.is_none() {
+        return Err(ApiError::NotFound(format!(
+            "No certificate with the ID {} exists",
+            certificate_id
+        )));
+    }
+
+    Ok(json!({
+        "data": {
+            "from": from,
+            "to": to,
+            "changes": diff(before, after),
+        },
+        "link": format!("/api/certificates/{}/diff?from={}&to={}", certificate_id, from, to),
+        "head": to,
+    }))
 }
 
 #[derive(Default, FromForm, Clone)]
 pub struct CertificateParams {
     certifying_body_id: Option<String>,
     factory_id: Option<String>,
+    expand: Option<String>,
+    fields: Option<String>,
     limit: Option<i64>,
     offset: Option<i64>,
     head: Option<i64>,
 }
 
 #[get("/certificates")]
-pub fn list_certificates(conn: DbConn) -> Result<JsonValue, ApiError> {
+pub fn list_certificates(conn: DbConn) -> Result<Negotiated, ApiError> {
     list_certificates_with_params(None, conn)
 }
 
@@ -203,7 +322,7 @@ pub fn list_certificates(conn: DbConn) -> Result<JsonValue, ApiError> {
 pub fn list_certificates_with_params(
     params: Option<Form<CertificateParams>>,
     conn: DbConn,
-) -> Result<JsonValue, ApiError> {
+) -> Result<Negotiated, ApiError> {
     // Increment HTTP request count for Prometheus metrics
     increment_http_req();
 
@@ -211,7 +330,13 @@ pub fn list_certificates_with_params(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
-    let head_block_num: i64 = get_head_block_num(params.head, &conn)?;
+    query_certificates(params, &conn).map(Negotiated)
+}
+
+fn query_certificates(params: CertificateParams, conn: &DbConn) -> Result<JsonValue, ApiError> {
+    let expansions = Expansions::parse(Resource::Certificate, &params.expand)?;
+    let fields = Fields::parse(&params.fields);
+    let head_block_num: i64 = get_head_block_num(params.head, conn)?;
 
     let mut certificate_query = certificates::table
         .filter(certificates::start_block_num.le(head_block_num))
@@ -256,30 +381,73 @@ pub fn list_certificates_with_params(
 
     let total_count = count_query
         .count()
-        .get_result(&*conn)
+        .get_result(&**conn)
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
-    let paging_info = apply_paging(link_params, head_block_num, total_count)?;
+    let paging_info = apply_paging(&expansions, link_params, head_block_num, total_count)?;
 
     certificate_query = certificate_query.limit(params.limit.unwrap_or(DEFAULT_LIMIT));
     certificate_query = certificate_query.offset(params.offset.unwrap_or(DEFAULT_OFFSET));
 
-    let certificates: Vec<ApiCertificate> = certificate_query
+    let rows = certificate_query
         .select((
             certificates::table::all_columns(),
             standards::table::all_columns().nullable(),
             organizations::table::all_columns().nullable(),
             assertions::assertion_id.nullable(),
         ))
-        .load::<(
-            Certificate,
-            Option<Standard>,
-            Option<Organization>,
-            Option<String>,
-        )>(&*conn)
+        .load::<CertificateRow>(&**conn)
+        .map_err(|err| ApiError::InternalError(err.to_string()))?;
+    let mut certificates = to_api_certificates(conn, rows, head_block_num)?
+        .iter()
+        .map(|certificate| json!(certificate).0)
+        .collect::<Vec<_>>();
+    expansions.apply(conn, &mut certificates, head_block_num)?;
+    fields.select(&mut certificates);
+
+    Ok(json!({ "data": certificates,
+                "link": paging_info.get("link"),
+                "head": head_block_num,
+                "paging": paging_info.get("paging") }))
+}
+
+/// A certificate with its standard, certifying body and assertion, as loaded by a single join
+type CertificateRow = (
+    Certificate,
+    Option<Standard>,
+    Option<Organization>,
+    Option<String>,
+);
+
+/// Completes certificates with their factories. The certifying body is already joined, and
+/// Diesel cannot join `organizations` a second time, so the factories of every row are loaded
+/// with one more query rather than one per certificate.
+fn to_api_certificates(
+    conn: &DbConn,
+    rows: Vec<CertificateRow>,
+    head_block_num: i64,
+) -> Result<Vec<ApiCertificate>, ApiError> {
+    let factory_ids: Vec<&str> = rows
+        .iter()
+        .map(|(cert, _, _, _)| cert.factory_id.as_str())
+        .collect();
+    let factories: HashMap<String, Organization> = organizations::table
+        .filter(organizations::organization_id.eq_any(factory_ids))
+        .filter(organizations::start_block_num.le(head_block_num))
+        .filter(organizations::end_block_num.gt(head_block_num))
+        .load::<Organization>(&**conn)
         .map_err(|err| ApiError::InternalError(err.to_string()))?
         .into_iter()
+        .map(|factory| (factory.organization_id.clone(), factory))
+        .collect();
+
+    rows.into_iter()
         .map(|(cert, std_opt, org_opt, assertion_id)| {
-            let factory = require_org(&conn, &cert.factory_id, head_block_num)?;
+            let factory = factories.get(&cert.factory_id).cloned().ok_or_else(|| {
+                ApiError::InternalError(format!(
+                    "No org exists for the id provided: {} (as of block num {})",
+                    cert.factory_id, head_block_num
+                ))
+            })?;
             Ok(ApiCertificate::from((
                 cert,
                 factory,
@@ -296,31 +464,11 @@ pub fn list_certificates_with_params(
                 assertion_id,
             )))
         })
-        .collect::<Result<Vec<_>, ApiError>>()?;
-
-    Ok(json!({ "data": certificates,
-                "link": paging_info.get("link"),
-                "head": head_block_num,
-                "paging": paging_info.get("paging") }))
-}
-
-fn require_org(conn: &DbConn, org_id: &str, head_block_num: i64) -> Result<Organization, ApiError> {
-    organizations::table
-        .filter(organizations::organization_id.eq(org_id))
-        .filter(organizations::start_block_num.le(head_block_num))
-        .filter(organizations::end_block_num.gt(head_block_num))
-        .first::<Organization>(&**conn)
-        .optional()
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .ok_or_else(|| {
-            ApiError::InternalError(format!(
-                "No org exists for the id provided: {} (as of block num {})",
-                org_id, head_block_num
-            ))
-        })
+        .collect()
 }
 
 fn apply_paging(
+    expansions: &Expansions,
     params: CertificateParams,
     head: i64,
     total_count: i64,
@@ -333,6 +481,12 @@ fn apply_paging(
     if let Some(factory_id) = params.factory_id {
         link = format!("{}factory_id={}&", link, factory_id);
     }
+    if !expansions.is_empty() {
+        link = format!("{}expand={}&", link, expansions.to_param());
+    }
+    if let Some(fields) = params.fields {
+        link = format!("{}fields={}&", link, Uri::percent_encode(&fields));
+    }
     link = format!("{}head={}&", link, head);
 
     get_response_paging_info(params.limit, params.offset, link, total_count)
@@ -342,8 +496,52 @@ fn apply_paging(
 mod tests {
     use super::*;
     use database_manager::custom_types::{AssertionTypeEnum, OrganizationTypeEnum};
-    use database_manager::models::{NewAssertion, NewCertificate, NewOrganization, NewStandard};
-    use route_handlers::tests::{get_connection_pool, run_test};
+    use database_manager::models::{
+        NewAssertion, NewCertificate, NewOrganization, NewStandard, NewStandardVersion,
+    };
+    use database_manager::tables_schema::standard_versions;
+    use route_handlers::factories::tests::setup_factory_db;
+    use route_handlers::tests::{count_table_scans, get_connection_pool, insert_blocks, run_test};
+
+    #[test]
+    /// Test that a GET to `/api/certificates/{id}/history` returns a new version when the
+    /// standard the certificate certifies changes, although the certificate itself does not
+    fn test_certificate_history_standard_change() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            insert_blocks(&conn, &[2, 3]);
+            diesel::update(standards::table.filter(standards::standard_id.eq("test_standard_id")))
+                .set(standards::end_block_num.eq(3))
+                .execute(&conn)
+                .unwrap();
+            diesel::insert_into(standards::table)
+                .values(NewStandard {
+                    start_block_num: 3,
+                    end_block_num: std::i64::MAX,
+                    standard_id: "test_standard_id".to_string(),
+                    organization_id: "test_standards_body_id".to_string(),
+                    name: "renamed_std_name".to_string(),
+                })
+                .execute(&conn)
+                .unwrap();
+
+            let res =
+                fetch_certificate_history("test_cert_id".to_string(), None, DbConn(conn)).unwrap();
+
+            let versions = res.get("data").unwrap().as_array().unwrap();
+            assert_eq!(versions.len(), 2);
+            assert_eq!(versions[0]["end_block_num"], json!(3).0);
+            assert_eq!(versions[1]["start_block_num"], json!(3).0);
+            assert_eq!(
+                versions[1]["data"]["standard_name"],
+                json!("renamed_std_name").0
+            );
+            assert_ne!(
+                versions[0]["data"]["standard_name"],
+                versions[1]["data"]["standard_name"]
+            );
+        })
+    }
 
     #[test]
     /// Test that a Get to `/api/certificates/{id}` succeeds
@@ -715,4 +913,190 @@ mod tests {
             );
         })
     }
+
+    #[test]
+    /// Test that listing certificates loads their organizations with the same number of
+    /// queries however many certificates are listed
+    fn test_certificates_list_query_count() {
+        run_test(|| {
+            let conn = get_connection_pool();
+            conn.begin_test_transaction().unwrap();
+
+            for (organization_id, organization_type) in &[
+                ("test_factory_id", OrganizationTypeEnum::Factory),
+                ("test_cert_body_id", OrganizationTypeEnum::CertifyingBody),
+            ] {
+                diesel::insert_into(organizations::table)
+                    .values(NewOrganization {
+                        start_block_num: 1,
+                        end_block_num: std::i64::MAX,
+                        organization_id: organization_id.to_string(),
+                        name: organization_id.to_string(),
+                        organization_type: organization_type.clone(),
+                    })
+                    .execute(&conn)
+                    .unwrap();
+            }
+            diesel::insert_into(standards::table)
+                .values(NewStandard {
+                    start_block_num: 1,
+                    end_block_num: std::i64::MAX,
+                    standard_id: "test_standard_id".to_string(),
+                    organization_id: "test_standards_body_id".to_string(),
+                    name: "test_standard_name".to_string(),
+                })
+                .execute(&conn)
+                .unwrap();
+            let conn = DbConn(conn);
+            let insert_certificates = |certificate_ids: &[&str]| {
+                for certificate_id in certificate_ids {
+                    diesel::insert_into(certificates::table)
+                        .values(NewCertificate {
+                            start_block_num: 1,
+                            end_block_num: std::i64::MAX,
+                            certificate_id: certificate_id.to_string(),
+                            certifying_body_id: "test_cert_body_id".to_string(),
+                            factory_id: "test_factory_id".to_string(),
+                            standard_id: "test_standard_id".to_string(),
+                            standard_version: "test_standard_version".to_string(),
+                            valid_from: 1,
+                            valid_to: 2,
+                        })
+                        .execute(&*conn)
+                        .unwrap();
+                }
+            };
+
+            insert_certificates(&["cert_1"]);
+            let one_certificate_scans = count_table_scans(&conn, &["organizations"], || {
+                query_certificates(CertificateParams::default(), &conn).unwrap();
+            });
+
+            insert_certificates(&["cert_2", "cert_3", "cert_4", "cert_5"]);
+            let five_certificate_scans = count_table_scans(&conn, &["organizations"], || {
+                let response = query_certificates(CertificateParams::default(), &conn).unwrap();
+                assert_eq!(response["data"].as_array().unwrap().len(), 5);
+            });
+
+            assert_eq!(one_certificate_scans, five_certificate_scans);
+        })
+    }
+
+    #[test]
+    /// Test that listing certificates with `expand` inlines the requested relations, nested
+    /// ones included, and rejects relations certificates do not have
+    fn test_certificates_list_expand() {
+        run_test(|| {
+            let conn = get_connection_pool();
+            conn.begin_test_transaction().unwrap();
+
+            for (organization_id, organization_type) in &[
+                ("test_factory_id", OrganizationTypeEnum::Factory),
+                ("test_cert_body_id", OrganizationTypeEnum::CertifyingBody),
+            ] {
+                diesel::insert_into(organizations::table)
+                    .values(NewOrganization {
+                        start_block_num: 1,
+                        end_block_num: std::i64::MAX,
+                        organization_id: organization_id.to_string(),
+                        name: organization_id.to_string(),
+                        organization_type: organization_type.clone(),
+                    })
+                    .execute(&conn)
+                    .unwrap();
+            }
+            diesel::insert_into(standards::table)
+                .values(NewStandard {
+                    start_block_num: 1,
+                    end_block_num: std::i64::MAX,
+                    standard_id: "test_standard_id".to_string(),
+                    organization_id: "test_standards_body_id".to_string(),
+                    name: "test_standard_name".to_string(),
+                })
+                .execute(&conn)
+                .unwrap();
+            diesel::insert_into(standard_versions::table)
+                .values(NewStandardVersion {
+                    start_block_num: 1,
+                    end_block_num: std::i64::MAX,
+                    standard_id: "test_standard_id".to_string(),
+                    version: "test_standard_version".to_string(),
+                    link: "test_link".to_string(),
+                    description: "test_description".to_string(),
+                    approval_date: 1,
+                })
+                .execute(&conn)
+                .unwrap();
+            diesel::insert_into(certificates::table)
+                .values(NewCertificate {
+                    start_block_num: 1,
+                    end_block_num: std::i64::MAX,
+                    certificate_id: "test_cert_id".to_string(),
+                    certifying_body_id: "test_cert_body_id".to_string(),
+                    factory_id: "test_factory_id".to_string(),
+                    standard_id: "test_standard_id".to_string(),
+                    standard_version: "test_standard_version".to_string(),
+                    valid_from: 1,
+                    valid_to: 2,
+                })
+                .execute(&conn)
+                .unwrap();
+            diesel::insert_into(assertions::table)
+                .values(NewAssertion {
+                    start_block_num: 1,
+                    end_block_num: std::i64::MAX,
+                    assertion_id: "test_assertion_id".to_string(),
+                    address: "some_state_address".to_string(),
+                    assertor_pub_key: "test_key".to_string(),
+                    assertion_type: AssertionTypeEnum::Factory,
+                    object_id: "test_factory_id".to_string(),
+                    data_id: None,
+                })
+                .execute(&conn)
+                .unwrap();
+            let conn = DbConn(conn);
+
+            let params = CertificateParams {
+                expand: Some("standard.versions,factory.assertion".to_string()),
+                ..Default::default()
+            };
+            let response = query_certificates(params, &conn).unwrap();
+            let certificate = &response["data"][0];
+            assert_eq!(certificate["factory"]["id"], "test_factory_id");
+            assert_eq!(
+                certificate["factory"]["assertion"]["assertor_pub_key"],
+                "test_key"
+            );
+            assert_eq!(certificate["standard"]["name"], "test_standard_name");
+            assert_eq!(
+                certificate["standard"]["versions"],
+                json!([{
+                    "version": "test_standard_version",
+                    "external_link": "test_link",
+                    "description": "test_description",
+                    "approval_date": 1,
+                }])
+                .0
+            );
+            // Relations that were not requested keep their summary
+            assert_eq!(certificate["certifying_body"], "test_cert_body_id");
+            assert_eq!(
+                response["link"],
+                "/api/certificates?expand=factory.assertion,standard.versions&head=1&limit=100&\
+                 offset=0"
+            );
+
+            let params = CertificateParams {
+                expand: Some("standard.factory".to_string()),
+                ..Default::default()
+            };
+            match query_certificates(params, &conn) {
+                Err(ApiError::BadRequest(message)) => assert_eq!(
+                    message,
+                    "Cannot expand standard.factory, factory is not one of: assertion, versions"
+                ),
+                _ => panic!("Expected a bad request"),
+            }
+        })
+    }
 }
@@ -0,0 +1,767 @@
+use common::addressing;
+use common::proto::{assertion, certificate, organization, standard};
+use database::DbConn;
+use database_manager::custom_types::{AssertionTypeEnum, OrganizationTypeEnum, RoleEnum};
+use database_manager::models::{
+    Address, Assertion, Authorization, Certificate, Contact, Organization, Standard,
+    ADDRESS_COLUMNS,
+};
+use database_manager::tables_schema::{
+    addresses, assertions, authorizations, blocks, certificates, contacts, organizations, standards,
+};
+use diesel::prelude::*;
+use errors::ApiError;
+use jwt;
+use paging::get_head_block_num;
+use protobuf;
+use protobuf::{Message, ProtobufEnum};
+use rocket::request::Form;
+use rocket::State;
+use rocket_contrib::json::JsonValue;
+use route_handlers::authorization::require_admin;
+use route_handlers::blockchain::send_request;
+use route_handlers::prom::increment_http_req;
+use sawtooth_sdk::messages::block::BlockHeader;
+use sawtooth_sdk::messages::client_block::{
+    ClientBlockGetByIdRequest, ClientBlockGetResponse, ClientBlockGetResponse_Status,
+};
+use sawtooth_sdk::messages::client_list_control::ClientPagingControls;
+use sawtooth_sdk::messages::client_state::{
+    ClientStateListRequest, ClientStateListResponse, ClientStateListResponse_Status,
+};
+use sawtooth_sdk::messages::validator::Message_MessageType;
+use std::collections::{BTreeMap, HashMap};
+
+/// Length of the family namespace plus the two character entity type
+/// at the start of every ConsenSource state address
+const TYPE_PREFIX_LEN: usize = 8;
+const STATE_PAGE_SIZE: i32 = 1000;
+
+/// A list of (field name, value) pairs describing a single record
+type Fields = Vec<(&'static str, String)>;
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Mismatch {
+    entity: &'static str,
+    id: String,
+    field: &'static str,
+    database: String,
+    chain: String,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct MissingRecord {
+    entity: &'static str,
+    id: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ConsistencyReport {
+    head: i64,
+    block_id: String,
+    checked: usize,
+    mismatches: Vec<Mismatch>,
+    missing_from_database: Vec<MissingRecord>,
+    missing_from_chain: Vec<MissingRecord>,
+}
+
+impl ConsistencyReport {
+    /// Returns true if the database agreed with on-chain state for every record
+    pub fn is_consistent(&self) -> bool {
+        self.mismatches.is_empty()
+            && self.missing_from_database.is_empty()
+            && self.missing_from_chain.is_empty()
+    }
+}
+
+#[derive(Default, FromForm, Clone)]
+pub struct ConsistencyParams {
+    head: Option<i64>,
+}
+
+#[get("/admin/consistency")]
+pub fn check_consistency(
+    claims: jwt::JWT,
+    validator_url: State<String>,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    check_consistency_with_params(None, claims, validator_url, conn)
+}
+
+/// Only admins can check consistency, as a check walks the whole of on-chain state
+#[get("/admin/consistency?<params..>")]
+pub fn check_consistency_with_params(
+    params: Option<Form<ConsistencyParams>>,
+    claims: jwt::JWT,
+    validator_url: State<String>,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    require_admin(&conn, &claims)?;
+
+    let params = match params {
+        Some(param) => param.into_inner(),
+        None => Default::default(),
+    };
+    let report = run_consistency_check(&conn, &validator_url, params.head)?;
+
+    Ok(json!({
+        "data": report,
+        "link": format!("/api/admin/consistency?head={}", report.head),
+        "head": report.head,
+    }))
+}
+
+/// If a consistency check fails due to JWT authentication issues,
+/// return a more specific error message.
+///
+/// Without this endpoint, when JWT auth fails there is nowhere to forward
+/// the request to and the client receives a 404 error.
+#[get("/admin/consistency", rank = 2)]
+pub fn check_consistency_jwt_failure() -> Result<JsonValue, ApiError> {
+    Err(ApiError::Unauthorized)
+}
+
+/// Compares the organizations, certificates, standards and assertions that are live in the
+/// database at `head` against the on-chain state of the same block.
+///
+/// Used by both the `/api/admin/consistency` endpoint and the `check` CLI subcommand.
+pub fn run_consistency_check(
+    conn: &DbConn,
+    validator_url: &str,
+    head: Option<i64>,
+) -> Result<ConsistencyReport, ApiError> {
+    let head_block_num = get_head_block_num(head, conn)?;
+    let block_id = blocks::table
+        .filter(blocks::block_num.eq(head_block_num))
+        .select(blocks::block_id)
+        .first::<String>(&**conn)
+        .optional()?
+        .ok_or_else(|| {
+            ApiError::NotFound(format!(
+                "No block with the number {} exists",
+                head_block_num
+            ))
+        })?;
+    let state_root = fetch_state_root(validator_url, &block_id)?;
+
+    let mut report = ConsistencyReport {
+        head: head_block_num,
+        block_id,
+        ..Default::default()
+    };
+
+    compare_records(
+        "organization",
+        load_db_organizations(conn, head_block_num)?,
+        load_chain_organizations(validator_url, &state_root)?,
+        &mut report,
+    );
+    compare_records(
+        "certificate",
+        load_db_certificates(conn, head_block_num)?,
+        load_chain_certificates(validator_url, &state_root)?,
+        &mut report,
+    );
+    compare_records(
+        "standard",
+        load_db_standards(conn, head_block_num)?,
+        load_chain_standards(validator_url, &state_root)?,
+        &mut report,
+    );
+    compare_records(
+        "assertion",
+        load_db_assertions(conn, head_block_num)?,
+        load_chain_assertions(validator_url, &state_root)?,
+        &mut report,
+    );
+
+    Ok(report)
+}
+
+/// Records every field-level difference and every record that only exists on one side
+fn compare_records(
+    entity: &'static str,
+    mut db_records: BTreeMap<String, Fields>,
+    chain_records: BTreeMap<String, Fields>,
+    report: &mut ConsistencyReport,
+) {
+    for (id, chain_fields) in chain_records {
+        report.checked += 1;
+        match db_records.remove(&id) {
+            Some(db_fields) => {
+                for ((field, db_value), (_, chain_value)) in
+                    db_fields.into_iter().zip(chain_fields.into_iter())
+                {
+                    if db_value != chain_value {
+                        report.mismatches.push(Mismatch {
+                            entity,
+                            id: id.clone(),
+                            field,
+                            database: db_value,
+                            chain: chain_value,
+                        });
+                    }
+                }
+            }
+            None => report
+                .missing_from_database
+                .push(MissingRecord { entity, id }),
+        }
+    }
+
+    for (id, _) in db_records {
+        report.checked += 1;
+        report.missing_from_chain.push(MissingRecord { entity, id });
+    }
+}
+
+fn load_db_organizations(
+    conn: &DbConn,
+    head_block_num: i64,
+) -> Result<BTreeMap<String, Fields>, ApiError> {
+    let mut contact_lists: HashMap<String, Vec<String>> = HashMap::new();
+    for contact in contacts::table
+        .filter(contacts::start_block_num.le(head_block_num))
+        .filter(contacts::end_block_num.gt(head_block_num))
+        .load::<Contact>(&**conn)?
+    {
+        contact_lists
+            .entry(contact.organization_id)
+            .or_insert_with(Vec::new)
+            .push(contact_value(
+                &contact.name,
+                &contact.phone_number,
+                &contact.language_code,
+            ));
+    }
+
+    let mut authorization_lists: HashMap<String, Vec<String>> = HashMap::new();
+    for authorization in authorizations::table
+        .filter(authorizations::start_block_num.le(head_block_num))
+        .filter(authorizations::end_block_num.gt(head_block_num))
+        .load::<Authorization>(&**conn)?
+    {
+        authorization_lists
+            .entry(authorization.organization_id)
+            .or_insert_with(Vec::new)
+            .push(authorization_value(
+                &authorization.public_key,
+                role_name(&authorization.role),
+            ));
+    }
+
+    let mut db_addresses: HashMap<String, Address> = addresses::table
+        .select(ADDRESS_COLUMNS)
+        .filter(addresses::start_block_num.le(head_block_num))
+        .filter(addresses::end_block_num.gt(head_block_num))
+        .load::<Address>(&**conn)?
+        .into_iter()
+        .map(|address| (address.organization_id.clone(), address))
+        .collect();
+
+    Ok(organizations::table
+        .filter(organizations::start_block_num.le(head_block_num))
+        .filter(organizations::end_block_num.gt(head_block_num))
+        .load::<Organization>(&**conn)?
+        .into_iter()
+        .map(|org| {
+            // Only factories have an address, which is empty on-chain for other organizations
+            let address = db_addresses
+                .remove(&org.organization_id)
+                .unwrap_or_else(Address::default);
+            let fields = vec![
+                ("name", org.name),
+                (
+                    "organization_type",
+                    organization_type_name(&org.organization_type).to_string(),
+                ),
+                (
+                    "contacts",
+                    list_value(
+                        contact_lists
+                            .remove(&org.organization_id)
+                            .unwrap_or_else(Vec::new),
+                    ),
+                ),
+                (
+                    "authorizations",
+                    list_value(
+                        authorization_lists
+                            .remove(&org.organization_id)
+                            .unwrap_or_else(Vec::new),
+                    ),
+                ),
+                ("address.street_line_1", address.street_line_1),
+                (
+                    "address.street_line_2",
+                    address.street_line_2.unwrap_or_default(),
+                ),
+                ("address.city", address.city),
+                (
+                    "address.state_province",
+                    address.state_province.unwrap_or_default(),
+                ),
+                ("address.country", address.country),
+                (
+                    "address.postal_code",
+                    address.postal_code.unwrap_or_default(),
+                ),
+            ];
+            (org.organization_id, fields)
+        })
+        .collect())
+}
+
+fn load_chain_organizations(
+    validator_url: &str,
+    state_root: &str,
+) -> Result<BTreeMap<String, Fields>, ApiError> {
+    let mut records = BTreeMap::new();
+    for data in list_state(
+        validator_url,
+        state_root,
+        &addressing::make_organization_address(""),
+    )? {
+        let container: organization::OrganizationContainer = parse_state(&data)?;
+        for org in container.get_entries() {
+            let address = org.get_factory_details().get_address();
+            records.insert(
+                org.get_id().to_string(),
+                vec![
+                    ("name", org.get_name().to_string()),
+                    (
+                        "organization_type",
+                        org.get_organization_type().descriptor().name().to_string(),
+                    ),
+                    (
+                        "contacts",
+                        list_value(
+                            org.get_contacts()
+                                .iter()
+                                .map(|contact| {
+                                    contact_value(
+                                        contact.get_name(),
+                                        contact.get_phone_number(),
+                                        contact.get_language_code(),
+                                    )
+                                })
+                                .collect(),
+                        ),
+                    ),
+                    (
+                        "authorizations",
+                        list_value(
+                            org.get_authorizations()
+                                .iter()
+                                .map(|authorization| {
+                                    authorization_value(
+                                        authorization.get_public_key(),
+                                        authorization.get_role().descriptor().name(),
+                                    )
+                                })
+                                .collect(),
+                        ),
+                    ),
+                    (
+                        "address.street_line_1",
+                        address.get_street_line_1().to_string(),
+                    ),
+                    (
+                        "address.street_line_2",
+                        address.get_street_line_2().to_string(),
+                    ),
+                    ("address.city", address.get_city().to_string()),
+                    (
+                        "address.state_province",
+                        address.get_state_province().to_string(),
+                    ),
+                    ("address.country", address.get_country().to_string()),
+                    ("address.postal_code", address.get_postal_code().to_string()),
+                ],
+            );
+        }
+    }
+    Ok(records)
+}
+
+fn load_db_certificates(
+    conn: &DbConn,
+    head_block_num: i64,
+) -> Result<BTreeMap<String, Fields>, ApiError> {
+    Ok(certificates::table
+        .filter(certificates::start_block_num.le(head_block_num))
+        .filter(certificates::end_block_num.gt(head_block_num))
+        .load::<Certificate>(&**conn)?
+        .into_iter()
+        .map(|cert| {
+            (
+                cert.certificate_id,
+                vec![
+                    ("certifying_body_id", cert.certifying_body_id),
+                    ("factory_id", cert.factory_id),
+                    ("standard_id", cert.standard_id),
+                    ("standard_version", cert.standard_version),
+                    ("valid_from", cert.valid_from.to_string()),
+                    ("valid_to", cert.valid_to.to_string()),
+                ],
+            )
+        })
+        .collect())
+}
+
+fn load_chain_certificates(
+    validator_url: &str,
+    state_root: &str,
+) -> Result<BTreeMap<String, Fields>, ApiError> {
+    let mut records = BTreeMap::new();
+    for data in list_state(
+        validator_url,
+        state_root,
+        &addressing::make_certificate_address(""),
+    )? {
+        let container: certificate::CertificateContainer = parse_state(&data)?;
+        for cert in container.get_entries() {
+            records.insert(
+                cert.get_id().to_string(),
+                vec![
+                    (
+                        "certifying_body_id",
+                        cert.get_certifying_body_id().to_string(),
+                    ),
+                    ("factory_id", cert.get_factory_id().to_string()),
+                    ("standard_id", cert.get_standard_id().to_string()),
+                    ("standard_version", cert.get_standard_version().to_string()),
+                    ("valid_from", cert.get_valid_from().to_string()),
+                    ("valid_to", cert.get_valid_to().to_string()),
+                ],
+            );
+        }
+    }
+    Ok(records)
+}
+
+fn load_db_standards(
+    conn: &DbConn,
+    head_block_num: i64,
+) -> Result<BTreeMap<String, Fields>, ApiError> {
+    Ok(standards::table
+        .filter(standards::start_block_num.le(head_block_num))
+        .filter(standards::end_block_num.gt(head_block_num))
+        .load::<Standard>(&**conn)?
+        .into_iter()
+        .map(|standard| {
+            (
+                standard.standard_id,
+                vec![
+                    ("organization_id", standard.organization_id),
+                    ("name", standard.name),
+                ],
+            )
+        })
+        .collect())
+}
+
+fn load_chain_standards(
+    validator_url: &str,
+    state_root: &str,
+) -> Result<BTreeMap<String, Fields>, ApiError> {
+    let mut records = BTreeMap::new();
+    for data in list_state(
+        validator_url,
+        state_root,
+        &addressing::make_standard_address(""),
+    )? {
+        let container: standard::StandardContainer = parse_state(&data)?;
+        for standard in container.get_entries() {
+            records.insert(
+                standard.get_id().to_string(),
+                vec![
+                    (
+                        "organization_id",
+                        standard.get_organization_id().to_string(),
+                    ),
+                    ("name", standard.get_name().to_string()),
+                ],
+            );
+        }
+    }
+    Ok(records)
+}
+
+fn load_db_assertions(
+    conn: &DbConn,
+    head_block_num: i64,
+) -> Result<BTreeMap<String, Fields>, ApiError> {
+    Ok(assertions::table
+        .filter(assertions::start_block_num.le(head_block_num))
+        .filter(assertions::end_block_num.gt(head_block_num))
+        .load::<Assertion>(&**conn)?
+        .into_iter()
+        .map(|assertion| {
+            (
+                assertion.assertion_id,
+                vec![
+                    ("assertor_pub_key", assertion.assertor_pub_key),
+                    (
+                        "assertion_type",
+                        assertion_type_name(&assertion.assertion_type).to_string(),
+                    ),
+                    ("object_id", assertion.object_id),
+                    ("data_id", assertion.data_id.unwrap_or_default()),
+                ],
+            )
+        })
+        .collect())
+}
+
+fn load_chain_assertions(
+    validator_url: &str,
+    state_root: &str,
+) -> Result<BTreeMap<String, Fields>, ApiError> {
+    let mut records = BTreeMap::new();
+    for data in list_state(
+        validator_url,
+        state_root,
+        &addressing::make_assertion_address(""),
+    )? {
+        let container: assertion::AssertionContainer = parse_state(&data)?;
+        for assertion in container.get_entries() {
+            records.insert(
+                assertion.get_id().to_string(),
+                vec![
+                    (
+                        "assertor_pub_key",
+                        assertion.get_assertor_pub_key().to_string(),
+                    ),
+                    (
+                        "assertion_type",
+                        assertion
+                            .get_assertion_type()
+                            .descriptor()
+                            .name()
+                            .to_string(),
+                    ),
+                    ("object_id", assertion.get_object_id().to_string()),
+                    ("data_id", assertion.get_data_id().to_string()),
+                ],
+            );
+        }
+    }
+    Ok(records)
+}
+
+/// Looks up the state root hash of the block with the given id
+fn fetch_state_root(validator_url: &str, block_id: &str) -> Result<String, ApiError> {
+    let mut block_request = ClientBlockGetByIdRequest::new();
+    block_request.set_block_id(block_id.to_string());
+
+    let response: ClientBlockGetResponse = send_request(
+        validator_url,
+        Message_MessageType::CLIENT_BLOCK_GET_BY_ID_REQUEST,
+        &block_request,
+    )
+    .map_err(ApiError::InternalError)?;
+
+    match response.status {
+        ClientBlockGetResponse_Status::OK => {
+            let header: BlockHeader = parse_state(response.get_block().get_header())?;
+            Ok(header.get_state_root_hash().to_string())
+        }
+        ClientBlockGetResponse_Status::NO_RESOURCE => Err(ApiError::NotFound(format!(
+            "Validator has no block with the ID {}",
+            block_id
+        ))),
+        _ => Err(ApiError::InternalError("Validator error".to_string())),
+    }
+}
+
+/// Returns the data stored at every address sharing the entity type prefix of `address`
+fn list_state(
+    validator_url: &str,
+    state_root: &str,
+    address: &str,
+) -> Result<Vec<Vec<u8>>, ApiError> {
+    let prefix = &address[..TYPE_PREFIX_LEN];
+    let mut entries = vec![];
+    let mut start = String::new();
+
+    loop {
+        let mut paging = ClientPagingControls::new();
+        paging.set_start(start);
+        paging.set_limit(STATE_PAGE_SIZE);
+
+        let mut state_request = ClientStateListRequest::new();
+        state_request.set_state_root(state_root.to_string());
+        state_request.set_address(prefix.to_string());
+        state_request.set_paging(paging);
+
+        let response: ClientStateListResponse = send_request(
+            validator_url,
+            Message_MessageType::CLIENT_STATE_LIST_REQUEST,
+            &state_request,
+        )
+        .map_err(ApiError::InternalError)?;
+
+        match response.status {
+            ClientStateListResponse_Status::OK => (),
+            ClientStateListResponse_Status::NO_RESOURCE => break,
+            _ => return Err(ApiError::InternalError("Validator error".to_string())),
+        }
+
+        entries.extend(
+            response
+                .get_entries()
+                .iter()
+                .map(|entry| entry.get_data().to_vec()),
+        );
+
+        let next = response.get_paging().get_next();
+        if next.is_empty() {
+            break;
+        }
+        start = next.to_string();
+    }
+
+    Ok(entries)
+}
+
+fn parse_state<T: protobuf::Message>(data: &[u8]) -> Result<T, ApiError> {
+    Message::parse_from_bytes(data).map_err(|err| ApiError::InternalError(err.to_string()))
+}
+
+/// Describes a contact as a single value, so a list of contacts can be compared as one field
+fn contact_value(name: &str, phone_number: &str, language_code: &str) -> String {
+    format!("{} ({}, {})", name, phone_number, language_code)
+}
+
+fn authorization_value(public_key: &str, role: &str) -> String {
+    format!("{} ({})", public_key, role)
+}
+
+/// Joins the values of a list field, which are stored in no particular order
+fn list_value(mut values: Vec<String>) -> String {
+    values.sort();
+    values.join("; ")
+}
+
+fn organization_type_name(organization_type: &OrganizationTypeEnum) -> &'static str {
+    match organization_type {
+        OrganizationTypeEnum::UnsetType => "UNSET_TYPE",
+        OrganizationTypeEnum::CertifyingBody => "CERTIFYING_BODY",
+        OrganizationTypeEnum::StandardsBody => "STANDARDS_BODY",
+        OrganizationTypeEnum::Factory => "FACTORY",
+        OrganizationTypeEnum::Ingestion => "INGESTION",
+    }
+}
+
+fn role_name(role: &RoleEnum) -> &'static str {
+    match role {
+        RoleEnum::UnsetRole => "UNSET_ROLE",
+        RoleEnum::Admin => "ADMIN",
+        RoleEnum::Transactor => "TRANSACTOR",
+    }
+}
+
+fn assertion_type_name(assertion_type: &AssertionTypeEnum) -> &'static str {
+    match assertion_type {
+        AssertionTypeEnum::UnsetType => "UNSET_TYPE",
+        AssertionTypeEnum::Factory => "FACTORY",
+        AssertionTypeEnum::Certificate => "CERTIFICATE",
+        AssertionTypeEnum::Standard => "STANDARD",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(entries: Vec<(&str, &str)>) -> BTreeMap<String, Fields> {
+        entries
+            .into_iter()
+            .map(|(id, name)| (id.to_string(), vec![("name", name.to_string())]))
+            .collect()
+    }
+
+    #[test]
+    /// Test that identical database and chain records produce a consistent report
+    fn test_compare_records_consistent() {
+        let mut report = ConsistencyReport::default();
+        compare_records(
+            "organization",
+            records(vec![("org_1", "factory")]),
+            records(vec![("org_1", "factory")]),
+            &mut report,
+        );
+        assert!(report.is_consistent());
+        assert_eq!(report.checked, 1);
+    }
+
+    #[test]
+    /// Test that a differing field is reported as a mismatch
+    fn test_compare_records_mismatch() {
+        let mut report = ConsistencyReport::default();
+        compare_records(
+            "organization",
+            records(vec![("org_1", "old_name")]),
+            records(vec![("org_1", "new_name")]),
+            &mut report,
+        );
+        assert!(!report.is_consistent());
+        assert_eq!(
+            report.mismatches,
+            vec![Mismatch {
+                entity: "organization",
+                id: "org_1".to_string(),
+                field: "name",
+                database: "old_name".to_string(),
+                chain: "new_name".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    /// Test that the values of a list field are compared regardless of their order
+    fn test_list_value_order() {
+        assert_eq!(
+            list_value(vec![
+                contact_value("b", "2", "en"),
+                contact_value("a", "1", "fr"),
+            ]),
+            list_value(vec![
+                contact_value("a", "1", "fr"),
+                contact_value("b", "2", "en"),
+            ])
+        );
+        assert_eq!(list_value(vec![]), "");
+    }
+
+    #[test]
+    /// Test that records only present on one side are reported as missing
+    fn test_compare_records_missing() {
+        let mut report = ConsistencyReport::default();
+        compare_records(
+            "standard",
+            records(vec![("std_1", "a")]),
+            records(vec![("std_2", "b")]),
+            &mut report,
+        );
+        assert_eq!(report.checked, 2);
+        assert_eq!(
+            report.missing_from_database,
+            vec![MissingRecord {
+                entity: "standard",
+                id: "std_2".to_string(),
+            }]
+        );
+        assert_eq!(
+            report.missing_from_chain,
+            vec![MissingRecord {
+                entity: "standard",
+                id: "std_1".to_string(),
+            }]
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use std::collections::HashMap;
+use std::slice;
 
-use database::{similarity, DbConn, SIMILARITY_THRESHOLD};
+use cache::{ResponseCache, RESPONSE_CACHE};
+use database::{factory_search_score, similarity, DbConn, SearchSettings, SEARCH_SETTINGS};
 use database_manager::custom_types::OrganizationTypeEnum;
 use database_manager::models::{
     Address, Authorization, Certificate, Contact, Organization, Standard, ADDRESS_COLUMNS,
@@ -9,9 +11,15 @@ use database_manager::tables_schema::addresses::dsl::text_searchable_address_col
 use database_manager::tables_schema::{
     addresses, assertions, authorizations, certificates, contacts, organizations, standards,
 };
+use diesel::pg::Pg;
 use diesel::prelude::*;
+use diesel::sql_types::{Bool, Float};
 use diesel_full_text_search::{to_tsquery, to_tsvector, TsVectorExtensions};
 use errors::ApiError;
+use expand::{Expansions, Resource};
+use fields::Fields;
+use geo::{feature_collection, load_locations, located_factories, ApiLocation, BoundingBox, Point};
+use negotiation::Negotiated;
 use paging::*;
 use rocket::http::uri::Uri;
 use rocket::request::Form;
@@ -19,7 +27,7 @@ use rocket_contrib::json::JsonValue;
 use route_handlers::organizations::ApiFactory;
 use route_handlers::prom::increment_http_req;
 
-#[derive(Default, FromForm, Clone)]
+#[derive(Default, FromForm, Clone, Debug)]
 pub struct FactoryParams {
     name: Option<String>,
     search: Option<String>, // Used for full text search
@@ -32,7 +40,64 @@ pub struct FactoryParams {
     limit: Option<i64>,
     offset: Option<i64>,
     head: Option<i64>,
-    expand: Option<bool>,
+    /// Comma separated relations to inline, or `true` to inline the factory's certificates
+    expand: Option<String>,
+    /// Comma separated fields to respond with, e.g. `id,name,address.country`
+    fields: Option<String>,
+    /// Overrides the configured similarity threshold for fuzzy matches
+    similarity: Option<f32>,
+    /// Override the configured weights used to rank `search` results
+    name_weight: Option<f32>,
+    address_weight: Option<f32>,
+    standard_weight: Option<f32>,
+    /// Only include factories within `radius_km` of this `lat,lon` point
+    near: Option<String>,
+    radius_km: Option<f64>,
+    /// Only include factories within this `min_lon,min_lat,max_lon,max_lat` box
+    bbox: Option<String>,
+    /// Either `json` (default) or `geojson`
+    format: Option<String>,
+}
+
+/// Output formats of `/api/factories`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FactoryFormat {
+    Json,
+    GeoJson,
+}
+
+impl FactoryFormat {
+    fn parse(format: Option<&str>) -> Result<Self, ApiError> {
+        match format {
+            None | Some("json") => Ok(FactoryFormat::Json),
+            Some("geojson") => Ok(FactoryFormat::GeoJson),
+            Some(format) => Err(ApiError::BadRequest(format!(
+                "Invalid format {}, expected json or geojson",
+                format
+            ))),
+        }
+    }
+}
+
+/// Parses the `near` and `radius_km` params, which must be given together
+fn parse_near(
+    near: Option<&str>,
+    radius_km: Option<f64>,
+) -> Result<Option<(Point, f64)>, ApiError> {
+    match (near, radius_km) {
+        (Some(near), Some(radius_km)) => {
+            if radius_km.is_nan() || radius_km <= 0.0 {
+                return Err(ApiError::BadRequest(
+                    "The radius_km must be greater than 0".to_string(),
+                ));
+            }
+            Ok(Some((Point::parse(near)?, radius_km)))
+        }
+        (None, None) => Ok(None),
+        _ => Err(ApiError::BadRequest(
+            "The near and radius_km params must be provided together".to_string(),
+        )),
+    }
 }
 
 #[get("/factories/<organization_id>")]
@@ -53,77 +118,109 @@ pub fn fetch_factory_with_head_param(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
+    let (expand_certificates, expansions) = parse_expansions(&params.expand)?;
+    let fields = Fields::parse(&params.fields);
     let head_block_num: i64 = get_head_block_num(params.head, &conn)?;
 
+    // The address and assertion are one-to-one with the factory, so they are joined to it
     let factory = organizations::table
         .filter(organizations::organization_type.eq(OrganizationTypeEnum::Factory))
         .filter(organizations::organization_id.eq(organization_id.to_string()))
         .filter(organizations::start_block_num.le(head_block_num))
         .filter(organizations::end_block_num.gt(head_block_num))
-        .first::<Organization>(&*conn)
+        .left_join(
+            addresses::table.on(addresses::organization_id
+                .eq(organizations::organization_id)
+                .and(addresses::start_block_num.le(head_block_num))
+                .and(addresses::end_block_num.gt(head_block_num))),
+        )
+        .left_join(
+            assertions::table.on(assertions::object_id
+                .eq(organizations::organization_id)
+                .and(assertions::start_block_num.le(head_block_num))
+                .and(assertions::end_block_num.gt(head_block_num))),
+        )
+        .select((
+            organizations::table::all_columns(),
+            ADDRESS_COLUMNS.nullable(),
+            assertions::assertion_id.nullable(),
+        ))
+        .first::<(Organization, Option<Address>, Option<String>)>(&*conn)
         .optional()
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
 
-    let link = format!("/api/factories/{}?head={}", organization_id, head_block_num);
+    let mut link = format!("/api/factories/{}?head={}", organization_id, head_block_num);
+    if expand_certificates {
+        link = format!("{}&expand=true", link);
+    } else if !expansions.is_empty() {
+        link = format!("{}&expand={}", link, expansions.to_param());
+    }
+    if let Some(ref fields) = params.fields {
+        link = format!("{}&fields={}", link, Uri::percent_encode(fields));
+    }
 
     match factory {
-        Some(factory) => {
-            let contact_results: Vec<Contact> = contacts::table
-                .filter(contacts::organization_id.eq(organization_id.to_string()))
-                .filter(contacts::start_block_num.le(head_block_num))
-                .filter(contacts::end_block_num.gt(head_block_num))
-                .load::<Contact>(&*conn)
-                .map_err(|err| ApiError::InternalError(err.to_string()))?;
-
-            let authorization_results: Vec<Authorization> = authorizations::table
-                .filter(authorizations::organization_id.eq(organization_id.to_string()))
-                .filter(authorizations::start_block_num.le(head_block_num))
-                .filter(authorizations::end_block_num.gt(head_block_num))
-                .load::<Authorization>(&*conn)
-                .map_err(|err| ApiError::InternalError(err.to_string()))?;
-
-            let address_results = addresses::table
-                .select(ADDRESS_COLUMNS)
-                .filter(addresses::organization_id.eq(organization_id.to_string()))
-                .filter(addresses::start_block_num.le(head_block_num))
-                .filter(addresses::end_block_num.gt(head_block_num))
-                .first::<Address>(&*conn)
-                .optional()
-                .map_err(|err| ApiError::InternalError(err.to_string()))?
-                .unwrap_or_else(Address::default);
-            let assertion_results = assertions::table
-                .filter(assertions::object_id.eq(organization_id.clone()))
-                .filter(assertions::start_block_num.le(head_block_num))
-                .filter(assertions::end_block_num.gt(head_block_num))
-                .select(assertions::assertion_id)
-                .first::<String>(&*conn)
-                .optional()
-                .map_err(|err| ApiError::InternalError(err.to_string()))?;
+        Some((factory, address_results, assertion_results)) => {
+            let address_results = address_results.unwrap_or_else(Address::default);
+            let contact_results: Vec<Contact> = if fields.includes("contacts") {
+                contacts::table
+                    .filter(contacts::organization_id.eq(organization_id.to_string()))
+                    .filter(contacts::start_block_num.le(head_block_num))
+                    .filter(contacts::end_block_num.gt(head_block_num))
+                    .load::<Contact>(&*conn)
+                    .map_err(|err| ApiError::InternalError(err.to_string()))?
+            } else {
+                vec![]
+            };
+
+            let authorization_results: Vec<Authorization> = if fields.includes("authorizations") {
+                authorizations::table
+                    .filter(authorizations::organization_id.eq(organization_id.to_string()))
+                    .filter(authorizations::start_block_num.le(head_block_num))
+                    .filter(authorizations::end_block_num.gt(head_block_num))
+                    .load::<Authorization>(&*conn)
+                    .map_err(|err| ApiError::InternalError(err.to_string()))?
+            } else {
+                vec![]
+            };
+
+            let location = if fields.includes("location") {
+                load_locations(&conn, &[organization_id.clone()])?
+                    .remove(&organization_id)
+                    .map(ApiLocation::from)
+            } else {
+                None
+            };
+
+            let mut data = json!(if expand_certificates && fields.includes("certificates") {
+                let certificate_results =
+                    query_certifications(&conn, head_block_num, &[organization_id])?;
+
+                ApiFactory::with_certificate_expanded_and_assertion(
+                    factory,
+                    address_results,
+                    contact_results,
+                    authorization_results,
+                    certificate_results,
+                    assertion_results,
+                )
+                .with_location(location)
+            } else {
+                ApiFactory::with_assertion(
+                    factory,
+                    address_results,
+                    contact_results,
+                    authorization_results,
+                    assertion_results,
+                )
+                .with_location(location)
+            })
+            .0;
+            expansions.apply(&conn, slice::from_mut(&mut data), head_block_num)?;
+            fields.select(slice::from_mut(&mut data));
 
             Ok(json!({
-                "data": match params.expand {
-                    Some(_) => {
-                        let certificate_results = query_certifications(conn, head_block_num, &[organization_id])?;
-
-                        ApiFactory::with_certificate_expanded_and_assertion(
-                            factory,
-                            address_results,
-                            contact_results,
-                            authorization_results,
-                            certificate_results,
-                            assertion_results
-                        )
-                    }
-                    _ => {
-                        ApiFactory::with_assertion(
-                            factory,
-                            address_results,
-                            contact_results,
-                            authorization_results,
-                            assertion_results
-                        )
-                    }
-                },
+                "data": data,
                 "link": link,
                 "head": head_block_num,
             }))
@@ -136,375 +233,588 @@ pub fn fetch_factory_with_head_param(
 }
 
 #[get("/factories")]
-pub fn list_factories(conn: DbConn) -> Result<JsonValue, ApiError> {
-    query_factories(None, conn)
+pub fn list_factories(conn: DbConn) -> Result<Negotiated, ApiError> {
+    query_factories(None, &conn, &RESPONSE_CACHE).map(Negotiated)
 }
 
 #[get("/factories?<params..>")]
 pub fn list_factories_params(
     params: Option<Form<FactoryParams>>,
     conn: DbConn,
-) -> Result<JsonValue, ApiError> {
-    query_factories(params, conn)
+) -> Result<Negotiated, ApiError> {
+    query_factories(params, &conn, &RESPONSE_CACHE).map(Negotiated)
 }
 
-fn query_factories(
-    params: Option<Form<FactoryParams>>,
-    conn: DbConn,
-) -> Result<JsonValue, ApiError> {
-    let params = match params {
-        Some(param) => param.into_inner(),
-        None => Default::default(),
-    };
-
-    let head_block_num: i64 = get_head_block_num(params.head, &conn)?;
-
-    let mut factories_query = organizations::table
-        .filter(organizations::start_block_num.le(head_block_num))
-        .filter(organizations::end_block_num.gt(head_block_num))
-        .filter(organizations::organization_type.eq(OrganizationTypeEnum::Factory))
-        .order_by(organizations::organization_id.asc())
-        .into_boxed();
-
-    let mut count_query = organizations::table
-        .filter(organizations::start_block_num.le(head_block_num))
-        .filter(organizations::end_block_num.gt(head_block_num))
-        .filter(organizations::organization_type.eq(OrganizationTypeEnum::Factory))
-        .into_boxed();
+/// A condition on factories whose type depends on the params of a request
+type FactoryCondition = Box<dyn BoxableExpression<organizations::table, Pg, SqlType = Bool>>;
 
-    let link_params = params.clone();
+/// The factories matching the filters of `/api/factories`, shared with the factory exports.
+///
+/// Every filter is a condition or subquery of the factories query, so Postgres plans the
+/// filtering, and the ranking of `search` results, as a single query.
+pub struct FactoryFilter {
+    pub head_block_num: i64,
+    name: Option<String>,
+    /// Minimum trigram similarity of fuzzy address matches
+    threshold: f32,
+    street: Option<String>,
+    city: Option<String>,
+    state_province: Option<String>,
+    /// Factories in any of these countries match
+    countries: Vec<String>,
+    postal_code: Option<String>,
+    /// Factories certified for any of the standards with these names match
+    certificate_standard_names: Vec<String>,
+    bbox: Option<BoundingBox>,
+    /// Point and radius in kilometres that factories must be within
+    near: Option<(Point, f64)>,
+    search: Option<FactorySearch>,
+}
 
-    let expand = params.expand.unwrap_or(false);
+impl FactoryFilter {
+    /// Builds the filter of `params` at `head_block_num`, which callers resolve from the
+    /// `head` of `params` once for the whole request
+    pub fn from_params(params: &FactoryParams, head_block_num: i64) -> Result<Self, ApiError> {
+        let params = params.clone();
+        let settings = SEARCH_SETTINGS.with_overrides(
+            params.similarity,
+            params.name_weight,
+            params.address_weight,
+            params.standard_weight,
+        )?;
+        let near = parse_near(params.near.as_ref().map(String::as_str), params.radius_km)?;
+        let bbox = match params.bbox {
+            Some(ref bbox) => Some(BoundingBox::parse(bbox)?),
+            None => None,
+        };
+        let search = params.search.map(|search| FactorySearch {
+            query: to_ts_string(&search),
+            text: search,
+            settings,
+            head_block_num,
+        });
 
-    if let Some(name) = params.name {
-        factories_query = factories_query.filter(organizations::name.eq(name.to_string()));
-        count_query = count_query.filter(organizations::name.eq(name));
+        Ok(FactoryFilter {
+            head_block_num,
+            name: params.name,
+            threshold: settings.similarity_threshold,
+            street: params.street,
+            city: params.city,
+            state_province: params.state_province,
+            //factories provided in format /api/factories?country=USA,Peru,Vietnam
+            countries: split_list(params.country),
+            postal_code: params.postal_code,
+            //standard names provided in format /api/factories?certificate=Standard1,Standard2
+            certificate_standard_names: split_list(params.certificate),
+            bbox,
+            near,
+            search,
+        })
     }
 
-    if let Some(street) = params.street {
-        let org_ids = addresses::table
-            .select(addresses::organization_id)
-            .filter(addresses::start_block_num.le(head_block_num))
-            .filter(addresses::end_block_num.gt(head_block_num))
-            .filter(
-                similarity(addresses::street_line_1.nullable(), street.clone())
-                    .gt(SIMILARITY_THRESHOLD)
-                    .or(similarity(addresses::street_line_2.nullable(), street)
-                        .gt(SIMILARITY_THRESHOLD)),
-            )
-            .order_by(addresses::street_line_1.desc())
-            .load::<String>(&*conn)?;
+    /// Returns a query of the live factories matching every filter
+    pub fn query(&self) -> organizations::BoxedQuery<'static, Pg> {
+        let head_block_num = self.head_block_num;
+        let threshold = self.threshold;
+        let mut query = organizations::table
+            .filter(organizations::start_block_num.le(head_block_num))
+            .filter(organizations::end_block_num.gt(head_block_num))
+            .filter(organizations::organization_type.eq(OrganizationTypeEnum::Factory))
+            .into_boxed();
 
-        factories_query =
-            factories_query.filter(organizations::organization_id.eq_any(org_ids.clone()));
-        count_query = count_query.filter(organizations::organization_id.eq_any(org_ids));
-    }
+        if let Some(ref name) = self.name {
+            query = query.filter(organizations::name.eq(name.to_string()));
+        }
 
-    if let Some(city) = params.city {
-        let org_ids = addresses::table
-            .select(addresses::organization_id)
-            .filter(addresses::start_block_num.le(head_block_num))
-            .filter(addresses::end_block_num.gt(head_block_num))
-            .filter(similarity(addresses::city.nullable(), city).gt(SIMILARITY_THRESHOLD))
-            .order_by(addresses::city.desc())
-            .load::<String>(&*conn)?;
+        if let Some(ref street) = self.street {
+            query = query.filter(
+                organizations::organization_id.eq_any(
+                    addresses::table
+                        .select(addresses::organization_id)
+                        .filter(addresses::start_block_num.le(head_block_num))
+                        .filter(addresses::end_block_num.gt(head_block_num))
+                        .filter(
+                            similarity(addresses::street_line_1.nullable(), street.to_string())
+                                .gt(threshold)
+                                .or(similarity(addresses::street_line_2, street.to_string())
+                                    .gt(threshold)),
+                        ),
+                ),
+            );
+        }
 
-        factories_query =
-            factories_query.filter(organizations::organization_id.eq_any(org_ids.clone()));
-        count_query = count_query.filter(organizations::organization_id.eq_any(org_ids));
-    }
+        if let Some(ref city) = self.city {
+            query = query.filter(
+                organizations::organization_id.eq_any(
+                    addresses::table
+                        .select(addresses::organization_id)
+                        .filter(addresses::start_block_num.le(head_block_num))
+                        .filter(addresses::end_block_num.gt(head_block_num))
+                        .filter(
+                            similarity(addresses::city.nullable(), city.to_string()).gt(threshold),
+                        ),
+                ),
+            );
+        }
 
-    if let Some(state_province) = params.state_province {
-        let org_ids = addresses::table
-            .select(addresses::organization_id)
-            .filter(addresses::start_block_num.le(head_block_num))
-            .filter(addresses::end_block_num.gt(head_block_num))
-            .filter(similarity(addresses::state_province, state_province).gt(SIMILARITY_THRESHOLD))
-            .order_by(addresses::state_province.desc())
-            .load::<String>(&*conn)?;
+        if let Some(ref state_province) = self.state_province {
+            query = query.filter(
+                organizations::organization_id.eq_any(
+                    addresses::table
+                        .select(addresses::organization_id)
+                        .filter(addresses::start_block_num.le(head_block_num))
+                        .filter(addresses::end_block_num.gt(head_block_num))
+                        .filter(
+                            similarity(addresses::state_province, state_province.to_string())
+                                .gt(threshold),
+                        ),
+                ),
+            );
+        }
 
-        factories_query =
-            factories_query.filter(organizations::organization_id.eq_any(org_ids.clone()));
-        count_query = count_query.filter(organizations::organization_id.eq_any(org_ids));
-    }
+        // A factory in any of the countries matches, so the subqueries of the countries are OR'd
+        let mut in_countries: Option<FactoryCondition> = None;
+        for country in &self.countries {
+            let in_country = organizations::organization_id.eq_any(
+                addresses::table
+                    .select(addresses::organization_id)
+                    .filter(addresses::start_block_num.le(head_block_num))
+                    .filter(addresses::end_block_num.gt(head_block_num))
+                    .filter(
+                        similarity(addresses::country.nullable(), country.to_string())
+                            .gt(threshold),
+                    ),
+            );
+            let condition: FactoryCondition = match in_countries {
+                Some(in_countries) => Box::new(in_countries.or(in_country)),
+                None => Box::new(in_country),
+            };
+            in_countries = Some(condition);
+        }
+        if let Some(in_countries) = in_countries {
+            query = query.filter(in_countries);
+        }
 
-    if let Some(country) = params.country {
-        //factories provided in format /api/factories?country=USA,Peru,Vietnam
-        let countries: Vec<&str> = country.split(',').collect();
-        let mut org_ids_query = addresses::table
-            .select(addresses::organization_id)
-            .into_boxed();
+        if let Some(ref postal_code) = self.postal_code {
+            query = query.filter(
+                organizations::organization_id.eq_any(
+                    addresses::table
+                        .select(addresses::organization_id)
+                        .filter(addresses::start_block_num.le(head_block_num))
+                        .filter(addresses::end_block_num.gt(head_block_num))
+                        .filter(
+                            similarity(addresses::postal_code, postal_code.to_string())
+                                .gt(threshold),
+                        ),
+                ),
+            );
+        }
 
-        for country in countries.iter() {
-            if !country.is_empty() {
-                org_ids_query = org_ids_query.or_filter(
-                    addresses::start_block_num
-                        .le(head_block_num)
-                        .and(addresses::end_block_num.gt(head_block_num))
-                        .and(
-                            similarity(addresses::country.nullable(), country)
-                                .gt(SIMILARITY_THRESHOLD),
+        if !self.certificate_standard_names.is_empty() {
+            query = query.filter(
+                organizations::organization_id.eq_any(
+                    certificates::table
+                        .select(certificates::factory_id)
+                        .filter(certificates::start_block_num.le(head_block_num))
+                        .filter(certificates::end_block_num.gt(head_block_num))
+                        .filter(
+                            certificates::standard_id.eq_any(
+                                standards::table
+                                    .select(standards::standard_id)
+                                    .filter(standards::start_block_num.le(head_block_num))
+                                    .filter(standards::end_block_num.gt(head_block_num))
+                                    .filter(
+                                        standards::name
+                                            .eq_any(self.certificate_standard_names.clone()),
+                                    ),
+                            ),
                         ),
-                );
-            }
+                ),
+            );
         }
 
-        let org_ids = org_ids_query
-            .order_by(addresses::country.desc())
-            .load::<String>(&*conn)?;
+        if self.bbox.is_some() || self.near.is_some() {
+            query = query.filter(
+                organizations::organization_id.eq_any(located_factories(self.bbox, self.near)),
+            );
+        }
 
-        factories_query =
-            factories_query.filter(organizations::organization_id.eq_any(org_ids.clone()));
-        count_query = count_query.filter(organizations::organization_id.eq_any(org_ids));
+        if let Some(ref search) = self.search {
+            query = query.filter(search.matches());
+        }
+        query
     }
+}
 
-    if let Some(postal_code) = params.postal_code {
-        let org_ids = addresses::table
-            .select(addresses::organization_id)
-            .filter(addresses::start_block_num.le(head_block_num))
-            .filter(addresses::end_block_num.gt(head_block_num))
-            .filter(
-                similarity(addresses::postal_code.nullable(), postal_code).gt(SIMILARITY_THRESHOLD),
-            )
-            .order_by(addresses::postal_code.desc())
-            .load::<String>(&*conn)?;
+/// The score of a factory, whose type depends on the params of a request
+type FactoryScore = Box<dyn BoxableExpression<organizations::table, Pg, SqlType = Float>>;
 
-        factories_query =
-            factories_query.filter(organizations::organization_id.eq_any(org_ids.clone()));
-        count_query = count_query.filter(organizations::organization_id.eq_any(org_ids));
-    }
+/// A `search` of factories by their name, their address or the names of the standards they
+/// are certified for
+struct FactorySearch {
+    text: String,
+    /// `text` as a prefix matching `tsquery`
+    query: String,
+    settings: SearchSettings,
+    head_block_num: i64,
+}
 
-    if let Some(search) = params.search {
-        let ts_string = to_ts_string(&search);
+impl FactorySearch {
+    /// Returns a condition on the factories with a full-text or fuzzy match
+    fn matches(&self) -> FactoryCondition {
+        let head_block_num = self.head_block_num;
+        let threshold = self.settings.similarity_threshold;
 
-        let mut matched_cert_org_ids = certificates::table
-            .select(certificates::factory_id)
-            .filter(certificates::start_block_num.le(head_block_num))
-            .filter(certificates::end_block_num.gt(head_block_num))
-            .filter(
-                certificates::standard_id.eq_any(
-                    standards::table
-                        .select(standards::standard_id)
-                        .filter(standards::start_block_num.le(head_block_num))
-                        .filter(standards::end_block_num.gt(head_block_num))
-                        .filter(
-                            to_tsvector(standards::name)
-                                .matches(to_tsquery(&ts_string))
-                                .or(similarity(standards::name.nullable(), &search)
-                                    .gt(SIMILARITY_THRESHOLD)),
+        let name_matches = to_tsvector(organizations::name)
+            .matches(to_tsquery(self.query.clone()))
+            .or(similarity(organizations::name.nullable(), self.text.clone()).gt(threshold));
+        // `text_searchable_address_col` is already a TS_VECTOR col
+        let address_matches = organizations::organization_id.eq_any(
+            addresses::table
+                .select(addresses::organization_id)
+                .filter(addresses::start_block_num.le(head_block_num))
+                .filter(addresses::end_block_num.gt(head_block_num))
+                .filter(
+                    text_searchable_address_col
+                        .matches(to_tsquery(self.query.clone()))
+                        .or(
+                            similarity(addresses::full_address.nullable(), self.text.clone())
+                                .gt(threshold),
                         ),
                 ),
-            )
-            .load::<String>(&*conn)?;
+        );
+        let standard_matches = organizations::organization_id.eq_any(
+            certificates::table
+                .select(certificates::factory_id)
+                .filter(certificates::start_block_num.le(head_block_num))
+                .filter(certificates::end_block_num.gt(head_block_num))
+                .filter(
+                    certificates::standard_id.eq_any(
+                        standards::table
+                            .select(standards::standard_id)
+                            .filter(standards::start_block_num.le(head_block_num))
+                            .filter(standards::end_block_num.gt(head_block_num))
+                            .filter(
+                                to_tsvector(standards::name)
+                                    .matches(to_tsquery(self.query.clone()))
+                                    .or(similarity(standards::name.nullable(), self.text.clone())
+                                        .gt(threshold)),
+                            ),
+                    ),
+                ),
+        );
 
-        // `text_searchable_address_col` is already a TS_VECTOR col
-        let mut matched_address_org_ids = addresses::table
-            .select(addresses::organization_id)
-            .filter(addresses::start_block_num.le(head_block_num))
-            .filter(addresses::end_block_num.gt(head_block_num))
-            .filter(
-                text_searchable_address_col
-                    .matches(to_tsquery(&ts_string))
-                    .or(similarity(addresses::full_address.nullable(), &search)
-                        .gt(SIMILARITY_THRESHOLD)),
-            )
-            .load::<String>(&*conn)?;
+        Box::new(name_matches.or(address_matches).or(standard_matches))
+    }
 
-        let mut matched_org_ids = organizations::table
-            .select(organizations::organization_id)
-            .filter(organizations::start_block_num.le(head_block_num))
-            .filter(organizations::end_block_num.gt(head_block_num))
-            .filter(
-                to_tsvector(organizations::name)
-                    .matches(to_tsquery(&ts_string))
-                    .or(similarity(organizations::name.nullable(), &search)
-                        .gt(SIMILARITY_THRESHOLD)),
-            )
-            .load::<String>(&*conn)?;
+    /// Returns the score of each factory, which is only meaningful for matching factories.
+    ///
+    /// Each kind of match scores its full-text `ts_rank` plus its trigram similarity,
+    /// multiplied by the kind's weight in the settings. A factory's score is the sum over its
+    /// matches, where only the best matching standard counts.
+    fn score(&self) -> FactoryScore {
+        Box::new(factory_search_score(
+            organizations::organization_id,
+            self.head_block_num,
+            self.text.clone(),
+            self.query.clone(),
+            self.settings.similarity_threshold,
+            self.settings.name_weight,
+            self.settings.address_weight,
+            self.settings.standard_weight,
+        ))
+    }
+}
 
-        let mut search_org_ids = vec![];
+/// Splits a comma separated list param, ignoring empty entries
+fn split_list(list: Option<String>) -> Vec<String> {
+    list.map_or_else(Vec::new, |list| {
+        list.split(',')
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_string)
+            .collect()
+    })
+}
 
-        search_org_ids.append(&mut matched_cert_org_ids);
-        search_org_ids.append(&mut matched_address_org_ids);
-        search_org_ids.append(&mut matched_org_ids);
+fn query_factories(
+    params: Option<Form<FactoryParams>>,
+    conn: &DbConn,
+    cache: &ResponseCache,
+) -> Result<JsonValue, ApiError> {
+    let params = match params {
+        Some(param) => param.into_inner(),
+        None => Default::default(),
+    };
 
-        factories_query = factories_query
-            .filter(organizations::organization_id.eq_any(search_org_ids.clone()))
-            .order_by(similarity(organizations::name.nullable(), search).desc());
-        count_query = count_query.filter(organizations::organization_id.eq_any(search_org_ids));
-    }
+    // Dashboards load the same factory lists over and over, and each takes several queries
+    let head_block_num = get_head_block_num(params.head, conn)?;
+    cache.get_or_load("factories", format!("{:?}", params), head_block_num, || {
+        load_factories(params, head_block_num, conn)
+    })
+}
 
-    if let Some(certificate) = params.certificate {
-        //standard names provided in format /api/factories?certificate=Standard1,Standard2
-        let cert_standard_names: Vec<&str> = certificate.split(',').collect();
-        let mut matched_cert_org_query = certificates::table
-            .select(certificates::factory_id)
-            .into_boxed();
-        for name in cert_standard_names.iter() {
-            matched_cert_org_query = matched_cert_org_query.or_filter(
-                certificates::start_block_num
-                    .le(head_block_num)
-                    .and(certificates::end_block_num.gt(head_block_num))
-                    .and(
-                        certificates::standard_id.eq_any(
-                            standards::table
-                                .select(standards::standard_id)
-                                .filter(standards::start_block_num.le(head_block_num))
-                                .filter(standards::end_block_num.gt(head_block_num))
-                                .filter(standards::name.eq(name)),
-                        ),
-                    ),
-            )
-        }
-        let matched_cert_org_ids = matched_cert_org_query.load::<String>(&*conn)?;
+fn load_factories(
+    params: FactoryParams,
+    head_block_num: i64,
+    conn: &DbConn,
+) -> Result<JsonValue, ApiError> {
+    let format = FactoryFormat::parse(params.format.as_ref().map(String::as_str))?;
+    let (expand_certificates, expansions) = parse_expansions(&params.expand)?;
+    let fields = Fields::parse(&params.fields);
+    let filter = FactoryFilter::from_params(&params, head_block_num)?;
 
-        factories_query = factories_query
-            .filter(organizations::organization_id.eq_any(matched_cert_org_ids.clone()));
-        count_query =
-            count_query.filter(organizations::organization_id.eq_any(matched_cert_org_ids));
-    }
+    let count_query = filter.query();
+
+    let link_params = params.clone();
+
+    let expand = expand_certificates && fields.includes("certificates");
 
     let total_count = count_query
         .count()
-        .get_result(&*conn)
+        .get_result(&**conn)
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
-    let paging_info = apply_paging(link_params, head_block_num, total_count)?;
-
-    factories_query = factories_query.limit(params.limit.unwrap_or(DEFAULT_LIMIT));
-    factories_query = factories_query.offset(params.offset.unwrap_or(DEFAULT_OFFSET));
-
-    let factory_results = factories_query.load::<Organization>(&*conn)?;
-
-    let mut contact_results: HashMap<String, Vec<Contact>> = contacts::table
-        .filter(contacts::start_block_num.le(head_block_num))
-        .filter(contacts::end_block_num.gt(head_block_num))
-        .filter(
-            contacts::organization_id.eq_any(
-                factory_results
+    let paging_info = apply_paging(
+        expand_certificates,
+        &expansions,
+        link_params,
+        head_block_num,
+        total_count,
+    )?;
+
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    let offset = params.offset.unwrap_or(DEFAULT_OFFSET);
+
+    // Search results are ranked by their score, and ties are broken by id like other lists
+    let (factory_results, mut scores): (Vec<Organization>, HashMap<String, f32>) =
+        match filter.search {
+            Some(ref search) => {
+                let scored_results = filter
+                    .query()
+                    .select((organizations::table::all_columns(), search.score()))
+                    .order_by((search.score().desc(), organizations::organization_id.asc()))
+                    .limit(limit)
+                    .offset(offset)
+                    .load::<(Organization, f32)>(&**conn)?;
+                let scores = scored_results
                     .iter()
-                    .map(|factory| factory.organization_id.to_string())
-                    .collect::<Vec<String>>(),
+                    .map(|(factory, score)| (factory.organization_id.clone(), *score))
+                    .collect();
+                (
+                    scored_results
+                        .into_iter()
+                        .map(|(factory, _)| factory)
+                        .collect(),
+                    scores,
+                )
+            }
+            None => (
+                filter
+                    .query()
+                    .order_by(organizations::organization_id.asc())
+                    .limit(limit)
+                    .offset(offset)
+                    .load::<Organization>(&**conn)?,
+                HashMap::new(),
             ),
-        )
-        .order_by(contacts::organization_id.asc())
-        .load::<Contact>(&*conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, contact| {
-            acc.entry(contact.organization_id.to_string())
-                .or_insert_with(Vec::new)
-                .push(contact);
-            acc
-        });
+        };
 
-    let mut authorization_results: HashMap<String, Vec<Authorization>> = authorizations::table
-        .filter(authorizations::start_block_num.le(head_block_num))
-        .filter(authorizations::end_block_num.gt(head_block_num))
-        .filter(
-            authorizations::organization_id.eq_any(
-                factory_results
-                    .iter()
-                    .map(|org| org.organization_id.to_string())
-                    .collect::<Vec<String>>(),
-            ),
-        )
-        .order_by(authorizations::organization_id.asc())
-        .load::<Authorization>(&*conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, authorization| {
-            acc.entry(authorization.organization_id.to_string())
-                .or_insert_with(Vec::new)
-                .push(authorization);
-            acc
-        });
+    let mut contact_results: HashMap<String, Vec<Contact>> = if fields.includes("contacts") {
+        contacts::table
+            .filter(contacts::start_block_num.le(head_block_num))
+            .filter(contacts::end_block_num.gt(head_block_num))
+            .filter(
+                contacts::organization_id.eq_any(
+                    factory_results
+                        .iter()
+                        .map(|factory| factory.organization_id.to_string())
+                        .collect::<Vec<String>>(),
+                ),
+            )
+            .order_by(contacts::organization_id.asc())
+            .load::<Contact>(&**conn)
+            .map_err(|err| ApiError::InternalError(err.to_string()))?
+            .into_iter()
+            .fold(HashMap::new(), |mut acc, contact| {
+                acc.entry(contact.organization_id.to_string())
+                    .or_insert_with(Vec::new)
+                    .push(contact);
+                acc
+            })
+    } else {
+        HashMap::new()
+    };
+
+    let mut authorization_results: HashMap<String, Vec<Authorization>> =
+        if fields.includes("authorizations") {
+            authorizations::table
+                .filter(authorizations::start_block_num.le(head_block_num))
+                .filter(authorizations::end_block_num.gt(head_block_num))
+                .filter(
+                    authorizations::organization_id.eq_any(
+                        factory_results
+                            .iter()
+                            .map(|org| org.organization_id.to_string())
+                            .collect::<Vec<String>>(),
+                    ),
+                )
+                .order_by(authorizations::organization_id.asc())
+                .load::<Authorization>(&**conn)
+                .map_err(|err| ApiError::InternalError(err.to_string()))?
+                .into_iter()
+                .fold(HashMap::new(), |mut acc, authorization| {
+                    acc.entry(authorization.organization_id.to_string())
+                        .or_insert_with(Vec::new)
+                        .push(authorization);
+                    acc
+                })
+        } else {
+            HashMap::new()
+        };
 
     let factory_ids: Vec<String> = factory_results
         .iter()
         .map(|org| org.organization_id.to_string())
         .collect();
 
-    let mut address_results: HashMap<String, Address> = addresses::table
-        .select(ADDRESS_COLUMNS)
-        .filter(addresses::start_block_num.le(head_block_num))
-        .filter(addresses::end_block_num.gt(head_block_num))
-        .filter(addresses::organization_id.eq_any(&factory_ids))
-        .order_by(addresses::organization_id.asc())
-        .load::<Address>(&*conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, address| {
-            acc.insert(address.organization_id.to_string(), address);
-            acc
-        });
+    let mut address_results: HashMap<String, Address> = if fields.includes("address") {
+        addresses::table
+            .select(ADDRESS_COLUMNS)
+            .filter(addresses::start_block_num.le(head_block_num))
+            .filter(addresses::end_block_num.gt(head_block_num))
+            .filter(addresses::organization_id.eq_any(&factory_ids))
+            .order_by(addresses::organization_id.asc())
+            .load::<Address>(&**conn)
+            .map_err(|err| ApiError::InternalError(err.to_string()))?
+            .into_iter()
+            .fold(HashMap::new(), |mut acc, address| {
+                acc.insert(address.organization_id.to_string(), address);
+                acc
+            })
+    } else {
+        HashMap::new()
+    };
 
-    let mut assertion_results = assertions::table
-        .filter(assertions::start_block_num.le(head_block_num))
-        .filter(assertions::end_block_num.gt(head_block_num))
-        .filter(
-            assertions::object_id.eq_any(
-                factory_results
-                    .iter()
-                    .map(|org| org.organization_id.to_string())
-                    .collect::<Vec<String>>(),
-            ),
-        )
-        .order_by(assertions::object_id.asc())
-        .select((assertions::object_id, assertions::assertion_id))
-        .load::<(String, String)>(&*conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, (object_id, assertion_id)| {
-            acc.insert(object_id, assertion_id);
-            acc
-        });
+    let mut assertion_results = if fields.includes("assertion_id") {
+        assertions::table
+            .filter(assertions::start_block_num.le(head_block_num))
+            .filter(assertions::end_block_num.gt(head_block_num))
+            .filter(
+                assertions::object_id.eq_any(
+                    factory_results
+                        .iter()
+                        .map(|org| org.organization_id.to_string())
+                        .collect::<Vec<String>>(),
+                ),
+            )
+            .order_by(assertions::object_id.asc())
+            .select((assertions::object_id, assertions::assertion_id))
+            .load::<(String, String)>(&**conn)
+            .map_err(|err| ApiError::InternalError(err.to_string()))?
+            .into_iter()
+            .fold(HashMap::new(), |mut acc, (object_id, assertion_id)| {
+                acc.insert(object_id, assertion_id);
+                acc
+            })
+    } else {
+        HashMap::new()
+    };
+
+    // GeoJSON needs the locations for the geometry of each feature
+    let mut location_results = if format == FactoryFormat::GeoJson || fields.includes("location") {
+        load_locations(conn, &factory_ids)?
+    } else {
+        HashMap::new()
+    };
 
     let mut cert_results: HashMap<
         String,
         Vec<(Certificate, Standard, Organization, Option<String>)>,
-    > = query_certifications(conn, head_block_num, &factory_ids)?
+    > = if expand {
+        query_certifications(conn, head_block_num, &factory_ids)?
+            .into_iter()
+            .fold(
+                HashMap::new(),
+                |mut acc, cert_info: (Certificate, Standard, Organization, Option<String>)| {
+                    acc.entry(cert_info.0.factory_id.to_string())
+                        .or_insert_with(Vec::new)
+                        .push(cert_info);
+                    acc
+                },
+            )
+    } else {
+        HashMap::new()
+    };
+
+    let mut factories = factory_results
         .into_iter()
-        .fold(
-            HashMap::new(),
-            |mut acc, cert_info: (Certificate, Standard, Organization, Option<String>)| {
-                acc.entry(cert_info.0.factory_id.to_string())
-                    .or_insert_with(Vec::new)
-                    .push(cert_info);
-                acc
-            },
-        );
+        .map(|factory| {
+            let org_id = factory.organization_id.clone();
+            let score = scores.remove(&org_id);
+            let location = location_results.remove(&org_id).map(ApiLocation::from);
+            if expand {
+                json!(ApiFactory::with_certificate_expanded_and_assertion(
+                    factory,
+                    address_results
+                        .remove(&org_id)
+                        .unwrap_or_else(Address::default),
+                    contact_results.remove(&org_id).unwrap_or_else(Vec::new),
+                    authorization_results
+                        .remove(&org_id)
+                        .unwrap_or_else(Vec::new),
+                    cert_results.remove(&org_id).unwrap_or_else(Vec::new),
+                    assertion_results.remove(&org_id)
+                )
+                .with_score(score)
+                .with_location(location))
+                .0
+            } else {
+                json!(ApiFactory::with_assertion(
+                    factory,
+                    address_results
+                        .remove(&org_id)
+                        .unwrap_or_else(Address::default),
+                    contact_results.remove(&org_id).unwrap_or_else(Vec::new),
+                    authorization_results
+                        .remove(&org_id)
+                        .unwrap_or_else(Vec::new),
+                    assertion_results.remove(&org_id)
+                )
+                .with_score(score)
+                .with_location(location))
+                .0
+            }
+        })
+        .collect::<Vec<_>>();
+    expansions.apply(conn, &mut factories, head_block_num)?;
 
-    Ok(json!({
-        "data": factory_results.into_iter()
-            .map(|factory| {
-                let org_id = factory.organization_id.clone();
-                if expand {
-                    json!(ApiFactory::with_certificate_expanded_and_assertion(
-                        factory,
-                        address_results.remove(&org_id).unwrap_or_else(Address::default),
-                        contact_results.remove(&org_id).unwrap_or_else(Vec::new),
-                        authorization_results.remove(&org_id).unwrap_or_else(Vec::new),
-                        cert_results.remove(&org_id).unwrap_or_else(Vec::new),
-                        assertion_results.remove(&org_id)
-                    ))
-                } else {
-                    json!(ApiFactory::with_assertion(
-                        factory,
-                        address_results.remove(&org_id).unwrap_or_else(Address::default),
-                        contact_results.remove(&org_id).unwrap_or_else(Vec::new),
-                        authorization_results.remove(&org_id).unwrap_or_else(Vec::new),
-                        assertion_results.remove(&org_id)
-                    ))
+    match format {
+        FactoryFormat::Json => {
+            fields.select(&mut factories);
+            Ok(json!({
+                "data": factories,
+                "link": paging_info.get("link"),
+                "head": head_block_num,
+                "paging": paging_info.get("paging")
+            }))
+        }
+        FactoryFormat::GeoJson => {
+            // Paging details are kept as foreign members of the FeatureCollection
+            let mut collection = feature_collection(factories);
+            if let Some(features) = collection["features"].as_array_mut() {
+                for feature in features {
+                    fields.select(slice::from_mut(&mut feature["properties"]));
                 }
-            }).collect::<Vec<_>>(),
-        "link": paging_info.get("link"),
-        "head": head_block_num,
-        "paging": paging_info.get("paging")
-    }))
+            }
+            collection["link"] = json!(paging_info.get("link")).0;
+            collection["head"] = json!(head_block_num).0;
+            collection["paging"] = json!(paging_info.get("paging")).0;
+            Ok(JsonValue(collection))
+        }
+    }
 }
 
 fn query_certifications(
-    conn: DbConn,
+    conn: &DbConn,
     head_block_num: i64,
     factory_ids: &[String],
 ) -> Result<Vec<(Certificate, Standard, Organization, Option<String>)>, ApiError> {
@@ -541,7 +851,7 @@ fn query_certifications(
             Option<Standard>,
             Option<Organization>,
             Option<String>,
-        )>(&*conn)
+        )>(&**conn)
         .map_err(|err| ApiError::InternalError(err.to_string()))?
         .into_iter()
         .map(|(cert, std_opt, org_opt, assertion_id)| {
@@ -563,7 +873,13 @@ fn query_certifications(
         .collect()
 }
 
-fn apply_paging(params: FactoryParams, head: i64, total_count: i64) -> Result<JsonValue, ApiError> {
+fn apply_paging(
+    expand_certificates: bool,
+    expansions: &Expansions,
+    params: FactoryParams,
+    head: i64,
+    total_count: i64,
+) -> Result<JsonValue, ApiError> {
     let mut link = String::from("/api/factories?");
 
     if let Some(name) = params.name {
@@ -571,22 +887,74 @@ fn apply_paging(params: FactoryParams, head: i64, total_count: i64) -> Result<Js
     }
     link = format!("{}head={}&", link, head);
 
-    if let Some(expand) = params.expand {
-        link = format!("{}expand={}&", link, expand);
+    if expand_certificates {
+        link = format!("{}expand=true&", link);
+    } else if !expansions.is_empty() {
+        link = format!("{}expand={}&", link, expansions.to_param());
+    }
+    if let Some(fields) = params.fields {
+        link = format!("{}fields={}&", link, Uri::percent_encode(&fields));
+    }
+
+    // Every filter is kept, so following a link pages through the same results
+    let filters = [
+        ("search", params.search),
+        ("street", params.street),
+        ("city", params.city),
+        ("state_province", params.state_province),
+        ("country", params.country),
+        ("postal_code", params.postal_code),
+        ("certificate", params.certificate),
+        (
+            "similarity",
+            params.similarity.map(|value| value.to_string()),
+        ),
+        (
+            "name_weight",
+            params.name_weight.map(|value| value.to_string()),
+        ),
+        (
+            "address_weight",
+            params.address_weight.map(|value| value.to_string()),
+        ),
+        (
+            "standard_weight",
+            params.standard_weight.map(|value| value.to_string()),
+        ),
+        ("near", params.near),
+        ("radius_km", params.radius_km.map(|value| value.to_string())),
+        ("bbox", params.bbox),
+        ("format", params.format),
+    ];
+    for (name, value) in filters.iter() {
+        if let Some(ref value) = *value {
+            link = format!("{}{}={}&", link, name, Uri::percent_encode(value));
+        }
     }
 
     get_response_paging_info(params.limit, params.offset, link, total_count)
 }
 
-fn to_ts_string(search: &str) -> String {
-    let mut result = String::from(search.trim());
-    if result == *"" {
-        return String::from("");
+/// Parses the expansions of a factory. `expand=true` predates expanding by relation and is
+/// still accepted as inlining the factory's certificates, which are not a relation of
+/// organizations, so it is returned separately from the relations to expand.
+fn parse_expansions(expand: &Option<String>) -> Result<(bool, Expansions), ApiError> {
+    match expand.as_ref().map(String::as_str) {
+        Some("true") => Ok((true, Expansions::parse(Resource::Organization, &None)?)),
+        Some("false") => Ok((false, Expansions::parse(Resource::Organization, &None)?)),
+        _ => Ok((false, Expansions::parse(Resource::Organization, expand)?)),
     }
-    result = result.replace(" ", ":* ");
-    result = result.replace(" ", " & ");
-    result.push_str(":*");
-    result
+}
+
+/// Builds a `tsquery` matching every word of `search` as a prefix. Punctuation, including the
+/// `tsquery` operators, only separates words, so any search is a valid query.
+pub fn to_ts_string(search: &str) -> String {
+    search
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("{}:*", word))
+        .collect::<Vec<_>>()
+        .join(" & ")
 }
 
 #[cfg(test)]
@@ -599,7 +967,12 @@ pub mod tests {
     };
     use diesel::pg::PgConnection;
     use diesel::r2d2::{ConnectionManager, PooledConnection};
-    use route_handlers::tests::{get_connection_pool, run_test};
+    use diesel::sql_types::BigInt;
+    use geo::{factory_locations, FactoryLocation};
+    use rocket::request::{FormItems, FromForm};
+    use route_handlers::tests::{count_table_scans, get_connection_pool, run_test};
+    use std::panic::AssertUnwindSafe;
+    use test::Bencher;
 
     #[test]
     fn test_to_ts_string() {
@@ -611,6 +984,14 @@ pub mod tests {
 
         let expected3 = "something:*";
         assert_eq!(expected3, to_ts_string(&String::from("something")));
+
+        let expected4 = "O:* & Brien:* & cotton:*";
+        assert_eq!(
+            expected4,
+            to_ts_string(&String::from("O'Brien & (cotton:*"))
+        );
+
+        assert_eq!("", to_ts_string(&String::from("!&|")));
     }
 
     #[test]
@@ -1006,6 +1387,260 @@ pub mod tests {
         })
     }
 
+    #[test]
+    /// Test that the `search` param ranks a factory certified for a matching standard above
+    /// a factory with a matching address, and that each result includes its score
+    fn test_factories_list_endpoint_with_search_param_ranking() {
+        run_test(|| {
+            let conn = get_connection_pool();
+            conn.begin_test_transaction().unwrap();
+            let conn = create_ranking_test_factories(conn);
+
+            let mut search_params = FACTORY_PARAMS_BASE.clone();
+            search_params.search = Some("cotton".to_string());
+
+            let res = list_factories_params(Some(Form(search_params)), DbConn(conn)).unwrap();
+            let factories = res.get("data").unwrap().as_array().unwrap();
+
+            assert_eq!(factories.len(), 2);
+            assert_eq!(factories[0]["id"], "certified_factory_id");
+            assert_eq!(factories[1]["id"], "cotton_street_factory_id");
+            assert!(
+                factories[0]["score"].as_f64().unwrap() > factories[1]["score"].as_f64().unwrap()
+            );
+        })
+    }
+
+    #[test]
+    /// Test that raising the similarity threshold above 1 is rejected
+    fn test_factories_list_endpoint_with_invalid_similarity() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+
+            let mut search_params = FACTORY_PARAMS_BASE.clone();
+            search_params.similarity = Some(1.5);
+
+            let res = list_factories_params(Some(Form(search_params)), DbConn(conn));
+            match res {
+                Err(ApiError::BadRequest(_)) => (),
+                _ => panic!("Expected a BadRequest error"),
+            }
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/factories?near=<lat,lon>&radius_km=<km>` returns the factories
+    /// located within the radius, with their location
+    fn test_factories_near() {
+        run_test(|| {
+            let conn = setup_factory_db(true);
+            insert_test_location(&conn, 44.9778, -93.265);
+
+            let mut near_params = FACTORY_PARAMS_BASE.clone();
+            near_params.near = Some("44.98,-93.27".to_string());
+            near_params.radius_km = Some(10.0);
+
+            let res = list_factories_params(Some(Form(near_params)), DbConn(conn)).unwrap();
+            let data = res.get("data").unwrap().as_array().unwrap();
+            assert_eq!(data.len(), 1);
+            assert_eq!(data[0]["id"], "test_factory_id");
+            assert_eq!(data[0]["location"]["source"], "supplied");
+        })
+    }
+
+    #[test]
+    /// Test that following the `next` link of a `near=<lat,lon>&radius_km=<km>` query returns
+    /// the second page of the same results
+    fn test_factories_near_next_page() {
+        run_test(|| {
+            let conn = setup_factory_db(true);
+            insert_test_location(&conn, 44.9778, -93.265);
+            diesel::insert_into(factory_locations::table)
+                .values(FactoryLocation {
+                    organization_id: format!("{}_id", FACTORY_NAME_ASSERTION_BASE),
+                    latitude: 44.95,
+                    longitude: -93.25,
+                    source: "supplied".to_string(),
+                })
+                .execute(&conn)
+                .unwrap();
+            let conn = DbConn(conn);
+
+            let mut near_params = FACTORY_PARAMS_BASE.clone();
+            near_params.near = Some("44.98,-93.27".to_string());
+            near_params.radius_km = Some(10.0);
+            near_params.limit = Some(1);
+
+            let res = query_factories(Some(Form(near_params)), &conn, &RESPONSE_CACHE).unwrap();
+            assert_eq!(res["data"][0]["id"], "test_factory_assertion_id");
+            let next = res["paging"]["next"].as_str().unwrap().to_string();
+            assert!(next.contains("radius_km=10&"));
+
+            let query = next.splitn(2, '?').nth(1).unwrap();
+            let next_params = FactoryParams::from_form(&mut FormItems::from(query), true).unwrap();
+            let res = query_factories(Some(Form(next_params)), &conn, &RESPONSE_CACHE).unwrap();
+            let data = res["data"].as_array().unwrap();
+            assert_eq!(data.len(), 1);
+            assert_eq!(data[0]["id"], "test_factory_id");
+            assert_eq!(res["paging"]["total"], 2);
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/factories?near=<lat,lon>&radius_km=<km>` excludes factories
+    /// located outside of the radius and factories without a location
+    fn test_factories_near_excludes_distant() {
+        run_test(|| {
+            let conn = setup_factory_db(true);
+            insert_test_location(&conn, 44.9778, -93.265);
+
+            let mut near_params = FACTORY_PARAMS_BASE.clone();
+            near_params.near = Some("41.8781,-87.6298".to_string());
+            near_params.radius_km = Some(100.0);
+
+            let res = list_factories_params(Some(Form(near_params)), DbConn(conn)).unwrap();
+            assert!(res.get("data").unwrap().as_array().unwrap().is_empty());
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/factories?bbox=<box>&format=geojson` returns a GeoJSON
+    /// FeatureCollection of the factories within the box
+    fn test_factories_geojson() {
+        run_test(|| {
+            let conn = setup_factory_db(true);
+            insert_test_location(&conn, 44.9778, -93.265);
+
+            let mut bbox_params = FACTORY_PARAMS_BASE.clone();
+            bbox_params.bbox = Some("-94,44,-93,45".to_string());
+            bbox_params.format = Some("geojson".to_string());
+
+            let res = list_factories_params(Some(Form(bbox_params)), DbConn(conn)).unwrap();
+            assert_eq!(res["type"], "FeatureCollection");
+            let features = res["features"].as_array().unwrap();
+            assert_eq!(features.len(), 1);
+            assert_eq!(features[0]["id"], "test_factory_id");
+            assert_eq!(
+                features[0]["geometry"]["coordinates"],
+                json!([-93.265, 44.9778]).0
+            );
+            assert_eq!(res["paging"]["total"], 1);
+        })
+    }
+
+    #[test]
+    /// Test that a factory list is answered from the response cache, when it is enabled, until
+    /// the cache is invalidated
+    fn test_factories_list_cached() {
+        run_test(|| {
+            let conn = DbConn(setup_factory_db(false));
+            let cache = ResponseCache::new(10);
+
+            let res = query_factories(None, &conn, &cache).unwrap();
+            assert_eq!(res["data"][0]["name"], "test_factory_name");
+
+            diesel::update(
+                organizations::table.filter(organizations::organization_id.eq("test_factory_id")),
+            )
+            .set(organizations::name.eq("renamed_factory_name"))
+            .execute(&*conn)
+            .unwrap();
+            let res = query_factories(None, &conn, &cache).unwrap();
+            assert_eq!(res["data"][0]["name"], "test_factory_name");
+
+            cache.invalidate();
+            let res = query_factories(None, &conn, &cache).unwrap();
+            assert_eq!(res["data"][0]["name"], "renamed_factory_name");
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/factories?fields=id,name,address.country` responds with only
+    /// those fields, without querying the contacts and authorizations it leaves out
+    fn test_factories_list_with_fields() {
+        run_test(|| {
+            let conn = DbConn(setup_factory_db(false));
+
+            let mut fields_params = FACTORY_PARAMS_BASE.clone();
+            fields_params.fields = Some("id,name,address.country".to_string());
+
+            let mut res = json!({});
+            let scans = count_table_scans(&conn, &["contacts", "authorizations"], || {
+                res = load_factories(fields_params, 1, &conn).unwrap();
+            });
+
+            assert_eq!(scans, 0);
+            assert_eq!(
+                res["data"],
+                json!([{
+                    "id": "test_factory_id",
+                    "name": "test_factory_name",
+                    "address": { "country": "test_factory_country" },
+                }])
+                .0
+            );
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/factories?near=<lat,lon>` without a `radius_km` returns a
+    /// `BadRequest`
+    fn test_factories_near_without_radius() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+
+            let mut near_params = FACTORY_PARAMS_BASE.clone();
+            near_params.near = Some("44.98,-93.27".to_string());
+
+            match list_factories_params(Some(Form(near_params)), DbConn(conn)) {
+                Err(ApiError::BadRequest(_)) => (),
+                _ => panic!("Expected a BadRequest error"),
+            }
+        })
+    }
+
+    #[test]
+    /// Test that `expand=true` still inlines certificates, while other values of `expand` are
+    /// parsed as relations of organizations
+    fn test_parse_factory_expansions() {
+        let (expand_certificates, expansions) =
+            parse_expansions(&Some("true".to_string())).unwrap();
+        assert!(expand_certificates);
+        assert!(expansions.is_empty());
+
+        let (expand_certificates, expansions) =
+            parse_expansions(&Some("false".to_string())).unwrap();
+        assert!(!expand_certificates);
+        assert!(expansions.is_empty());
+
+        let (expand_certificates, expansions) =
+            parse_expansions(&Some("assertion".to_string())).unwrap();
+        assert!(!expand_certificates);
+        assert_eq!(expansions.to_param(), "assertion");
+
+        match parse_expansions(&Some("certificates".to_string())) {
+            Err(ApiError::BadRequest(_)) => (),
+            _ => panic!("Expected a BadRequest error"),
+        }
+    }
+
+    // helper function to locate the test factory
+    fn insert_test_location(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        latitude: f64,
+        longitude: f64,
+    ) {
+        diesel::insert_into(factory_locations::table)
+            .values(FactoryLocation {
+                organization_id: format!("{}_id", FACTORY_NAME_BASE),
+                latitude,
+                longitude,
+                source: "supplied".to_string(),
+            })
+            .execute(&**conn)
+            .unwrap();
+    }
+
     pub static FACTORY_NAME_BASE: &str = "test_factory";
     static FACTORY_NAME_ASSERTION_BASE: &str = "test_factory_assertion";
     pub static STD_NAME_BASE: &str = "test_std";
@@ -1024,6 +1659,15 @@ pub mod tests {
         offset: Some(0 as i64),
         head: Some(1 as i64),
         expand: None,
+        fields: None,
+        similarity: None,
+        name_weight: None,
+        address_weight: None,
+        standard_weight: None,
+        near: None,
+        radius_km: None,
+        bbox: None,
+        format: None,
     };
 
     fn get_list_factory_res() -> JsonValue {
@@ -1253,6 +1897,69 @@ pub mod tests {
         conn
     }
 
+    // Creates one factory certified for a cotton standard and one with "Cotton St" in its
+    // address, to test the ranking of `search` results
+    fn create_ranking_test_factories(
+        conn: PooledConnection<ConnectionManager<PgConnection>>,
+    ) -> PooledConnection<ConnectionManager<PgConnection>> {
+        for (org_id, name, street) in &[
+            ("certified_factory_id", "Riverside Mill", "1 Main St"),
+            ("cotton_street_factory_id", "Harbor Works", "12 Cotton St"),
+        ] {
+            diesel::insert_into(organizations::table)
+                .values(NewOrganization {
+                    start_block_num: 1,
+                    end_block_num: std::i64::MAX,
+                    organization_id: org_id.to_string(),
+                    name: name.to_string(),
+                    organization_type: OrganizationTypeEnum::Factory,
+                })
+                .execute(&conn)
+                .unwrap();
+            diesel::insert_into(addresses::table)
+                .values(NewAddress {
+                    start_block_num: 1,
+                    end_block_num: std::i64::MAX,
+                    organization_id: org_id.to_string(),
+                    street_line_1: street.to_string(),
+                    street_line_2: None,
+                    city: "Springfield".to_string(),
+                    state_province: None,
+                    country: "USA".to_string(),
+                    postal_code: None,
+                })
+                .execute(&conn)
+                .unwrap();
+        }
+
+        diesel::insert_into(standards::table)
+            .values(NewStandard {
+                start_block_num: 1,
+                end_block_num: std::i64::MAX,
+                standard_id: "organic_cotton_id".to_string(),
+                organization_id: "test_standards_body_id".to_string(),
+                name: "Organic Cotton Standard".to_string(),
+            })
+            .execute(&conn)
+            .unwrap();
+        diesel::insert_into(certificates::table)
+            .values(NewCertificate {
+                start_block_num: 1,
+                end_block_num: std::i64::MAX,
+                certificate_id: "organic_cotton_cert_id".to_string(),
+                certifying_body_id: "test_certifying_body_id".to_string(),
+                factory_id: "certified_factory_id".to_string(),
+                standard_id: "organic_cotton_id".to_string(),
+                standard_version: "1".to_string(),
+                valid_from: 1 as i64,
+                valid_to: 2 as i64,
+            })
+            .execute(&conn)
+            .unwrap();
+
+        conn
+    }
+
     // helper function to create and insert a test factory and assertion in the database
     fn create_test_factory_with_assertion(
         factory_name: &str,
@@ -1278,4 +1985,123 @@ pub mod tests {
             .unwrap();
         conn
     }
+
+    /// Number of factories the benchmarks filter
+    const BENCHMARK_FACTORIES: i64 = 100_000;
+
+    /// Generates `BENCHMARK_FACTORIES` factories spread over 100 cities in 20 countries, each
+    /// certified for one of 10 standards, and analyzes the tables so queries are planned as
+    /// they would be for a registry of that size
+    fn setup_benchmark_db() -> PooledConnection<ConnectionManager<PgConnection>> {
+        let conn = get_connection_pool();
+        conn.begin_test_transaction().unwrap();
+
+        for statement in &[
+            "INSERT INTO organizations
+                 (start_block_num, end_block_num, organization_id, name, organization_type)
+             SELECT 1, 9223372036854775807, 'bench_factory_' || n, 'Bench Factory ' || n,
+                    'FACTORY'
+             FROM generate_series(1, $1) AS n",
+            "INSERT INTO addresses
+                 (start_block_num, end_block_num, organization_id, street_line_1, city,
+                  state_province, country, postal_code)
+             SELECT 1, 9223372036854775807, 'bench_factory_' || n, n || ' Main Street',
+                    'City ' || n % 100, 'Province ' || n % 50, 'Country ' || n % 20,
+                    lpad((n % 10000)::TEXT, 5, '0')
+             FROM generate_series(1, $1) AS n",
+            "INSERT INTO certificates
+                 (start_block_num, end_block_num, certificate_id, certifying_body_id,
+                  factory_id, standard_id, standard_version, valid_from, valid_to)
+             SELECT 1, 9223372036854775807, 'bench_certificate_' || n, 'bench_certifying_body',
+                    'bench_factory_' || n, 'bench_standard_' || n % 10, '1', 0, 1
+             FROM generate_series(1, $1) AS n",
+        ] {
+            diesel::sql_query(*statement)
+                .bind::<BigInt, _>(BENCHMARK_FACTORIES)
+                .execute(&conn)
+                .unwrap();
+        }
+        diesel::sql_query(
+            "INSERT INTO standards
+                 (start_block_num, end_block_num, standard_id, organization_id, name)
+             SELECT 1, 9223372036854775807, 'bench_standard_' || n, 'bench_standards_body',
+                    'Bench Standard ' || n
+             FROM generate_series(0, 9) AS n",
+        )
+        .execute(&conn)
+        .unwrap();
+        diesel::sql_query("ANALYZE organizations, addresses, certificates, standards")
+            .execute(&conn)
+            .unwrap();
+
+        conn
+    }
+
+    /// Benchmarks listing the factories matching `params` out of `BENCHMARK_FACTORIES`
+    fn bench_factories(bencher: &mut Bencher, params: FactoryParams) {
+        run_test(AssertUnwindSafe(|| {
+            let conn = DbConn(setup_benchmark_db());
+            bencher.iter(|| load_factories(params.clone(), 1, &conn).unwrap());
+        }))
+    }
+
+    #[bench]
+    fn bench_factories_by_city(bencher: &mut Bencher) {
+        bench_factories(
+            bencher,
+            FactoryParams {
+                city: Some("City 42".to_string()),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[bench]
+    fn bench_factories_by_countries(bencher: &mut Bencher) {
+        bench_factories(
+            bencher,
+            FactoryParams {
+                country: Some("Country 3,Country 7".to_string()),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[bench]
+    fn bench_factories_by_certificate(bencher: &mut Bencher) {
+        bench_factories(
+            bencher,
+            FactoryParams {
+                certificate: Some("Bench Standard 4".to_string()),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[bench]
+    fn bench_factories_by_search(bencher: &mut Bencher) {
+        bench_factories(
+            bencher,
+            FactoryParams {
+                search: Some("Factory 42".to_string()),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[bench]
+    fn bench_factories_by_every_filter(bencher: &mut Bencher) {
+        bench_factories(
+            bencher,
+            FactoryParams {
+                street: Some("42 Main Street".to_string()),
+                city: Some("City 42".to_string()),
+                state_province: Some("Province 42".to_string()),
+                country: Some("Country 2".to_string()),
+                postal_code: Some("00042".to_string()),
+                certificate: Some("Bench Standard 2".to_string()),
+                ..Default::default()
+            },
+        );
+    }
 }
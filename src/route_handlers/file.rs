@@ -1,42 +1,75 @@
 use chrono::{TimeZone, Utc};
 use csv;
 use database::DbConn;
-use database_manager::custom_types::OrganizationTypeEnum;
-use database_manager::models::{Address, Certificate, Organization, ADDRESS_COLUMNS};
-use database_manager::tables_schema::{addresses, certificates, organizations, standards};
+use database_manager::custom_types::{OrganizationTypeEnum, RequestStatusEnum};
+use database_manager::models::{
+    Address, Certificate, Organization, Request, Standard, StandardVersion, ADDRESS_COLUMNS,
+};
+use database_manager::tables_schema::{
+    addresses, certificates, organizations, requests, standard_versions, standards,
+};
+use diesel::pg::Pg;
 use diesel::prelude::*;
+use diesel::sql_types::Text;
 use errors::ApiError;
-use paging::*;
-use rocket::response::NamedFile;
+use geo::{feature_collection, load_locations, ApiLocation};
+use paging::get_head_block_num;
+use rocket::http::ContentType;
+use rocket::request::Form;
+use rocket::response::content::Content;
+use rocket::response::Stream;
+use route_handlers::factories::{FactoryFilter, FactoryParams};
 use route_handlers::prom::increment_http_req;
 use serde::Serialize;
+use serde_json;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use xlsx;
+use xml::escape_xml;
 
-const FILENAME: &str = "consensource_factories.csv";
+/// Number of factories loaded by each query while streaming a CSV export
+const CSV_BATCH_SIZE: i64 = 500;
+
+/// The columns of a factory CSV export, in the order they are exported by default
+const ROW_COLUMNS: [&str; 11] = [
+    "name",
+    "organization_id",
+    "country",
+    "street_line_1",
+    "street_line_2",
+    "city",
+    "state",
+    "postal_code",
+    "certificate_standard_name",
+    "valid_from",
+    "valid_to",
+];
+
+/// A factory joined with its address, one of its certificates and the certificate's standard name
+type FactoryRow = (
+    Organization,
+    Option<Address>,
+    Option<Certificate>,
+    Option<String>,
+);
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Row {
-    name: String,
-    organization_id: String,
-    country: String,
-    street_line_1: String,
-    street_line_2: String,
-    city: String,
-    state: String,
-    postal_code: String,
-    certificate_standard_name: Option<String>,
-    valid_from: Option<String>,
-    valid_to: Option<String>,
+pub struct Row {
+    pub name: String,
+    pub organization_id: String,
+    pub country: String,
+    pub street_line_1: String,
+    pub street_line_2: String,
+    pub city: String,
+    pub state: String,
+    pub postal_code: String,
+    pub certificate_standard_name: Option<String>,
+    pub valid_from: Option<String>,
+    pub valid_to: Option<String>,
 }
 
 impl Row {
-    pub fn from(
-        db_object: &(
-            Organization,
-            Option<Address>,
-            Option<Certificate>,
-            Option<String>,
-        ),
-    ) -> Self {
+    pub fn from(db_object: &FactoryRow) -> Self {
         let db_org = db_object.0.clone();
         let db_address = db_object.1.clone().unwrap_or_default();
         let db_cert = db_object.2.as_ref();
@@ -51,32 +84,662 @@ impl Row {
             state: db_address.state_province.unwrap_or_default(),
             postal_code: db_address.postal_code.unwrap_or_default(),
             certificate_standard_name: db_standard_name,
-            valid_from: db_cert.map(|cert| {
-                Utc.timestamp_opt(cert.valid_from, 0)
-                    .earliest()
-                    .map(|d| d.to_string())
-                    .unwrap_or("".to_string())
-            }),
-            valid_to: db_cert.map(|cert| {
-                Utc.timestamp_opt(cert.valid_to, 0)
-                    .latest()
-                    .map(|d| d.to_string())
-                    .unwrap_or("".to_string())
-            }),
+            valid_from: db_cert.map(|cert| format_timestamp(cert.valid_from)),
+            valid_to: db_cert.map(|cert| format_timestamp(cert.valid_to)),
+        }
+    }
+
+    fn value(&self, column: &str) -> &str {
+        match column {
+            "name" => &self.name,
+            "organization_id" => &self.organization_id,
+            "country" => &self.country,
+            "street_line_1" => &self.street_line_1,
+            "street_line_2" => &self.street_line_2,
+            "city" => &self.city,
+            "state" => &self.state,
+            "postal_code" => &self.postal_code,
+            "certificate_standard_name" => self
+                .certificate_standard_name
+                .as_ref()
+                .map_or("", String::as_str),
+            "valid_from" => self.valid_from.as_ref().map_or("", String::as_str),
+            "valid_to" => self.valid_to.as_ref().map_or("", String::as_str),
+            _ => "",
+        }
+    }
+}
+
+/// Parses a comma separated list of columns, defaulting to every column
+fn parse_columns(columns: Option<&str>) -> Result<Vec<&'static str>, ApiError> {
+    match columns {
+        Some(columns) => columns
+            .split(',')
+            .map(|column| {
+                ROW_COLUMNS
+                    .iter()
+                    .find(|known| **known == column.trim())
+                    .cloned()
+                    .ok_or_else(|| {
+                        ApiError::BadRequest(format!(
+                            "Invalid column {}, expected any of {}",
+                            column,
+                            ROW_COLUMNS.join(", ")
+                        ))
+                    })
+            })
+            .collect(),
+        None => Ok(ROW_COLUMNS.to_vec()),
+    }
+}
+
+/// Streams the rows of a factory CSV export, loading the factories from the database in
+/// batches as the response is sent, so the whole export is never held in memory.
+pub struct FactoryCsvStream {
+    conn: DbConn,
+    filter: FactoryFilter,
+    columns: Vec<&'static str>,
+    batch_size: i64,
+    /// Organization id of the last factory written, the next batch starts after it
+    last_factory_id: Option<String>,
+    finished: bool,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl FactoryCsvStream {
+    /// Writes the header and the first batch, so errors are returned before the response starts
+    fn new(
+        conn: DbConn,
+        filter: FactoryFilter,
+        columns: Vec<&'static str>,
+        batch_size: i64,
+    ) -> Result<Self, ApiError> {
+        let mut stream = FactoryCsvStream {
+            conn,
+            filter,
+            columns,
+            batch_size,
+            last_factory_id: None,
+            finished: false,
+            buffer: vec![],
+            position: 0,
+        };
+        {
+            let mut wtr = csv::Writer::from_writer(&mut stream.buffer);
+            wtr.write_record(&stream.columns)
+                .map_err(|err| ApiError::InternalError(err.to_string()))?;
+        }
+        stream.write_next_batch()?;
+        Ok(stream)
+    }
+
+    fn write_next_batch(&mut self) -> Result<(), ApiError> {
+        let mut ids_query = self
+            .filter
+            .query()
+            .select(organizations::organization_id)
+            .order_by(organizations::organization_id.asc())
+            .limit(self.batch_size);
+        if let Some(ref last_factory_id) = self.last_factory_id {
+            ids_query =
+                ids_query.filter(organizations::organization_id.gt(last_factory_id.clone()));
+        }
+        let rows = query_factory_rows(&self.conn, self.filter.head_block_num, ids_query)?;
+
+        // Factories have a row per certificate, and the rows are ordered by organization id
+        let mut factory_count = 0;
+        for row in &rows {
+            if self.last_factory_id.as_ref() != Some(&row.0.organization_id) {
+                factory_count += 1;
+                self.last_factory_id = Some(row.0.organization_id.clone());
+            }
+        }
+        self.finished = factory_count < self.batch_size;
+
+        let mut wtr = csv::Writer::from_writer(&mut self.buffer);
+        for row in rows.iter().map(Row::from) {
+            wtr.write_record(self.columns.iter().map(|column| row.value(column)))
+                .map_err(|err| ApiError::InternalError(err.to_string()))?;
+        }
+        wtr.flush()
+            .map_err(|err| ApiError::InternalError(err.to_string()))
+    }
+}
+
+impl Read for FactoryCsvStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.buffer.clear();
+            self.position = 0;
+            self.write_next_batch()
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{:?}", err)))?;
+        }
+        let read = (&self.buffer[self.position..]).read(buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// A factory with all of its certificates, as exported to GIS formats
+#[derive(Debug, Serialize)]
+struct FactoryFeature {
+    id: String,
+    name: String,
+    street_line_1: String,
+    street_line_2: String,
+    city: String,
+    state: String,
+    country: String,
+    postal_code: String,
+    certificates: Vec<CertificateProperties>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<ApiLocation>,
+}
+
+#[derive(Debug, Serialize)]
+struct CertificateProperties {
+    certificate_id: String,
+    standard_name: Option<String>,
+    valid_from: String,
+    valid_to: String,
+}
+
+impl FactoryFeature {
+    /// Groups rows ordered by organization id into one feature per factory
+    fn from_rows(rows: Vec<FactoryRow>) -> Vec<Self> {
+        let mut features: Vec<FactoryFeature> = vec![];
+        for (organization, address, certificate, standard_name) in rows {
+            let is_new_factory = features
+                .last()
+                .map_or(true, |feature| feature.id != organization.organization_id);
+            if is_new_factory {
+                let address = address.unwrap_or_default();
+                features.push(FactoryFeature {
+                    id: organization.organization_id,
+                    name: organization.name,
+                    street_line_1: address.street_line_1,
+                    street_line_2: address.street_line_2.unwrap_or_default(),
+                    city: address.city,
+                    state: address.state_province.unwrap_or_default(),
+                    country: address.country,
+                    postal_code: address.postal_code.unwrap_or_default(),
+                    certificates: vec![],
+                    location: None,
+                });
+            }
+            if let (Some(certificate), Some(feature)) = (certificate, features.last_mut()) {
+                feature.certificates.push(CertificateProperties {
+                    certificate_id: certificate.certificate_id,
+                    standard_name,
+                    valid_from: format_timestamp(certificate.valid_from),
+                    valid_to: format_timestamp(certificate.valid_to),
+                });
+            }
+        }
+        features
+    }
+
+    /// The address on a single line, skipping the parts that are missing
+    fn full_address(&self) -> String {
+        [
+            &self.street_line_1,
+            &self.street_line_2,
+            &self.city,
+            &self.state,
+            &self.postal_code,
+            &self.country,
+        ]
+        .iter()
+        .filter(|part| !part.is_empty())
+        .map(|part| part.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0)
+        .earliest()
+        .map(|d| d.to_string())
+        .unwrap_or_default()
+}
+
+#[derive(Serialize)]
+struct CertificateRow {
+    certificate_id: String,
+    factory_id: String,
+    factory_name: String,
+    certifying_body_id: String,
+    certifying_body_name: String,
+    standard_id: String,
+    standard_name: String,
+    standard_version: String,
+    valid_from: String,
+    valid_to: String,
+}
+
+impl CertificateRow {
+    fn from(
+        certificate: Certificate,
+        organization_names: &HashMap<String, String>,
+        standard_names: &HashMap<String, String>,
+    ) -> Self {
+        let name_of =
+            |names: &HashMap<String, String>, id: &str| names.get(id).cloned().unwrap_or_default();
+        CertificateRow {
+            factory_name: name_of(organization_names, &certificate.factory_id),
+            certifying_body_name: name_of(organization_names, &certificate.certifying_body_id),
+            standard_name: name_of(standard_names, &certificate.standard_id),
+            certificate_id: certificate.certificate_id,
+            factory_id: certificate.factory_id,
+            certifying_body_id: certificate.certifying_body_id,
+            standard_id: certificate.standard_id,
+            standard_version: certificate.standard_version,
+            valid_from: format_timestamp(certificate.valid_from),
+            valid_to: format_timestamp(certificate.valid_to),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RequestRow {
+    request_id: String,
+    factory_id: String,
+    factory_name: String,
+    standard_id: String,
+    standard_name: String,
+    status: RequestStatusEnum,
+    request_date: String,
+}
+
+impl RequestRow {
+    fn from(
+        request: Request,
+        organization_names: &HashMap<String, String>,
+        standard_names: &HashMap<String, String>,
+    ) -> Self {
+        RequestRow {
+            factory_name: organization_names
+                .get(&request.factory_id)
+                .cloned()
+                .unwrap_or_default(),
+            standard_name: standard_names
+                .get(&request.standard_id)
+                .cloned()
+                .unwrap_or_default(),
+            request_id: request.request_id,
+            factory_id: request.factory_id,
+            standard_id: request.standard_id,
+            status: request.status,
+            request_date: format_timestamp(request.request_date),
+        }
+    }
+}
+
+/// One row per version of a standard, or a single row without a version if it has none
+#[derive(Serialize)]
+struct StandardRow {
+    standard_id: String,
+    name: String,
+    organization_id: String,
+    version: String,
+    description: String,
+    external_link: String,
+    approval_date: String,
+}
+
+impl StandardRow {
+    fn from(standard: &Standard, version: Option<&StandardVersion>) -> Self {
+        StandardRow {
+            standard_id: standard.standard_id.clone(),
+            name: standard.name.clone(),
+            organization_id: standard.organization_id.clone(),
+            version: version.map(|v| v.version.clone()).unwrap_or_default(),
+            description: version.map(|v| v.description.clone()).unwrap_or_default(),
+            external_link: version.map(|v| v.link.clone()).unwrap_or_default(),
+            approval_date: version
+                .map(|v| format_timestamp(v.approval_date))
+                .unwrap_or_default(),
         }
     }
 }
 
 #[get("/csv/factories.csv")]
-pub fn get_factories(conn: DbConn) -> Result<NamedFile, ApiError> {
+pub fn get_factories(conn: DbConn) -> Result<Content<Stream<FactoryCsvStream>>, ApiError> {
+    get_factories_with_params(None, None, conn)
+}
+
+/// Streams the factories matching the filters of `/api/factories` at `head` as CSV, one row
+/// per certificate. Paging params are ignored, so every matching factory is exported.
+///
+/// `columns` selects and orders the exported columns, e.g. `columns=name,country`.
+#[get("/csv/factories.csv?<columns>&<params..>")]
+pub fn get_factories_with_params(
+    columns: Option<String>,
+    params: Option<Form<FactoryParams>>,
+    conn: DbConn,
+) -> Result<Content<Stream<FactoryCsvStream>>, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let params = match params {
+        Some(param) => param.into_inner(),
+        None => Default::default(),
+    };
+    let columns = parse_columns(columns.as_ref().map(String::as_str))?;
+    let head_block_num = get_head_block_num(params.head, &conn)?;
+    let filter = FactoryFilter::from_params(&params, head_block_num)?;
+    let stream = FactoryCsvStream::new(conn, filter, columns, CSV_BATCH_SIZE)?;
+
+    Ok(Content(ContentType::CSV, Stream::from(stream)))
+}
+
+#[get("/geojson/factories.geojson")]
+pub fn get_factories_geojson(conn: DbConn) -> Result<Content<String>, ApiError> {
+    get_factories_geojson_with_params(None, conn)
+}
+
+/// Exports the factories matching the filters of `/api/factories` as a GeoJSON
+/// FeatureCollection. Paging params are ignored, so every matching factory is exported.
+#[get("/geojson/factories.geojson?<params..>")]
+pub fn get_factories_geojson_with_params(
+    params: Option<Form<FactoryParams>>,
+    conn: DbConn,
+) -> Result<Content<String>, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let features = query_factory_features(params, &conn)?
+        .iter()
+        .map(serde_json::to_value)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| ApiError::InternalError(err.to_string()))?;
+
+    Ok(Content(
+        ContentType::new("application", "geo+json"),
+        feature_collection(features).to_string(),
+    ))
+}
+
+#[get("/kml/factories.kml")]
+pub fn get_factories_kml(conn: DbConn) -> Result<Content<String>, ApiError> {
+    get_factories_kml_with_params(None, conn)
+}
+
+/// Exports the factories matching the filters of `/api/factories` as KML placemarks.
+/// Paging params are ignored, so every matching factory is exported.
+#[get("/kml/factories.kml?<params..>")]
+pub fn get_factories_kml_with_params(
+    params: Option<Form<FactoryParams>>,
+    conn: DbConn,
+) -> Result<Content<String>, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let features = query_factory_features(params, &conn)?;
+
+    Ok(Content(
+        ContentType::new("application", "vnd.google-earth.kml+xml"),
+        write_factories_kml(&features),
+    ))
+}
+
+#[get("/csv/certificates.csv?<head>")]
+pub fn get_certificates_csv(head: Option<i64>, conn: DbConn) -> Result<Content<Vec<u8>>, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let head_block_num = get_head_block_num(head, &conn)?;
+    let rows = query_certificate_rows(&conn, head_block_num)?;
+    Ok(Content(ContentType::CSV, write_csv(&rows)?))
+}
+
+#[get("/xlsx/certificates.xlsx?<head>")]
+pub fn get_certificates_xlsx(
+    head: Option<i64>,
+    conn: DbConn,
+) -> Result<Content<Vec<u8>>, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let head_block_num = get_head_block_num(head, &conn)?;
+    let rows = query_certificate_rows(&conn, head_block_num)?;
+    Ok(Content(
+        xlsx_content_type(),
+        write_xlsx("certificates", &rows)?,
+    ))
+}
+
+#[get("/csv/requests.csv?<head>")]
+pub fn get_requests_csv(head: Option<i64>, conn: DbConn) -> Result<Content<Vec<u8>>, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let head_block_num = get_head_block_num(head, &conn)?;
+    let rows = query_request_rows(&conn, head_block_num)?;
+    Ok(Content(ContentType::CSV, write_csv(&rows)?))
+}
+
+#[get("/xlsx/requests.xlsx?<head>")]
+pub fn get_requests_xlsx(head: Option<i64>, conn: DbConn) -> Result<Content<Vec<u8>>, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let head_block_num = get_head_block_num(head, &conn)?;
+    let rows = query_request_rows(&conn, head_block_num)?;
+    Ok(Content(xlsx_content_type(), write_xlsx("requests", &rows)?))
+}
+
+#[get("/csv/standards.csv?<head>")]
+pub fn get_standards_csv(head: Option<i64>, conn: DbConn) -> Result<Content<Vec<u8>>, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let head_block_num = get_head_block_num(head, &conn)?;
+    let rows = query_standard_rows(&conn, head_block_num)?;
+    Ok(Content(ContentType::CSV, write_csv(&rows)?))
+}
+
+#[get("/xlsx/standards.xlsx?<head>")]
+pub fn get_standards_xlsx(head: Option<i64>, conn: DbConn) -> Result<Content<Vec<u8>>, ApiError> {
     // Increment HTTP request count for Prometheus metrics
     increment_http_req();
-    let head_block_num: i64 = get_head_block_num(None, &conn)?;
 
+    let head_block_num = get_head_block_num(head, &conn)?;
+    let rows = query_standard_rows(&conn, head_block_num)?;
+    Ok(Content(
+        xlsx_content_type(),
+        write_xlsx("standards", &rows)?,
+    ))
+}
+
+fn xlsx_content_type() -> ContentType {
+    ContentType::new(
+        "application",
+        "vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    )
+}
+
+/// Loads the names of the organizations and standards live at `head_block_num`, keyed by id
+fn query_names(
+    conn: &DbConn,
+    head_block_num: i64,
+    organization_ids: Vec<String>,
+    standard_ids: Vec<String>,
+) -> Result<(HashMap<String, String>, HashMap<String, String>), ApiError> {
+    let organization_names = organizations::table
+        .select((organizations::organization_id, organizations::name))
+        .filter(organizations::start_block_num.le(head_block_num))
+        .filter(organizations::end_block_num.gt(head_block_num))
+        .filter(organizations::organization_id.eq_any(organization_ids))
+        .load::<(String, String)>(&**conn)?
+        .into_iter()
+        .collect();
+    let standard_names = standards::table
+        .select((standards::standard_id, standards::name))
+        .filter(standards::start_block_num.le(head_block_num))
+        .filter(standards::end_block_num.gt(head_block_num))
+        .filter(standards::standard_id.eq_any(standard_ids))
+        .load::<(String, String)>(&**conn)?
+        .into_iter()
+        .collect();
+
+    Ok((organization_names, standard_names))
+}
+
+fn query_certificate_rows(
+    conn: &DbConn,
+    head_block_num: i64,
+) -> Result<Vec<CertificateRow>, ApiError> {
+    let certificates = certificates::table
+        .filter(certificates::start_block_num.le(head_block_num))
+        .filter(certificates::end_block_num.gt(head_block_num))
+        .order_by(certificates::certificate_id.asc())
+        .load::<Certificate>(&**conn)?;
+
+    let organization_ids = certificates
+        .iter()
+        .flat_map(|cert| vec![cert.factory_id.clone(), cert.certifying_body_id.clone()])
+        .collect();
+    let standard_ids = certificates
+        .iter()
+        .map(|cert| cert.standard_id.clone())
+        .collect();
+    let (organization_names, standard_names) =
+        query_names(conn, head_block_num, organization_ids, standard_ids)?;
+
+    Ok(certificates
+        .into_iter()
+        .map(|cert| CertificateRow::from(cert, &organization_names, &standard_names))
+        .collect())
+}
+
+fn query_request_rows(conn: &DbConn, head_block_num: i64) -> Result<Vec<RequestRow>, ApiError> {
+    let requests = requests::table
+        .filter(requests::start_block_num.le(head_block_num))
+        .filter(requests::end_block_num.gt(head_block_num))
+        .order_by(requests::request_id.asc())
+        .load::<Request>(&**conn)?;
+
+    let organization_ids = requests.iter().map(|req| req.factory_id.clone()).collect();
+    let standard_ids = requests.iter().map(|req| req.standard_id.clone()).collect();
+    let (organization_names, standard_names) =
+        query_names(conn, head_block_num, organization_ids, standard_ids)?;
+
+    Ok(requests
+        .into_iter()
+        .map(|req| RequestRow::from(req, &organization_names, &standard_names))
+        .collect())
+}
+
+fn query_standard_rows(conn: &DbConn, head_block_num: i64) -> Result<Vec<StandardRow>, ApiError> {
+    let standards = standards::table
+        .filter(standards::start_block_num.le(head_block_num))
+        .filter(standards::end_block_num.gt(head_block_num))
+        .order_by(standards::standard_id.asc())
+        .load::<Standard>(&**conn)?;
+
+    let mut versions: HashMap<String, Vec<StandardVersion>> = standard_versions::table
+        .filter(standard_versions::start_block_num.le(head_block_num))
+        .filter(standard_versions::end_block_num.gt(head_block_num))
+        .filter(
+            standard_versions::standard_id.eq_any(
+                standards
+                    .iter()
+                    .map(|standard| standard.standard_id.clone())
+                    .collect::<Vec<String>>(),
+            ),
+        )
+        .order_by(standard_versions::approval_date.asc())
+        .load::<StandardVersion>(&**conn)?
+        .into_iter()
+        .fold(HashMap::new(), |mut acc, version| {
+            acc.entry(version.standard_id.clone())
+                .or_insert_with(Vec::new)
+                .push(version);
+            acc
+        });
+
+    Ok(standards
+        .iter()
+        .flat_map(|standard| match versions.remove(&standard.standard_id) {
+            Some(versions) => versions
+                .iter()
+                .map(|version| StandardRow::from(standard, Some(version)))
+                .collect(),
+            None => vec![StandardRow::from(standard, None)],
+        })
+        .collect())
+}
+
+/// Writes `rows` as CSV with a header row
+fn write_csv<T: Serialize>(rows: &[T]) -> Result<Vec<u8>, ApiError> {
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    rows.iter()
+        .try_for_each(|row| wtr.serialize(row))
+        .map_err(|err| ApiError::InternalError(err.to_string()))?;
+    wtr.into_inner()
+        .map_err(|err| ApiError::InternalError(err.to_string()))
+}
+
+/// Writes `rows` to a workbook. Rows are serialized through the CSV writer, so the columns of
+/// an XLSX export are always the same as those of the CSV export.
+fn write_xlsx<T: Serialize>(sheet_name: &str, rows: &[T]) -> Result<Vec<u8>, ApiError> {
+    let csv = write_csv(rows)?;
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(&csv[..]);
+    let records = rdr
+        .records()
+        .map(|record| record.map(|record| record.iter().map(String::from).collect()))
+        .collect::<Result<Vec<Vec<String>>, _>>()
+        .map_err(|err| ApiError::InternalError(err.to_string()))?;
+
+    xlsx::write_workbook(sheet_name, &records)
+}
+
+/// Loads the factories matching `params` with their certificates and locations
+fn query_factory_features(
+    params: Option<Form<FactoryParams>>,
+    conn: &DbConn,
+) -> Result<Vec<FactoryFeature>, ApiError> {
+    let params = match params {
+        Some(param) => param.into_inner(),
+        None => Default::default(),
+    };
+    let head_block_num = get_head_block_num(params.head, conn)?;
+    let filter = FactoryFilter::from_params(&params, head_block_num)?;
+
+    let mut features = FactoryFeature::from_rows(query_factory_rows(
+        conn,
+        filter.head_block_num,
+        filter.query().select(organizations::organization_id),
+    )?);
+    let factory_ids: Vec<String> = features.iter().map(|feature| feature.id.clone()).collect();
+    let mut locations = load_locations(conn, &factory_ids)?;
+    for feature in &mut features {
+        feature.location = locations.remove(&feature.id).map(ApiLocation::from);
+    }
+
+    Ok(features)
+}
+
+/// Loads the factories whose ids `factory_ids` selects, joined with their address and
+/// certificates and ordered by organization id
+fn query_factory_rows(
+    conn: &DbConn,
+    head_block_num: i64,
+    factory_ids: organizations::BoxedQuery<'static, Pg, Text>,
+) -> Result<Vec<FactoryRow>, ApiError> {
     let factories_query = organizations::table
         .filter(organizations::start_block_num.le(head_block_num))
         .filter(organizations::end_block_num.gt(head_block_num))
         .filter(organizations::organization_type.eq(OrganizationTypeEnum::Factory))
+        .filter(organizations::organization_id.eq_any(factory_ids))
         .left_join(
             addresses::table.on(addresses::organization_id
                 .eq(organizations::organization_id)
@@ -95,131 +758,269 @@ pub fn get_factories(conn: DbConn) -> Result<NamedFile, ApiError> {
                 .and(standards::start_block_num.le(head_block_num))
                 .and(standards::end_block_num.gt(head_block_num))),
         )
+        .order_by(organizations::organization_id.asc())
         .into_boxed();
 
-    let factories = factories_query
+    Ok(factories_query
         .select((
             organizations::table::all_columns(),
             ADDRESS_COLUMNS.nullable(),
             certificates::table::all_columns().nullable(),
             standards::name.nullable(),
         ))
-        .load::<(
-            Organization,
-            Option<Address>,
-            Option<Certificate>,
-            Option<String>,
-        )>(&*conn)?;
-
-    write_factories_csv(factories)?;
-
-    let file = NamedFile::open(FILENAME).map_err(|err| ApiError::InternalError(err.to_string()));
-
-    file
+        .load::<FactoryRow>(&**conn)?)
 }
 
-fn write_factories_csv(
-    factories: Vec<(
-        Organization,
-        Option<Address>,
-        Option<Certificate>,
-        Option<String>,
-    )>,
-) -> Result<(), ApiError> {
-    let mut wtr =
-        csv::Writer::from_path(FILENAME).map_err(|err| ApiError::InternalError(err.to_string()))?;
+/// Writes one placemark per factory. Factories that have not been located have no point, but
+/// keep their `address` so GIS tools can geocode them.
+fn write_factories_kml(features: &[FactoryFeature]) -> String {
+    let mut kml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <kml xmlns=\"http://www.opengis.net/kml/2.2\">\n\
+         <Document>\n\
+         <name>ConsenSource factories</name>\n",
+    );
 
-    factories
-        .iter()
-        .try_for_each(|factory| wtr.serialize(Row::from(factory)))
-        .map_err(|err| ApiError::InternalError(err.to_string()))?;
+    for feature in features {
+        let certificates = feature
+            .certificates
+            .iter()
+            .map(|cert| {
+                format!(
+                    "{} ({} to {})",
+                    cert.standard_name.as_ref().unwrap_or(&cert.certificate_id),
+                    cert.valid_from,
+                    cert.valid_to
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        let data = [
+            ("organization_id", &feature.id),
+            ("street_line_1", &feature.street_line_1),
+            ("street_line_2", &feature.street_line_2),
+            ("city", &feature.city),
+            ("state", &feature.state),
+            ("country", &feature.country),
+            ("postal_code", &feature.postal_code),
+            ("certificates", &certificates),
+        ];
 
-    wtr.flush()
-        .map_err(|err| ApiError::InternalError(err.to_string()))
+        kml.push_str(&format!(
+            "<Placemark id=\"{}\">\n<name>{}</name>\n<address>{}</address>\n<ExtendedData>\n",
+            escape_xml(&feature.id),
+            escape_xml(&feature.name),
+            escape_xml(&feature.full_address())
+        ));
+        for (name, value) in data.iter() {
+            kml.push_str(&format!(
+                "<Data name=\"{}\"><value>{}</value></Data>\n",
+                name,
+                escape_xml(value)
+            ));
+        }
+        kml.push_str("</ExtendedData>\n");
+        if let Some(ref location) = feature.location {
+            kml.push_str(&format!(
+                "<Point><coordinates>{},{}</coordinates></Point>\n",
+                location.longitude, location.latitude
+            ));
+        }
+        kml.push_str("</Placemark>\n");
+    }
+
+    kml.push_str("</Document>\n</kml>\n");
+    kml
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rocket::request::{FormItems, FromForm};
     use route_handlers::factories::tests::{setup_factory_db, FACTORY_NAME_BASE, STD_NAME_BASE};
     use route_handlers::tests::run_test;
-    use std::path::Path;
 
     #[test]
-    /// Test that write_factories_csv handles an empty vec
-    fn test_write_factory_csv_empty() {
-        let res = write_factories_csv(vec![]);
-        assert!(res.is_ok());
-        let factories = read_csv(FILENAME);
-        assert_eq!(factories.len(), 0);
-    }
-
-    #[test]
-    /// Test that write_factories_csv handles one factory
-    fn test_write_factory_csv_one_factory() {
-        let res = write_factories_csv(vec![(
+    /// Test that a row without a certificate exports empty certificate columns
+    fn test_row_values() {
+        let row = Row::from(&(
             get_factory(FACTORY_NAME_BASE),
             Some(get_address(FACTORY_NAME_BASE)),
             None,
             None,
-        )]);
-        assert!(res.is_ok());
-        let factories = read_csv(FILENAME);
-        assert_eq!(factories.len(), 1);
-        assert_eq!(
-            factories.get(0).unwrap().name,
-            String::from(format!("{}_name", FACTORY_NAME_BASE))
-        );
+        ));
+        assert_eq!(row.value("name"), format!("{}_name", FACTORY_NAME_BASE));
+        assert_eq!(row.value("street_line_2"), "");
+        assert_eq!(row.value("certificate_standard_name"), "");
     }
 
     #[test]
-    /// Test that write_factories_csv handles one factory
-    fn test_write_factory_csv_one_factory_with_cert() {
-        let res = write_factories_csv(vec![(
+    /// Test that a row with a certificate exports its standard name
+    fn test_row_values_with_cert() {
+        let row = Row::from(&(
             get_factory(FACTORY_NAME_BASE),
             Some(get_address(FACTORY_NAME_BASE)),
             Some(get_cert()),
             Some(get_standard_name(STD_NAME_BASE)),
-        )]);
-        assert!(res.is_ok());
-        let factories = read_csv(FILENAME);
-        assert_eq!(factories.len(), 1);
+        ));
         assert_eq!(
-            factories.get(0).unwrap().name,
-            String::from(format!("{}_name", FACTORY_NAME_BASE))
+            row.value("certificate_standard_name"),
+            get_standard_name(STD_NAME_BASE)
         );
+        assert_eq!(row.value("valid_from"), "1970-01-01 00:00:01 UTC");
+    }
+
+    #[test]
+    /// Test that columns are selected in the given order and validated
+    fn test_parse_columns() {
+        assert_eq!(parse_columns(None).unwrap(), ROW_COLUMNS.to_vec());
         assert_eq!(
-            factories
-                .get(0)
-                .unwrap()
-                .certificate_standard_name
-                .clone()
-                .unwrap(),
-            String::from(format!("{}_name", STD_NAME_BASE))
+            parse_columns(Some("country, name")).unwrap(),
+            vec!["country", "name"]
         );
+        match parse_columns(Some("name,password")) {
+            Err(ApiError::BadRequest(_)) => (),
+            _ => panic!("Expected a BadRequest error"),
+        }
+    }
+
+    #[test]
+    /// Test that the CSV stream exports every factory when it loads them in several batches
+    fn test_factory_csv_stream() {
+        run_test(|| {
+            let conn = DbConn(setup_factory_db(true));
+            let filter = FactoryFilter::from_params(&FactoryParams::default(), 1).unwrap();
+            let stream = FactoryCsvStream::new(conn, filter, ROW_COLUMNS.to_vec(), 1).unwrap();
+
+            let factories = read_csv(stream);
+            assert_eq!(factories.len(), 2);
+            assert_eq!(factories[0].organization_id, "test_factory_assertion_id");
+            assert_eq!(factories[1].organization_id, "test_factory_id");
+            assert_eq!(
+                factories[1].certificate_standard_name,
+                Some(get_standard_name(STD_NAME_BASE))
+            );
+        })
     }
 
     #[test]
-    /// Test that a GET to `/api/csv/factories.csv` returns an `Ok` response
-    /// with a factory
-    fn test_get_factory_as_csv_endpoint() {
+    /// Test that a GET to `/api/csv/factories.csv?columns=name,city&name=<name>` only exports
+    /// the selected columns of the matching factories
+    fn test_factory_csv_stream_filtered() {
         run_test(|| {
-            let conn = setup_factory_db(false);
-            let response = get_factories(DbConn(conn));
-            assert!(response.is_ok());
-            let file = response.unwrap();
-            let file_path = file.path();
-            let factories = read_csv(file_path);
-            assert_eq!(factories.len(), 1);
+            let conn = DbConn(setup_factory_db(true));
+            let mut items = FormItems::from("name=test_factory_name");
+            let params = FactoryParams::from_form(&mut items, true).unwrap();
+            let filter = FactoryFilter::from_params(&params, 1).unwrap();
+            let columns = parse_columns(Some("name,city")).unwrap();
+            let mut stream = FactoryCsvStream::new(conn, filter, columns, CSV_BATCH_SIZE).unwrap();
+
+            let mut csv = String::new();
+            stream.read_to_string(&mut csv).unwrap();
+            assert_eq!(csv, "name,city\ntest_factory_name,test_factory_city\n");
+        })
+    }
+
+    #[test]
+    /// Test that the certificate export includes the names of the organizations and standard
+    fn test_query_certificate_rows() {
+        run_test(|| {
+            let conn = DbConn(setup_factory_db(false));
+            let rows = query_certificate_rows(&conn, 1).unwrap();
+            assert_eq!(rows.len(), 1);
+            assert_eq!(rows[0].factory_name, "test_factory_name");
+            assert_eq!(rows[0].certifying_body_name, "test_certifying_body_name");
+            assert_eq!(rows[0].standard_name, get_standard_name(STD_NAME_BASE));
+        })
+    }
+
+    #[test]
+    /// Test that the CSV and XLSX exports of standards have the same columns
+    fn test_write_standards_csv_and_xlsx() {
+        run_test(|| {
+            let conn = DbConn(setup_factory_db(false));
+            let rows = query_standard_rows(&conn, 1).unwrap();
+            assert_eq!(rows.len(), 1);
+            assert_eq!(rows[0].version, "");
+
+            let csv = String::from_utf8(write_csv(&rows).unwrap()).unwrap();
+            assert!(csv.starts_with(
+                "standard_id,name,organization_id,version,description,external_link,approval_date\n"
+            ));
+            assert!(write_xlsx("standards", &rows).is_ok());
+        })
+    }
+
+    #[test]
+    /// Test that rows of the same factory are grouped into one feature with every certificate
+    fn test_factory_features_from_rows() {
+        let features = FactoryFeature::from_rows(vec![
+            (
+                get_factory(FACTORY_NAME_BASE),
+                Some(get_address(FACTORY_NAME_BASE)),
+                Some(get_cert()),
+                Some(get_standard_name(STD_NAME_BASE)),
+            ),
+            (
+                get_factory(FACTORY_NAME_BASE),
+                Some(get_address(FACTORY_NAME_BASE)),
+                Some(get_cert()),
+                None,
+            ),
+            (get_factory("other_factory"), None, None, None),
+        ]);
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0].certificates.len(), 2);
+        assert_eq!(
+            features[0].full_address(),
+            "test_factory_street_line_1, test_factory_city, test_factory_province, \
+             test_factory_code, test_factory_country"
+        );
+        assert!(features[1].certificates.is_empty());
+        assert_eq!(features[1].full_address(), "");
+    }
+
+    #[test]
+    /// Test that factories are written as escaped KML placemarks, with a point if located
+    fn test_write_factories_kml() {
+        let mut features = FactoryFeature::from_rows(vec![(get_factory("a&b"), None, None, None)]);
+        features[0].location = Some(ApiLocation {
+            latitude: 44.97,
+            longitude: -93.26,
+            source: "supplied".to_string(),
+        });
+        let kml = write_factories_kml(&features);
+        assert!(kml.contains("<name>a&amp;b_name</name>"));
+        assert!(kml.contains("<coordinates>-93.26,44.97</coordinates>"));
+
+        features[0].location = None;
+        assert!(!write_factories_kml(&features).contains("<Point>"));
+    }
+
+    #[test]
+    /// Test that a GET to `/api/geojson/factories.geojson` only exports the factories matching
+    /// the filters
+    fn test_get_factories_geojson() {
+        run_test(|| {
+            let conn = setup_factory_db(true);
+            let mut items = FormItems::from("city=test_factory_city&head=1");
+            let params = FactoryParams::from_form(&mut items, true).unwrap();
+            let response =
+                get_factories_geojson_with_params(Some(Form(params)), DbConn(conn)).unwrap();
+            let collection: serde_json::Value = serde_json::from_str(&response.1).unwrap();
+
+            let features = collection["features"].as_array().unwrap();
+            assert_eq!(features.len(), 1);
+            assert_eq!(features[0]["id"], "test_factory_id");
             assert_eq!(
-                factories.get(0).unwrap().name,
-                String::from(format!("{}_name", FACTORY_NAME_BASE))
+                features[0]["properties"]["certificates"][0]["certificate_id"],
+                "test_cert_id"
             );
         })
     }
 
-    fn read_csv<P: AsRef<Path>>(path: P) -> Vec<Row> {
-        let mut rdr = csv::Reader::from_path(path).unwrap();
+    fn read_csv<R: Read>(reader: R) -> Vec<Row> {
+        let mut rdr = csv::Reader::from_reader(reader);
         let mut factories = vec![];
         for result in rdr.deserialize() {
             let row: Row = result.unwrap();
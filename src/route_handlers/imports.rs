@@ -0,0 +1,322 @@
+use base64;
+use common::proto::payload;
+use csv;
+use database::DbConn;
+use database_manager::tables_schema::organizations;
+use diesel::prelude::*;
+use errors::ApiError;
+use paging::get_head_block_num;
+use protobuf::Message;
+use rocket::Data;
+use rocket_contrib::json::JsonValue;
+use route_handlers::file::Row;
+use route_handlers::prom::increment_http_req;
+use route_handlers::transactions::{create_organization_action, AddressInput, CreateOrganization};
+use std::collections::HashSet;
+use std::io::Read;
+
+/// Upper bound on the number of rows previewed in a single import, so a preview can be
+/// reviewed and signed as a handful of batches
+const MAX_IMPORT_ROWS: usize = 1000;
+
+/// Upper bound on the size of an uploaded CSV, so a single oversized line is not buffered
+/// without limit before the row count is checked
+const MAX_IMPORT_BYTES: u64 = 1024 * 1024;
+
+/// The unsigned CREATE_ORGANIZATION payload built from a valid row
+#[derive(Debug, Serialize)]
+pub struct ImportTransaction {
+    row: u64,
+    organization_id: String,
+    /// The base64-encoded serialized `CertificateRegistryPayload`
+    payload: String,
+}
+
+/// Everything wrong with a row that was left out of the import
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ImportError {
+    row: u64,
+    organization_id: Option<String>,
+    messages: Vec<String>,
+}
+
+/// Handle a POST request with a CSV of factories in the same column layout as
+/// `/api/csv/factories.csv` and return the unsigned CREATE_ORGANIZATION transactions for
+/// the rows that can be imported, along with the errors of the rows that cannot.
+///
+/// Nothing is submitted: the client signs the returned payloads and submits them as
+/// batches to `/api/batches`. Certificate columns are ignored.
+#[post(
+    "/import/factories/preview?<head>",
+    format = "text/csv",
+    data = "<data>"
+)]
+pub fn preview_factory_import(
+    head: Option<i64>,
+    data: Data,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let head_block_num = get_head_block_num(head, &conn)?;
+    let csv = read_upload(data.open())?;
+    let (transactions, errors) = preview_rows(&conn, head_block_num, csv.as_slice())?;
+
+    Ok(json!({
+        "data": {
+            "transactions": transactions,
+            "errors": errors,
+        },
+        "head": head_block_num,
+    }))
+}
+
+/// Reads an uploaded CSV, rejecting it once it grows past `MAX_IMPORT_BYTES`
+fn read_upload<R: Read>(upload: R) -> Result<Vec<u8>, ApiError> {
+    let mut csv = vec![];
+    upload
+        .take(MAX_IMPORT_BYTES + 1)
+        .read_to_end(&mut csv)
+        .map_err(|err| ApiError::BadRequest(format!("Unable to read the CSV: {}", err)))?;
+    if csv.len() as u64 > MAX_IMPORT_BYTES {
+        return Err(ApiError::BadRequest(format!(
+            "An import can be at most {} bytes",
+            MAX_IMPORT_BYTES
+        )));
+    }
+    Ok(csv)
+}
+
+/// Validates every row of `csv` and builds the payloads of the valid ones. Rows are
+/// identified by their line number in the CSV, counting the header as line 1.
+fn preview_rows<R: Read>(
+    conn: &DbConn,
+    head_block_num: i64,
+    csv: R,
+) -> Result<(Vec<ImportTransaction>, Vec<ImportError>), ApiError> {
+    // Rows with missing columns are reported like any other invalid row
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(csv);
+    let headers = reader
+        .headers()
+        .map_err(|err| ApiError::BadRequest(format!("Invalid CSV header: {}", err)))?
+        .clone();
+
+    let mut rows = vec![];
+    for record in reader.records() {
+        let record = record.map_err(|err| ApiError::BadRequest(err.to_string()))?;
+        if rows.len() == MAX_IMPORT_ROWS {
+            return Err(ApiError::BadRequest(format!(
+                "An import can contain at most {} rows",
+                MAX_IMPORT_ROWS
+            )));
+        }
+        let line = record.position().map_or(0, |position| position.line());
+        rows.push((line, record.deserialize::<Row>(Some(&headers))));
+    }
+
+    let organization_ids: Vec<&str> = rows
+        .iter()
+        .filter_map(|(_, row)| row.as_ref().ok())
+        .map(|row| row.organization_id.as_str())
+        .collect();
+    let existing_ids: HashSet<String> = organizations::table
+        .select(organizations::organization_id)
+        .filter(organizations::organization_id.eq_any(organization_ids))
+        .filter(organizations::start_block_num.le(head_block_num))
+        .filter(organizations::end_block_num.gt(head_block_num))
+        .load::<String>(&**conn)?
+        .into_iter()
+        .collect();
+
+    let mut seen_ids = HashSet::new();
+    let mut transactions = vec![];
+    let mut errors = vec![];
+    for (line, row) in rows {
+        let row = match row {
+            Ok(row) => row,
+            Err(err) => {
+                errors.push(ImportError {
+                    row: line,
+                    organization_id: None,
+                    messages: vec![err.to_string()],
+                });
+                continue;
+            }
+        };
+
+        let mut messages = validate_row(&row);
+        if existing_ids.contains(&row.organization_id) {
+            messages.push(format!(
+                "An organization with the ID {} already exists",
+                row.organization_id
+            ));
+        } else if !row.organization_id.is_empty() && !seen_ids.insert(row.organization_id.clone()) {
+            messages.push(format!(
+                "The organization ID {} appears more than once in the import",
+                row.organization_id
+            ));
+        }
+
+        if messages.is_empty() {
+            let payload = create_organization_payload(&row)?
+                .write_to_bytes()
+                .map_err(|err| ApiError::InternalError(err.to_string()))?;
+            transactions.push(ImportTransaction {
+                row: line,
+                organization_id: row.organization_id,
+                payload: base64::encode(&payload),
+            });
+        } else {
+            errors.push(ImportError {
+                row: line,
+                organization_id: Some(row.organization_id),
+                messages,
+            });
+        }
+    }
+
+    Ok((transactions, errors))
+}
+
+/// Checks the fields a factory must have to be created
+fn validate_row(row: &Row) -> Vec<String> {
+    [
+        ("organization_id", &row.organization_id),
+        ("name", &row.name),
+        ("street_line_1", &row.street_line_1),
+        ("city", &row.city),
+        ("country", &row.country),
+    ]
+    .iter()
+    .filter(|(_, value)| value.trim().is_empty())
+    .map(|(column, _)| format!("The {} column is required", column))
+    .collect()
+}
+
+fn create_organization_payload(row: &Row) -> Result<payload::CertificateRegistryPayload, ApiError> {
+    let action = create_organization_action(&CreateOrganization {
+        id: row.organization_id.clone(),
+        name: row.name.clone(),
+        organization_type: "factory".to_string(),
+        contacts: vec![],
+        address: Some(AddressInput {
+            street_line_1: row.street_line_1.clone(),
+            street_line_2: row.street_line_2.clone(),
+            city: row.city.clone(),
+            state_province: row.state.clone(),
+            country: row.country.clone(),
+            postal_code: row.postal_code.clone(),
+        }),
+    })?;
+
+    let mut payload = payload::CertificateRegistryPayload::new();
+    payload.set_action(payload::CertificateRegistryPayload_Action::CREATE_ORGANIZATION);
+    payload.set_create_organization(action);
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::organization;
+    use protobuf::parse_from_bytes;
+    use route_handlers::factories::tests::setup_factory_db;
+    use route_handlers::tests::run_test;
+
+    const HEADER: &str = "name,organization_id,country,street_line_1,street_line_2,city,state,\
+                          postal_code,certificate_standard_name,valid_from,valid_to\n";
+
+    #[test]
+    /// Test that a valid row is turned into a CREATE_ORGANIZATION payload for a factory
+    fn test_preview_valid_row() {
+        run_test(|| {
+            let conn = DbConn(setup_factory_db(false));
+            let csv = format!(
+                "{}new_factory,new_factory_id,Peru,1 Main St,,Lima,,,,,\n",
+                HEADER
+            );
+            let (transactions, errors) = preview_rows(&conn, 1, csv.as_bytes()).unwrap();
+
+            assert!(errors.is_empty());
+            assert_eq!(transactions.len(), 1);
+            assert_eq!(transactions[0].row, 2);
+            let payload: payload::CertificateRegistryPayload =
+                parse_from_bytes(&base64::decode(&transactions[0].payload).unwrap()).unwrap();
+            assert_eq!(
+                payload.get_action(),
+                payload::CertificateRegistryPayload_Action::CREATE_ORGANIZATION
+            );
+            let action = payload.get_create_organization();
+            assert_eq!(action.get_id(), "new_factory_id");
+            assert_eq!(
+                action.get_organization_type(),
+                organization::Organization_Type::FACTORY
+            );
+            assert_eq!(action.get_address().get_city(), "Lima");
+        })
+    }
+
+    #[test]
+    /// Test that rows missing address fields or reusing organization IDs are reported
+    /// instead of being imported
+    fn test_preview_invalid_rows() {
+        run_test(|| {
+            let conn = DbConn(setup_factory_db(false));
+            let csv = format!(
+                "{}\
+                 existing,test_factory_id,Peru,1 Main St,,Lima,,,,,\n\
+                 no_city,no_city_id,Peru,1 Main St,,,,,,,\n\
+                 first,repeated_id,Peru,1 Main St,,Lima,,,,,\n\
+                 second,repeated_id,Peru,2 Main St,,Lima,,,,,\n\
+                 short,row\n",
+                HEADER
+            );
+            let (transactions, errors) = preview_rows(&conn, 1, csv.as_bytes()).unwrap();
+
+            assert_eq!(transactions.len(), 1);
+            assert_eq!(transactions[0].row, 4);
+            assert_eq!(
+                errors.iter().map(|error| error.row).collect::<Vec<_>>(),
+                vec![2, 3, 5, 6]
+            );
+            assert_eq!(
+                errors[0].messages,
+                vec!["An organization with the ID test_factory_id already exists".to_string()]
+            );
+            assert_eq!(
+                errors[1].messages,
+                vec!["The city column is required".to_string()]
+            );
+            assert_eq!(errors[3].organization_id, None);
+        })
+    }
+
+    #[test]
+    /// Test that rows of a CSV without the expected columns are reported as errors
+    fn test_preview_missing_columns() {
+        run_test(|| {
+            let conn = DbConn(setup_factory_db(false));
+            let (transactions, errors) =
+                preview_rows(&conn, 1, "name\nfactory\n".as_bytes()).unwrap();
+
+            assert!(transactions.is_empty());
+            assert_eq!(errors.len(), 1);
+        })
+    }
+
+    #[test]
+    /// Test that an upload larger than the limit is rejected, even when it is a single line
+    fn test_read_upload_too_large() {
+        let csv = vec![b'a'; MAX_IMPORT_BYTES as usize];
+        assert_eq!(read_upload(csv.as_slice()).unwrap().len(), csv.len());
+
+        let csv = vec![b'a'; MAX_IMPORT_BYTES as usize + 1];
+        match read_upload(csv.as_slice()) {
+            Err(ApiError::BadRequest(message)) => {
+                assert_eq!(message, "An import can be at most 1048576 bytes")
+            }
+            _ => panic!("Expected a bad request"),
+        }
+    }
+}
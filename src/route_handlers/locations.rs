@@ -0,0 +1,206 @@
+use cache::RESPONSE_CACHE;
+use database::DbConn;
+use database_manager::custom_types::OrganizationTypeEnum;
+use database_manager::tables_schema::organizations;
+use diesel::prelude::*;
+use errors::ApiError;
+use geo::{
+    geocode_addresses, store_location, ApiLocation, FactoryLocation, Point, SUPPLIED_SOURCE,
+};
+use jwt;
+use paging::get_head_block_num;
+use rocket_contrib::json::{Json, JsonValue};
+use route_handlers::authorization::{require_admin, require_agent_of};
+use route_handlers::prom::increment_http_req;
+
+#[derive(Serialize, Deserialize)]
+pub struct LocationPayload {
+    latitude: f64,
+    longitude: f64,
+}
+
+/// Handle a PUT request with proper Bearer token to set the coordinates of a factory.
+/// Supplied coordinates take precedence over geocoded ones and are never replaced by geocoding,
+/// so only agents of the factory and admins can set them.
+#[put(
+    "/factories/<organization_id>/location",
+    format = "application/json",
+    data = "<payload>",
+    rank = 1
+)]
+pub fn put_factory_location(
+    organization_id: String,
+    payload: Json<LocationPayload>,
+    claims: jwt::JWT,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let point = Point::new(payload.latitude, payload.longitude)?;
+    let head_block_num = get_head_block_num(None, &conn)?;
+    let factory_count: i64 = organizations::table
+        .filter(organizations::organization_type.eq(OrganizationTypeEnum::Factory))
+        .filter(organizations::organization_id.eq(&organization_id))
+        .filter(organizations::start_block_num.le(head_block_num))
+        .filter(organizations::end_block_num.gt(head_block_num))
+        .count()
+        .get_result(&*conn)?;
+    if factory_count == 0 {
+        return Err(ApiError::NotFound(format!(
+            "No factory with the organization ID {} exists",
+            organization_id
+        )));
+    }
+    require_agent_of(&conn, &claims, &organization_id, head_block_num)?;
+
+    let location = FactoryLocation {
+        organization_id: organization_id.clone(),
+        latitude: point.latitude,
+        longitude: point.longitude,
+        source: SUPPLIED_SOURCE.to_string(),
+    };
+    store_location(&conn, &location)?;
+    // Cached factory lists include locations, which change without a new block
+    RESPONSE_CACHE.invalidate();
+
+    Ok(json!({
+        "data": ApiLocation::from(location),
+        "link": format!("/api/factories/{}", organization_id),
+        "head": head_block_num,
+    }))
+}
+
+/// If setting a factory location fails due to JWT authentication issues,
+/// return a more specific error message.
+///
+/// Without this endpoint, when JWT auth fails there is nowhere to forward
+/// the request to and the client receives a 404 error.
+#[put("/factories/<_organization_id>/location", rank = 2)]
+pub fn put_factory_location_jwt_failure(_organization_id: String) -> Result<JsonValue, ApiError> {
+    Err(ApiError::Unauthorized)
+}
+
+/// Handle a POST request with proper Bearer token to geocode the current address of every
+/// factory from the `geocoding_places` table. Only admins can geocode.
+#[post("/admin/geocode", rank = 1)]
+pub fn geocode_factories(claims: jwt::JWT, conn: DbConn) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    require_admin(&conn, &claims)?;
+    let head_block_num = get_head_block_num(None, &conn)?;
+    let geocoded = geocode_addresses(&conn, head_block_num)?;
+    RESPONSE_CACHE.invalidate();
+
+    Ok(json!({
+        "data": { "geocoded": geocoded },
+        "head": head_block_num,
+    }))
+}
+
+/// If geocoding fails due to JWT authentication issues,
+/// return a more specific error message.
+///
+/// Without this endpoint, when JWT auth fails there is nowhere to forward
+/// the request to and the client receives a 404 error.
+#[post("/admin/geocode", rank = 2)]
+pub fn geocode_factories_jwt_failure() -> Result<JsonValue, ApiError> {
+    Err(ApiError::Unauthorized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database_manager::models::{NewAgent, User};
+    use database_manager::tables_schema::{agents, users};
+    use diesel::pg::PgConnection;
+    use diesel::r2d2::{ConnectionManager, PooledConnection};
+    use route_handlers::factories::tests::setup_factory_db;
+    use route_handlers::tests::run_test;
+
+    // helper function to add a user that is an agent of `organization_id`
+    fn insert_test_agent(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        organization_id: Option<String>,
+    ) -> jwt::JWT {
+        diesel::insert_into(users::table)
+            .values(&vec![User {
+                public_key: "test_agent_key".to_string(),
+                encrypted_private_key: "test_encrypted_private_key".to_string(),
+                username: "test_agent".to_string(),
+                hashed_password: "test_hashed_password".to_string(),
+            }])
+            .execute(&**conn)
+            .unwrap();
+        diesel::insert_into(agents::table)
+            .values(NewAgent {
+                start_block_num: 1,
+                end_block_num: std::i64::MAX,
+                public_key: "test_agent_key".to_string(),
+                name: "test_agent".to_string(),
+                organization_id,
+                timestamp: 1,
+            })
+            .execute(&**conn)
+            .unwrap();
+        jwt::JWT(json!({ "username": "test_agent" }).0)
+    }
+
+    #[test]
+    /// Test that an agent of a factory can set its location
+    fn test_put_factory_location_agent() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            let claims = insert_test_agent(&conn, Some("test_factory_id".to_string()));
+
+            let res = put_factory_location(
+                "test_factory_id".to_string(),
+                Json(LocationPayload {
+                    latitude: 44.97,
+                    longitude: -93.26,
+                }),
+                claims,
+                DbConn(conn),
+            )
+            .unwrap();
+            assert_eq!(res["data"]["source"], SUPPLIED_SOURCE);
+        })
+    }
+
+    #[test]
+    /// Test that a user who is not an agent of a factory cannot set its location
+    fn test_put_factory_location_not_agent() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            let claims = insert_test_agent(&conn, None);
+
+            match put_factory_location(
+                "test_factory_id".to_string(),
+                Json(LocationPayload {
+                    latitude: 44.97,
+                    longitude: -93.26,
+                }),
+                claims,
+                DbConn(conn),
+            ) {
+                Err(ApiError::Forbidden(_)) => (),
+                _ => panic!("Expected a Forbidden error"),
+            }
+        })
+    }
+
+    #[test]
+    /// Test that a user who is not an admin cannot geocode the factories
+    fn test_geocode_factories_not_admin() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            let claims = insert_test_agent(&conn, Some("test_factory_id".to_string()));
+
+            match geocode_factories(claims, DbConn(conn)) {
+                Err(ApiError::Forbidden(_)) => (),
+                _ => panic!("Expected a Forbidden error"),
+            }
+        })
+    }
+}
@@ -1,18 +1,24 @@
 pub mod agents;
 pub mod assertions;
 pub mod authorization;
+pub mod autocomplete;
 pub mod blockchain;
 pub mod blocks;
 pub mod certificates;
+pub mod consistency;
 pub mod cors;
 pub mod factories;
 pub mod file;
 pub mod health;
+pub mod imports;
+pub mod locations;
 pub mod organizations;
 pub mod prom;
 pub mod requests;
+pub mod search;
 pub mod standards;
 pub mod standards_body;
+pub mod transactions;
 pub mod vault;
 
 #[cfg(test)]
@@ -23,10 +29,12 @@ pub mod tests {
     use database_manager::tables_schema::{blocks as blocks_schema, users};
     use diesel::pg::PgConnection;
     use diesel::r2d2::{ConnectionManager, PooledConnection};
+    use diesel::sql_types::{Array, BigInt, Text};
     use diesel::RunQueryDsl;
     use errors;
-    use fairings::CORS;
+    use fairings::{AsOf, HeadBlock, CORS};
     use rocket::http::ContentType;
+    use rocket::http::Header;
     use rocket::http::Status;
     use rocket::local::Client;
     use serde_json::Value;
@@ -56,6 +64,7 @@ pub mod tests {
                     cors::cors_batches_route,
                     agents::fetch_agent,
                     agents::fetch_agent_with_head_param,
+                    agents::fetch_agent_history,
                     agents::list_agents,
                     agents::list_agents_with_params,
                     assertions::fetch_assertions,
@@ -76,31 +85,70 @@ pub mod tests {
                     factories::fetch_factory_with_head_param,
                     factories::list_factories,
                     factories::list_factories_params,
+                    imports::preview_factory_import,
+                    locations::put_factory_location,
+                    locations::put_factory_location_jwt_failure,
+                    locations::geocode_factories,
+                    locations::geocode_factories_jwt_failure,
                     health::check,
+                    transactions::build_transaction,
                     requests::fetch_request,
                     requests::fetch_request_with_head_param,
+                    requests::fetch_request_history,
                     requests::list_requests,
                     requests::list_request_with_params,
                     organizations::fetch_organization,
                     organizations::fetch_organization_with_params,
+                    organizations::fetch_organization_history,
+                    organizations::fetch_organization_diff,
                     organizations::list_organizations,
                     organizations::list_organizations_with_params,
                     certificates::fetch_certificate,
                     certificates::fetch_certificate_with_head_param,
+                    certificates::fetch_certificate_history,
+                    certificates::fetch_certificate_diff,
                     certificates::list_certificates,
                     certificates::list_certificates_with_params,
+                    consistency::check_consistency,
+                    consistency::check_consistency_with_params,
+                    consistency::check_consistency_jwt_failure,
                     standards::list_standards,
                     standards::list_standards_with_params,
+                    standards::fetch_standard_history,
+                    standards::fetch_standard_diff,
                     standards_body::list_standards_belonging_to_org,
                     prom::get_metrics,
+                    search::search,
+                    search::search_with_params,
+                    autocomplete::autocomplete,
+                    autocomplete::autocomplete_with_params,
                     vault::store_key,
                     vault::get_key,
                     vault::store_key_jwt_failure,
                     vault::get_key_jwt_failure,
+                    vault::delete_key,
+                    vault::delete_key_jwt_failure,
+                    vault::list_key_versions,
+                    vault::list_key_versions_jwt_failure,
+                    vault::rollback_key,
+                    vault::rollback_key_jwt_failure,
                     file::get_factories,
+                    file::get_factories_with_params,
+                    file::get_factories_geojson,
+                    file::get_factories_geojson_with_params,
+                    file::get_factories_kml,
+                    file::get_factories_kml_with_params,
+                    file::get_certificates_csv,
+                    file::get_certificates_xlsx,
+                    file::get_requests_csv,
+                    file::get_requests_xlsx,
+                    file::get_standards_csv,
+                    file::get_standards_xlsx,
                 ],
             )
-            .attach(CORS());
+            .attach(CORS())
+            .attach(AsOf())
+            .attach(HeadBlock());
 
         Client::new(rocket).expect("Valid Rocket instance")
     }
@@ -124,6 +172,9 @@ pub mod tests {
     /// Minimum setup required to make a query against the DB
     ///
     fn setup() {
+        // Each test loads its own data at the same block in a transaction that is rolled back,
+        // so responses cached by one test would be wrong for the others
+        env::set_var("RESPONSE_CACHE_SIZE", "0");
         let conn = get_connection_pool();
 
         let genesis_block = Block {
@@ -137,6 +188,60 @@ pub mod tests {
             .unwrap();
     }
 
+    ///
+    /// Insert blocks after the genesis block, e.g. to move the default head forward
+    ///
+    pub fn insert_blocks(conn: &PgConnection, block_nums: &[i64]) {
+        for block_num in block_nums {
+            diesel::insert_into(blocks_schema::table)
+                .values(Block {
+                    block_num: *block_num,
+                    block_id: format!("block_{}", block_num),
+                })
+                .execute(conn)
+                .unwrap();
+        }
+    }
+
+    ///
+    /// Count how many times `tables` are scanned while running `query`, which must use `conn`
+    /// inside a test transaction.
+    ///
+    /// A handler that queries a table once per row it returns scans it more often the more rows
+    /// there are, so comparing the counts of two calls catches N+1 queries. Nested loop joins
+    /// rescan their inner table once per outer row, so they are disabled for the rest of the
+    /// transaction to make every query scan each of its tables once.
+    ///
+    pub fn count_table_scans<F>(conn: &PgConnection, tables: &[&str], query: F) -> i64
+    where
+        F: FnOnce(),
+    {
+        #[derive(QueryableByName)]
+        struct TableScans {
+            #[sql_type = "BigInt"]
+            scans: i64,
+        }
+
+        let scans = || {
+            diesel::sql_query(
+                "SELECT COALESCE(SUM(seq_scan + COALESCE(idx_scan, 0)), 0)::BIGINT AS scans
+                 FROM pg_stat_xact_user_tables
+                 WHERE relname = ANY($1)",
+            )
+            .bind::<Array<Text>, _>(tables.to_vec())
+            .get_result::<TableScans>(conn)
+            .unwrap()
+            .scans
+        };
+
+        diesel::sql_query("SET LOCAL enable_nestloop = off")
+            .execute(conn)
+            .unwrap();
+        let before = scans();
+        query();
+        scans() - before
+    }
+
     ///
     /// Clear user defined env vars that may have been set during the tests
     ///
@@ -147,6 +252,18 @@ pub mod tests {
             "VAULT_USERNAME".to_string(),
             "VAULT_PASSWORD".to_string(),
             "VAULT_PATH".to_string(),
+            "VAULT_AUTH_METHOD".to_string(),
+            "VAULT_AUTH_MOUNT".to_string(),
+            "VAULT_ROLE_ID".to_string(),
+            "VAULT_SECRET_ID".to_string(),
+            "VAULT_K8S_ROLE".to_string(),
+            "VAULT_K8S_TOKEN_PATH".to_string(),
+            "VAULT_TOKEN".to_string(),
+            "VAULT_MOUNT".to_string(),
+            "VAULT_KV_VERSION".to_string(),
+            "KEY_STORE".to_string(),
+            "LOCAL_KEY_STORE_PATH".to_string(),
+            "LOCAL_KEY_STORE_SECRET".to_string(),
         ];
 
         for env_var in env_vars {
@@ -723,6 +840,65 @@ pub mod tests {
         })
     }
 
+    #[test]
+    /// Test that a DELETE to `/api/key` returns an `Unauthorized` response
+    /// when there is no `Authorization` header in the request
+    fn test_delete_key_endpoint() {
+        run_test(|| {
+            env::set_var("OAUTH_VALIDATION_URL", "bad-url");
+            let response = CLIENT.delete("/api/key").dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/key/versions` returns an `Unauthorized` response
+    /// when there is no `Authorization` header in the request
+    fn test_list_key_versions_endpoint() {
+        run_test(|| {
+            env::set_var("OAUTH_VALIDATION_URL", "bad-url");
+            let response = CLIENT.get("/api/key/versions").dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+        })
+    }
+
+    #[test]
+    /// Test that a POST to `/api/key/rollback/<version>` returns an `Unauthorized` response
+    /// when there is no `Authorization` header in the request
+    fn test_rollback_key_endpoint() {
+        run_test(|| {
+            env::set_var("OAUTH_VALIDATION_URL", "bad-url");
+            let response = CLIENT.post("/api/key/rollback/1").dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+        })
+    }
+
+    #[test]
+    /// Test that a PUT to `/api/factories/<organization_id>/location` returns an
+    /// `Unauthorized` response when there is no `Authorization` header in the request
+    fn test_put_factory_location_endpoint() {
+        run_test(|| {
+            env::set_var("OAUTH_VALIDATION_URL", "bad-url");
+            let response = CLIENT
+                .put("/api/factories/test_factory_id/location")
+                .header(ContentType::JSON)
+                .body(r#"{"latitude": 44.97, "longitude": -93.26}"#)
+                .dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+        })
+    }
+
+    #[test]
+    /// Test that a POST to `/api/admin/geocode` returns an `Unauthorized` response
+    /// when there is no `Authorization` header in the request
+    fn test_geocode_factories_endpoint() {
+        run_test(|| {
+            env::set_var("OAUTH_VALIDATION_URL", "bad-url");
+            let response = CLIENT.post("/api/admin/geocode").dispatch();
+            assert_eq!(response.status(), Status::Unauthorized);
+        })
+    }
+
     #[test]
     /// Test that a GET to `/api/csv/factories.csv` returns an `Ok` response and sends back an
     /// empty file
@@ -732,4 +908,253 @@ pub mod tests {
             assert_eq!(response.status(), Status::Ok);
         })
     }
+
+    #[test]
+    /// Test that a GET to `/api/csv/factories.csv` with an unknown column returns a
+    /// `BadRequest` response
+    fn test_get_factory_csv_file_invalid_columns() {
+        run_test(|| {
+            let response = CLIENT
+                .get("/api/csv/factories.csv?columns=name,password")
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/geojson/factories.geojson` returns an `Ok` response
+    fn test_get_factory_geojson_file() {
+        run_test(|| {
+            let response = CLIENT.get("/api/geojson/factories.geojson").dispatch();
+            assert_eq!(response.status(), Status::Ok);
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/kml/factories.kml?country=Peru` returns an `Ok` response
+    fn test_get_factory_kml_file() {
+        run_test(|| {
+            let response = CLIENT.get("/api/kml/factories.kml?country=Peru").dispatch();
+            assert_eq!(response.status(), Status::Ok);
+        })
+    }
+
+    #[test]
+    /// Test that a GET to each certificate, request and standard export returns an `Ok`
+    /// response
+    fn test_get_entity_exports() {
+        run_test(|| {
+            for export in &[
+                "/api/csv/certificates.csv",
+                "/api/xlsx/certificates.xlsx",
+                "/api/csv/requests.csv",
+                "/api/xlsx/requests.xlsx",
+                "/api/csv/standards.csv",
+                "/api/xlsx/standards.xlsx?head=1",
+            ] {
+                let response = CLIENT.get(*export).dispatch();
+                assert_eq!(response.status(), Status::Ok);
+            }
+        })
+    }
+
+    #[test]
+    /// Test that list endpoints send CSV or newline delimited JSON when it is the preferred
+    /// type of the `Accept` header, along with the total record count
+    fn test_negotiated_list_endpoints() {
+        run_test(|| {
+            let response = CLIENT
+                .get("/api/certificates")
+                .header(Header::new("Accept", "text/csv"))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.content_type(), Some(ContentType::CSV));
+            assert_eq!(response.headers().get_one("X-Total-Count"), Some("0"));
+
+            let response = CLIENT
+                .get("/api/organizations")
+                .header(Header::new("Accept", "application/x-ndjson"))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(
+                response.content_type(),
+                Some(ContentType::new("application", "x-ndjson"))
+            );
+
+            let response = CLIENT
+                .get("/api/standards")
+                .header(Header::new("Accept", "application/json"))
+                .dispatch();
+            assert_eq!(response.content_type(), Some(ContentType::JSON));
+        })
+    }
+
+    #[test]
+    /// Test that a POST of a factory CSV to `/api/import/factories/preview` returns an `Ok`
+    /// response with the transactions and errors of the import
+    fn test_preview_factory_import_endpoint() {
+        run_test(|| {
+            let mut response = CLIENT
+                .post("/api/import/factories/preview")
+                .header(ContentType::CSV)
+                .body(
+                    "name,organization_id,country,street_line_1,street_line_2,city,state,\
+                     postal_code,certificate_standard_name,valid_from,valid_to\n\
+                     factory,factory_id,Peru,1 Main St,,Lima,,,,,\n",
+                )
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+
+            let body: Value =
+                serde_json::from_str(&response.body().unwrap().into_string().unwrap()).unwrap();
+            assert_eq!(body["data"]["transactions"].as_array().unwrap().len(), 1);
+            assert_eq!(body["data"]["errors"].as_array().unwrap().len(), 0);
+        })
+    }
+
+    #[test]
+    /// Test that a POST to `/api/transactions/create_agent` returns an `Ok` response with
+    /// the payload and header of the transaction, and that unknown actions are not found
+    fn test_build_transaction_endpoint() {
+        run_test(|| {
+            let mut response = CLIENT
+                .post("/api/transactions/create_agent")
+                .header(ContentType::JSON)
+                .body(r#"{"signer_public_key": "public_key", "name": "agent"}"#)
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+
+            let body: Value =
+                serde_json::from_str(&response.body().unwrap().into_string().unwrap()).unwrap();
+            assert_eq!(body["data"]["inputs"].as_array().unwrap().len(), 1);
+            assert_eq!(
+                body["data"]["header"]["signer_public_key"],
+                Value::from("public_key")
+            );
+
+            let response = CLIENT
+                .post("/api/transactions/delete_agent")
+                .header(ContentType::JSON)
+                .body(r#"{"signer_public_key": "public_key"}"#)
+                .dispatch();
+            assert_eq!(response.status(), Status::NotFound);
+        })
+    }
+
+    #[test]
+    /// Test that a GET to the history of an entity that never existed returns a `NotFound`
+    /// response
+    fn test_history_endpoints_not_found() {
+        run_test(|| {
+            for history in &[
+                "/api/agents/0/history",
+                "/api/certificates/0/history",
+                "/api/organizations/0/history",
+                "/api/requests/0/history",
+                "/api/standards/0/history?head=1",
+            ] {
+                let response = CLIENT.get(*history).dispatch();
+                assert_eq!(response.status(), Status::NotFound);
+            }
+        })
+    }
+
+    #[test]
+    /// Test that a GET to the diff of an entity requires a `from` block and returns a
+    /// `NotFound` response for entities that never existed
+    fn test_diff_endpoints() {
+        run_test(|| {
+            let response = CLIENT.get("/api/certificates/0/diff").dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+
+            for diff in &[
+                "/api/certificates/0/diff?from=1",
+                "/api/organizations/0/diff?from=1",
+                "/api/standards/0/diff?from=1&to=1",
+            ] {
+                let response = CLIENT.get(*diff).dispatch();
+                assert_eq!(response.status(), Status::NotFound);
+            }
+        })
+    }
+
+    #[test]
+    /// Test that `as_of` is resolved to the last block committed at or before it, and that
+    /// invalid or unresolvable timestamps are rejected
+    fn test_as_of_queries() {
+        run_test(|| {
+            let mut response = CLIENT
+                .get("/api/certificates?as_of=2999-01-01T00:00:00Z")
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let body: Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+            assert_eq!(body["head"], 1);
+
+            for (query, status) in &[
+                ("as_of=1970-01-01T00:00:00Z", Status::NotFound),
+                ("as_of=yesterday", Status::BadRequest),
+                ("head=1&as_of=2999-01-01T00:00:00Z", Status::BadRequest),
+            ] {
+                let response = CLIENT
+                    .get(format!("/api/certificates?{}", query))
+                    .dispatch();
+                assert_eq!(response.status(), *status);
+            }
+        })
+    }
+
+    #[test]
+    /// Test that requests at a block that has not been committed are rejected, and that
+    /// responses carry the block they were answered at
+    fn test_head_block_validation() {
+        run_test(|| {
+            let response = CLIENT.get("/api/certificates?head=999999999").dispatch();
+            assert_eq!(response.status(), Status::NotFound);
+
+            let response = CLIENT.get("/api/certificates?head=-1").dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+
+            let response = CLIENT.get("/api/certificates?head=1").dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.headers().get_one("X-Head-Block"), Some("1"));
+            assert_eq!(response.headers().get_one("ETag"), Some("\"1-json\""));
+
+            let response = CLIENT
+                .get("/api/certificates?head=1")
+                .header(Header::new("Accept", "text/csv"))
+                .dispatch();
+            assert_eq!(response.headers().get_one("X-Head-Block"), Some("1"));
+            assert_eq!(response.headers().get_one("ETag"), Some("\"1-csv\""));
+        })
+    }
+
+    #[test]
+    /// Test that a request whose `If-None-Match` matches the `ETag` of the response is
+    /// answered with `304 Not Modified`
+    fn test_conditional_requests() {
+        run_test(|| {
+            let response = CLIENT.get("/api/organizations?head=1").dispatch();
+            let etag = response.headers().get_one("ETag").unwrap().to_string();
+            assert_eq!(
+                response.headers().get_one("Cache-Control"),
+                Some("public, max-age=31536000, immutable")
+            );
+
+            let response = CLIENT
+                .get("/api/organizations?head=1")
+                .header(Header::new("If-None-Match", etag.clone()))
+                .dispatch();
+            assert_eq!(response.status(), Status::NotModified);
+
+            let response = CLIENT
+                .get("/api/organizations")
+                .header(Header::new("If-None-Match", etag))
+                .dispatch();
+            assert_eq!(response.status(), Status::NotModified);
+            assert_eq!(
+                response.headers().get_one("Cache-Control"),
+                Some("public, no-cache")
+            );
+        })
+    }
 }
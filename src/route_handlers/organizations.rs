@@ -8,7 +8,13 @@ use database_manager::tables_schema::{
     addresses, assertions, authorizations, contacts, organizations,
 };
 use diesel::prelude::*;
+use diff::{diff, diff_range};
 use errors::ApiError;
+use expand::{Expansions, Resource};
+use fields::Fields;
+use geo::ApiLocation;
+use history::versions;
+use negotiation::Negotiated;
 use paging::*;
 use rocket::http::uri::Uri;
 use rocket::request::Form;
@@ -16,6 +22,7 @@ use rocket_contrib::json::JsonValue;
 use route_handlers::certificates::ApiCertificate;
 use route_handlers::prom::increment_http_req;
 use std::collections::HashMap;
+use std::slice;
 
 #[derive(Serialize)]
 pub struct ApiAddress {
@@ -113,6 +120,12 @@ pub struct ApiFactory {
     organization_type: OrganizationTypeEnum,
     #[serde(skip_serializing_if = "Option::is_none")]
     assertion_id: Option<String>,
+    /// Relevance of the factory to a search
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<f32>,
+    /// Coordinates of the factory, if it has been located
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<ApiLocation>,
 }
 
 impl ApiFactory {
@@ -134,6 +147,8 @@ impl ApiFactory {
             certificates: None,
             organization_type: db_organization.organization_type,
             assertion_id: None,
+            score: None,
+            location: None,
         }
     }
 
@@ -156,6 +171,8 @@ impl ApiFactory {
             certificates: None,
             organization_type: db_organization.organization_type,
             assertion_id,
+            score: None,
+            location: None,
         }
     }
 
@@ -186,6 +203,8 @@ impl ApiFactory {
                     .collect(),
             ),
             assertion_id: None,
+            score: None,
+            location: None,
         }
     }
 
@@ -217,6 +236,8 @@ impl ApiFactory {
                     .collect(),
             ),
             assertion_id,
+            score: None,
+            location: None,
         }
     }
 
@@ -241,6 +262,8 @@ impl ApiFactory {
             certificates: None,
             organization_type: db_organization.organization_type.clone(),
             assertion_id: None,
+            score: None,
+            location: None,
         }
     }
 
@@ -266,8 +289,21 @@ impl ApiFactory {
             certificates: None,
             organization_type: db_organization.organization_type.clone(),
             assertion_id: assertion_id.clone(),
+            score: None,
+            location: None,
         }
     }
+
+    /// Sets the relevance of the factory to a search
+    pub fn with_score(mut self, score: Option<f32>) -> Self {
+        self.score = score;
+        self
+    }
+
+    pub fn with_location(mut self, location: Option<ApiLocation>) -> Self {
+        self.location = location;
+        self
+    }
 }
 
 #[derive(Serialize)]
@@ -344,44 +380,75 @@ pub fn fetch_organization_with_params(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
+    let expansions = Expansions::parse(Resource::Organization, &head_param.expand)?;
+    let fields = Fields::parse(&head_param.fields);
     let head_block_num: i64 = get_head_block_num(head_param.head, &conn)?;
-    let link = format!(
+    let mut link = format!(
         "/api/organizations/{}?head={}",
         organization_id, head_block_num
     );
+    if !expansions.is_empty() {
+        link = format!("{}&expand={}", link, expansions.to_param());
+    }
+    if let Some(ref fields) = head_param.fields {
+        link = format!("{}&fields={}", link, Uri::percent_encode(fields));
+    }
+
+    match load_organization(&conn, &organization_id, head_block_num)? {
+        Some(data) => {
+            let mut data = data.0;
+            expansions.apply(&conn, slice::from_mut(&mut data), head_block_num)?;
+            fields.select(slice::from_mut(&mut data));
+            Ok(json!({ "data": data,
+                            "link": link,
+                            "head": head_block_num,}))
+        }
+        None => Err(ApiError::NotFound(format!(
+            "No organization with the organization ID {} exists",
+            organization_id
+        ))),
+    }
+}
 
+/// Loads the organization with its contacts, authorizations and, for factories, its address
+/// and assertion as they were at `head_block_num`
+pub fn load_organization(
+    conn: &DbConn,
+    organization_id: &str,
+    head_block_num: i64,
+) -> Result<Option<JsonValue>, ApiError> {
     let org = organizations::table
-        .filter(organizations::organization_id.eq(organization_id.to_string()))
+        .filter(organizations::organization_id.eq(organization_id))
         .filter(organizations::start_block_num.le(head_block_num))
         .filter(organizations::end_block_num.gt(head_block_num))
-        .first::<Organization>(&*conn)
+        .first::<Organization>(&**conn)
         .optional()
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
 
     match org {
         Some(org) => {
             let contact_results: Vec<Contact> = contacts::table
-                .filter(contacts::organization_id.eq(organization_id.to_string()))
+                .filter(contacts::organization_id.eq(organization_id))
                 .filter(contacts::start_block_num.le(head_block_num))
                 .filter(contacts::end_block_num.gt(head_block_num))
-                .load::<Contact>(&*conn)
+                .load::<Contact>(&**conn)
                 .map_err(|err| ApiError::InternalError(err.to_string()))?;
 
             let authorization_results: Vec<Authorization> = authorizations::table
-                .filter(authorizations::organization_id.eq(organization_id.to_string()))
+                .filter(authorizations::organization_id.eq(organization_id))
                 .filter(authorizations::start_block_num.le(head_block_num))
                 .filter(authorizations::end_block_num.gt(head_block_num))
-                .load::<Authorization>(&*conn)
+                .load::<Authorization>(&**conn)
                 .map_err(|err| ApiError::InternalError(err.to_string()))?;
 
             let data = match org.organization_type {
                 OrganizationTypeEnum::Factory => {
                     let address_results = addresses::table
                         .select(ADDRESS_COLUMNS)
-                        .filter(addresses::organization_id.eq(organization_id.clone()))
+                        .filter(addresses::organization_id.eq(organization_id))
                         .filter(addresses::start_block_num.le(head_block_num))
                         .filter(addresses::end_block_num.gt(head_block_num))
-                        .first::<Address>(&*conn)
+                        .first::<Address>(&**conn)
                         .optional()
                         .map_err(|err| ApiError::InternalError(err.to_string()))?
                         .unwrap_or_else(Address::default);
@@ -390,7 +457,7 @@ pub fn fetch_organization_with_params(
                         .filter(assertions::start_block_num.le(head_block_num))
                         .filter(assertions::end_block_num.gt(head_block_num))
                         .select(assertions::assertion_id)
-                        .first::<String>(&*conn)
+                        .first::<String>(&**conn)
                         .optional()
                         .map_err(|err| ApiError::InternalError(err.to_string()))?;
                     json!(ApiFactory::with_assertion(
@@ -415,28 +482,119 @@ pub fn fetch_organization_with_params(
                 OrganizationTypeEnum::UnsetType => json!({}),
             };
 
-            Ok(json!({ "data": data,
-                            "link": link,
-                            "head": head_block_num,}))
+            Ok(Some(data))
         }
-        None => Err(ApiError::NotFound(format!(
+        None => Ok(None),
+    }
+}
+
+/// Returns every version of an organization up to `head` with the range of blocks it was live
+/// for, including changes to its contacts, authorizations and address
+#[get("/organizations/<organization_id>/history?<head>")]
+pub fn fetch_organization_history(
+    organization_id: String,
+    head: Option<i64>,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let head_block_num: i64 = get_head_block_num(head, &conn)?;
+    let mut ranges = organizations::table
+        .select((organizations::start_block_num, organizations::end_block_num))
+        .filter(organizations::organization_id.eq(&organization_id))
+        .load::<(i64, i64)>(&*conn)?;
+    ranges.extend(
+        contacts::table
+            .select((contacts::start_block_num, contacts::end_block_num))
+            .filter(contacts::organization_id.eq(&organization_id))
+            .load::<(i64, i64)>(&*conn)?,
+    );
+    ranges.extend(
+        authorizations::table
+            .select((
+                authorizations::start_block_num,
+                authorizations::end_block_num,
+            ))
+            .filter(authorizations::organization_id.eq(&organization_id))
+            .load::<(i64, i64)>(&*conn)?,
+    );
+    ranges.extend(
+        addresses::table
+            .select((addresses::start_block_num, addresses::end_block_num))
+            .filter(addresses::organization_id.eq(&organization_id))
+            .load::<(i64, i64)>(&*conn)?,
+    );
+    ranges.extend(
+        assertions::table
+            .select((assertions::start_block_num, assertions::end_block_num))
+            .filter(assertions::object_id.eq(&organization_id))
+            .load::<(i64, i64)>(&*conn)?,
+    );
+
+    let versions = versions(&ranges, head_block_num, |block_num| {
+        load_organization(&conn, &organization_id, block_num)
+    })?;
+    if versions.is_empty() {
+        return Err(ApiError::NotFound(format!(
             "No organization with the organization ID {} exists",
             organization_id
-        ))),
+        )));
     }
+
+    Ok(json!({
+        "data": versions,
+        "link": format!("/api/organizations/{}/history?head={}", organization_id, head_block_num),
+        "head": head_block_num,
+    }))
+}
+
+/// Returns the changes to an organization, including its contacts, authorizations and
+/// address, between the `from` block and the `to` block, which defaults to the latest block
+#[get("/organizations/<organization_id>/diff?<from>&<to>")]
+pub fn fetch_organization_diff(
+    organization_id: String,
+    from: Option<i64>,
+    to: Option<i64>,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let (from, to) = diff_range(from, to, &conn)?;
+    let before = load_organization(&conn, &organization_id, from)?;
+    let after = load_organization(&conn, &organization_id, to)?;
+    if before.is_none() && after.is_none() {
+        return Err(ApiError::NotFound(format!(
+            "No organization with the organization ID {} exists",
+            organization_id
+        )));
+    }
+
+    Ok(json!({
+        "data": {
+            "from": from,
+            "to": to,
+            "changes": diff(before, after),
+        },
+        "link": format!("/api/organizations/{}/diff?from={}&to={}", organization_id, from, to),
+        "head": to,
+    }))
 }
 
 #[derive(Default, FromForm, Clone)]
 pub struct OrganizationParams {
     name: Option<String>,
     organization_type: Option<i64>,
+    expand: Option<String>,
+    fields: Option<String>,
     limit: Option<i64>,
     offset: Option<i64>,
     head: Option<i64>,
 }
 
 #[get("/organizations")]
-pub fn list_organizations(conn: DbConn) -> Result<JsonValue, ApiError> {
+pub fn list_organizations(conn: DbConn) -> Result<Negotiated, ApiError> {
     list_organizations_with_params(None, conn)
 }
 
@@ -444,7 +602,7 @@ pub fn list_organizations(conn: DbConn) -> Result<JsonValue, ApiError> {
 pub fn list_organizations_with_params(
     params: Option<Form<OrganizationParams>>,
     conn: DbConn,
-) -> Result<JsonValue, ApiError> {
+) -> Result<Negotiated, ApiError> {
     // Increment HTTP request count for Prometheus metrics
     increment_http_req();
 
@@ -452,6 +610,8 @@ pub fn list_organizations_with_params(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
+    let expansions = Expansions::parse(Resource::Organization, &params.expand)?;
+    let fields = Fields::parse(&params.fields);
     let head_block_num: i64 = get_head_block_num(params.head, &conn)?;
 
     let mut organizations_query = organizations::table
@@ -485,139 +645,212 @@ pub fn list_organizations_with_params(
         .count()
         .get_result(&*conn)
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
-    let paging_info = apply_paging(link_params, head_block_num, total_count)?;
+    let paging_info = apply_paging(&expansions, link_params, head_block_num, total_count)?;
 
     organizations_query = organizations_query.limit(params.limit.unwrap_or(DEFAULT_LIMIT));
     organizations_query = organizations_query.offset(params.offset.unwrap_or(DEFAULT_OFFSET));
 
     let organization_results: Vec<Organization> =
         organizations_query.load::<Organization>(&*conn)?;
-
-    let mut contact_results: HashMap<String, Vec<Contact>> = contacts::table
-        .filter(contacts::start_block_num.le(head_block_num))
-        .filter(contacts::end_block_num.gt(head_block_num))
-        .filter(
-            contacts::organization_id.eq_any(
-                organization_results
-                    .iter()
-                    .map(|org| org.organization_id.to_string())
-                    .collect::<Vec<String>>(),
-            ),
-        )
-        .order_by(contacts::organization_id.asc())
-        .load::<Contact>(&*conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, contact| {
-            acc.entry(contact.organization_id.to_string())
-                .or_insert_with(Vec::new)
-                .push(contact);
-            acc
-        });
-
-    let mut authorization_results: HashMap<String, Vec<Authorization>> = authorizations::table
-        .filter(authorizations::start_block_num.le(head_block_num))
-        .filter(authorizations::end_block_num.gt(head_block_num))
-        .filter(
-            authorizations::organization_id.eq_any(
-                organization_results
-                    .iter()
-                    .map(|org| org.organization_id.to_string())
-                    .collect::<Vec<String>>(),
-            ),
-        )
-        .order_by(authorizations::organization_id.asc())
-        .load::<Authorization>(&*conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, authorization| {
-            acc.entry(authorization.organization_id.to_string())
-                .or_insert_with(Vec::new)
-                .push(authorization);
-            acc
-        });
-
-    let mut address_results: HashMap<String, Address> = addresses::table
-        .select(ADDRESS_COLUMNS)
-        .filter(addresses::start_block_num.le(head_block_num))
-        .filter(addresses::end_block_num.gt(head_block_num))
-        .filter(
-            addresses::organization_id.eq_any(
-                organization_results
-                    .iter()
-                    .map(|org| org.organization_id.to_string())
-                    .collect::<Vec<String>>(),
-            ),
-        )
-        .order_by(addresses::organization_id.asc())
-        .load::<Address>(&*conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, address| {
-            acc.insert(address.organization_id.to_string(), address);
-            acc
-        });
-
-    let mut assertion_results = assertions::table
-        .filter(assertions::start_block_num.le(head_block_num))
-        .filter(assertions::end_block_num.gt(head_block_num))
-        .filter(
-            assertions::object_id.eq_any(
-                organization_results
-                    .iter()
-                    .map(|org| org.organization_id.to_string())
-                    .collect::<Vec<String>>(),
-            ),
-        )
-        .order_by(assertions::object_id.asc())
-        .select((assertions::object_id, assertions::assertion_id))
-        .load::<(String, String)>(&*conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
+    let mut data = organizations_json(&conn, organization_results, head_block_num, &fields)?
         .into_iter()
-        .fold(HashMap::new(), |mut acc, (object_id, assertion_id)| {
-            acc.insert(object_id, assertion_id);
-            acc
-        });
+        .map(|org| org.0)
+        .collect::<Vec<_>>();
+    expansions.apply(&conn, &mut data, head_block_num)?;
+    fields.select(&mut data);
 
-    Ok(json!({
-        "data": organization_results.into_iter()
-            .map(|org| {
-                let org_id = org.organization_id.clone();
-                match org.organization_type {
-                    OrganizationTypeEnum::Factory => {
-                        json!(ApiFactory::with_assertion(
-                            org,
-                            address_results.remove(&org_id).unwrap_or_else(Address::default),
-                            contact_results.remove(&org_id).unwrap_or_else(Vec::new),
-                            authorization_results.remove(&org_id).unwrap_or_else(Vec::new),
-                            assertion_results.remove(&org_id)
-                        ))
-                    }
-                    OrganizationTypeEnum::CertifyingBody => {
-                        json!(ApiCertifyingBody::from(
-                            org,
-                            contact_results.remove(&org_id).unwrap_or_else(Vec::new),
-                            authorization_results.remove(&org_id).unwrap_or_else(Vec::new),
-                        ))
-                    }
-                    OrganizationTypeEnum::StandardsBody => {
-                        json!(ApiStandardsBody::from(
-                            org,
-                            contact_results.remove(&org_id).unwrap_or_else(Vec::new),
-                            authorization_results.remove(&org_id).unwrap_or_else(Vec::new),
-                        ))
-                    }
-                    OrganizationTypeEnum::Ingestion => json!({}),
-                    OrganizationTypeEnum::UnsetType => json!({})
-                }
-            }).collect::<Vec<_>>(),
+    Ok(Negotiated(json!({
+        "data": data,
         "link": paging_info.get("link"),
         "head": head_block_num,
         "paging": paging_info.get("paging")
-    }))
+    })))
+}
+
+/// Loads the organizations with the given ids as they were at `head_block_num`, keyed by id
+pub fn load_organizations(
+    conn: &DbConn,
+    organization_ids: &[String],
+    head_block_num: i64,
+) -> Result<HashMap<String, JsonValue>, ApiError> {
+    let organization_results: Vec<Organization> = organizations::table
+        .filter(organizations::organization_id.eq_any(organization_ids))
+        .filter(organizations::start_block_num.le(head_block_num))
+        .filter(organizations::end_block_num.gt(head_block_num))
+        .load::<Organization>(&**conn)?;
+    let ids: Vec<String> = organization_results
+        .iter()
+        .map(|org| org.organization_id.clone())
+        .collect();
+
+    Ok(ids
+        .into_iter()
+        .zip(organizations_json(
+            conn,
+            organization_results,
+            head_block_num,
+            &Fields::default(),
+        )?)
+        .collect())
+}
+
+/// Completes organizations with their contacts, authorizations and, for factories, their
+/// addresses and assertions, loading each that is among `fields` with one query for all of
+/// the organizations
+fn organizations_json(
+    conn: &DbConn,
+    organization_results: Vec<Organization>,
+    head_block_num: i64,
+    fields: &Fields,
+) -> Result<Vec<JsonValue>, ApiError> {
+    let mut contact_results: HashMap<String, Vec<Contact>> = if fields.includes("contacts") {
+        contacts::table
+            .filter(contacts::start_block_num.le(head_block_num))
+            .filter(contacts::end_block_num.gt(head_block_num))
+            .filter(
+                contacts::organization_id.eq_any(
+                    organization_results
+                        .iter()
+                        .map(|org| org.organization_id.to_string())
+                        .collect::<Vec<String>>(),
+                ),
+            )
+            .order_by(contacts::organization_id.asc())
+            .load::<Contact>(&**conn)
+            .map_err(|err| ApiError::InternalError(err.to_string()))?
+            .into_iter()
+            .fold(HashMap::new(), |mut acc, contact| {
+                acc.entry(contact.organization_id.to_string())
+                    .or_insert_with(Vec::new)
+                    .push(contact);
+                acc
+            })
+    } else {
+        HashMap::new()
+    };
+
+    let mut authorization_results: HashMap<String, Vec<Authorization>> =
+        if fields.includes("authorizations") {
+            authorizations::table
+                .filter(authorizations::start_block_num.le(head_block_num))
+                .filter(authorizations::end_block_num.gt(head_block_num))
+                .filter(
+                    authorizations::organization_id.eq_any(
+                        organization_results
+                            .iter()
+                            .map(|org| org.organization_id.to_string())
+                            .collect::<Vec<String>>(),
+                    ),
+                )
+                .order_by(authorizations::organization_id.asc())
+                .load::<Authorization>(&**conn)
+                .map_err(|err| ApiError::InternalError(err.to_string()))?
+                .into_iter()
+                .fold(HashMap::new(), |mut acc, authorization| {
+                    acc.entry(authorization.organization_id.to_string())
+                        .or_insert_with(Vec::new)
+                        .push(authorization);
+                    acc
+                })
+        } else {
+            HashMap::new()
+        };
+
+    let mut address_results: HashMap<String, Address> = if fields.includes("address") {
+        addresses::table
+            .select(ADDRESS_COLUMNS)
+            .filter(addresses::start_block_num.le(head_block_num))
+            .filter(addresses::end_block_num.gt(head_block_num))
+            .filter(
+                addresses::organization_id.eq_any(
+                    organization_results
+                        .iter()
+                        .map(|org| org.organization_id.to_string())
+                        .collect::<Vec<String>>(),
+                ),
+            )
+            .order_by(addresses::organization_id.asc())
+            .load::<Address>(&**conn)
+            .map_err(|err| ApiError::InternalError(err.to_string()))?
+            .into_iter()
+            .fold(HashMap::new(), |mut acc, address| {
+                acc.insert(address.organization_id.to_string(), address);
+                acc
+            })
+    } else {
+        HashMap::new()
+    };
+
+    let mut assertion_results = if fields.includes("assertion_id") {
+        assertions::table
+            .filter(assertions::start_block_num.le(head_block_num))
+            .filter(assertions::end_block_num.gt(head_block_num))
+            .filter(
+                assertions::object_id.eq_any(
+                    organization_results
+                        .iter()
+                        .map(|org| org.organization_id.to_string())
+                        .collect::<Vec<String>>(),
+                ),
+            )
+            .order_by(assertions::object_id.asc())
+            .select((assertions::object_id, assertions::assertion_id))
+            .load::<(String, String)>(&**conn)
+            .map_err(|err| ApiError::InternalError(err.to_string()))?
+            .into_iter()
+            .fold(HashMap::new(), |mut acc, (object_id, assertion_id)| {
+                acc.insert(object_id, assertion_id);
+                acc
+            })
+    } else {
+        HashMap::new()
+    };
+
+    Ok(organization_results
+        .into_iter()
+        .map(|org| {
+            let org_id = org.organization_id.clone();
+            match org.organization_type {
+                OrganizationTypeEnum::Factory => {
+                    json!(ApiFactory::with_assertion(
+                        org,
+                        address_results
+                            .remove(&org_id)
+                            .unwrap_or_else(Address::default),
+                        contact_results.remove(&org_id).unwrap_or_else(Vec::new),
+                        authorization_results
+                            .remove(&org_id)
+                            .unwrap_or_else(Vec::new),
+                        assertion_results.remove(&org_id)
+                    ))
+                }
+                OrganizationTypeEnum::CertifyingBody => {
+                    json!(ApiCertifyingBody::from(
+                        org,
+                        contact_results.remove(&org_id).unwrap_or_else(Vec::new),
+                        authorization_results
+                            .remove(&org_id)
+                            .unwrap_or_else(Vec::new),
+                    ))
+                }
+                OrganizationTypeEnum::StandardsBody => {
+                    json!(ApiStandardsBody::from(
+                        org,
+                        contact_results.remove(&org_id).unwrap_or_else(Vec::new),
+                        authorization_results
+                            .remove(&org_id)
+                            .unwrap_or_else(Vec::new),
+                    ))
+                }
+                OrganizationTypeEnum::Ingestion => json!({}),
+                OrganizationTypeEnum::UnsetType => json!({}),
+            }
+        })
+        .collect())
 }
 
 fn apply_paging(
+    expansions: &Expansions,
     params: OrganizationParams,
     head: i64,
     total_count: i64,
@@ -627,6 +860,12 @@ fn apply_paging(
     if let Some(name) = params.name {
         link = format!("{}name={}&", link, Uri::percent_encode(&name));
     }
+    if !expansions.is_empty() {
+        link = format!("{}expand={}&", link, expansions.to_param());
+    }
+    if let Some(fields) = params.fields {
+        link = format!("{}fields={}&", link, Uri::percent_encode(&fields));
+    }
     link = format!("{}head={}&", link, head);
 
     get_response_paging_info(params.limit, params.offset, link, total_count)
@@ -639,7 +878,8 @@ mod tests {
     use database_manager::models::{
         NewAddress, NewAssertion, NewAuthorization, NewContact, NewOrganization,
     };
-    use route_handlers::tests::{get_connection_pool, run_test};
+    use route_handlers::factories::tests::setup_factory_db;
+    use route_handlers::tests::{get_connection_pool, insert_blocks, run_test};
 
     #[test]
     /// Test that a Get to `/api/organizations/{org_id}` succeeds
@@ -1060,4 +1300,140 @@ mod tests {
             );
         })
     }
+
+    #[test]
+    /// Test that a GET to `/api/organizations/{org_id}/history` returns a version for every
+    /// change to the factory's address with the blocks it was live for
+    fn test_organization_history() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            insert_blocks(&conn, &[2, 3]);
+            diesel::update(
+                addresses::table.filter(addresses::organization_id.eq("test_factory_id")),
+            )
+            .set(addresses::end_block_num.eq(3))
+            .execute(&conn)
+            .unwrap();
+            diesel::insert_into(addresses::table)
+                .values(NewAddress {
+                    start_block_num: 3,
+                    end_block_num: std::i64::MAX,
+                    organization_id: "test_factory_id".to_string(),
+                    street_line_1: "test_factory_street_line_1".to_string(),
+                    street_line_2: None,
+                    city: "moved_city".to_string(),
+                    state_province: None,
+                    country: "test_factory_country".to_string(),
+                    postal_code: None,
+                })
+                .execute(&conn)
+                .unwrap();
+
+            let res = fetch_organization_history("test_factory_id".to_string(), None, DbConn(conn))
+                .unwrap();
+
+            let versions = res.get("data").unwrap().as_array().unwrap();
+            assert_eq!(versions.len(), 2);
+            assert_eq!(versions[0]["start_block_num"], json!(1).0);
+            assert_eq!(versions[0]["end_block_num"], json!(3).0);
+            assert_eq!(
+                versions[0]["data"]["address"]["city"],
+                json!("test_factory_city").0
+            );
+            assert_eq!(versions[1]["start_block_num"], json!(3).0);
+            assert!(versions[1]["end_block_num"].is_null());
+            assert_eq!(
+                versions[1]["data"]["address"]["city"],
+                json!("moved_city").0
+            );
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/organizations/{org_id}/history` for an unknown organization
+    /// returns a `NotFound` error
+    fn test_organization_history_not_found() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            match fetch_organization_history("unknown_id".to_string(), None, DbConn(conn)) {
+                Err(ApiError::NotFound(_)) => (),
+                _ => panic!("Expected a NotFound error"),
+            }
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/organizations/{org_id}/diff` returns the fields of the
+    /// factory's address that changed between the two blocks
+    fn test_organization_diff() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            insert_blocks(&conn, &[2]);
+            diesel::update(
+                addresses::table.filter(addresses::organization_id.eq("test_factory_id")),
+            )
+            .set(addresses::end_block_num.eq(2))
+            .execute(&conn)
+            .unwrap();
+            diesel::insert_into(addresses::table)
+                .values(NewAddress {
+                    start_block_num: 2,
+                    end_block_num: std::i64::MAX,
+                    organization_id: "test_factory_id".to_string(),
+                    street_line_1: "test_factory_street_line_1".to_string(),
+                    street_line_2: None,
+                    city: "moved_city".to_string(),
+                    state_province: Some("test_factory_province".to_string()),
+                    country: "test_factory_country".to_string(),
+                    postal_code: Some("test_factory_code".to_string()),
+                })
+                .execute(&conn)
+                .unwrap();
+
+            let res =
+                fetch_organization_diff("test_factory_id".to_string(), Some(1), None, DbConn(conn))
+                    .unwrap();
+
+            assert_eq!(res.get("head").unwrap(), &json!(2).0);
+            assert_eq!(
+                res.get("data").unwrap()["changes"],
+                json!([{
+                    "path": "/address/city",
+                    "from": "test_factory_city",
+                    "to": "moved_city",
+                }])
+                .0
+            );
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/organizations/{org_id}/diff` without a `from` block returns a
+    /// `BadRequest` error
+    fn test_organization_diff_without_from() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            match fetch_organization_diff("test_factory_id".to_string(), None, None, DbConn(conn)) {
+                Err(ApiError::BadRequest(_)) => (),
+                _ => panic!("Expected a BadRequest error"),
+            }
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/organizations/{org_id}/diff` from a negative block returns a
+    /// `BadRequest` error, and from a block that was never committed a `NotFound` error
+    fn test_organization_diff_invalid_from() {
+        run_test(|| {
+            let conn = DbConn(setup_factory_db(false));
+            match diff_range(Some(-1), None, &conn) {
+                Err(ApiError::BadRequest(_)) => (),
+                _ => panic!("Expected a BadRequest error"),
+            }
+            match diff_range(Some(5), None, &conn) {
+                Err(ApiError::NotFound(_)) => (),
+                _ => panic!("Expected a NotFound error"),
+            }
+        })
+    }
 }
@@ -20,6 +20,18 @@ lazy_static! {
         &["user"]
     )
     .unwrap();
+    static ref KEY_ACCESS_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "consensource_key_accesses",
+        "Number of times each user's escrowed private key has been accessed",
+        &["action", "user"]
+    )
+    .unwrap();
+    static ref CACHE_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "consensource_api_response_cache_lookups",
+        "Number of response cache hits and misses of each route",
+        &["route", "result"]
+    )
+    .unwrap();
 }
 
 #[get("/prom_metrics")]
@@ -46,6 +58,18 @@ pub fn increment_signin(username: &str) {
     SIGNIN_COUNTER_VEC.with_label_values(&[&username]).inc();
 }
 
+pub fn increment_key_access(action: &str, username: &str) {
+    KEY_ACCESS_COUNTER_VEC
+        .with_label_values(&[&action, &username])
+        .inc();
+}
+
+pub fn increment_cache_lookup(route: &str, result: &str) {
+    CACHE_COUNTER_VEC
+        .with_label_values(&[&route, &result])
+        .inc();
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -71,4 +95,18 @@ mod tests {
         assert!(get_metrics().contains("consensource_signins"));
         assert!(get_metrics().contains("testuser"));
     }
+
+    #[test]
+    fn test_get_metrics_key_access() {
+        increment_key_access("read", "keyuser");
+        assert!(get_metrics().contains("consensource_key_accesses"));
+        assert!(get_metrics().contains("keyuser"));
+    }
+
+    #[test]
+    fn test_get_metrics_cache_lookup() {
+        increment_cache_lookup("factories", "hit");
+        assert!(get_metrics().contains("consensource_api_response_cache_lookups"));
+        assert!(get_metrics().contains("factories"));
+    }
 }
@@ -1,23 +1,20 @@
 use database::DbConn;
 use database_manager::custom_types::RequestStatusEnum;
-use database_manager::models::{
-    Address, Authorization, Contact, Organization, Request, Standard, StandardVersion,
-    ADDRESS_COLUMNS,
-};
-use database_manager::tables_schema::{
-    addresses, authorizations, contacts, organizations, requests, standard_versions, standards,
-};
+use database_manager::models::Request;
+use database_manager::tables_schema::requests;
 use diesel::prelude::*;
 use errors::ApiError;
+use expand::{Expansions, Resource};
+use fields::Fields;
+use history::versions;
+use negotiation::Negotiated;
 use paging::*;
 use rocket::http::uri::Uri;
 use rocket::request::Form;
 use rocket_contrib::json::JsonValue;
-use std::collections::HashMap;
+use std::slice;
 
-use route_handlers::organizations::ApiFactory;
 use route_handlers::prom::increment_http_req;
-use route_handlers::standards::ApiStandard;
 
 #[get("/requests/<request_id>")]
 pub fn fetch_request(request_id: String, conn: DbConn) -> Result<JsonValue, ApiError> {
@@ -37,6 +34,8 @@ pub fn fetch_request_with_head_param(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
+    let expansions = parse_expansions(&head_param.expand)?;
+    let fields = Fields::parse(&head_param.fields);
     let head_block_num: i64 = get_head_block_num(head_param.head, &conn)?;
 
     let request = requests::table
@@ -48,58 +47,23 @@ pub fn fetch_request_with_head_param(
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
 
     let mut link = format!("/api/requests/{}?head={}", request_id, head_block_num);
-    if let Some(expand) = head_param.expand {
-        link = format!("{}&expand={}", link, expand);
+    if !expansions.is_empty() {
+        link = format!("{}&expand={}", link, expansions.to_param());
+    }
+    if let Some(ref fields) = head_param.fields {
+        link = format!("{}&fields={}", link, Uri::percent_encode(fields));
     }
 
     match request {
         Some(request) => {
-            if let Some(true) = head_param.expand {
-                let expansions = fetch_expansions(
-                    &conn,
-                    &[request.factory_id.clone()],
-                    &[request.standard_id.clone()],
-                    head_block_num,
-                )?;
-                let factory_results = expansions.factory_results;
-                let contact_results = expansions.contact_results;
-                let authorization_results = expansions.authorization_results;
-                let address_results = expansions.address_results;
-                let standard_results = expansions.standard_results;
-                let standard_version_results = expansions.standard_version_results;
-                let factory_id = request.factory_id.clone();
-                let standard_id = request.standard_id.clone();
-                let factory = ApiFactory::from_ref(
-                    factory_results
-                        .get(&factory_id)
-                        .expect("Error getting factory"),
-                    address_results
-                        .get(&factory_id)
-                        .unwrap_or(&Address::default()),
-                    contact_results.get(&factory_id).unwrap_or(&vec![]),
-                    authorization_results.get(&factory_id).unwrap_or(&vec![]),
-                );
-                let standard = ApiStandard::from((
-                    standard_results
-                        .get(&standard_id)
-                        .expect("Error getting standard"),
-                    standard_version_results
-                        .get(&standard_id)
-                        .expect("Error getting standard versions"),
-                ));
-
-                Ok(json!({
-                    "data": ApiRequest::with_expansion(request, factory, standard),
-                    "link": link,
-                    "head": head_block_num,
-                }))
-            } else {
-                Ok(json!({
-                    "data": ApiRequest::from(request),
-                    "link": link,
-                    "head": head_block_num,
-                }))
-            }
+            let mut data = json!(ApiRequest::from(request)).0;
+            expansions.apply(&conn, slice::from_mut(&mut data), head_block_num)?;
+            fields.select(slice::from_mut(&mut data));
+            Ok(json!({
+                "data": data,
+                "link": link,
+                "head": head_block_num,
+            }))
         }
         None => Err(ApiError::NotFound(format!(
             "No certification request with the ID {} exists",
@@ -108,20 +72,83 @@ pub fn fetch_request_with_head_param(
     }
 }
 
+/// Loads the certification request as it was at `head_block_num`, without expansions
+pub fn load_request(
+    conn: &DbConn,
+    request_id: &str,
+    head_block_num: i64,
+) -> Result<Option<JsonValue>, ApiError> {
+    Ok(requests::table
+        .filter(requests::request_id.eq(request_id))
+        .filter(requests::start_block_num.le(head_block_num))
+        .filter(requests::end_block_num.gt(head_block_num))
+        .first::<Request>(&**conn)
+        .optional()?
+        .map(|request| json!(ApiRequest::from(request))))
+}
+
+/// Returns every version of a certification request up to `head` with the range of blocks it
+/// was live for, e.g. to see when its status changed
+#[get("/requests/<request_id>/history?<head>")]
+pub fn fetch_request_history(
+    request_id: String,
+    head: Option<i64>,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let head_block_num: i64 = get_head_block_num(head, &conn)?;
+    let ranges = requests::table
+        .select((requests::start_block_num, requests::end_block_num))
+        .filter(requests::request_id.eq(&request_id))
+        .load::<(i64, i64)>(&*conn)?;
+
+    let versions = versions(&ranges, head_block_num, |block_num| {
+        load_request(&conn, &request_id, block_num)
+    })?;
+    if versions.is_empty() {
+        return Err(ApiError::NotFound(format!(
+            "No certification request with the ID {} exists",
+            request_id
+        )));
+    }
+
+    Ok(json!({
+        "data": versions,
+        "link": format!("/api/requests/{}/history?head={}", request_id, head_block_num),
+        "head": head_block_num,
+    }))
+}
+
 #[derive(Default, FromForm, Clone)]
 pub struct CertRequestParams {
     factory_id: Option<String>,
-    expand: Option<bool>,
+    expand: Option<String>,
+    fields: Option<String>,
     limit: Option<i64>,
     offset: Option<i64>,
     head: Option<i64>,
 }
 
+/// Parses the expansions of a request. `expand=true` predates expanding by relation and is
+/// still accepted as expanding the factory and the standard with its versions.
+fn parse_expansions(expand: &Option<String>) -> Result<Expansions, ApiError> {
+    match expand.as_ref().map(String::as_str) {
+        Some("true") => Expansions::parse(
+            Resource::Request,
+            &Some("factory,standard.versions".to_string()),
+        ),
+        Some("false") => Expansions::parse(Resource::Request, &None),
+        _ => Expansions::parse(Resource::Request, expand),
+    }
+}
+
 #[derive(Serialize)]
 pub struct ApiRequest {
     id: String,
-    factory: FactoryExpansion,
-    standard: StandardExpansion,
+    factory: ApiRef,
+    standard: ApiRef,
     status: RequestStatusEnum,
     request_date: i64,
 }
@@ -130,14 +157,14 @@ impl ApiRequest {
     pub fn from(req: Request) -> Self {
         ApiRequest {
             id: req.request_id,
-            factory: FactoryExpansion::Ref {
+            factory: ApiRef {
                 id: req.factory_id.clone(),
                 link: format!(
                     "/api/organizations/{}",
                     Uri::percent_encode(&req.factory_id)
                 ),
             },
-            standard: StandardExpansion::Ref {
+            standard: ApiRef {
                 id: req.standard_id.clone(),
                 link: format!("/api/standards/{}", Uri::percent_encode(&req.standard_id)),
             },
@@ -145,43 +172,26 @@ impl ApiRequest {
             request_date: req.request_date,
         }
     }
-
-    pub fn with_expansion(req: Request, factory: ApiFactory, standard: ApiStandard) -> Self {
-        ApiRequest {
-            id: req.request_id,
-            factory: FactoryExpansion::Expanded(Box::new(factory)),
-            standard: StandardExpansion::Expanded(standard),
-            status: req.status,
-            request_date: req.request_date,
-        }
-    }
 }
 
+/// A resource a request refers to, which is replaced by the resource itself when it is expanded
 #[derive(Serialize)]
-#[serde(untagged)]
-pub enum FactoryExpansion {
-    Ref { id: String, link: String },
-    Expanded(Box<ApiFactory>),
-}
-
-#[derive(Serialize)]
-#[serde(untagged)]
-pub enum StandardExpansion {
-    Ref { id: String, link: String },
-    Expanded(ApiStandard),
+pub struct ApiRef {
+    id: String,
+    link: String,
 }
 
 #[get("/requests")]
-pub fn list_requests(conn: DbConn) -> Result<JsonValue, ApiError> {
-    query_requests(None, conn)
+pub fn list_requests(conn: DbConn) -> Result<Negotiated, ApiError> {
+    query_requests(None, conn).map(Negotiated)
 }
 
 #[get("/requests?<params..>")]
 pub fn list_request_with_params(
     params: Option<Form<CertRequestParams>>,
     conn: DbConn,
-) -> Result<JsonValue, ApiError> {
-    query_requests(params, conn)
+) -> Result<Negotiated, ApiError> {
+    query_requests(params, conn).map(Negotiated)
 }
 
 fn query_requests(
@@ -195,8 +205,9 @@ fn query_requests(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
+    let expansions = parse_expansions(&params.expand)?;
+    let fields = Fields::parse(&params.fields);
     let head_block_num: i64 = get_head_block_num(params.head, &conn)?;
-    let expand = params.expand.unwrap_or(false);
 
     let mut requests_query = requests::table
         .filter(requests::start_block_num.le(head_block_num))
@@ -220,7 +231,7 @@ fn query_requests(
         .count()
         .get_result(&*conn)
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
-    let paging_info = apply_paging(link_params, head_block_num, total_count)?;
+    let paging_info = apply_paging(&expansions, link_params, head_block_num, total_count)?;
 
     requests_query = requests_query.limit(params.limit.unwrap_or(DEFAULT_LIMIT));
     requests_query = requests_query.offset(params.offset.unwrap_or(DEFAULT_OFFSET));
@@ -229,164 +240,23 @@ fn query_requests(
         .load::<Request>(&*conn)
         .map_err(|err| ApiError::InternalError(err.to_string()))?;
 
-    if expand {
-        let factory_ids = request_results
-            .iter()
-            .map(|request| request.factory_id.to_string())
-            .collect::<Vec<String>>();
-        let standard_ids = request_results
-            .iter()
-            .map(|request| request.standard_id.to_string())
-            .collect::<Vec<String>>();
-        let expansions = fetch_expansions(&conn, &factory_ids, &standard_ids, head_block_num)?;
-        let factory_results = expansions.factory_results;
-        let contact_results = expansions.contact_results;
-        let authorization_results = expansions.authorization_results;
-        let address_results = expansions.address_results;
-        let standard_results = expansions.standard_results;
-        let standard_version_results = expansions.standard_version_results;
-
-        Ok(json!({
-            "data": request_results.into_iter()
-                .map(|request| {
-                    let factory_id = request.factory_id.clone();
-                    let standard_id = request.standard_id.clone();
-                    let factory = ApiFactory::from_ref(
-                        factory_results.get(&factory_id).expect("Error getting factory"),
-                        address_results.get(&factory_id).unwrap_or(&Address::default()),
-                        contact_results.get(&factory_id).unwrap_or(&vec![]),
-                        authorization_results.get(&factory_id).unwrap_or(&vec![]));
-                    let standard = ApiStandard::from((
-                        standard_results.get(&standard_id).expect("Error getting standard"),
-                        standard_version_results.get(&standard_id).expect("Error getting standard versions")
-                    ));
-                    json!(ApiRequest::with_expansion(request, factory, standard))
-                }).collect::<Vec<_>>(),
-            "link": paging_info.get("link"),
-            "head": head_block_num,
-            "paging":paging_info.get("paging")
-        }))
-    } else {
-        Ok(json!({
-            "data": request_results.into_iter()
-                .map(ApiRequest::from).collect::<Vec<_>>(),
-            "link": paging_info.get("link"),
-            "head": head_block_num,
-            "paging":paging_info.get("paging")
-        }))
-    }
-}
-
-pub struct Expansions {
-    factory_results: HashMap<String, Organization>,
-    contact_results: HashMap<String, Vec<Contact>>,
-    authorization_results: HashMap<String, Vec<Authorization>>,
-    address_results: HashMap<String, Address>,
-    standard_results: HashMap<String, Standard>,
-    standard_version_results: HashMap<String, Vec<StandardVersion>>,
-}
-
-fn fetch_expansions(
-    conn: &DbConn,
-    factory_ids: &[String],
-    standard_ids: &[String],
-    head_block_num: i64,
-) -> Result<Expansions, ApiError> {
-    let factory_results: HashMap<String, Organization> = organizations::table
-        .filter(organizations::start_block_num.le(head_block_num))
-        .filter(organizations::end_block_num.gt(head_block_num))
-        .filter(organizations::organization_id.eq_any(factory_ids))
-        .order_by(organizations::organization_id.asc())
-        .load::<Organization>(&**conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, factory| {
-            acc.insert(factory.organization_id.to_string(), factory);
-            acc
-        });
-
-    let contact_results: HashMap<String, Vec<Contact>> = contacts::table
-        .filter(contacts::start_block_num.le(head_block_num))
-        .filter(contacts::end_block_num.gt(head_block_num))
-        .filter(contacts::organization_id.eq_any(factory_ids))
-        .order_by(contacts::organization_id.asc())
-        .load::<Contact>(&**conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, contact| {
-            acc.entry(contact.organization_id.to_string())
-                .or_insert_with(Vec::new)
-                .push(contact);
-            acc
-        });
-
-    let authorization_results: HashMap<String, Vec<Authorization>> = authorizations::table
-        .filter(authorizations::start_block_num.le(head_block_num))
-        .filter(authorizations::end_block_num.gt(head_block_num))
-        .filter(authorizations::organization_id.eq_any(factory_ids))
-        .order_by(authorizations::organization_id.asc())
-        .load::<Authorization>(&**conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
+    let mut data = request_results
         .into_iter()
-        .fold(HashMap::new(), |mut acc, authorization| {
-            acc.entry(authorization.organization_id.to_string())
-                .or_insert_with(Vec::new)
-                .push(authorization);
-            acc
-        });
-
-    let address_results: HashMap<String, Address> = addresses::table
-        .select(ADDRESS_COLUMNS)
-        .filter(addresses::start_block_num.le(head_block_num))
-        .filter(addresses::end_block_num.gt(head_block_num))
-        .filter(addresses::organization_id.eq_any(factory_ids))
-        .order_by(addresses::organization_id.asc())
-        .load::<Address>(&**conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, address| {
-            acc.insert(address.organization_id.to_string(), address);
-            acc
-        });
-
-    let standard_results: HashMap<String, Standard> = standards::table
-        .filter(standards::start_block_num.le(head_block_num))
-        .filter(standards::end_block_num.gt(head_block_num))
-        .filter(standards::standard_id.eq_any(standard_ids))
-        .order_by(standards::standard_id.asc())
-        .load::<Standard>(&**conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, standard| {
-            acc.insert(standard.standard_id.to_string(), standard);
-            acc
-        });
-
-    let standard_version_results: HashMap<String, Vec<StandardVersion>> = standard_versions::table
-        .filter(standard_versions::standard_id.eq_any(standard_ids))
-        .order_by(standard_versions::approval_date.desc())
-        .load::<StandardVersion>(&**conn)
-        .map_err(|err| ApiError::InternalError(err.to_string()))?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, standard_version| {
-            acc.entry(standard_version.standard_id.to_string())
-                .or_insert_with(Vec::new)
-                .push(standard_version);
-            acc
-        });
-    let expansions = Expansions {
-        factory_results,
-        contact_results,
-        authorization_results,
-        address_results,
-        standard_results,
-        standard_version_results,
-    };
-
-    Ok(expansions)
+        .map(|request| json!(ApiRequest::from(request)).0)
+        .collect::<Vec<_>>();
+    expansions.apply(&conn, &mut data, head_block_num)?;
+    fields.select(&mut data);
+
+    Ok(json!({
+        "data": data,
+        "link": paging_info.get("link"),
+        "head": head_block_num,
+        "paging":paging_info.get("paging")
+    }))
 }
 
 fn apply_paging(
+    expansions: &Expansions,
     params: CertRequestParams,
     head: i64,
     total_count: i64,
@@ -396,8 +266,11 @@ fn apply_paging(
     if let Some(factory_id) = params.factory_id {
         link = format!("{}factory_id={}&", link, Uri::percent_encode(&factory_id));
     }
-    if let Some(expand) = params.expand {
-        link = format!("{}expand={}&", link, expand);
+    if !expansions.is_empty() {
+        link = format!("{}expand={}&", link, expansions.to_param());
+    }
+    if let Some(fields) = params.fields {
+        link = format!("{}fields={}&", link, Uri::percent_encode(&fields));
     }
     link = format!("{}head={}&", link, head);
 
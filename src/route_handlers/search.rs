@@ -0,0 +1,619 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use database::{certificate_search_score, similarity, DbConn, SEARCH_SETTINGS};
+use database_manager::custom_types::OrganizationTypeEnum;
+use database_manager::tables_schema::{agents, certificates, organizations, standards};
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::Float;
+use diesel_full_text_search::{to_tsquery, to_tsvector, TsVectorExtensions};
+use errors::ApiError;
+use paging::*;
+use rocket::http::uri::Uri;
+use rocket::request::Form;
+use rocket_contrib::json::JsonValue;
+use route_handlers::factories::to_ts_string;
+use route_handlers::prom::increment_http_req;
+use xml::escape_xml;
+
+/// The most hits a page of search results may hold
+const MAX_LIMIT: i64 = 1000;
+
+/// Hits of different types are ranked together in memory, so only this many of the best hits
+/// can be paged through
+const MAX_RESULT_WINDOW: i64 = 10_000;
+
+#[derive(Default, FromForm, Clone)]
+pub struct SearchParams {
+    q: Option<String>,
+    /// Comma separated hit types to search, e.g. `organization,standard`. Defaults to all.
+    types: Option<String>,
+    /// Overrides the configured similarity threshold for fuzzy matches
+    similarity: Option<f32>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    head: Option<i64>,
+}
+
+/// The kinds of records that are searched. Hits with equal scores are ordered by kind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HitType {
+    Organization,
+    Standard,
+    Certificate,
+    Agent,
+}
+
+impl HitType {
+    fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "organization" => Some(HitType::Organization),
+            "standard" => Some(HitType::Standard),
+            "certificate" => Some(HitType::Certificate),
+            "agent" => Some(HitType::Agent),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SearchHit {
+    #[serde(rename = "type")]
+    hit_type: HitType,
+    id: String,
+    title: String,
+    /// The title with each matched search term wrapped in `<em>` tags
+    highlight: String,
+    score: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    organization_type: Option<OrganizationTypeEnum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<String>,
+}
+
+#[get("/search")]
+pub fn search(conn: DbConn) -> Result<JsonValue, ApiError> {
+    search_with_params(None, conn)
+}
+
+#[get("/search?<params..>")]
+pub fn search_with_params(
+    params: Option<Form<SearchParams>>,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let params = match params {
+        Some(param) => param.into_inner(),
+        None => Default::default(),
+    };
+    let query = match params.q {
+        Some(ref q) if !q.trim().is_empty() => q.trim().to_string(),
+        _ => {
+            return Err(ApiError::BadRequest(
+                "A search query must be provided with the `q` param".to_string(),
+            ))
+        }
+    };
+    let hit_types = parse_hit_types(params.types.as_ref().map(String::as_str))?;
+    let threshold = SEARCH_SETTINGS
+        .with_overrides(params.similarity, None, None, None)?
+        .similarity_threshold;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    let offset = params.offset.unwrap_or(DEFAULT_OFFSET);
+    if limit < 1 || limit > MAX_LIMIT {
+        return Err(ApiError::BadRequest(format!(
+            "The limit must be between 1 and {}",
+            MAX_LIMIT
+        )));
+    }
+    if offset < 0 || offset + limit > MAX_RESULT_WINDOW {
+        return Err(ApiError::BadRequest(format!(
+            "Only the first {} hits can be paged through",
+            MAX_RESULT_WINDOW
+        )));
+    }
+    let head_block_num: i64 = get_head_block_num(params.head, &conn)?;
+
+    let search = Search {
+        query: to_ts_string(&query),
+        text: query,
+        threshold,
+        head_block_num,
+    };
+    let (mut hits, total_count) = search_hits(&conn, &search, &hit_types, offset + limit)?;
+    hits.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.hit_type.cmp(&b.hit_type))
+            .then_with(|| a.id.cmp(&b.id))
+    });
+
+    let paging_info = apply_paging(params.clone(), head_block_num, total_count)?;
+
+    let hits: Vec<SearchHit> = hits
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit as usize)
+        .collect();
+
+    Ok(json!({ "data": hits,
+                "link": paging_info.get("link"),
+                "head": head_block_num,
+                "paging": paging_info.get("paging") }))
+}
+
+/// A search at a block, as text for trigram similarity and as a `tsquery` for full-text matches
+struct Search {
+    text: String,
+    /// `text` as a prefix matching `tsquery`
+    query: String,
+    threshold: f32,
+    head_block_num: i64,
+}
+
+impl Search {
+    /// Returns a query of the live organizations whose name matches
+    fn organizations(&self) -> organizations::BoxedQuery<'static, Pg> {
+        organizations::table
+            .filter(organizations::start_block_num.le(self.head_block_num))
+            .filter(organizations::end_block_num.gt(self.head_block_num))
+            .filter(
+                to_tsvector(organizations::name)
+                    .matches(to_tsquery(self.query.clone()))
+                    .or(
+                        similarity(organizations::name.nullable(), self.text.clone())
+                            .gt(self.threshold),
+                    ),
+            )
+            .into_boxed()
+    }
+
+    /// Returns a query of the live standards whose name matches
+    fn standards(&self) -> standards::BoxedQuery<'static, Pg> {
+        standards::table
+            .filter(standards::start_block_num.le(self.head_block_num))
+            .filter(standards::end_block_num.gt(self.head_block_num))
+            .filter(
+                to_tsvector(standards::name)
+                    .matches(to_tsquery(self.query.clone()))
+                    .or(similarity(standards::name.nullable(), self.text.clone())
+                        .gt(self.threshold)),
+            )
+            .into_boxed()
+    }
+
+    /// Returns a query of the live agents whose name matches
+    fn agents(&self) -> agents::BoxedQuery<'static, Pg> {
+        agents::table
+            .filter(agents::start_block_num.le(self.head_block_num))
+            .filter(agents::end_block_num.gt(self.head_block_num))
+            .filter(
+                to_tsvector(agents::name)
+                    .matches(to_tsquery(self.query.clone()))
+                    .or(similarity(agents::name.nullable(), self.text.clone()).gt(self.threshold)),
+            )
+            .into_boxed()
+    }
+
+    /// Returns a query of the live certificates that match. Certificates have no name of their
+    /// own, so they match through the factory that holds them or the standard they certify, or
+    /// by their id.
+    fn certificates(&self) -> certificates::BoxedQuery<'static, Pg> {
+        certificates::table
+            .filter(certificates::start_block_num.le(self.head_block_num))
+            .filter(certificates::end_block_num.gt(self.head_block_num))
+            .filter(
+                certificates::factory_id
+                    .eq_any(
+                        self.organizations()
+                            .filter(
+                                organizations::organization_type.eq(OrganizationTypeEnum::Factory),
+                            )
+                            .select(organizations::organization_id),
+                    )
+                    .or(certificates::standard_id
+                        .eq_any(self.standards().select(standards::standard_id)))
+                    .or(certificates::certificate_id.eq(self.text.clone())),
+            )
+            .into_boxed()
+    }
+
+    /// Returns the score of each certificate, which is only meaningful for matching
+    /// certificates: 1 for a matching id, or else the score of the better of its factory and
+    /// standard
+    fn certificate_score(&self) -> CertificateScore {
+        Box::new(certificate_search_score(
+            certificates::certificate_id,
+            certificates::factory_id,
+            certificates::standard_id,
+            self.head_block_num,
+            self.text.clone(),
+            self.query.clone(),
+            self.threshold,
+        ))
+    }
+}
+
+type CertificateScore = Box<dyn BoxableExpression<certificates::table, Pg, SqlType = Float>>;
+
+/// Returns the best `window` hits of every requested type, unranked, along with the number of
+/// hits of every requested type. The best hits of each type are ranked in SQL, so a page ending
+/// within the first `window` hits of the merged ranking is among them.
+fn search_hits(
+    conn: &DbConn,
+    search: &Search,
+    hit_types: &[HitType],
+    window: i64,
+) -> Result<(Vec<SearchHit>, i64), ApiError> {
+    let mut hits = vec![];
+    let mut total_count = 0;
+
+    if hit_types.contains(&HitType::Certificate) {
+        total_count += search.certificates().count().get_result::<i64>(&**conn)?;
+        hits.extend(search_certificates(conn, search, window)?);
+    }
+
+    if hit_types.contains(&HitType::Organization) {
+        let score = || similarity(organizations::name.nullable(), search.text.clone());
+        total_count += search.organizations().count().get_result::<i64>(&**conn)?;
+        hits.extend(
+            search
+                .organizations()
+                .select((
+                    organizations::organization_id,
+                    organizations::name,
+                    organizations::organization_type,
+                    score(),
+                ))
+                .order_by((score().desc(), organizations::organization_id.asc()))
+                .limit(window)
+                .load::<(String, String, OrganizationTypeEnum, f32)>(&**conn)?
+                .into_iter()
+                .map(|(id, name, organization_type, score)| SearchHit {
+                    hit_type: HitType::Organization,
+                    link: Some(format!("/api/organizations/{}", id)),
+                    id,
+                    highlight: highlight(&name, &search.text),
+                    title: name,
+                    score,
+                    organization_type: Some(organization_type),
+                }),
+        );
+    }
+
+    if hit_types.contains(&HitType::Standard) {
+        let score = || similarity(standards::name.nullable(), search.text.clone());
+        total_count += search.standards().count().get_result::<i64>(&**conn)?;
+        hits.extend(
+            search
+                .standards()
+                .select((standards::standard_id, standards::name, score()))
+                .order_by((score().desc(), standards::standard_id.asc()))
+                .limit(window)
+                .load::<(String, String, f32)>(&**conn)?
+                .into_iter()
+                .map(|(id, name, score)| SearchHit {
+                    hit_type: HitType::Standard,
+                    id,
+                    highlight: highlight(&name, &search.text),
+                    title: name,
+                    score,
+                    organization_type: None,
+                    link: None,
+                }),
+        );
+    }
+
+    if hit_types.contains(&HitType::Agent) {
+        let score = || similarity(agents::name.nullable(), search.text.clone());
+        total_count += search.agents().count().get_result::<i64>(&**conn)?;
+        hits.extend(
+            search
+                .agents()
+                .select((agents::public_key, agents::name, score()))
+                .order_by((score().desc(), agents::public_key.asc()))
+                .limit(window)
+                .load::<(String, String, f32)>(&**conn)?
+                .into_iter()
+                .map(|(public_key, name, score)| SearchHit {
+                    hit_type: HitType::Agent,
+                    link: Some(format!("/api/agents/{}", public_key)),
+                    id: public_key,
+                    highlight: highlight(&name, &search.text),
+                    title: name,
+                    score,
+                    organization_type: None,
+                }),
+        );
+    }
+
+    Ok((hits, total_count))
+}
+
+/// Returns the best `window` certificate hits, titled after the standard they certify and the
+/// factory that holds them
+fn search_certificates(
+    conn: &DbConn,
+    search: &Search,
+    window: i64,
+) -> Result<Vec<SearchHit>, ApiError> {
+    let head_block_num = search.head_block_num;
+    let certificate_results = search
+        .certificates()
+        .select((
+            certificates::certificate_id,
+            certificates::factory_id,
+            certificates::standard_id,
+            search.certificate_score(),
+        ))
+        .order_by((
+            search.certificate_score().desc(),
+            certificates::certificate_id.asc(),
+        ))
+        .limit(window)
+        .load::<(String, String, String, f32)>(&**conn)?;
+
+    let factory_names: HashMap<String, String> = organizations::table
+        .select((organizations::organization_id, organizations::name))
+        .filter(organizations::start_block_num.le(head_block_num))
+        .filter(organizations::end_block_num.gt(head_block_num))
+        .filter(
+            organizations::organization_id.eq_any(
+                certificate_results
+                    .iter()
+                    .map(|(_, factory_id, _, _)| factory_id.clone())
+                    .collect::<Vec<_>>(),
+            ),
+        )
+        .load::<(String, String)>(&**conn)?
+        .into_iter()
+        .collect();
+    let standard_names: HashMap<String, String> = standards::table
+        .select((standards::standard_id, standards::name))
+        .filter(standards::start_block_num.le(head_block_num))
+        .filter(standards::end_block_num.gt(head_block_num))
+        .filter(
+            standards::standard_id.eq_any(
+                certificate_results
+                    .iter()
+                    .map(|(_, _, standard_id, _)| standard_id.clone())
+                    .collect::<Vec<_>>(),
+            ),
+        )
+        .load::<(String, String)>(&**conn)?
+        .into_iter()
+        .collect();
+
+    Ok(certificate_results
+        .into_iter()
+        .map(|(certificate_id, factory_id, standard_id, score)| {
+            let title = format!(
+                "{} - {}",
+                standard_names.get(&standard_id).unwrap_or(&standard_id),
+                factory_names.get(&factory_id).unwrap_or(&factory_id)
+            );
+            SearchHit {
+                hit_type: HitType::Certificate,
+                link: Some(format!("/api/certificates/{}", certificate_id)),
+                id: certificate_id,
+                highlight: highlight(&title, &search.text),
+                title,
+                score,
+                organization_type: None,
+            }
+        })
+        .collect())
+}
+
+fn parse_hit_types(types: Option<&str>) -> Result<Vec<HitType>, ApiError> {
+    match types {
+        None => Ok(vec![
+            HitType::Organization,
+            HitType::Standard,
+            HitType::Certificate,
+            HitType::Agent,
+        ]),
+        Some(types) => types
+            .split(',')
+            .filter(|name| !name.trim().is_empty())
+            .map(|name| {
+                HitType::parse(name).ok_or_else(|| {
+                    ApiError::BadRequest(format!("Invalid search type {}", name.trim()))
+                })
+            })
+            .collect(),
+    }
+}
+
+/// Escapes `text` for HTML and wraps each case-insensitive occurrence of a term of `query`
+/// in `<em>` tags.
+fn highlight(text: &str, query: &str) -> String {
+    let lower_text = text.to_ascii_lowercase();
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|term| term.to_ascii_lowercase())
+        .collect();
+
+    // Byte ranges of `text` to highlight, merged where terms overlap
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for term in &terms {
+        let mut start = 0;
+        while let Some(position) = lower_text[start..].find(term.as_str()) {
+            let begin = start + position;
+            ranges.push((begin, begin + term.len()));
+            start = begin + term.len();
+        }
+    }
+    ranges.sort();
+    let mut merged: Vec<(usize, usize)> = vec![];
+    for (begin, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if begin <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((begin, end)),
+        }
+    }
+
+    let mut result = String::new();
+    let mut position = 0;
+    for (begin, end) in merged {
+        result.push_str(&escape_xml(&text[position..begin]));
+        result.push_str("<em>");
+        result.push_str(&escape_xml(&text[begin..end]));
+        result.push_str("</em>");
+        position = end;
+    }
+    result.push_str(&escape_xml(&text[position..]));
+    result
+}
+
+fn apply_paging(params: SearchParams, head: i64, total_count: i64) -> Result<JsonValue, ApiError> {
+    let mut link = String::from("/api/search?");
+
+    if let Some(q) = params.q {
+        link = format!("{}q={}&", link, Uri::percent_encode(&q));
+    }
+    if let Some(types) = params.types {
+        link = format!("{}types={}&", link, Uri::percent_encode(&types));
+    }
+    if let Some(similarity) = params.similarity {
+        link = format!("{}similarity={}&", link, similarity);
+    }
+    link = format!("{}head={}&", link, head);
+
+    get_response_paging_info(params.limit, params.offset, link, total_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use route_handlers::factories::tests::setup_factory_db;
+    use route_handlers::tests::run_test;
+
+    #[test]
+    /// Test that matched terms are wrapped in `<em>` tags regardless of case
+    fn test_highlight() {
+        assert_eq!(
+            highlight("Organic Cotton Standard", "cotton"),
+            "Organic <em>Cotton</em> Standard"
+        );
+        assert_eq!(
+            highlight("Organic Cotton", "organic cotton"),
+            "<em>Organic</em> <em>Cotton</em>"
+        );
+        assert_eq!(highlight("Wool", "cotton"), "Wool");
+    }
+
+    #[test]
+    /// Test that overlapping terms are highlighted once and text is escaped
+    fn test_highlight_overlap_and_escape() {
+        assert_eq!(highlight("Cottons", "cot cotton"), "<em>Cotton</em>s");
+        assert_eq!(
+            highlight("<b>Cotton</b> & Co", "cotton"),
+            "&lt;b&gt;<em>Cotton</em>&lt;/b&gt; &amp; Co"
+        );
+    }
+
+    #[test]
+    /// Test that the `types` param is parsed and unknown types are rejected
+    fn test_parse_hit_types() {
+        assert_eq!(parse_hit_types(None).unwrap().len(), 4);
+        assert_eq!(
+            parse_hit_types(Some("standard, agent")).unwrap(),
+            vec![HitType::Standard, HitType::Agent]
+        );
+        assert!(parse_hit_types(Some("factory")).is_err());
+    }
+
+    #[test]
+    /// Test that a GET to `/api/search` without a query returns a `BadRequest`
+    fn test_search_without_query() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            match search(DbConn(conn)) {
+                Err(ApiError::BadRequest(_)) => (),
+                _ => panic!("Expected a BadRequest error"),
+            }
+        })
+    }
+
+    #[test]
+    /// Test that a GET to `/api/search?q=test_factory_name` returns the factory and the
+    /// certificate it holds, with the factory ranked first
+    fn test_search_organization_and_certificate() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            let params = SearchParams {
+                q: Some("test_factory_name".to_string()),
+                ..Default::default()
+            };
+            let res = search_with_params(Some(Form(params)), DbConn(conn)).unwrap();
+            let hits = res.get("data").unwrap().as_array().unwrap();
+
+            assert_eq!(hits[0]["type"], "organization");
+            assert_eq!(hits[0]["id"], "test_factory_id");
+            assert_eq!(hits[0]["highlight"], "<em>test_factory_name</em>");
+            assert!(hits
+                .iter()
+                .any(|hit| hit["type"] == "certificate" && hit["id"] == "test_cert_id"));
+        })
+    }
+
+    #[test]
+    /// Test that a search with punctuation, including `tsquery` operators, is answered rather
+    /// than rejected by Postgres
+    fn test_search_with_punctuation() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            let params = SearchParams {
+                q: Some("test_factory_name & (':* !".to_string()),
+                ..Default::default()
+            };
+            let res = search_with_params(Some(Form(params)), DbConn(conn)).unwrap();
+            let hits = res.get("data").unwrap().as_array().unwrap();
+
+            assert!(hits
+                .iter()
+                .any(|hit| hit["type"] == "organization" && hit["id"] == "test_factory_id"));
+        })
+    }
+
+    #[test]
+    /// Test that a limit above the maximum is rejected
+    fn test_search_limit_too_large() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            let params = SearchParams {
+                q: Some("test_factory_name".to_string()),
+                limit: Some(MAX_LIMIT + 1),
+                ..Default::default()
+            };
+            match search_with_params(Some(Form(params)), DbConn(conn)) {
+                Err(ApiError::BadRequest(_)) => (),
+                _ => panic!("Expected a BadRequest error"),
+            }
+        })
+    }
+
+    #[test]
+    /// Test that the `types` param limits the hits to the given types
+    fn test_search_with_types() {
+        run_test(|| {
+            let conn = setup_factory_db(false);
+            let params = SearchParams {
+                q: Some("test_std_name".to_string()),
+                types: Some("standard".to_string()),
+                ..Default::default()
+            };
+            let res = search_with_params(Some(Form(params)), DbConn(conn)).unwrap();
+            let hits = res.get("data").unwrap().as_array().unwrap();
+
+            assert!(!hits.is_empty());
+            assert!(hits.iter().all(|hit| hit["type"] == "standard"));
+        })
+    }
+}
@@ -1,12 +1,17 @@
 use database::DbConn;
 use database_manager::models::{Standard, StandardVersion};
-use database_manager::tables_schema::{assertions, standards};
+use database_manager::tables_schema::{assertions, standard_versions, standards};
 use diesel::prelude::*;
+use diff::{diff, diff_range};
 use errors::ApiError;
+use fields::Fields;
+use history::versions;
+use negotiation::Negotiated;
 use paging::get_head_block_num;
 use rocket::request::Form;
 use rocket_contrib::json::JsonValue;
 use route_handlers::prom::increment_http_req;
+use serde_json::Value;
 use std::collections::HashMap;
 
 #[derive(Default, FromForm, Clone)]
@@ -15,6 +20,7 @@ pub struct StandardParams {
     organization_id: Option<String>,
     standard_id: Option<String>,
     head: Option<i64>,
+    fields: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -22,7 +28,9 @@ pub struct ApiStandard {
     standard_id: String,
     organization_id: String,
     name: String,
-    versions: Vec<ApiVersion>,
+    /// Left out when the standard is expanded into another resource without its versions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    versions: Option<Vec<ApiVersion>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     assertion_id: Option<String>,
 }
@@ -35,6 +43,17 @@ pub struct ApiVersion {
     approval_date: i64,
 }
 
+impl<'a> From<&'a StandardVersion> for ApiVersion {
+    fn from(version: &StandardVersion) -> Self {
+        ApiVersion {
+            version: version.version.clone(),
+            external_link: version.link.clone(),
+            description: version.description.clone(),
+            approval_date: version.approval_date,
+        }
+    }
+}
+
 impl From<(Standard, Vec<StandardVersion>)> for ApiStandard {
     fn from(standard_version: (Standard, Vec<StandardVersion>)) -> Self {
         let (standard, version) = standard_version;
@@ -42,15 +61,7 @@ impl From<(Standard, Vec<StandardVersion>)> for ApiStandard {
             standard_id: standard.standard_id,
             organization_id: standard.organization_id,
             name: standard.name,
-            versions: version
-                .iter()
-                .map(|version| ApiVersion {
-                    version: version.version.clone(),
-                    external_link: version.link.clone(),
-                    description: version.description.clone(),
-                    approval_date: version.approval_date,
-                })
-                .collect::<Vec<ApiVersion>>(),
+            versions: Some(version.iter().map(ApiVersion::from).collect()),
             assertion_id: None,
         }
     }
@@ -63,15 +74,7 @@ impl From<(Standard, Vec<StandardVersion>, Option<String>)> for ApiStandard {
             standard_id: standard.standard_id,
             organization_id: standard.organization_id,
             name: standard.name,
-            versions: version
-                .iter()
-                .map(|version| ApiVersion {
-                    version: version.version.clone(),
-                    external_link: version.link.clone(),
-                    description: version.description.clone(),
-                    approval_date: version.approval_date,
-                })
-                .collect::<Vec<ApiVersion>>(),
+            versions: Some(version.iter().map(ApiVersion::from).collect()),
             assertion_id,
         }
     }
@@ -84,15 +87,7 @@ impl<'a> From<(&'a Standard, &'a Vec<StandardVersion>)> for ApiStandard {
             standard_id: standard.standard_id.clone(),
             organization_id: standard.organization_id.clone(),
             name: standard.name.clone(),
-            versions: version
-                .iter()
-                .map(|version| ApiVersion {
-                    version: version.version.clone(),
-                    external_link: version.link.clone(),
-                    description: version.description.clone(),
-                    approval_date: version.approval_date,
-                })
-                .collect::<Vec<ApiVersion>>(),
+            versions: Some(version.iter().map(ApiVersion::from).collect()),
             assertion_id: None,
         }
     }
@@ -105,22 +100,14 @@ impl<'a> From<(&'a Standard, &'a Vec<StandardVersion>, &'a Option<String>)> for
             standard_id: standard.standard_id.clone(),
             organization_id: standard.organization_id.clone(),
             name: standard.name.clone(),
-            versions: version
-                .iter()
-                .map(|version| ApiVersion {
-                    version: version.version.clone(),
-                    external_link: version.link.clone(),
-                    description: version.description.clone(),
-                    approval_date: version.approval_date,
-                })
-                .collect::<Vec<ApiVersion>>(),
+            versions: Some(version.iter().map(ApiVersion::from).collect()),
             assertion_id: assertion_id.clone(),
         }
     }
 }
 
 #[get("/standards")]
-pub fn list_standards(conn: DbConn) -> Result<JsonValue, ApiError> {
+pub fn list_standards(conn: DbConn) -> Result<Negotiated, ApiError> {
     list_standards_with_params(None, conn)
 }
 
@@ -128,7 +115,7 @@ pub fn list_standards(conn: DbConn) -> Result<JsonValue, ApiError> {
 pub fn list_standards_with_params(
     params: Option<Form<StandardParams>>,
     conn: DbConn,
-) -> Result<JsonValue, ApiError> {
+) -> Result<Negotiated, ApiError> {
     // Increment HTTP request count for Prometheus metrics
     increment_http_req();
 
@@ -136,6 +123,7 @@ pub fn list_standards_with_params(
         Some(param) => param.into_inner(),
         None => Default::default(),
     };
+    let fields = Fields::parse(&params.fields);
     let head_block_num: i64 = get_head_block_num(params.head, &conn)?;
     let mut standards_query = standards::table
         .filter(standards::start_block_num.le(head_block_num))
@@ -160,7 +148,7 @@ pub fn list_standards_with_params(
         standards_query = standards_query.filter(standards::standard_id.eq(standard_id));
     }
 
-    let standards = standards_query
+    let mut standards: Vec<Value> = standards_query
         .select((
             standards::standard_id,
             standards::name,
@@ -190,20 +178,208 @@ pub fn list_standards_with_params(
                 );
             }
             acc
+        })
+        .into_iter()
+        .map(|standard| json!(standard).0)
+        .collect();
+    fields.select(&mut standards);
+
+    Ok(Negotiated(json!({ "data": standards })))
+}
+
+/// Loads the standard with its versions and assertion as they were at `head_block_num`
+pub fn load_standard(
+    conn: &DbConn,
+    standard_id: &str,
+    head_block_num: i64,
+) -> Result<Option<JsonValue>, ApiError> {
+    let standard = standards::table
+        .filter(standards::standard_id.eq(standard_id))
+        .filter(standards::start_block_num.le(head_block_num))
+        .filter(standards::end_block_num.gt(head_block_num))
+        .first::<Standard>(&**conn)
+        .optional()?;
+    let standard = match standard {
+        Some(standard) => standard,
+        None => return Ok(None),
+    };
+
+    let versions = standard_versions::table
+        .filter(standard_versions::standard_id.eq(standard_id))
+        .filter(standard_versions::start_block_num.le(head_block_num))
+        .filter(standard_versions::end_block_num.gt(head_block_num))
+        .order_by(standard_versions::approval_date.asc())
+        .load::<StandardVersion>(&**conn)?;
+    let assertion_id = assertions::table
+        .select(assertions::assertion_id)
+        .filter(assertions::object_id.eq(standard_id))
+        .filter(assertions::start_block_num.le(head_block_num))
+        .filter(assertions::end_block_num.gt(head_block_num))
+        .first::<String>(&**conn)
+        .optional()?;
+
+    Ok(Some(json!(ApiStandard::from((
+        standard,
+        versions,
+        assertion_id
+    )))))
+}
+
+/// Loads the standards with the given ids and their assertions as they were at
+/// `head_block_num`, keyed by id. Their versions are left out, as they are a relation of their
+/// own when standards are expanded.
+pub fn load_standards(
+    conn: &DbConn,
+    standard_ids: &[String],
+    head_block_num: i64,
+) -> Result<HashMap<String, JsonValue>, ApiError> {
+    Ok(standards::table
+        .filter(standards::standard_id.eq_any(standard_ids))
+        .filter(standards::start_block_num.le(head_block_num))
+        .filter(standards::end_block_num.gt(head_block_num))
+        .left_join(
+            assertions::table.on(assertions::object_id
+                .eq(standards::standard_id)
+                .and(assertions::start_block_num.le(head_block_num))
+                .and(assertions::end_block_num.gt(head_block_num))),
+        )
+        .select((
+            standards::table::all_columns(),
+            assertions::assertion_id.nullable(),
+        ))
+        .load::<(Standard, Option<String>)>(&**conn)?
+        .into_iter()
+        .map(|(standard, assertion_id)| {
+            let standard = ApiStandard {
+                standard_id: standard.standard_id,
+                organization_id: standard.organization_id,
+                name: standard.name,
+                versions: None,
+                assertion_id,
+            };
+            (standard.standard_id.clone(), json!(standard))
+        })
+        .collect())
+}
+
+/// Loads the versions of the standards with the given ids as they were at `head_block_num`,
+/// keyed by the id of their standard
+pub fn load_versions(
+    conn: &DbConn,
+    standard_ids: &[String],
+    head_block_num: i64,
+) -> Result<HashMap<String, JsonValue>, ApiError> {
+    let versions = standard_versions::table
+        .filter(standard_versions::standard_id.eq_any(standard_ids))
+        .filter(standard_versions::start_block_num.le(head_block_num))
+        .filter(standard_versions::end_block_num.gt(head_block_num))
+        .order_by(standard_versions::approval_date.asc())
+        .load::<StandardVersion>(&**conn)?
+        .into_iter()
+        .fold(HashMap::new(), |mut acc, version| {
+            acc.entry(version.standard_id.clone())
+                .or_insert_with(Vec::new)
+                .push(ApiVersion::from(&version));
+            acc
         });
 
-    Ok(json!({ "data": standards }))
+    Ok(versions
+        .into_iter()
+        .map(|(standard_id, versions)| (standard_id, json!(versions)))
+        .collect())
+}
+
+/// Returns every version of a standard up to `head` with the range of blocks it was live for,
+/// including the versions of the standard document that were approved
+#[get("/standards/<standard_id>/history?<head>")]
+pub fn fetch_standard_history(
+    standard_id: String,
+    head: Option<i64>,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let head_block_num: i64 = get_head_block_num(head, &conn)?;
+    let mut ranges = standards::table
+        .select((standards::start_block_num, standards::end_block_num))
+        .filter(standards::standard_id.eq(&standard_id))
+        .load::<(i64, i64)>(&*conn)?;
+    ranges.extend(
+        standard_versions::table
+            .select((
+                standard_versions::start_block_num,
+                standard_versions::end_block_num,
+            ))
+            .filter(standard_versions::standard_id.eq(&standard_id))
+            .load::<(i64, i64)>(&*conn)?,
+    );
+    ranges.extend(
+        assertions::table
+            .select((assertions::start_block_num, assertions::end_block_num))
+            .filter(assertions::object_id.eq(&standard_id))
+            .load::<(i64, i64)>(&*conn)?,
+    );
+
+    let versions = versions(&ranges, head_block_num, |block_num| {
+        load_standard(&conn, &standard_id, block_num)
+    })?;
+    if versions.is_empty() {
+        return Err(ApiError::NotFound(format!(
+            "No standard with the ID {} exists",
+            standard_id
+        )));
+    }
+
+    Ok(json!({
+        "data": versions,
+        "link": format!("/api/standards/{}/history?head={}", standard_id, head_block_num),
+        "head": head_block_num,
+    }))
+}
+
+/// Returns the changes to a standard, including its versions, between the `from` block and
+/// the `to` block, which defaults to the latest block
+#[get("/standards/<standard_id>/diff?<from>&<to>")]
+pub fn fetch_standard_diff(
+    standard_id: String,
+    from: Option<i64>,
+    to: Option<i64>,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let (from, to) = diff_range(from, to, &conn)?;
+    let before = load_standard(&conn, &standard_id, from)?;
+    let after = load_standard(&conn, &standard_id, to)?;
+    if before.is_none() && after.is_none() {
+        return Err(ApiError::NotFound(format!(
+            "No standard with the ID {} exists",
+            standard_id
+        )));
+    }
+
+    Ok(json!({
+        "data": {
+            "from": from,
+            "to": to,
+            "changes": diff(before, after),
+        },
+        "link": format!("/api/standards/{}/diff?from={}&to={}", standard_id, from, to),
+        "head": to,
+    }))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use database_manager::custom_types::AssertionTypeEnum;
-    use database_manager::models::{NewAssertion, NewStandard};
+    use database_manager::models::{NewAssertion, NewStandard, NewStandardVersion};
     use database_manager::tables_schema::{assertions, standards};
     use diesel::pg::PgConnection;
     use diesel::r2d2::{ConnectionManager, PooledConnection};
-    use route_handlers::tests::{get_connection_pool, run_test};
+    use route_handlers::tests::{get_connection_pool, insert_blocks, run_test};
 
     #[test]
     /// Test that a GET to `/api/standards` returns an `Ok` response and sends back all
@@ -221,6 +397,7 @@ mod tests {
                     standard_id: None,
                     organization_id: Some("test_standard_organization_id".to_string()),
                     head: None,
+                    fields: None,
                 })),
                 DbConn(conn),
             );
@@ -253,6 +430,7 @@ mod tests {
                     standard_id: None,
                     organization_id: Some("test_standard_organization_id".to_string()),
                     head: None,
+                    fields: None,
                 })),
                 DbConn(conn),
             );
@@ -287,6 +465,7 @@ mod tests {
                     standard_id: None,
                     organization_id: None,
                     head: None,
+                    fields: None,
                 })),
                 DbConn(conn),
             );
@@ -330,6 +509,45 @@ mod tests {
         conn
     }
 
+    #[test]
+    /// Test that a GET to `/api/standards/{standard_id}/history` returns a new version of the
+    /// standard when a version of the standard document is approved
+    fn test_standard_history() {
+        run_test(|| {
+            let mut conn = get_connection_pool();
+            conn.begin_test_transaction().unwrap();
+            conn = create_test_standard("test_standard", conn);
+            insert_blocks(&conn, &[2]);
+            diesel::insert_into(standard_versions::table)
+                .values(NewStandardVersion {
+                    start_block_num: 2,
+                    end_block_num: std::i64::MAX,
+                    standard_id: "test_standard_id".to_string(),
+                    version: "test_standard_version".to_string(),
+                    link: "test_link".to_string(),
+                    description: "test_description".to_string(),
+                    approval_date: 2 as i64,
+                })
+                .execute(&conn)
+                .unwrap();
+
+            let res =
+                fetch_standard_history("test_standard_id".to_string(), None, DbConn(conn)).unwrap();
+
+            let versions = res.get("data").unwrap().as_array().unwrap();
+            assert_eq!(versions.len(), 2);
+            assert_eq!(versions[0]["end_block_num"], json!(2).0);
+            assert!(versions[0]["data"]["versions"]
+                .as_array()
+                .unwrap()
+                .is_empty());
+            assert_eq!(
+                versions[1]["data"]["versions"][0]["version"],
+                json!("test_standard_version").0
+            );
+        })
+    }
+
     // helper function to create and insert a test standard in the database
     fn create_test_standard(
         standard_name: &str,
@@ -0,0 +1,531 @@
+use base64;
+use chrono::Utc;
+use common::addressing;
+use common::proto::{organization, payload, request};
+use database::DbConn;
+use database_manager::tables_schema::agents;
+use diesel::prelude::*;
+use errors::ApiError;
+use paging::get_head_block_num;
+use protobuf::{Message, RepeatedField};
+use rocket_contrib::json::{Json, JsonValue};
+use route_handlers::prom::increment_http_req;
+use sawtooth_sdk::messages::transaction::TransactionHeader;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+use sha2::{Digest, Sha512};
+use std::collections::BTreeSet;
+use uuid;
+
+const FAMILY_NAME: &str = "certificate_registry";
+const FAMILY_VERSION: &str = "0.1";
+
+#[derive(Deserialize)]
+struct Signers {
+    signer_public_key: String,
+    batcher_public_key: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ContactInput {
+    pub name: String,
+    pub phone_number: String,
+    pub language_code: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AddressInput {
+    pub street_line_1: String,
+    #[serde(default)]
+    pub street_line_2: String,
+    pub city: String,
+    #[serde(default)]
+    pub state_province: String,
+    pub country: String,
+    #[serde(default)]
+    pub postal_code: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CreateAgent {
+    name: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CreateOrganization {
+    pub id: String,
+    pub name: String,
+    /// One of `factory`, `certifying_body` or `standards_body`
+    pub organization_type: String,
+    #[serde(default)]
+    pub contacts: Vec<ContactInput>,
+    pub address: Option<AddressInput>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CreateStandard {
+    standard_id: String,
+    name: String,
+    version: String,
+    description: String,
+    link: String,
+    /// Seconds since the epoch
+    approval_date: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct IssueCertificate {
+    id: String,
+    factory_id: String,
+    standard_id: String,
+    /// The request the certificate is issued for, if any
+    request_id: Option<String>,
+    /// Seconds since the epoch
+    valid_from: u64,
+    /// Seconds since the epoch
+    valid_to: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OpenRequest {
+    id: String,
+    standard_id: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChangeRequestStatus {
+    request_id: String,
+    /// One of `open`, `in_progress`, `closed` or `certified`
+    status: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AssertFactory {
+    assertion_id: String,
+    factory: CreateOrganization,
+}
+
+/// The actions a transaction can be built for, named as in the URL
+#[derive(Clone, Debug)]
+enum TransactionAction {
+    CreateAgent(CreateAgent),
+    CreateOrganization(CreateOrganization),
+    CreateStandard(CreateStandard),
+    IssueCertificate(IssueCertificate),
+    OpenRequest(OpenRequest),
+    ChangeRequestStatus(ChangeRequestStatus),
+    AssertFactory(AssertFactory),
+}
+
+impl TransactionAction {
+    fn parse(action: &str, body: Value) -> Result<Self, ApiError> {
+        Ok(match action {
+            "create_agent" => TransactionAction::CreateAgent(parse_body(body)?),
+            "create_organization" => TransactionAction::CreateOrganization(parse_body(body)?),
+            "create_standard" => TransactionAction::CreateStandard(parse_body(body)?),
+            "issue_certificate" => TransactionAction::IssueCertificate(parse_body(body)?),
+            "open_request" => TransactionAction::OpenRequest(parse_body(body)?),
+            "change_request_status" => TransactionAction::ChangeRequestStatus(parse_body(body)?),
+            "assert_factory" => TransactionAction::AssertFactory(parse_body(body)?),
+            _ => {
+                return Err(ApiError::NotFound(format!(
+                    "No transaction builder for the action {}",
+                    action
+                )))
+            }
+        })
+    }
+
+    /// Whether the payload or its addresses depend on the organization of the signer
+    fn needs_signer_organization(&self) -> bool {
+        match self {
+            TransactionAction::CreateAgent(_)
+            | TransactionAction::CreateOrganization(_)
+            | TransactionAction::ChangeRequestStatus(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Builds the payload and the state addresses it reads and writes
+    fn build(
+        &self,
+        signer_public_key: &str,
+        signer_organization_id: &str,
+    ) -> Result<(payload::CertificateRegistryPayload, Vec<String>), ApiError> {
+        let mut payload = payload::CertificateRegistryPayload::new();
+        let mut addresses = BTreeSet::new();
+        addresses.insert(addressing::make_agent_address(signer_public_key));
+        if !signer_organization_id.is_empty() {
+            addresses.insert(addressing::make_organization_address(
+                signer_organization_id,
+            ));
+        }
+
+        match self {
+            TransactionAction::CreateAgent(create) => {
+                let mut action = payload::CreateAgentAction::new();
+                action.set_name(create.name.clone());
+                action.set_timestamp(now());
+                payload.set_action(payload::CertificateRegistryPayload_Action::CREATE_AGENT);
+                payload.set_create_agent(action);
+            }
+            TransactionAction::CreateOrganization(create) => {
+                addresses.insert(addressing::make_organization_address(&create.id));
+                payload.set_action(payload::CertificateRegistryPayload_Action::CREATE_ORGANIZATION);
+                payload.set_create_organization(create_organization_action(create)?);
+            }
+            TransactionAction::CreateStandard(create) => {
+                addresses.insert(addressing::make_standard_address(&create.standard_id));
+                let mut action = payload::CreateStandardAction::new();
+                action.set_standard_id(create.standard_id.clone());
+                action.set_name(create.name.clone());
+                action.set_version(create.version.clone());
+                action.set_description(create.description.clone());
+                action.set_link(create.link.clone());
+                action.set_approval_date(create.approval_date);
+                payload.set_action(payload::CertificateRegistryPayload_Action::CREATE_STANDARD);
+                payload.set_create_standard(action);
+            }
+            TransactionAction::IssueCertificate(issue) => {
+                if issue.valid_to <= issue.valid_from {
+                    return Err(ApiError::BadRequest(
+                        "valid_to must be later than valid_from".to_string(),
+                    ));
+                }
+                addresses.insert(addressing::make_certificate_address(&issue.id));
+                addresses.insert(addressing::make_organization_address(&issue.factory_id));
+                addresses.insert(addressing::make_standard_address(&issue.standard_id));
+                let mut action = payload::IssueCertificateAction::new();
+                action.set_id(issue.id.clone());
+                action.set_factory_id(issue.factory_id.clone());
+                action.set_standard_id(issue.standard_id.clone());
+                action.set_valid_from(issue.valid_from);
+                action.set_valid_to(issue.valid_to);
+                match issue.request_id {
+                    Some(ref request_id) => {
+                        addresses.insert(addressing::make_request_address(request_id));
+                        action.set_source(payload::IssueCertificateAction_Source::FROM_REQUEST);
+                        action.set_request_id(request_id.clone());
+                    }
+                    None => action.set_source(payload::IssueCertificateAction_Source::INDEPENDENT),
+                }
+                payload.set_action(payload::CertificateRegistryPayload_Action::ISSUE_CERTIFICATE);
+                payload.set_issue_certificate(action);
+            }
+            TransactionAction::OpenRequest(open) => {
+                addresses.insert(addressing::make_request_address(&open.id));
+                addresses.insert(addressing::make_standard_address(&open.standard_id));
+                let mut action = payload::OpenRequestAction::new();
+                action.set_id(open.id.clone());
+                action.set_standard_id(open.standard_id.clone());
+                action.set_request_date(now());
+                payload.set_action(payload::CertificateRegistryPayload_Action::OPEN_REQUEST_ACTION);
+                payload.set_open_request_action(action);
+            }
+            TransactionAction::ChangeRequestStatus(change) => {
+                addresses.insert(addressing::make_request_address(&change.request_id));
+                let mut action = payload::ChangeRequestStatusAction::new();
+                action.set_request_id(change.request_id.clone());
+                action.set_status(parse_request_status(&change.status)?);
+                action.set_status_change_date(now());
+                payload.set_action(
+                    payload::CertificateRegistryPayload_Action::CHANGE_REQUEST_STATUS_ACTION,
+                );
+                payload.set_change_request_status_action(action);
+            }
+            TransactionAction::AssertFactory(assert) => {
+                addresses.insert(addressing::make_assertion_address(&assert.assertion_id));
+                addresses.insert(addressing::make_organization_address(&assert.factory.id));
+                let mut factory = payload::AssertAction_FactoryAssertion::new();
+                factory.set_factory(create_organization_action(&assert.factory)?);
+                let mut action = payload::AssertAction::new();
+                action.set_assertion_id(assert.assertion_id.clone());
+                action.set_new_factory(factory);
+                payload.set_action(payload::CertificateRegistryPayload_Action::ASSERT_ACTION);
+                payload.set_assert_action(action);
+            }
+        }
+
+        Ok((payload, addresses.into_iter().collect()))
+    }
+}
+
+/// Handle a POST request with the JSON of an action and return the serialized
+/// `CertificateRegistryPayload`, the addresses it reads and writes and the header of an
+/// unsigned transaction, so clients do not have to build protobufs or compute addresses.
+///
+/// Every body has a `signer_public_key` and an optional `batcher_public_key`, which
+/// defaults to the signer. Actions taken on behalf of the signer's organization look the
+/// organization up from the signer's agent at `head`.
+#[post(
+    "/transactions/<action>?<head>",
+    format = "application/json",
+    data = "<body>"
+)]
+pub fn build_transaction(
+    action: String,
+    head: Option<i64>,
+    body: Json<Value>,
+    conn: DbConn,
+) -> Result<JsonValue, ApiError> {
+    // Increment HTTP request count for Prometheus metrics
+    increment_http_req();
+
+    let body = body.into_inner();
+    let signers: Signers = parse_body(body.clone())?;
+    let action = TransactionAction::parse(&action, body)?;
+    let head_block_num = get_head_block_num(head, &conn)?;
+
+    let signer_organization_id = if action.needs_signer_organization() {
+        let organization_id = agents::table
+            .select(agents::organization_id)
+            .filter(agents::public_key.eq(&signers.signer_public_key))
+            .filter(agents::start_block_num.le(head_block_num))
+            .filter(agents::end_block_num.gt(head_block_num))
+            .first::<Option<String>>(&*conn)
+            .optional()?;
+        match organization_id {
+            Some(Some(organization_id)) => organization_id,
+            _ => {
+                return Err(ApiError::BadRequest(format!(
+                    "The agent {} does not belong to an organization",
+                    signers.signer_public_key
+                )))
+            }
+        }
+    } else {
+        String::new()
+    };
+
+    let (payload, addresses) = action.build(&signers.signer_public_key, &signer_organization_id)?;
+    let payload_bytes = payload
+        .write_to_bytes()
+        .map_err(|err| ApiError::InternalError(err.to_string()))?;
+    let header = transaction_header(
+        &payload_bytes,
+        addresses.clone(),
+        signers.signer_public_key,
+        signers.batcher_public_key,
+    );
+    let header_bytes = header
+        .write_to_bytes()
+        .map_err(|err| ApiError::InternalError(err.to_string()))?;
+
+    Ok(json!({
+        "data": {
+            "payload": base64::encode(&payload_bytes),
+            "inputs": addresses,
+            "outputs": addresses,
+            "header": {
+                "family_name": header.get_family_name(),
+                "family_version": header.get_family_version(),
+                "inputs": header.get_inputs(),
+                "outputs": header.get_outputs(),
+                "dependencies": header.get_dependencies(),
+                "nonce": header.get_nonce(),
+                "payload_sha512": header.get_payload_sha512(),
+                "signer_public_key": header.get_signer_public_key(),
+                "batcher_public_key": header.get_batcher_public_key(),
+            },
+            "header_bytes": base64::encode(&header_bytes),
+        },
+        "head": head_block_num,
+    }))
+}
+
+fn transaction_header(
+    payload_bytes: &[u8],
+    addresses: Vec<String>,
+    signer_public_key: String,
+    batcher_public_key: Option<String>,
+) -> TransactionHeader {
+    let mut header = TransactionHeader::new();
+    header.set_family_name(FAMILY_NAME.to_string());
+    header.set_family_version(FAMILY_VERSION.to_string());
+    header.set_inputs(RepeatedField::from_vec(addresses.clone()));
+    header.set_outputs(RepeatedField::from_vec(addresses));
+    header.set_nonce(uuid::Uuid::new_v4().to_simple().to_string());
+    header.set_payload_sha512(
+        Sha512::digest(payload_bytes)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    );
+    header.set_batcher_public_key(batcher_public_key.unwrap_or_else(|| signer_public_key.clone()));
+    header.set_signer_public_key(signer_public_key);
+    header
+}
+
+/// Builds the CREATE_ORGANIZATION action used both on its own and in factory assertions
+pub fn create_organization_action(
+    create: &CreateOrganization,
+) -> Result<payload::CreateOrganizationAction, ApiError> {
+    let organization_type = match create.organization_type.as_str() {
+        "factory" => organization::Organization_Type::FACTORY,
+        "certifying_body" => organization::Organization_Type::CERTIFYING_BODY,
+        "standards_body" => organization::Organization_Type::STANDARDS_BODY,
+        _ => {
+            return Err(ApiError::BadRequest(format!(
+                "Invalid organization type {}, expected one of factory, certifying_body or \
+                 standards_body",
+                create.organization_type
+            )))
+        }
+    };
+
+    let mut action = payload::CreateOrganizationAction::new();
+    action.set_id(create.id.clone());
+    action.set_organization_type(organization_type);
+    action.set_name(create.name.clone());
+    action.set_contacts(RepeatedField::from_vec(
+        create
+            .contacts
+            .iter()
+            .map(|input| {
+                let mut contact = organization::Organization_Contact::new();
+                contact.set_name(input.name.clone());
+                contact.set_phone_number(input.phone_number.clone());
+                contact.set_language_code(input.language_code.clone());
+                contact
+            })
+            .collect(),
+    ));
+    if let Some(ref input) = create.address {
+        let mut address = organization::Factory_Address::new();
+        address.set_street_line_1(input.street_line_1.clone());
+        address.set_street_line_2(input.street_line_2.clone());
+        address.set_city(input.city.clone());
+        address.set_state_province(input.state_province.clone());
+        address.set_country(input.country.clone());
+        address.set_postal_code(input.postal_code.clone());
+        action.set_address(address);
+    }
+    Ok(action)
+}
+
+fn parse_request_status(status: &str) -> Result<request::Request_Status, ApiError> {
+    match status {
+        "open" => Ok(request::Request_Status::OPEN),
+        "in_progress" => Ok(request::Request_Status::IN_PROGRESS),
+        "closed" => Ok(request::Request_Status::CLOSED),
+        "certified" => Ok(request::Request_Status::CERTIFIED),
+        _ => Err(ApiError::BadRequest(format!(
+            "Invalid status {}, expected one of open, in_progress, closed or certified",
+            status
+        ))),
+    }
+}
+
+fn parse_body<T: DeserializeOwned>(body: Value) -> Result<T, ApiError> {
+    serde_json::from_value(body).map_err(|err| ApiError::BadRequest(err.to_string()))
+}
+
+/// Seconds since the epoch
+fn now() -> u64 {
+    Utc::now().timestamp() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::parse_from_bytes;
+
+    fn factory() -> CreateOrganization {
+        CreateOrganization {
+            id: "factory_id".to_string(),
+            name: "factory".to_string(),
+            organization_type: "factory".to_string(),
+            contacts: vec![],
+            address: Some(AddressInput {
+                street_line_1: "1 Main St".to_string(),
+                city: "Lima".to_string(),
+                country: "Peru".to_string(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    /// Test that only known actions with valid bodies can be built
+    fn test_parse_action() {
+        let body = json!({ "signer_public_key": "key", "name": "agent" }).0;
+        match TransactionAction::parse("create_agent", body.clone()) {
+            Ok(TransactionAction::CreateAgent(_)) => (),
+            _ => panic!("Expected a create agent action"),
+        }
+        match TransactionAction::parse("create_organization", body.clone()) {
+            Err(ApiError::BadRequest(_)) => (),
+            _ => panic!("Expected a BadRequest error"),
+        }
+        match TransactionAction::parse("delete_agent", body) {
+            Err(ApiError::NotFound(_)) => (),
+            _ => panic!("Expected a NotFound error"),
+        }
+    }
+
+    #[test]
+    /// Test that a create organization payload reads and writes the agent and the new
+    /// organization
+    fn test_build_create_organization() {
+        let action = TransactionAction::CreateOrganization(factory());
+        let (payload, addresses) = action.build("key", "").unwrap();
+
+        assert_eq!(
+            payload.get_action(),
+            payload::CertificateRegistryPayload_Action::CREATE_ORGANIZATION
+        );
+        assert_eq!(
+            payload
+                .get_create_organization()
+                .get_address()
+                .get_country(),
+            "Peru"
+        );
+        let mut expected = vec![
+            addressing::make_agent_address("key"),
+            addressing::make_organization_address("factory_id"),
+        ];
+        expected.sort();
+        assert_eq!(addresses, expected);
+    }
+
+    #[test]
+    /// Test that a certificate issued for a request also reads and writes the request and
+    /// the certifying body of the signer
+    fn test_build_issue_certificate() {
+        let action = TransactionAction::IssueCertificate(IssueCertificate {
+            id: "cert_id".to_string(),
+            factory_id: "factory_id".to_string(),
+            standard_id: "standard_id".to_string(),
+            request_id: Some("request_id".to_string()),
+            valid_from: 1,
+            valid_to: 2,
+        });
+        let (payload, addresses) = action.build("key", "certifying_body_id").unwrap();
+
+        assert_eq!(
+            payload.get_issue_certificate().get_source(),
+            payload::IssueCertificateAction_Source::FROM_REQUEST
+        );
+        assert_eq!(addresses.len(), 6);
+        assert!(addresses.contains(&addressing::make_request_address("request_id")));
+        assert!(addresses.contains(&addressing::make_organization_address("certifying_body_id")));
+    }
+
+    #[test]
+    /// Test that the header template matches the payload it was built for
+    fn test_transaction_header() {
+        let payload = create_organization_action(&factory())
+            .unwrap()
+            .write_to_bytes()
+            .unwrap();
+        let header = transaction_header(&payload, vec!["address".to_string()], "key".into(), None);
+        let header: TransactionHeader =
+            parse_from_bytes(&header.write_to_bytes().unwrap()).unwrap();
+
+        assert_eq!(header.get_family_name(), FAMILY_NAME);
+        assert_eq!(header.get_batcher_public_key(), "key");
+        assert_eq!(header.get_inputs(), ["address".to_string()]);
+        assert_eq!(header.get_payload_sha512().len(), 128);
+    }
+}
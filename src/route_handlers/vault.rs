@@ -1,49 +1,45 @@
-extern crate alcoholic_jwt;
-extern crate reqwest;
 use errors::ApiError;
 use jwt;
+use key_store::KeyStore;
+use logging::log_key_access;
 use rocket::State;
 use rocket_contrib::json::{Json, JsonValue};
 use route_handlers::prom::increment_http_req;
 use serde_json;
-use std::collections::HashMap;
-use std::env;
-use VaultConfig;
 
 #[derive(Serialize, Deserialize)]
 pub struct Key {
     private_key: String,
 }
 
-/// Handle a POST request with proper Bearer token to write private key to HashiCorp Vault
+/// Returns the `username` claim of a JWT, which keys are stored under
+fn jwt_username(claims: &jwt::JWT) -> Result<&str, ApiError> {
+    if let serde_json::Value::String(username) = &claims.0["username"] {
+        Ok(username)
+    } else {
+        Err(ApiError::InternalError(
+            "Unable to get username from JWT".to_string(),
+        ))
+    }
+}
+
+/// Handle a POST request with proper Bearer token to write private key to the key store.
+/// Storing a key again rotates it, keeping earlier versions if the key store keeps versions.
 #[post("/key", format = "application/json", data = "<payload>", rank = 1)]
 pub fn store_key(
     payload: Json<Key>,
     claims: jwt::JWT,
-    vault_config: State<VaultConfig>,
+    key_store: State<Box<dyn KeyStore>>,
 ) -> Result<JsonValue, ApiError> {
     increment_http_req();
     let priv_key = payload.0;
-    let vault_url = &vault_config.url;
-    let mut lock = vault_config
-        .token
-        .lock()
-        .expect("Could not acquire Vault Config lock");
-    let client_token = vault_login(vault_url.to_string())?;
-    *lock = client_token.clone();
+    let username = jwt_username(&claims)?;
 
-    if let serde_json::Value::String(username) = &claims.0["username"] {
-        vault_write(
-            vault_url.to_string(),
-            client_token,
-            username.to_string(),
-            priv_key,
-        )?;
-        Ok(json!({"status": "200"}))
-    } else {
-        Err(ApiError::InternalError(
-            "Unable to get username from JWT".to_string(),
-        ))
+    let version = key_store.store_key(username, &priv_key.private_key)?;
+    log_key_access(username, "store", version);
+    match version {
+        Some(version) => Ok(json!({"status": "200", "version": version})),
+        None => Ok(json!({"status": "200"})),
     }
 }
 
@@ -57,23 +53,22 @@ pub fn store_key_jwt_failure() -> Result<JsonValue, ApiError> {
     Err(ApiError::Unauthorized)
 }
 
-/// Handle a Get request with proper Bearer token to read private key from HashiCorp Vault
-#[get("/key")]
-pub fn get_key(claims: jwt::JWT, vault_config: State<VaultConfig>) -> Result<JsonValue, ApiError> {
+/// Handle a Get request with proper Bearer token to read private key from the key store.
+/// A specific `version` of the key may be requested if the key store keeps versions.
+///
+/// Every read is recorded as an audit event.
+#[get("/key?<version>")]
+pub fn get_key(
+    version: Option<u64>,
+    claims: jwt::JWT,
+    key_store: State<Box<dyn KeyStore>>,
+) -> Result<JsonValue, ApiError> {
     increment_http_req();
-    let vault_url = &vault_config.url;
-    let mut lock = vault_config.token.lock().expect("lock shared data");
-    let client_token = vault_login(vault_url.to_string())?;
-    *lock = client_token.clone();
+    let username = jwt_username(&claims)?;
 
-    if let serde_json::Value::String(username) = &claims.0["username"] {
-        let private_key = vault_read(vault_url.to_string(), client_token, username.to_string())?;
-        Ok(json!({"data" : { "private_key": private_key }}))
-    } else {
-        Err(ApiError::InternalError(
-            "Unable to get username from JWT".to_string(),
-        ))
-    }
+    let private_key = key_store.get_key(username, version)?;
+    log_key_access(username, "read", version);
+    Ok(json!({"data" : { "private_key": private_key }}))
 }
 
 /// If a key store fails due to JWT authentication issues,
@@ -86,96 +81,81 @@ pub fn get_key_jwt_failure() -> Result<JsonValue, ApiError> {
     Err(ApiError::Unauthorized)
 }
 
-/// Attempt to log in to an instance of HashiCorp Vault through LDAP.
+/// Handle a DELETE request with proper Bearer token to delete the private key from the key store.
+/// Without a `version` every version of the key is deleted.
+#[delete("/key?<version>")]
+pub fn delete_key(
+    version: Option<u64>,
+    claims: jwt::JWT,
+    key_store: State<Box<dyn KeyStore>>,
+) -> Result<JsonValue, ApiError> {
+    increment_http_req();
+    let username = jwt_username(&claims)?;
+
+    key_store.delete_key(username, version)?;
+    log_key_access(username, "delete", version);
+    Ok(json!({"status": "200"}))
+}
+
+/// If a key delete fails due to JWT authentication issues,
+/// return a more specific error message.
 ///
-/// If the env vars `VAULT_USERNAME` and `VAULT_PASSWORD` are not provided
-/// this will fail with an `InternalError`
-fn vault_login(url: String) -> Result<String, ApiError> {
-    if let Ok(username) = env::var("VAULT_USERNAME") {
-        if let Ok(password) = env::var("VAULT_PASSWORD") {
-            let login_url = url + "v1/auth/ldap/login/" + &username;
-            let client = reqwest::Client::new();
-            let mut map = HashMap::new();
-            map.insert("password", password);
-            let res: serde_json::Value = client
-                .post(&login_url)
-                .json(&map)
-                .send()
-                .map_err(|e| ApiError::InternalError(e.to_string()))?
-                .json()
-                .map_err(|e| ApiError::InternalError(e.to_string()))?;
-            let token = &res["auth"]["client_token"];
-
-            if let serde_json::Value::String(token) = token {
-                Ok(token.to_string())
-            } else {
-                Err(ApiError::InternalError(
-                    "Vault did not return client token".to_string(),
-                ))
-            }
-        } else {
-            Err(ApiError::InternalError(
-                "Vault credentials incorrect".to_string(),
-            ))
-        }
-    } else {
-        Err(ApiError::InternalError(
-            "Vault credentials incorrect".to_string(),
-        ))
-    }
+/// Without this endpoint, when JWT auth fails there is nowhere to forward
+/// the request to and the client receives a 404 error.
+#[delete("/key", rank = 2)]
+pub fn delete_key_jwt_failure() -> Result<JsonValue, ApiError> {
+    Err(ApiError::Unauthorized)
+}
+
+/// Handle a Get request with proper Bearer token to list the versions of the private key,
+/// without the keys themselves.
+#[get("/key/versions")]
+pub fn list_key_versions(
+    claims: jwt::JWT,
+    key_store: State<Box<dyn KeyStore>>,
+) -> Result<JsonValue, ApiError> {
+    increment_http_req();
+    let username = jwt_username(&claims)?;
+
+    let versions = key_store.list_versions(username)?;
+    Ok(json!({ "data": versions }))
 }
 
-/// Attempt to write to an instance of HashiCorp Vault given the Vault URL and a valid client token.
-/// This stores private key 'key' to Vault location `VAULT_PATH/user_id`.
+/// If listing key versions fails due to JWT authentication issues,
+/// return a more specific error message.
 ///
-/// If the env var `VAULT_PATH` is not provided
-/// this will fail with an `InternalError`
-fn vault_write(url: String, token: String, user_id: String, key: Key) -> Result<(), ApiError> {
-    if let Ok(path) = env::var("VAULT_PATH") {
-        let write_url = url + "v1/secret/" + &path + &user_id;
-        let client = reqwest::Client::new();
-        let mut map = HashMap::new();
-        map.insert("private_key", key.private_key);
-        client
-            .post(&write_url)
-            .json(&map)
-            .header("X-Vault-Token", token)
-            .send()
-            .map_err(|e| ApiError::InternalError(e.to_string()))?;
-        Ok(())
-    } else {
-        Err(ApiError::InternalError(
-            "No VAULT_PATH provided".to_string(),
-        ))
+/// Without this endpoint, when JWT auth fails there is nowhere to forward
+/// the request to and the client receives a 404 error.
+#[get("/key/versions", rank = 2)]
+pub fn list_key_versions_jwt_failure() -> Result<JsonValue, ApiError> {
+    Err(ApiError::Unauthorized)
+}
+
+/// Handle a POST request with proper Bearer token to roll the private key back to an
+/// earlier version, which is stored again as the latest version.
+#[post("/key/rollback/<version>", rank = 1)]
+pub fn rollback_key(
+    version: u64,
+    claims: jwt::JWT,
+    key_store: State<Box<dyn KeyStore>>,
+) -> Result<JsonValue, ApiError> {
+    increment_http_req();
+    let username = jwt_username(&claims)?;
+
+    let new_version = key_store.rollback_key(username, version)?;
+    log_key_access(username, "rollback", Some(version));
+    match new_version {
+        Some(new_version) => Ok(json!({"status": "200", "version": new_version})),
+        None => Ok(json!({"status": "200"})),
     }
 }
 
-/// Attempt to read from an instance of HashiCorp Vault given the Vault URL and a valid client token.
-/// This reads from the Vault location `VAULT_PATH/user_id` and assumes a JSON response.
+/// If a key rollback fails due to JWT authentication issues,
+/// return a more specific error message.
 ///
-/// If the env var `VAULT_PATH` is not provided
-/// this will fail with an `InternalError`
-fn vault_read(url: String, token: String, user_id: String) -> Result<String, ApiError> {
-    if let Ok(path) = env::var("VAULT_PATH") {
-        let read_url = url + "v1/secret/" + &path + &user_id;
-        let client = reqwest::Client::new();
-        let res: serde_json::Value = client
-            .get(&read_url)
-            .header("X-Vault-Token", token)
-            .send()
-            .map_err(|e| ApiError::InternalError(e.to_string()))?
-            .json()
-            .map_err(|e| ApiError::InternalError(e.to_string()))?;
-        if let serde_json::Value::String(res_private_key) = &res["data"]["private_key"] {
-            Ok(res_private_key.to_string())
-        } else {
-            Err(ApiError::InternalError(
-                "Vault did not return the private key".to_string(),
-            ))
-        }
-    } else {
-        Err(ApiError::InternalError(
-            "No VAULT_PATH provided".to_string(),
-        ))
-    }
+/// Without this endpoint, when JWT auth fails there is nowhere to forward
+/// the request to and the client receives a 404 error.
+#[post("/key/rollback/<_version>", rank = 2)]
+pub fn rollback_key_jwt_failure(_version: u64) -> Result<JsonValue, ApiError> {
+    Err(ApiError::Unauthorized)
 }
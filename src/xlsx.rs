@@ -0,0 +1,131 @@
+use errors::ApiError;
+use std::io::{Cursor, Write};
+use xml::escape_xml;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+const CONTENT_TYPES: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types">
+<Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/>
+<Default Extension="xml" ContentType="application/xml"/>
+<Override PartName="/xl/workbook.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/>
+<Override PartName="/xl/worksheets/sheet1.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/>
+</Types>"#;
+
+const ROOT_RELS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+<Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/>
+</Relationships>"#;
+
+const WORKBOOK_RELS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+<Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet1.xml"/>
+</Relationships>"#;
+
+/// Writes `rows` to the only sheet of an XLSX workbook. Every cell is written as an inline
+/// string, so values are shown exactly as they are exported to CSV.
+pub fn write_workbook(sheet_name: &str, rows: &[Vec<String>]) -> Result<Vec<u8>, ApiError> {
+    let workbook = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+         <workbook xmlns=\"http://schemas.openxmlformats.org/spreadsheetml/2006/main\" \
+         xmlns:r=\"http://schemas.openxmlformats.org/officeDocument/2006/relationships\">\n\
+         <sheets><sheet name=\"{}\" sheetId=\"1\" r:id=\"rId1\"/></sheets>\n\
+         </workbook>",
+        escape_xml(sheet_name)
+    );
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let parts = [
+        ("[Content_Types].xml", CONTENT_TYPES.to_string()),
+        ("_rels/.rels", ROOT_RELS.to_string()),
+        ("xl/workbook.xml", workbook),
+        ("xl/_rels/workbook.xml.rels", WORKBOOK_RELS.to_string()),
+        ("xl/worksheets/sheet1.xml", write_sheet(rows)),
+    ];
+    for (name, contents) in parts.iter() {
+        zip.start_file(*name, FileOptions::default())
+            .map_err(|err| ApiError::InternalError(err.to_string()))?;
+        zip.write_all(contents.as_bytes())
+            .map_err(|err| ApiError::InternalError(err.to_string()))?;
+    }
+
+    zip.finish()
+        .map(Cursor::into_inner)
+        .map_err(|err| ApiError::InternalError(err.to_string()))
+}
+
+fn write_sheet(rows: &[Vec<String>]) -> String {
+    let mut sheet = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n\
+         <worksheet xmlns=\"http://schemas.openxmlformats.org/spreadsheetml/2006/main\">\n\
+         <sheetData>\n",
+    );
+    for (row_index, row) in rows.iter().enumerate() {
+        sheet.push_str(&format!("<row r=\"{}\">", row_index + 1));
+        for (column_index, value) in row.iter().enumerate() {
+            sheet.push_str(&format!(
+                "<c r=\"{}{}\" t=\"inlineStr\"><is><t xml:space=\"preserve\">{}</t></is></c>",
+                column_name(column_index),
+                row_index + 1,
+                escape_xml(value)
+            ));
+        }
+        sheet.push_str("</row>\n");
+    }
+    sheet.push_str("</sheetData>\n</worksheet>");
+    sheet
+}
+
+/// Returns the letters of a zero-based column index, e.g. `A`, `Z`, `AA`
+fn column_name(index: usize) -> String {
+    let mut name = Vec::new();
+    let mut remaining = index + 1;
+    while remaining > 0 {
+        let letter = (remaining - 1) % 26;
+        name.push(b'A' + letter as u8);
+        remaining = (remaining - 1) / 26;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use zip::ZipArchive;
+
+    #[test]
+    /// Test that column indexes are converted to spreadsheet column names
+    fn test_column_name() {
+        assert_eq!(column_name(0), "A");
+        assert_eq!(column_name(25), "Z");
+        assert_eq!(column_name(26), "AA");
+        assert_eq!(column_name(701), "ZZ");
+        assert_eq!(column_name(702), "AAA");
+    }
+
+    #[test]
+    /// Test that a workbook contains every part of the package and escaped cell values, without
+    /// the characters XML does not allow
+    fn test_write_workbook() {
+        let rows = vec![
+            vec!["name".to_string(), "country".to_string()],
+            vec!["Smith & Sons".to_string(), "Peru\u{1}".to_string()],
+        ];
+        let workbook = write_workbook("factories", &rows).unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(workbook)).unwrap();
+        assert_eq!(archive.len(), 5);
+        let mut sheet = String::new();
+        archive
+            .by_name("xl/worksheets/sheet1.xml")
+            .unwrap()
+            .read_to_string(&mut sheet)
+            .unwrap();
+        assert!(sheet.contains("<c r=\"A2\" t=\"inlineStr\"><is><t xml:space=\"preserve\">Smith &amp; Sons</t></is></c>"));
+        assert!(sheet.contains(
+            "<c r=\"B2\" t=\"inlineStr\"><is><t xml:space=\"preserve\">Peru</t></is></c>"
+        ));
+    }
+}
@@ -0,0 +1,51 @@
+/// Escapes `text` for the content or attribute values of an XML document, which also makes it
+/// safe to embed in HTML. Characters that XML 1.0 does not allow, like most control characters,
+/// are dropped, since no escape can represent them.
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c if is_xml_char(c) => escaped.push(c),
+            _ => (),
+        }
+    }
+    escaped
+}
+
+/// Whether `c` may appear in an XML 1.0 document
+fn is_xml_char(c: char) -> bool {
+    match c {
+        '\t' | '\n' | '\r' => true,
+        '\u{0}'..='\u{1F}' | '\u{FFFE}' | '\u{FFFF}' => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that markup characters are escaped
+    fn test_escape_xml() {
+        assert_eq!(
+            escape_xml("<b>\"Cotton\" & 'Wool'</b>"),
+            "&lt;b&gt;&quot;Cotton&quot; &amp; &apos;Wool&apos;&lt;/b&gt;"
+        );
+    }
+
+    #[test]
+    /// Test that characters XML 1.0 does not allow are dropped, and whitespace is kept
+    fn test_escape_xml_invalid_chars() {
+        assert_eq!(
+            escape_xml("a\u{0}b\u{8}c\u{B}d\u{C}e\u{E}f\u{1F}g\u{FFFF}"),
+            "abcdefg"
+        );
+        assert_eq!(escape_xml("a\tb\nc\rd é"), "a\tb\nc\rd é");
+    }
+}
@@ -0,0 +1,7 @@
+#!/bin/sh
+# Applies the REST API's own migrations once the chain tables above exist
+set -e
+
+for migration in /migrations/*/up.sql; do
+  psql -v ON_ERROR_STOP=1 --username "$POSTGRES_USER" --dbname "$POSTGRES_DB" -f "$migration"
+done